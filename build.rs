@@ -0,0 +1,63 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Country-only IP info built in with the embedded-geo feature
+const GEO_FILES: [&str; 2] = [
+    "geolite2-country-ipv4-num.csv.gz",
+    "geolite2-country-ipv6-num.csv.gz",
+];
+const GEO_BASE_URL: &str = "https://github.com/sapics/ip-location-db/raw/main/geolite2-country/";
+
+fn main() {
+    println!("cargo::rerun-if-changed=build.rs");
+    if env::var_os("CARGO_FEATURE_EMBEDDED_GEO").is_none() {
+        return;
+    }
+    println!("cargo::rerun-if-changed=geo");
+    let geo_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("geo");
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    for name in GEO_FILES {
+        let local = geo_dir.join(name);
+        let out = out_dir.join(name);
+        if local.exists() {
+            fs::copy(&local, &out).unwrap_or_else(|err| {
+                panic!("Failed to copy {}: {err}", local.display());
+            });
+        } else if !out.exists() {
+            download(&format!("{GEO_BASE_URL}{name}"), &out, &local);
+        }
+    }
+}
+
+/// Uses curl rather than an HTTP client build dependency, since this only runs for embedded-geo
+fn download(url: &str, out: &Path, local: &Path) {
+    let partial = out.with_extension("part");
+    let status = Command::new("curl")
+        .args([
+            "--fail",
+            "--location",
+            "--silent",
+            "--show-error",
+            "--output",
+        ])
+        .arg(&partial)
+        .arg(url)
+        .status();
+    match status {
+        Ok(status) if status.success() => fs::rename(&partial, out).unwrap(),
+        result => {
+            let _ = fs::remove_file(&partial);
+            let reason = match result {
+                Ok(status) => format!("curl exited with {status}"),
+                Err(err) => format!("couldn't run curl: {err}"),
+            };
+            panic!(
+                "The embedded-geo feature needs {url}, but it couldn't be downloaded ({reason}). \
+                Download it to {} and build again.",
+                local.display()
+            );
+        }
+    }
+}
@@ -1,26 +1,64 @@
 use crate::authlib::environment::{Environment, PROD_ENVIRONMENT};
 use crate::authlib::session_service::YggdrasilMinecraftSessionService;
+use crate::server_state::FullServerConfig;
 use log::info;
+use reqwest::Url;
+use std::time::Duration;
 
 pub struct YggdrasilAuthenticationService<'a> {
-    environment: Environment<'a>,
+    /// Tried in order when verifying a player
+    environments: Vec<Environment<'a>>,
+    timeout: Duration,
 }
 
 impl<'a> YggdrasilAuthenticationService<'a> {
-    pub fn new() -> Self {
-        Self::new_with_environment(determine_environment())
+    pub fn new(config: &'a FullServerConfig) -> Self {
+        Self::new_with_environments(determine_environments(config), config.auth_timeout)
     }
 
-    pub fn new_with_environment(environment: Environment<'a>) -> Self {
-        info!("Environment: {environment:?}");
-        YggdrasilAuthenticationService { environment }
+    pub fn new_with_environments(environments: Vec<Environment<'a>>, timeout: Duration) -> Self {
+        for environment in &environments {
+            info!("Environment: {environment:?}");
+        }
+        YggdrasilAuthenticationService {
+            environments,
+            timeout,
+        }
     }
 
     pub fn create_session_service(&self) -> YggdrasilMinecraftSessionService {
-        YggdrasilMinecraftSessionService::new(&self.environment)
+        YggdrasilMinecraftSessionService::new(&self.environments, self.timeout)
     }
 }
 
-fn determine_environment<'a>() -> Environment<'a> {
-    PROD_ENVIRONMENT
+/// Uses Mojang's endpoints unless --session-host or --services-host replace them. Each
+/// --session-host is its own environment.
+fn determine_environments(config: &FullServerConfig) -> Vec<Environment<'_>> {
+    if config.session_hosts.is_empty() && config.services_host.is_none() {
+        return vec![PROD_ENVIRONMENT];
+    }
+    let services_host = config
+        .services_host
+        .as_ref()
+        .map_or(PROD_ENVIRONMENT.services_host, trim_url);
+    if config.session_hosts.is_empty() {
+        return vec![Environment {
+            services_host,
+            name: "CUSTOM",
+            ..PROD_ENVIRONMENT
+        }];
+    }
+    config
+        .session_hosts
+        .iter()
+        .map(|session_host| Environment {
+            session_host: trim_url(session_host),
+            services_host,
+            name: "CUSTOM",
+        })
+        .collect()
+}
+
+fn trim_url(url: &Url) -> &str {
+    url.as_str().trim_end_matches('/')
 }
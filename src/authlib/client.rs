@@ -1,34 +1,212 @@
 use crate::USER_AGENT;
-use reqwest::IntoUrl;
+use anyhow::anyhow;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{IntoUrl, StatusCode};
 use serde::de::DeserializeOwned;
+use std::fmt::{Display, Formatter};
 use std::time::Duration;
 
 pub struct MinecraftClient {
     client: reqwest::Client,
 }
 
+/// A request that failed without a definitive answer from the server
+#[derive(Debug)]
+pub enum RequestError {
+    /// The same request might succeed if tried again, such as after a timeout or a 5xx
+    Transient(anyhow::Error),
+    /// The server responded with 429, optionally saying how long to wait with Retry-After
+    RateLimited(Option<Duration>),
+    Fatal(anyhow::Error),
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Transient(error) | RequestError::Fatal(error) => error.fmt(f),
+            RequestError::RateLimited(Some(retry_after)) => {
+                write!(f, "Rate limited for {retry_after:?}")
+            }
+            RequestError::RateLimited(None) => f.write_str("Rate limited"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl RequestError {
+    fn transient(error: impl Into<anyhow::Error>) -> Self {
+        RequestError::Transient(error.into())
+    }
+
+    fn fatal(error: impl Into<anyhow::Error>) -> Self {
+        RequestError::Fatal(error.into())
+    }
+}
+
+/// A definitive answer from the server
+pub enum AuthResponse<T> {
+    Found(T),
+    /// The server answered successfully with an empty body, such as when a player hasn't joined
+    NotFound,
+}
+
 impl MinecraftClient {
-    pub fn unauthenticated() -> Self {
+    pub fn unauthenticated(timeout: Duration) -> Self {
         let client = reqwest::ClientBuilder::new()
-            .connect_timeout(Duration::from_millis(5000))
-            .read_timeout(Duration::from_millis(5000))
+            .connect_timeout(timeout)
+            .read_timeout(timeout)
             .user_agent(USER_AGENT)
             .build()
             .unwrap();
         MinecraftClient { client }
     }
 
-    pub async fn get<T: DeserializeOwned, U: IntoUrl>(&self, url: U) -> anyhow::Result<Option<T>> {
-        let response = self.client.get(url).send().await?;
+    /// Only a successful response with an empty body is [AuthResponse::NotFound]. Connection
+    /// errors, timeouts, and 5xx responses are transient errors, and other 4xx responses are fatal
+    /// errors that include the status and the start of the body.
+    pub async fn get<T: DeserializeOwned, U: IntoUrl>(
+        &self,
+        url: U,
+        timeout: Duration,
+    ) -> Result<AuthResponse<T>, RequestError> {
+        let response = self
+            .client
+            .get(url)
+            .timeout(timeout)
+            .send()
+            .await
+            .map_err(RequestError::transient)?;
         let status = response.status();
-        if status.as_u16() < 400 {
-            let result = response.bytes().await?;
-            if result.is_empty() {
-                return Ok(None);
-            }
-            Ok(Some(serde_json::from_slice(&result)?))
-        } else {
-            Ok(None)
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            // Only the delay-seconds form is used by the session server
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok()?.trim().parse().ok())
+                .map(Duration::from_secs);
+            return Err(RequestError::RateLimited(retry_after));
+        }
+        let body = response.bytes().await.map_err(RequestError::transient)?;
+        if !status.is_success() {
+            let error = anyhow!("Server responded with {status}: {}", body_snippet(&body));
+            return Err(if status.is_server_error() {
+                RequestError::Transient(error)
+            } else {
+                RequestError::Fatal(error)
+            });
+        }
+        if body.is_empty() {
+            return Ok(AuthResponse::NotFound);
+        }
+        serde_json::from_slice(&body)
+            .map(AuthResponse::Found)
+            .map_err(|error| {
+                RequestError::fatal(anyhow!(
+                    "Invalid response ({error}): {}",
+                    body_snippet(&body)
+                ))
+            })
+    }
+}
+
+/// The start of a response body, for error messages
+fn body_snippet(body: &[u8]) -> String {
+    const MAX_SNIPPET_LENGTH: usize = 200;
+    let body = String::from_utf8_lossy(body);
+    match body.char_indices().nth(MAX_SNIPPET_LENGTH) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authlib::mock_session_server::{MockResponse, MockSessionServer};
+    use serde_json::Value;
+    use std::net::Ipv4Addr;
+    use tokio::net::TcpListener;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn get(url: impl IntoUrl) -> Result<AuthResponse<Value>, RequestError> {
+        MinecraftClient::unauthenticated(TIMEOUT)
+            .get(url, TIMEOUT)
+            .await
+    }
+
+    async fn respond(response: MockResponse) -> Result<AuthResponse<Value>, RequestError> {
+        let server = MockSessionServer::start(vec![response]).await;
+        get(server.url.clone()).await
+    }
+
+    #[tokio::test]
+    async fn responses() {
+        assert!(matches!(
+            respond(MockResponse::Body(200, r#"{"id":1}"#.to_string())).await,
+            Ok(AuthResponse::Found(value)) if value["id"] == 1
+        ));
+        assert!(matches!(
+            respond(MockResponse::NotJoined).await,
+            Ok(AuthResponse::NotFound)
+        ));
+        assert!(matches!(
+            respond(MockResponse::Status(204)).await,
+            Ok(AuthResponse::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn error_responses() {
+        match respond(MockResponse::Body(404, "No such profile".to_string())).await {
+            Err(RequestError::Fatal(error)) => assert_eq!(
+                error.to_string(),
+                "Server responded with 404 Not Found: No such profile"
+            ),
+            _ => panic!("Expected a fatal error"),
+        }
+        match respond(MockResponse::Body(403, "x".repeat(300))).await {
+            Err(RequestError::Fatal(error)) => assert_eq!(
+                error.to_string(),
+                format!(
+                    "Server responded with 403 Forbidden: {}...",
+                    "x".repeat(200)
+                )
+            ),
+            _ => panic!("Expected a fatal error"),
         }
+        assert!(matches!(
+            respond(MockResponse::Body(200, "not json".to_string())).await,
+            Err(RequestError::Fatal(_))
+        ));
+        match respond(MockResponse::Body(500, "Oops".to_string())).await {
+            Err(RequestError::Transient(error)) => assert_eq!(
+                error.to_string(),
+                "Server responded with 500 Internal Server Error: Oops"
+            ),
+            _ => panic!("Expected a transient error"),
+        }
+        assert!(matches!(
+            respond(MockResponse::Status(503)).await,
+            Err(RequestError::Transient(_))
+        ));
+        assert!(matches!(
+            respond(MockResponse::RateLimited(Some(7))).await,
+            Err(RequestError::RateLimited(Some(retry_after))) if retry_after == Duration::from_secs(7)
+        ));
+    }
+
+    #[tokio::test]
+    async fn refused_connection() {
+        let addr = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        assert!(matches!(
+            get(format!("http://{addr}")).await,
+            Err(RequestError::Transient(_))
+        ));
     }
 }
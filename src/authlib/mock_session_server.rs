@@ -0,0 +1,107 @@
+//! A session server for tests that answers hasJoined requests with scripted responses
+
+use reqwest::Url;
+use std::future::pending;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use uuid::Uuid;
+
+pub enum MockResponse {
+    Joined(Uuid),
+    /// A successful response with an empty body
+    NotJoined,
+    Status(u16),
+    /// A response with a status and body
+    Body(u16, String),
+    /// 429, optionally with a Retry-After in seconds
+    RateLimited(Option<u64>),
+    /// Accepts the request but never answers
+    Hang,
+}
+
+pub struct MockSessionServer {
+    pub url: Url,
+    /// The path and query of every request, in order
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockSessionServer {
+    /// Responses are used in order, and the last is repeated once they run out
+    pub async fn start(responses: Vec<MockResponse>) -> Self {
+        assert!(!responses.is_empty());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn(serve(listener, responses, requests.clone()));
+        Self { url, requests }
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+async fn serve(
+    listener: TcpListener,
+    responses: Vec<MockResponse>,
+    requests: Arc<Mutex<Vec<String>>>,
+) {
+    loop {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut socket = BufReader::new(socket);
+        let Some(target) = read_request(&mut socket).await else {
+            continue;
+        };
+        let index = {
+            let mut requests = requests.lock().unwrap();
+            requests.push(target);
+            requests.len() - 1
+        };
+        let response = &responses[index.min(responses.len() - 1)];
+        let (status, headers, body) = match response {
+            MockResponse::Joined(uuid) => (
+                200,
+                String::new(),
+                format!(r#"{{"id":"{}"}}"#, uuid.simple()),
+            ),
+            MockResponse::NotJoined => (200, String::new(), String::new()),
+            MockResponse::Status(status) => (*status, String::new(), String::new()),
+            MockResponse::Body(status, body) => (*status, String::new(), body.clone()),
+            MockResponse::RateLimited(retry_after) => (
+                429,
+                retry_after.map_or(String::new(), |secs| format!("Retry-After: {secs}\r\n")),
+                String::new(),
+            ),
+            MockResponse::Hang => {
+                tokio::spawn(async move {
+                    let _socket = socket;
+                    pending::<()>().await
+                });
+                continue;
+            }
+        };
+        let response = format!(
+            "HTTP/1.1 {status} Mock\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        let _ = socket.get_mut().write_all(response.as_bytes()).await;
+    }
+}
+
+/// Reads the request head, returning its target
+async fn read_request(socket: &mut BufReader<TcpStream>) -> Option<String> {
+    let mut request_line = String::new();
+    socket.read_line(&mut request_line).await.ok()?;
+    let target = request_line.split(' ').nth(1)?.to_string();
+    loop {
+        let mut line = String::new();
+        if socket.read_line(&mut line).await.ok()? == 0 || line == "\r\n" {
+            break;
+        }
+    }
+    Some(target)
+}
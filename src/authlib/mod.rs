@@ -1,5 +1,7 @@
 pub mod auth_service;
 mod client;
 pub mod environment;
+#[cfg(test)]
+pub mod mock_session_server;
 mod response;
 pub mod session_service;
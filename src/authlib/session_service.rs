@@ -1,33 +1,381 @@
-use crate::authlib::client::MinecraftClient;
+use crate::authlib::client::{AuthResponse, MinecraftClient, RequestError};
 use crate::authlib::environment::Environment;
 use crate::authlib::response::HasJoinedMinecraftServerResponse;
+use anyhow::bail;
+use log::warn;
+use rand::Rng;
 use reqwest::Url;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::time::{Instant, sleep, sleep_until};
 use uuid::Uuid;
 
+/// Attempts made for transient errors before giving up
+const MAX_ATTEMPTS: u32 = 3;
+/// Delay before the first retry. Each retry doubles it, plus up to 50% jitter.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+/// How long to wait after a 429 without a Retry-After header
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+/// Default limit on all attempts to all servers together, so that a handshake can't wait on the
+/// session servers forever
+const TOTAL_BUDGET: Duration = Duration::from_secs(4);
+
 pub struct YggdrasilMinecraftSessionService {
     client: MinecraftClient,
+    /// One per environment, tried in order
+    servers: Vec<SessionServer>,
+    /// Limit on all attempts to all servers together
+    total_budget: Duration,
+}
+
+struct SessionServer {
     check_url: Url,
+    /// Set when the server rate limits us, so that other handshakes wait it out instead of
+    /// sending more requests
+    cooldown_until: Mutex<Option<Instant>>,
+}
+
+impl SessionServer {
+    fn cooldown_until(&self) -> Option<Instant> {
+        let cooldown_until = *self.cooldown_until.lock().unwrap();
+        cooldown_until.filter(|&until| until > Instant::now())
+    }
+
+    fn start_cooldown(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut cooldown_until = self.cooldown_until.lock().unwrap();
+        if cooldown_until.is_none_or(|current| current < until) {
+            *cooldown_until = Some(until);
+        }
+    }
 }
 
 impl YggdrasilMinecraftSessionService {
-    pub fn new(env: &Environment) -> Self {
-        let base_url = format!("{}/session/minecraft/", env.session_host);
+    /// `timeout` is used for connecting and for reading. The total budget is extended to fit at
+    /// least one attempt.
+    pub fn new(environments: &[Environment], timeout: Duration) -> Self {
         Self {
-            client: MinecraftClient::unauthenticated(),
-            check_url: format!("{base_url}hasJoined").parse().unwrap(),
+            client: MinecraftClient::unauthenticated(timeout),
+            total_budget: TOTAL_BUDGET.max(timeout),
+            servers: environments
+                .iter()
+                .map(|env| SessionServer {
+                    check_url: format!("{}/session/minecraft/hasJoined", env.session_host)
+                        .parse()
+                        .unwrap(),
+                    cooldown_until: Mutex::new(None),
+                })
+                .collect(),
         }
     }
 
+    #[cfg(test)]
+    fn with_total_budget(self, total_budget: Duration) -> Self {
+        Self {
+            total_budget,
+            ..self
+        }
+    }
+
+    /// Asks each session server in order, returning the first that knows the player. `None` means
+    /// at least one server definitively didn't, and an error means every server failed or rate
+    /// limited us. The budget is split evenly between the servers. If `ip` is passed, players with
+    /// prevent-proxy-connections enabled are only verified if they logged in from that address.
     pub async fn has_joined_server(
         &self,
         profile_name: &str,
         server_id: &str,
+        ip: Option<IpAddr>,
+        retries: &AtomicU64,
     ) -> anyhow::Result<Option<Uuid>> {
-        let arguments = vec![("username", profile_name), ("serverId", server_id)];
-        let url = format!("{}?{}", self.check_url, querystring::stringify(arguments));
-        self.client
-            .get::<HasJoinedMinecraftServerResponse, _>(url)
+        let budget = self.total_budget / self.servers.len() as u32;
+        let mut not_joined = false;
+        let mut last_error = None;
+        for server in &self.servers {
+            let mut url = server.check_url.clone();
+            {
+                let mut query = url.query_pairs_mut();
+                query
+                    .append_pair("username", profile_name)
+                    .append_pair("serverId", server_id);
+                if let Some(ip) = ip {
+                    query.append_pair("ip", &ip.to_canonical().to_string());
+                }
+            }
+            match self
+                .check_with_retries(server, url, profile_name, budget, retries)
+                .await
+            {
+                Ok(Some(uuid)) => return Ok(Some(uuid)),
+                Ok(None) => not_joined = true,
+                Err(error) => {
+                    if self.servers.len() > 1 {
+                        warn!(
+                            "Session server {} failed to verify {profile_name}: {error}",
+                            server.check_url
+                        );
+                    }
+                    last_error = Some(error);
+                }
+            }
+        }
+        match last_error {
+            Some(error) if !not_joined => Err(error),
+            _ => Ok(None),
+        }
+    }
+
+    /// Retries transient errors with backoff, and a rate limit once after its Retry-After. Each
+    /// retry increments `retries`.
+    async fn check_with_retries(
+        &self,
+        server: &SessionServer,
+        url: Url,
+        profile_name: &str,
+        budget: Duration,
+        retries: &AtomicU64,
+    ) -> anyhow::Result<Option<Uuid>> {
+        let deadline = Instant::now() + budget;
+        let mut delay = RETRY_BASE_DELAY;
+        let mut attempt = 1;
+        let mut rate_limit_retried = false;
+        loop {
+            if let Some(until) = server.cooldown_until() {
+                if until >= deadline {
+                    bail!("Rate limited by the session server");
+                }
+                sleep_until(until).await;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let error = match self
+                .client
+                .get::<HasJoinedMinecraftServerResponse, _>(url.clone(), remaining)
+                .await
+            {
+                Ok(AuthResponse::Found(response)) => return Ok(Some(response.id)),
+                Ok(AuthResponse::NotFound) => return Ok(None),
+                Err(error) => error,
+            };
+            let wait = match &error {
+                RequestError::Fatal(_) => return Err(error.into()),
+                RequestError::RateLimited(retry_after) => {
+                    let retry_after = retry_after.unwrap_or(DEFAULT_RETRY_AFTER);
+                    server.start_cooldown(retry_after);
+                    if rate_limit_retried {
+                        return Err(error.into());
+                    }
+                    rate_limit_retried = true;
+                    retry_after
+                }
+                RequestError::Transient(_) => {
+                    if attempt >= MAX_ATTEMPTS {
+                        return Err(error.into());
+                    }
+                    attempt += 1;
+                    let jitter = delay.mul_f64(rand::thread_rng().gen_range(0.0..0.5));
+                    let wait = delay + jitter;
+                    delay *= 2;
+                    wait
+                }
+            };
+            if Instant::now() + wait >= deadline {
+                return Err(error.into());
+            }
+            warn!("Failed to verify {profile_name}, retrying in {wait:?}: {error}");
+            retries.fetch_add(1, Ordering::Relaxed);
+            sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authlib::environment::PROD_ENVIRONMENT;
+    use crate::authlib::mock_session_server::{MockResponse, MockSessionServer};
+    use std::net::Ipv4Addr;
+
+    const PLAYER: Uuid = Uuid::from_u128(0x12345678_1234_4234_8234_123456789abc);
+
+    fn session_service(servers: &[&MockSessionServer]) -> YggdrasilMinecraftSessionService {
+        session_service_with_timeout(servers, Duration::from_secs(1))
+    }
+
+    fn session_service_with_timeout(
+        servers: &[&MockSessionServer],
+        timeout: Duration,
+    ) -> YggdrasilMinecraftSessionService {
+        let hosts: Vec<_> = servers
+            .iter()
+            .map(|server| server.url.as_str().trim_end_matches('/').to_string())
+            .collect();
+        let environments: Vec<_> = hosts
+            .iter()
+            .map(|host| Environment {
+                session_host: host,
+                services_host: PROD_ENVIRONMENT.services_host,
+                name: "TEST",
+            })
+            .collect();
+        YggdrasilMinecraftSessionService::new(&environments, timeout)
+    }
+
+    async fn has_joined(
+        service: &YggdrasilMinecraftSessionService,
+        ip: Option<IpAddr>,
+    ) -> anyhow::Result<Option<Uuid>> {
+        service
+            .has_joined_server("Steve", "server-id", ip, &AtomicU64::new(0))
             .await
-            .map(|o| o.map(|r| r.id))
+    }
+
+    #[tokio::test]
+    async fn sends_canonical_ip() {
+        let server = MockSessionServer::start(vec![MockResponse::Joined(PLAYER)]).await;
+        let service = session_service(&[&server]);
+        let mapped = Ipv4Addr::new(203, 0, 113, 5).to_ipv6_mapped();
+        assert_eq!(
+            has_joined(&service, Some(mapped.into())).await.unwrap(),
+            Some(PLAYER)
+        );
+        assert_eq!(has_joined(&service, None).await.unwrap(), Some(PLAYER));
+        assert_eq!(
+            server.requests(),
+            [
+                "/session/minecraft/hasJoined?username=Steve&serverId=server-id&ip=203.0.113.5",
+                "/session/minecraft/hasJoined?username=Steve&serverId=server-id",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_next_server_after_error() {
+        let failing = MockSessionServer::start(vec![MockResponse::Status(403)]).await;
+        let working = MockSessionServer::start(vec![MockResponse::Joined(PLAYER)]).await;
+        let service = session_service(&[&failing, &working]);
+        assert_eq!(has_joined(&service, None).await.unwrap(), Some(PLAYER));
+        assert_eq!(failing.requests().len(), 1);
+        assert_eq!(working.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_next_server_that_knows_player() {
+        let unaware = MockSessionServer::start(vec![MockResponse::NotJoined]).await;
+        let aware = MockSessionServer::start(vec![MockResponse::Joined(PLAYER)]).await;
+        let service = session_service(&[&unaware, &aware]);
+        assert_eq!(has_joined(&service, None).await.unwrap(), Some(PLAYER));
+    }
+
+    #[tokio::test]
+    async fn first_server_that_knows_player_wins() {
+        let first = MockSessionServer::start(vec![MockResponse::Joined(PLAYER)]).await;
+        let second = MockSessionServer::start(vec![MockResponse::Joined(PLAYER)]).await;
+        let service = session_service(&[&first, &second]);
+        assert_eq!(has_joined(&service, None).await.unwrap(), Some(PLAYER));
+        assert!(second.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn not_joined_beats_errors() {
+        let failing = MockSessionServer::start(vec![MockResponse::Status(403)]).await;
+        let unaware = MockSessionServer::start(vec![MockResponse::NotJoined]).await;
+        let service = session_service(&[&failing, &unaware]);
+        assert_eq!(has_joined(&service, None).await.unwrap(), None);
+
+        let service = session_service(&[&failing, &failing]);
+        assert!(has_joined(&service, None).await.is_err());
+    }
+
+    /// Returns the result and how many retries were counted
+    async fn has_joined_counting_retries(
+        service: &YggdrasilMinecraftSessionService,
+    ) -> (anyhow::Result<Option<Uuid>>, u64) {
+        let retries = AtomicU64::new(0);
+        let result = service
+            .has_joined_server("Steve", "server-id", None, &retries)
+            .await;
+        (result, retries.load(Ordering::Relaxed))
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors() {
+        let server = MockSessionServer::start(vec![
+            MockResponse::Status(500),
+            MockResponse::Status(503),
+            MockResponse::Joined(PLAYER),
+        ])
+        .await;
+        let (result, retries) = has_joined_counting_retries(&session_service(&[&server])).await;
+        assert_eq!(result.unwrap(), Some(PLAYER));
+        assert_eq!(retries, 2);
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let server = MockSessionServer::start(vec![MockResponse::Status(500)]).await;
+        let (result, retries) = has_joined_counting_retries(&session_service(&[&server])).await;
+        assert!(result.is_err());
+        assert_eq!(retries, MAX_ATTEMPTS as u64 - 1);
+        assert_eq!(server.requests().len(), MAX_ATTEMPTS as usize);
+    }
+
+    #[tokio::test]
+    async fn fatal_errors_not_retried() {
+        let server = MockSessionServer::start(vec![MockResponse::Status(403)]).await;
+        let (result, retries) = has_joined_counting_retries(&session_service(&[&server])).await;
+        assert!(result.is_err());
+        assert_eq!(retries, 0);
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_retried_once() {
+        let server = MockSessionServer::start(vec![
+            MockResponse::RateLimited(Some(0)),
+            MockResponse::Joined(PLAYER),
+        ])
+        .await;
+        let (result, _) = has_joined_counting_retries(&session_service(&[&server])).await;
+        assert_eq!(result.unwrap(), Some(PLAYER));
+
+        let server = MockSessionServer::start(vec![MockResponse::RateLimited(Some(0))]).await;
+        let (result, _) = has_joined_counting_retries(&session_service(&[&server])).await;
+        assert!(result.is_err());
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_longer_than_budget_fails_fast() {
+        let server = MockSessionServer::start(vec![MockResponse::RateLimited(Some(60))]).await;
+        let service = session_service(&[&server]);
+        let start = std::time::Instant::now();
+        let (result, retries) = has_joined_counting_retries(&service).await;
+        assert!(result.is_err());
+        assert_eq!(retries, 0);
+        // The cooldown is shared, so the next verification doesn't ask at all
+        let (result, _) = has_joined_counting_retries(&service).await;
+        assert!(result.is_err());
+        assert_eq!(server.requests().len(), 1);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn hanging_servers_limited_by_budget() {
+        let budget = Duration::from_millis(800);
+        let first = MockSessionServer::start(vec![MockResponse::Hang]).await;
+        let second = MockSessionServer::start(vec![MockResponse::Hang]).await;
+        let service = session_service_with_timeout(&[&first, &second], Duration::from_millis(100))
+            .with_total_budget(budget);
+        let start = std::time::Instant::now();
+        let (result, _) = has_joined_counting_retries(&service).await;
+        let elapsed = start.elapsed();
+        assert!(result.is_err());
+        // Each server gets half of the budget, so both are asked
+        assert!(!first.requests().is_empty());
+        assert!(!second.requests().is_empty());
+        assert!(elapsed < budget + Duration::from_millis(300), "{elapsed:?}");
     }
 }
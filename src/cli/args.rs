@@ -1,5 +1,15 @@
-use crate::cli::parser::DurationValueParser;
+use crate::cli::parser::{
+    DurationValueParser, MessageNameValueParser, RateLimitValueParser, auth_timeout_in_range,
+};
+use crate::modules::analytics::AnalyticsRotation;
+use crate::protocol::compat::CompatMode;
+use crate::protocol::join_type::JoinTypeKind;
+use crate::ratelimit::bucket::{DEFAULT_RATE_LIMITS, RateLimitSpec};
+use crate::util::asn_map::DEFAULT_HOSTING_ASNS;
 use clap::Parser;
+use clap::builder::TypedValueParser;
+use reqwest::Url;
+use std::path::PathBuf;
 use std::time::Duration;
 
 #[derive(Parser, Debug)]
@@ -9,6 +19,19 @@ pub struct Args {
     #[arg(short, long, default_value = "9646")]
     pub port: u16,
 
+    /// PEM certificate chain to accept TLS connections with on --tls-port
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Port to accept TLS connections on with --tls-cert. Plaintext connections are still accepted
+    /// on --port.
+    #[arg(long, default_value = "9647")]
+    pub tls_port: u16,
+
     /// Base address to use for proxy connections
     #[arg(short = 'a', long)]
     pub base_addr: Option<String>,
@@ -21,15 +44,245 @@ pub struct Args {
     #[arg(short = 'J', long)]
     pub ex_java_port: Option<u16>,
 
+    /// Join types that hosts may grant
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "upnp,proxy,punch"
+    )]
+    pub allowed_join_types: Vec<JoinTypeKind>,
+
+    /// Use Proxy joins when a host grants a UPnP join and UPnP joins aren't allowed
+    #[arg(long)]
+    pub substitute_join_types: bool,
+
+    /// Deliver joins granted by hosts even if the joining connection didn't request them. Only
+    /// for compatibility with clients that grant joins unprompted.
+    #[arg(long)]
+    pub allow_unrequested_joins: bool,
+
+    /// Mirror the observable behavior of another server implementation, for clients with
+    /// workarounds tuned to it. See the README for what each mode changes.
+    #[arg(long, value_enum)]
+    pub compat: Option<CompatMode>,
+
+    /// Answer RequestDirectJoin with ConnectionNotFound if the host published its world to
+    /// friends that don't include the requester
+    #[arg(long)]
+    pub friends_only_direct_joins: bool,
+
+    /// Maximum number of friends in ListOnline, PublishedWorld and QueryRequest
+    #[arg(long, default_value = "1024", value_parser = clap::value_parser!(u32).range(1..=8192))]
+    pub max_friends: u32,
+
+    /// Most friends a connection's world can be published to. Friends published past this are
+    /// ignored.
+    #[arg(long, default_value = "4096", value_parser = clap::value_parser!(u32).range(1..=65536))]
+    pub max_open_to_friends: u32,
+
+    /// Accept usernames with any characters other than control characters, instead of only
+    /// letters, digits, and underscores. Usernames are still limited to 16 characters.
+    #[arg(long)]
+    pub relaxed_usernames: bool,
+
+    /// Base URL of the session server used to verify players, for authlib-injector setups.
+    /// Defaults to Mojang's. Pass multiple times to try each in order until one knows the player.
+    #[arg(
+        long = "session-host",
+        env = "WORLD_HOST_SESSION_HOST",
+        value_delimiter = ','
+    )]
+    pub session_hosts: Vec<Url>,
+
+    /// Base URL of the Minecraft services API, for authlib-injector setups. Defaults to Mojang's.
+    #[arg(long, env = "WORLD_HOST_SERVICES_HOST")]
+    pub services_host: Option<Url>,
+
+    /// Don't verify players with the session server, for deployments without internet access.
+    /// Anyone can connect as anyone.
+    #[arg(long)]
+    pub offline_mode: bool,
+
+    /// Send players' addresses to the session server, so that players with
+    /// prevent-proxy-connections enabled must connect from the address they logged in from. Off by
+    /// default, since NAT and proxies can make the addresses differ.
+    #[arg(long)]
+    pub verify_client_ip: bool,
+
+    /// Reject players that can't be verified because the session server is unreachable, instead
+    /// of allowing them anyway. Also disables --auth-cache-time.
+    #[arg(long, conflicts_with = "offline_mode")]
+    pub strict_auth: bool,
+
+    /// Connect and read timeout for session server requests, up to 60s. All attempts,
+    /// including retries, are limited to this or 4s, whichever is longer.
+    #[arg(long, default_value = "5s", value_parser = DurationValueParser.try_map(auth_timeout_in_range))]
+    pub auth_timeout: Duration,
+
+    /// How long a successful login verification is remembered for reconnects of the same player
+    /// from the same address. 0s disables this.
+    #[arg(long, default_value = "60s", value_parser = DurationValueParser)]
+    pub auth_cache_time: Duration,
+
+    /// Amount of time a new connection has to receive its setup messages
+    #[arg(long, default_value = "10s", value_parser = DurationValueParser)]
+    pub setup_timeout: Duration,
+
+    /// Close connections whose setup advisories (warnings about outdated or insecure clients)
+    /// can't be delivered within --setup-timeout, instead of continuing without them
+    #[arg(long)]
+    pub require_setup_advisories: bool,
+
+    /// Largest proxy packet a host may send, in bytes. Larger packets are dropped.
+    #[arg(long, default_value = "262144", value_parser = clap::value_parser!(u32).range(65536..=1048576))]
+    pub max_proxy_packet_size: u32,
+
     /// Amount of time between analytics syncs
     #[arg(long, default_value = "0m", value_parser = DurationValueParser)]
     pub analytics_time: Duration,
 
+    /// File to write analytics to. Parent directories are created if missing.
+    #[arg(long, default_value = "analytics.csv")]
+    pub analytics_file: PathBuf,
+
+    /// Maximum number of countries listed in each analytics sample. The rest are summed into
+    /// "other".
+    #[arg(long, default_value = "50")]
+    pub analytics_max_countries: usize,
+
+    /// Also count connections per 1-degree latitude/longitude cell in analytics. Off by default
+    /// for privacy.
+    #[arg(long)]
+    pub analytics_grid: bool,
+
+    /// Maximum number of cells listed in each analytics sample with --analytics-grid. The rest are
+    /// summed into "other".
+    #[arg(long, default_value = "100")]
+    pub analytics_max_grid_cells: usize,
+
+    /// Maximum number of client brands listed in each analytics sample. The rest are summed into
+    /// "other".
+    #[arg(long, default_value = "20")]
+    pub analytics_max_brands: usize,
+
+    /// When to rotate the analytics file into analytics-YYYY-MM-DD.csv
+    #[arg(long, value_enum, default_value_t = AnalyticsRotation::Off)]
+    pub analytics_rotation: AnalyticsRotation,
+
+    /// Size in bytes at which the analytics file is rotated with --analytics-rotation size
+    #[arg(long, default_value = "10485760")]
+    pub analytics_rotation_size: u64,
+
+    /// Gzip rotated analytics files
+    #[arg(long)]
+    pub analytics_gzip: bool,
+
+    /// URL to POST each analytics sample to as JSON
+    #[arg(long)]
+    pub analytics_webhook: Option<Url>,
+
+    /// Secret sent as a bearer token with --analytics-webhook requests
+    #[arg(long, requires = "analytics_webhook")]
+    pub analytics_webhook_secret: Option<String>,
+
     /// The amount of time before the server automatically shuts down. Useful for restart scripts.
     #[arg(long, value_parser = DurationValueParser)]
     pub shutdown_time: Option<Duration>,
 
+    /// Message types to log at debug level, such as ListOnline,FriendRequest. All types are logged
+    /// if this isn't passed. Payloads are only logged as their length.
+    #[arg(long, value_delimiter = ',', value_parser = MessageNameValueParser)]
+    pub debug_messages: Option<Vec<&'static str>>,
+
+    /// Connection rate limits per IP, as name:count/duration, such as per_minute:20/60s. Fixed
+    /// windows by default, or smoothly refilled with a :token-bucket suffix. A connection is
+    /// refused if any bucket is exceeded.
+    #[arg(
+        long = "rate-limit",
+        value_delimiter = ',',
+        default_value = DEFAULT_RATE_LIMITS,
+        value_parser = RateLimitValueParser
+    )]
+    pub rate_limits: Vec<RateLimitSpec>,
+
+    /// Don't rate limit connections at all
+    #[arg(long, conflicts_with = "rate_limits")]
+    pub no_rate_limit: bool,
+
+    /// Port to accept admin HTTP requests on, such as POST
+    /// /users/{uuid}/redeliver-friend-requests. Only bound on localhost. Off if this isn't passed.
+    #[arg(long)]
+    pub admin_port: Option<u16>,
+
+    /// How long friend requests delivered to online users are kept, so that the admin API can
+    /// replay ones the client lost and cancelling them is forwarded. 0s disables this.
+    #[arg(long, default_value = "24h", value_parser = DurationValueParser)]
+    pub friend_request_retention: Duration,
+
+    /// File of 16384 words to build connection IDs from, one per line, instead of the built-in
+    /// English list. Words may only contain lowercase ASCII letters and digits
+    #[arg(long)]
+    pub cid_wordlist: Option<PathBuf>,
+
+    /// GeoLite2 City CSVs (like sapics/ip-location-db's geolite2-city-*-num files) to load IP info
+    /// from, optionally gzipped. http and https URLs are downloaded, and anything else is read as
+    /// a path. Defaults to downloading sapics/ip-location-db's IPv4 and IPv6 files.
+    #[arg(long = "ip-info-source", value_delimiter = ',')]
+    pub ip_info_sources: Option<Vec<String>>,
+
+    /// Store IP info locations to about 0.0055° instead of 0.18°, at the cost of 4 more bytes per
+    /// range. Has no effect on --ip-info-mmdb.
+    #[arg(long)]
+    pub precise_locations: bool,
+
+    /// Exit at startup if an IP info source fails to load, or if more than 10% of its records fail
+    /// to parse. Otherwise, these are logged and the server starts without them.
+    #[arg(long)]
+    pub strict_geo: bool,
+
+    /// ASN CSVs (like sapics/ip-location-db's asn-*-num files) to classify clients hosted in
+    /// datacenters with, optionally gzipped. http and https URLs are downloaded, and anything else
+    /// is read as a path. Hosted clients aren't sent an external proxy, since they usually connect
+    /// well directly. Off if this isn't passed.
+    #[arg(long = "asn-source", value_delimiter = ',')]
+    pub asn_sources: Vec<String>,
+
+    /// ASNs of hosting providers for --asn-source. Defaults to major cloud and VPS providers.
+    #[arg(long, value_delimiter = ',', default_value = DEFAULT_HOSTING_ASNS)]
+    pub hosting_asns: Vec<u32>,
+
+    /// HTTP API to look up the location of addresses that aren't in the IP info database, such as
+    /// http://ip-api.com/json/{ip}?fields=status,countryCode,lat,lon. {ip} is replaced with the
+    /// address. Responses must have countryCode, lat, and lon fields, like ip-api.com's.
+    #[arg(long)]
+    pub geo_lookup_url: Option<String>,
+
+    /// Longest a --geo-lookup-url request may take. Lookups happen after setup, so they never
+    /// delay connecting.
+    #[arg(long, default_value = "2s", value_parser = DurationValueParser)]
+    pub geo_lookup_timeout: Duration,
+
+    /// Most --geo-lookup-url requests that may be in flight at once
+    #[arg(long, default_value = "4", value_parser = clap::value_parser!(u32).range(1..=64))]
+    pub geo_lookup_concurrency: u32,
+
+    /// MaxMind City database (such as GeoLite2-City.mmdb) to look up countries and locations in,
+    /// instead of downloading GeoLite2 City CSVs
+    #[cfg(feature = "maxminddb")]
+    #[arg(long)]
+    pub ip_info_mmdb: Option<PathBuf>,
+
     /// The path to a log4rs yaml logging configuration
     #[arg(long)]
     pub log_config: Option<String>,
+
+    /// Print the JSON schema for external_proxies.json and exit
+    #[arg(long)]
+    pub print_external_proxies_schema: bool,
+
+    /// Check external_proxies.json and reserved_ids.json, then exit without starting the server.
+    /// Exits with status 1 if either is invalid.
+    #[arg(long)]
+    pub validate_config: bool,
 }
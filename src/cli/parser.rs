@@ -1,3 +1,6 @@
+use crate::protocol::c2s_message::C2S_MESSAGES;
+use crate::protocol::s2c_message::S2C_MESSAGES;
+use crate::ratelimit::bucket::RateLimitSpec;
 use clap::builder::{StringValueParser, TypedValueParser};
 use clap::error::ErrorKind::Format;
 use clap::{Arg, Command, Error};
@@ -22,3 +25,208 @@ impl TypedValueParser for DurationValueParser {
             .and_then(|value| parse(&value).map_err(|message| Error::raw(Format, message)))
     }
 }
+
+/// Parses the name of a C2S or S2C message type
+#[derive(Clone)]
+pub struct MessageNameValueParser;
+
+impl TypedValueParser for MessageNameValueParser {
+    type Value = &'static str;
+
+    fn parse_ref(
+        &self,
+        cmd: &Command,
+        arg: Option<&Arg>,
+        value: &OsStr,
+    ) -> Result<Self::Value, Error> {
+        let value = StringValueParser::new().parse_ref(cmd, arg, value)?;
+        C2S_MESSAGES
+            .iter()
+            .chain(S2C_MESSAGES)
+            .map(|info| info.name)
+            .find(|&name| name == value)
+            .ok_or_else(|| Error::raw(Format, format!("Unknown message type {value}\n")))
+    }
+}
+
+/// Parses a --rate-limit bucket, like `per_minute:20/60s`, with an optional `:token-bucket` suffix
+#[derive(Clone)]
+pub struct RateLimitValueParser;
+
+impl TypedValueParser for RateLimitValueParser {
+    type Value = RateLimitSpec;
+
+    fn parse_ref(
+        &self,
+        cmd: &Command,
+        arg: Option<&Arg>,
+        value: &OsStr,
+    ) -> Result<Self::Value, Error> {
+        let value = StringValueParser::new().parse_ref(cmd, arg, value)?;
+        parse_rate_limit(&value).map_err(|message| {
+            Error::raw(Format, format!("Invalid rate limit {value}: {message}\n"))
+        })
+    }
+}
+
+fn parse_rate_limit(value: &str) -> Result<RateLimitSpec, String> {
+    let mut parts = value.split(':');
+    let name = parts.next().unwrap_or_default();
+    if name.is_empty() {
+        return Err("missing bucket name".to_string());
+    }
+    let Some((max_count, period)) = parts.next().and_then(|limit| limit.split_once('/')) else {
+        return Err("expected name:count/duration".to_string());
+    };
+    let max_count = match max_count.parse::<u32>() {
+        Ok(0) | Err(_) => return Err(format!("count must be a positive integer, not {max_count}")),
+        Ok(max_count) => max_count,
+    };
+    let period = parse(period).map_err(|err| err.to_string())?;
+    if period.is_zero() {
+        return Err("duration must be more than 0s".to_string());
+    }
+    let token_bucket = match parts.next() {
+        None => false,
+        Some("token-bucket") => true,
+        Some(kind) => return Err(format!("unknown bucket kind {kind}")),
+    };
+    if parts.next().is_some() {
+        return Err("too many parts".to_string());
+    }
+    Ok(RateLimitSpec {
+        name: name.to_string(),
+        max_count,
+        period,
+        token_bucket,
+    })
+}
+
+/// Bounds for --auth-timeout, so that handshakes neither fail instantly nor hang
+pub fn auth_timeout_in_range(timeout: Duration) -> Result<Duration, String> {
+    const MAX_AUTH_TIMEOUT: Duration = Duration::from_secs(60);
+    if timeout.is_zero() || timeout > MAX_AUTH_TIMEOUT {
+        return Err(format!(
+            "must be more than 0s and at most {}s",
+            MAX_AUTH_TIMEOUT.as_secs()
+        ));
+    }
+    Ok(timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::args::Args;
+    use clap::Parser;
+
+    fn rate_limits(value: &str) -> Result<Vec<RateLimitSpec>, Error> {
+        Args::try_parse_from(["world-host-server", "--rate-limit", value])
+            .map(|args| args.rate_limits)
+    }
+
+    #[test]
+    fn parses_fixed_window() {
+        let spec = parse_rate_limit("per_minute:20/60s").unwrap();
+        assert_eq!(spec.name, "per_minute");
+        assert_eq!(spec.max_count, 20);
+        assert_eq!(spec.period, Duration::from_secs(60));
+        assert!(!spec.token_bucket);
+    }
+
+    #[test]
+    fn parses_token_bucket() {
+        let spec = parse_rate_limit("burst:5/1h:token-bucket").unwrap();
+        assert_eq!(spec.name, "burst");
+        assert_eq!(spec.max_count, 5);
+        assert_eq!(spec.period, Duration::from_secs(3600));
+        assert!(spec.token_bucket);
+    }
+
+    #[test]
+    fn rejects_invalid_specs() {
+        for (value, error) in [
+            (":20/60s", "missing bucket name"),
+            ("", "missing bucket name"),
+            ("per_minute", "expected name:count/duration"),
+            ("per_minute:20", "expected name:count/duration"),
+            (
+                "per_minute:0/60s",
+                "count must be a positive integer, not 0",
+            ),
+            (
+                "per_minute:-1/60s",
+                "count must be a positive integer, not -1",
+            ),
+            (
+                "per_minute:many/60s",
+                "count must be a positive integer, not many",
+            ),
+            ("per_minute:20/0s", "duration must be more than 0s"),
+            ("per_minute:20/60s:leaky", "unknown bucket kind leaky"),
+            ("per_minute:20/60s:", "unknown bucket kind "),
+            ("per_minute:20/60s:token-bucket:extra", "too many parts"),
+        ] {
+            assert_eq!(parse_rate_limit(value).unwrap_err(), error, "{value}");
+        }
+        assert!(parse_rate_limit("per_minute:20/soon").is_err());
+    }
+
+    #[test]
+    fn parses_comma_separated_list() {
+        let specs = rate_limits("a:1/1s,b:2/2m:token-bucket,c:3/3h").unwrap();
+        let specs = specs
+            .iter()
+            .map(|spec| {
+                (
+                    spec.name.as_str(),
+                    spec.max_count,
+                    spec.period,
+                    spec.token_bucket,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            specs,
+            [
+                ("a", 1, Duration::from_secs(1), false),
+                ("b", 2, Duration::from_secs(120), true),
+                ("c", 3, Duration::from_secs(3 * 3600), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_list_with_invalid_entry() {
+        let error = rate_limits("a:1/1s,b:0/1s").unwrap_err();
+        assert!(
+            error.to_string().contains("Invalid rate limit b:0/1s"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn default_is_two_fixed_windows() {
+        let specs = Args::try_parse_from(["world-host-server"])
+            .unwrap()
+            .rate_limits;
+        let specs = specs
+            .iter()
+            .map(|spec| {
+                (
+                    spec.name.as_str(),
+                    spec.max_count,
+                    spec.period,
+                    spec.token_bucket,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            specs,
+            [
+                ("per_minute", 20, Duration::from_secs(60), false),
+                ("per_hour", 400, Duration::from_secs(3600), false),
+            ]
+        );
+    }
+}
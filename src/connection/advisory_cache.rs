@@ -0,0 +1,65 @@
+use crate::util::shrink_if_sparse;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// How long a user has to reconnect after dropping mid-setup before advisories are resent.
+pub const SETUP_RESUME_WINDOW: Duration = Duration::from_secs(30);
+
+/// Non-essential messages sent during connection setup. These are safe to skip if the client
+/// already received them on a connection that dropped before setup finished.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Advisory {
+    HandshakeWarning,
+    OutdatedWorldHost,
+    InsecureAuth,
+}
+
+struct DeliveredAdvisories {
+    expiry: Instant,
+    advisories: HashSet<Advisory>,
+}
+
+#[derive(Default)]
+pub struct AdvisoryCache {
+    delivered: HashMap<Uuid, DeliveredAdvisories>,
+}
+
+impl AdvisoryCache {
+    pub fn delivered(&mut self, user: Uuid) -> HashSet<Advisory> {
+        match self.delivered.get(&user) {
+            Some(entry) if entry.expiry > Instant::now() => entry.advisories.clone(),
+            Some(_) => {
+                self.delivered.remove(&user);
+                HashSet::new()
+            }
+            None => HashSet::new(),
+        }
+    }
+
+    /// Drops expired entries and returns how many slots were reclaimed
+    pub fn shrink(&mut self) -> usize {
+        let now = Instant::now();
+        self.delivered.retain(|_, entry| entry.expiry > now);
+        shrink_if_sparse(&mut self.delivered)
+    }
+
+    pub fn record(&mut self, user: Uuid, advisories: impl IntoIterator<Item = Advisory>) {
+        let now = Instant::now();
+        self.delivered.retain(|_, entry| entry.expiry > now);
+        let entry = self
+            .delivered
+            .entry(user)
+            .or_insert_with(|| DeliveredAdvisories {
+                expiry: now,
+                advisories: HashSet::new(),
+            });
+        entry.expiry = now + SETUP_RESUME_WINDOW;
+        entry.advisories.extend(advisories);
+    }
+
+    pub fn complete(&mut self, user: Uuid) {
+        self.delivered.remove(&user);
+    }
+}
@@ -1,27 +1,91 @@
 use crate::serialization::serializable::PacketSerializable;
 use anyhow::{anyhow, bail};
 use case_insensitive_hashmap::CaseInsensitiveHashMap;
-use lazy_static::lazy_static;
+use rand::Rng;
+use serde::de::Visitor;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
 use std::fmt;
 use std::fmt::{Display, Formatter};
 use std::str::FromStr;
+use std::sync::OnceLock;
 use unicase::UniCase;
 
 const MAX_CONNECTION_IDS: u64 = 1 << 42;
 const WORD_SHIFT: u8 = 14;
 const WORD_MASK: u64 = (1 << WORD_SHIFT) - 1;
 
-lazy_static! {
-    static ref WORDS_FOR_CID: Vec<String> = include_str!("16k.txt")
-        .lines()
-        .filter(|line| !line.starts_with("//"))
-        .map(String::from)
-        .collect();
-    static ref WORDS_FOR_CID_INVERSE: CaseInsensitiveHashMap<u16> = WORDS_FOR_CID
-        .iter()
-        .enumerate()
-        .map(|(index, value)| (UniCase::new(value.clone()), index as u16))
-        .collect();
+const WORD_COUNT: usize = 1 << WORD_SHIFT;
+/// Three words of this length and their separators still fit in a 63 character DNS label
+const MAX_WORD_LENGTH: usize = 20;
+
+static WORD_LIST: OnceLock<WordList> = OnceLock::new();
+
+/// The words that make up connection IDs. The embedded 16k.txt is used unless a custom list is
+/// installed with [WordList::install] before the first ID is parsed or displayed.
+pub struct WordList {
+    words: Vec<String>,
+    inverse: CaseInsensitiveHashMap<u16>,
+    custom: bool,
+}
+
+impl WordList {
+    /// Parses a list of exactly 16384 words of lowercase ASCII letters and digits, one per line.
+    /// Lines starting with // are comments.
+    pub fn parse(text: &str, custom: bool) -> anyhow::Result<Self> {
+        let words: Vec<String> = text
+            .lines()
+            .filter(|line| !line.starts_with("//"))
+            .map(String::from)
+            .collect();
+        if words.len() != WORD_COUNT {
+            bail!("Expected {WORD_COUNT} words, found {}", words.len());
+        }
+        let mut inverse = CaseInsensitiveHashMap::with_capacity(WORD_COUNT);
+        for (index, word) in words.iter().enumerate() {
+            if word.is_empty() || word.len() > MAX_WORD_LENGTH {
+                bail!(
+                    "Word {} \"{word}\" must be 1 to {MAX_WORD_LENGTH} characters long",
+                    index + 1
+                );
+            }
+            // IDs are lowercased before lookup, and have to survive being typed and used in DNS
+            if let Some(invalid) = word
+                .chars()
+                .find(|c| !c.is_ascii_alphanumeric() || c.is_ascii_uppercase())
+            {
+                bail!(
+                    "Word {} \"{word}\" contains invalid character '{invalid}'",
+                    index + 1
+                );
+            }
+            // Every word has to map back to its own index, or two IDs would share a phrase
+            if let Some(previous) = inverse.insert(UniCase::new(word.clone()), index as u16) {
+                bail!(
+                    "Word {} \"{word}\" duplicates word {}",
+                    index + 1,
+                    previous + 1
+                );
+            }
+        }
+        Ok(Self {
+            words,
+            inverse,
+            custom,
+        })
+    }
+
+    pub fn embedded() -> anyhow::Result<Self> {
+        Self::parse(include_str!("16k.txt"), false)
+    }
+
+    /// Fails if a word list is already in use
+    pub fn install(self) -> Result<(), Self> {
+        WORD_LIST.set(self)
+    }
+
+    fn get() -> &'static Self {
+        WORD_LIST.get_or_init(|| Self::embedded().expect("Embedded word list is invalid"))
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -35,53 +99,377 @@ impl ConnectionId {
             bail!("Connection ID {id} out of range")
         }
     }
+
+    pub fn random() -> Self {
+        ConnectionId(rand::thread_rng().gen_range(0..MAX_CONNECTION_IDS))
+    }
+
+    pub fn value(self) -> u64 {
+        self.into()
+    }
+
+    /// The nine character base36 form, which [FromStr] accepts as well as the three words
+    pub fn short_string(self) -> String {
+        let mut digits = [b'0'; MAX_SHORT_ID_LENGTH];
+        let mut value = self.value();
+        for digit in digits.iter_mut().rev() {
+            *digit = char::from_digit((value % 36) as u32, 36).unwrap() as u8;
+            value /= 36;
+        }
+        String::from_utf8(digits.to_vec()).unwrap()
+    }
+}
+
+const ACCEPTED_FORMATS: &str =
+    "Expected three words such as apple-banana-cherry, or a short ID of 1 to 9 letters and digits.";
+const MAX_SHORT_ID_LENGTH: usize = 9;
+
+/// Whether a character is likely to have been meant as the separator between words. Covers the
+/// dashes that chat apps substitute for hyphens.
+fn is_separator(c: char) -> bool {
+    c == '-'
+        || c == '_'
+        || c.is_whitespace()
+        || matches!(
+            c,
+            '\u{2010}'..='\u{2015}' | '\u{2212}' | '\u{FE58}' | '\u{FE63}' | '\u{FF0D}'
+        )
 }
 
 impl FromStr for ConnectionId {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> anyhow::Result<ConnectionId> {
-        let words: Vec<_> = s.split("-").collect();
-        if words.len() != 3 {
-            if words.len() != 1 {
-                bail!("Three words are expected. Found {} words.", words.len());
-            }
-            let word = words[0];
-            if word.len() != 9 {
-                bail!(
-                    "Expected nine digit short connection ID, found {} digits.",
-                    word.len()
-                );
+        let normalized = s.trim().to_lowercase();
+        let words: Vec<_> = normalized
+            .split(is_separator)
+            .filter(|word| !word.is_empty())
+            .collect();
+        match words.len() {
+            0 => bail!("Empty connection ID. {ACCEPTED_FORMATS}"),
+            1 => parse_short_id(words[0]),
+            3 => {
+                let word_list = WordList::get();
+                let mut result = 0;
+                let mut shift = 0;
+                for (index, word) in words.iter().enumerate() {
+                    let part = word_list.inverse.get(*word).ok_or_else(|| {
+                        anyhow!(
+                            "Unknown word \"{word}\" (word {} of 3). {ACCEPTED_FORMATS}{}",
+                            index + 1,
+                            if word_list.custom {
+                                " This server uses a custom word list."
+                            } else {
+                                ""
+                            }
+                        )
+                    })?;
+                    result |= (*part as u64) << shift;
+                    shift += WORD_SHIFT;
+                }
+                Ok(ConnectionId(result))
             }
-            return Ok(ConnectionId(u64::from_str_radix(word, 36)?));
+            count => bail!(
+                "Found {count} words in \"{}\". {ACCEPTED_FORMATS}",
+                s.trim()
+            ),
         }
-        let mut result = 0;
-        let mut shift = 0;
-        for word in words {
-            let part = WORDS_FOR_CID_INVERSE
-                .get(word)
-                .ok_or_else(|| anyhow!("Unknown word {word}."))?;
-            result |= (*part as u64) << shift;
-            shift += WORD_SHIFT;
-        }
-        Ok(ConnectionId(result))
     }
 }
 
+fn parse_short_id(word: &str) -> anyhow::Result<ConnectionId> {
+    // Full length short IDs can spell a word, and still have to round trip with short_string
+    if word.len() < MAX_SHORT_ID_LENGTH && WordList::get().inverse.contains_key(word) {
+        bail!("\"{word}\" is only one word of a connection ID. {ACCEPTED_FORMATS}");
+    }
+    if word.len() > MAX_SHORT_ID_LENGTH {
+        bail!(
+            "Short ID \"{word}\" has {} characters, but at most {MAX_SHORT_ID_LENGTH} are allowed. {ACCEPTED_FORMATS}",
+            word.len()
+        );
+    }
+    if let Some(invalid) = word.chars().find(|c| !c.is_ascii_alphanumeric()) {
+        bail!("Short ID \"{word}\" contains invalid character '{invalid}'. {ACCEPTED_FORMATS}");
+    }
+    let id = u64::from_str_radix(word, 36)?;
+    ConnectionId::new(id).map_err(|_| anyhow!("Short ID \"{word}\" is too large."))
+}
+
 impl Display for ConnectionId {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let first = (self.0 & WORD_MASK) as usize;
         let second = ((self.0 >> WORD_SHIFT) & WORD_MASK) as usize;
         let third = ((self.0 >> WORD_SHIFT >> WORD_SHIFT) & WORD_MASK) as usize;
+        let words = &WordList::get().words;
         f.write_fmt(format_args!(
             "{}-{}-{}",
-            WORDS_FOR_CID[first], WORDS_FOR_CID[second], WORDS_FOR_CID[third]
+            words[first], words[second], words[third]
         ))
     }
 }
 
+impl Serialize for ConnectionId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl TryFrom<u64> for ConnectionId {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u64) -> anyhow::Result<Self> {
+        ConnectionId::new(value)
+    }
+}
+
+impl From<ConnectionId> for u64 {
+    fn from(value: ConnectionId) -> Self {
+        value.0
+    }
+}
+
+/// Accepts the word form, the short form, or the raw number
+impl<'de> Deserialize<'de> for ConnectionId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ConnectionIdVisitor;
+
+        impl Visitor<'_> for ConnectionIdVisitor {
+            type Value = ConnectionId;
+
+            fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+                f.write_str("a connection ID")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                ConnectionId::try_from(v).map_err(E::custom)
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                let v = u64::try_from(v)
+                    .map_err(|_| E::custom(format!("Connection ID {v} out of range")))?;
+                self.visit_u64(v)
+            }
+        }
+
+        deserializer.deserialize_any(ConnectionIdVisitor)
+    }
+}
+
 impl PacketSerializable for ConnectionId {
     fn serialize_to(&self, buf: &mut Vec<u8>) {
         self.0.serialize_to(buf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// The embedded list with one word replaced
+    fn list_with_word(word: &str) -> String {
+        let mut words: Vec<_> = include_str!("16k.txt")
+            .lines()
+            .filter(|line| !line.starts_with("//"))
+            .collect();
+        words[0] = word;
+        words.join("\n")
+    }
+
+    #[test]
+    fn word_characters() {
+        for word in ["zq9x", "abc123", "7"] {
+            assert!(
+                WordList::parse(&list_with_word(word), true).is_ok(),
+                "{word}"
+            );
+        }
+        for (word, invalid) in [
+            ("Apple", 'A'),
+            ("café", 'é'),
+            ("straße", 'ß'),
+            ("٣", '٣'),
+            ("ａｂｃ", 'ａ'),
+        ] {
+            let error = WordList::parse(&list_with_word(word), true).err().unwrap();
+            assert_eq!(
+                error.to_string(),
+                format!("Word 1 \"{word}\" contains invalid character '{invalid}'")
+            );
+        }
+    }
+
+    #[test]
+    fn word_list_shape() {
+        let embedded = WordList::embedded().unwrap();
+        assert_eq!(embedded.words.len(), WORD_COUNT);
+        assert!(!embedded.custom);
+
+        let list = list_with_word("the");
+        let mut words: Vec<_> = list.lines().collect();
+        words.pop();
+        assert_eq!(
+            WordList::parse(&words.join("\n"), true)
+                .err()
+                .unwrap()
+                .to_string(),
+            format!("Expected {WORD_COUNT} words, found {}", WORD_COUNT - 1)
+        );
+        assert_eq!(
+            WordList::parse(&format!("{list}\nzzzzzz"), true)
+                .err()
+                .unwrap()
+                .to_string(),
+            format!("Expected {WORD_COUNT} words, found {}", WORD_COUNT + 1)
+        );
+        // Comments don't count as words
+        assert!(WordList::parse(&format!("// comment\n{list}"), true).is_ok());
+
+        // Uppercase words are rejected before they're compared, so only exact duplicates are left
+        assert_eq!(
+            WordList::parse(&list_with_word("of"), true)
+                .err()
+                .unwrap()
+                .to_string(),
+            "Word 2 \"of\" duplicates word 1"
+        );
+        assert_eq!(
+            WordList::parse(&list_with_word(""), true)
+                .err()
+                .unwrap()
+                .to_string(),
+            format!("Word 1 \"\" must be 1 to {MAX_WORD_LENGTH} characters long")
+        );
+        assert!(WordList::parse(&list_with_word(&"z".repeat(MAX_WORD_LENGTH + 1)), true).is_err());
+    }
+
+    #[test]
+    fn parse_inputs() {
+        // the-of-and
+        let id = ConnectionId::new(1 << WORD_SHIFT | 2 << (WORD_SHIFT * 2)).unwrap();
+        assert_eq!(id.to_string(), "the-of-and");
+        let max = ConnectionId::new(MAX_CONNECTION_IDS - 1).unwrap();
+        for (input, expected) in [
+            ("the-of-and", id),
+            ("  the-of-and\n", id),
+            ("the of and", id),
+            ("the  of\tand", id),
+            ("the_of_and", id),
+            ("the\u{2013}of\u{2013}and", id),
+            ("the\u{2014}of\u{2014}and", id),
+            ("The-OF-anD", id),
+            ("1", ConnectionId::new(1).unwrap()),
+            ("7", ConnectionId::new(7).unwrap()),
+            ("000000001", ConnectionId::new(1).unwrap()),
+            (&max.short_string(), max),
+            (&max.short_string().to_uppercase(), max),
+        ] {
+            assert_eq!(
+                input.parse::<ConnectionId>().unwrap(),
+                expected,
+                "{input:?}"
+            );
+        }
+
+        for (input, token) in [
+            ("", ""),
+            ("  - ", ""),
+            ("the-of-notaword", "notaword"),
+            ("notaword the of", "notaword"),
+            ("the-of", "the-of"),
+            ("the-of-and-the", "the-of-and-the"),
+            ("the", "the"),
+            ("0000000001", "0000000001"),
+            ("zzzzzzzzz", "zzzzzzzzz"),
+            ("ab!c", "ab!c"),
+        ] {
+            let error = input.parse::<ConnectionId>().unwrap_err().to_string();
+            assert!(
+                error.contains(&format!("\"{token}\"")) || token.is_empty(),
+                "{error}"
+            );
+            // Except for IDs that are simply too large, the formats are listed
+            if token != "zzzzzzzzz" {
+                assert!(error.contains(ACCEPTED_FORMATS), "{error}");
+            }
+        }
+        assert_eq!(
+            "the-of-notaword"
+                .parse::<ConnectionId>()
+                .unwrap_err()
+                .to_string(),
+            format!("Unknown word \"notaword\" (word 3 of 3). {ACCEPTED_FORMATS}")
+        );
+        assert_eq!(
+            "the-of".parse::<ConnectionId>().unwrap_err().to_string(),
+            format!("Found 2 words in \"the-of\". {ACCEPTED_FORMATS}")
+        );
+        assert_eq!(
+            "0000000001"
+                .parse::<ConnectionId>()
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "Short ID \"0000000001\" has 10 characters, but at most 9 are allowed. {ACCEPTED_FORMATS}"
+            )
+        );
+        assert_eq!(
+            "zzzzzzzzz".parse::<ConnectionId>().unwrap_err().to_string(),
+            "Short ID \"zzzzzzzzz\" is too large."
+        );
+    }
+
+    #[test]
+    fn json() {
+        let id = ConnectionId::new(1 << WORD_SHIFT | 2 << (WORD_SHIFT * 2)).unwrap();
+        assert_eq!(serde_json::to_string(&id).unwrap(), "\"the-of-and\"");
+        for json in [
+            "\"the-of-and\"".to_string(),
+            "\"The of and\"".to_string(),
+            format!("\"{}\"", id.short_string()),
+            id.value().to_string(),
+        ] {
+            assert_eq!(
+                serde_json::from_str::<ConnectionId>(&json).unwrap(),
+                id,
+                "{json}"
+            );
+        }
+        assert_eq!(
+            serde_json::from_str::<ConnectionId>(&(MAX_CONNECTION_IDS - 1).to_string()).unwrap(),
+            ConnectionId::new(MAX_CONNECTION_IDS - 1).unwrap()
+        );
+
+        for value in [
+            MAX_CONNECTION_IDS.to_string(),
+            u64::MAX.to_string(),
+            "-1".to_string(),
+        ] {
+            let error = serde_json::from_str::<ConnectionId>(&value)
+                .unwrap_err()
+                .to_string();
+            assert!(
+                error.starts_with(&format!("Connection ID {value} out of range")),
+                "{error}"
+            );
+        }
+        assert!(serde_json::from_str::<ConnectionId>("\"the-of\"").is_err());
+        assert!(serde_json::from_str::<ConnectionId>("1.5").is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(value in 0..MAX_CONNECTION_IDS) {
+            let id = ConnectionId::new(value).unwrap();
+            prop_assert_eq!(id.value(), value);
+            prop_assert_eq!(ConnectionId::try_from(id.value()).unwrap(), id);
+            prop_assert_eq!(id.to_string().parse::<ConnectionId>().unwrap(), id);
+            prop_assert_eq!(id.short_string().len(), MAX_SHORT_ID_LENGTH);
+            prop_assert_eq!(id.short_string().parse::<ConnectionId>().unwrap(), id);
+        }
+    }
+}
@@ -1,81 +1,490 @@
 use crate::connection::Connection;
 use crate::connection::connection_id::ConnectionId;
+use crate::country_code::CountryCode;
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use smallvec::SmallVec;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Notify;
+use tokio::sync::futures::Notified;
 use uuid::Uuid;
 
-pub type SafeConnectionList = Arc<Mutex<Vec<Connection>>>;
+/// Almost every user has only a few connections, so these fit without allocating
+pub type UserConnections = SmallVec<[Connection; 4]>;
 
+/// The open connections, indexed by ID and by user. Safe to use concurrently without an outer
+/// lock. Entries in [Self::connections] are always locked before entries in
+/// [Self::connections_by_user_id], and no guard is held across an await.
 pub struct ConnectionSet {
-    connections: HashMap<ConnectionId, Connection>,
-    connections_by_user_id: HashMap<Uuid, SafeConnectionList>,
+    connections: DashMap<ConnectionId, Connection>,
+    connections_by_user_id: DashMap<Uuid, Vec<Connection>>,
+    /// Connections per country, kept up to date as connections are added and removed
+    countries: DashMap<CountryCode, usize>,
+    peak_len: AtomicUsize,
+    removed: Notify,
 }
 
 impl ConnectionSet {
     pub fn new() -> Self {
         Self {
-            connections: HashMap::new(),
-            connections_by_user_id: HashMap::new(),
+            connections: DashMap::new(),
+            connections_by_user_id: DashMap::new(),
+            countries: DashMap::new(),
+            peak_len: AtomicUsize::new(0),
+            removed: Notify::new(),
         }
     }
 
-    pub fn by_id(&self, id: ConnectionId) -> Option<&Connection> {
-        self.connections.get(&id)
+    /// Lookups skip connections that have been marked closed but not yet removed
+    pub fn by_id(&self, id: ConnectionId) -> Option<Connection> {
+        self.connections
+            .get(&id)
+            .filter(|c| c.is_open())
+            .map(|c| c.clone())
     }
 
     pub fn by_user_id(&self, user_id: Uuid) -> Vec<Connection> {
         match self.connections_by_user_id.get(&user_id) {
-            Some(connections) => connections.clone().lock().unwrap().clone(),
+            Some(connections) => connections
+                .iter()
+                .filter(|c| c.is_open())
+                .cloned()
+                .collect(),
             None => Vec::default(),
         }
     }
 
-    pub fn add(&mut self, connection: Connection) -> bool {
-        if self.connections.contains_key(&connection.id) {
-            return false;
+    /// Like [Self::by_user_id], but doesn't allocate for users with up to four connections. Used
+    /// on the hot broadcast paths.
+    pub fn user_connections(&self, user_id: Uuid) -> UserConnections {
+        match self.connections_by_user_id.get(&user_id) {
+            Some(connections) => connections
+                .iter()
+                .filter(|c| c.is_open())
+                .cloned()
+                .collect(),
+            None => UserConnections::new(),
+        }
+    }
+
+    /// Returns `preferred` unless it's held by a connection from another address, in which case a
+    /// random unused ID is returned instead. Nothing is reserved, so [Self::add] can still fail.
+    pub fn claim_or_assign(&self, preferred: ConnectionId, addr: IpAddr) -> ConnectionId {
+        match self.connections.get(&preferred) {
+            Some(other) if other.addr != addr => {}
+            _ => return preferred,
+        }
+        loop {
+            let id = ConnectionId::random();
+            if !self.connections.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    pub fn user_connection_count(&self, user_id: Uuid) -> usize {
+        match self.connections_by_user_id.get(&user_id) {
+            Some(connections) => connections.iter().filter(|c| c.is_open()).count(),
+            None => 0,
         }
-        self.add_force(connection)
     }
 
-    pub fn add_force(&mut self, connection: Connection) -> bool {
-        let old = self.connections.insert(connection.id, connection.clone());
-        let by_uuid_arc = self
-            .connections_by_user_id
+    pub fn add(&self, connection: Connection) -> bool {
+        match self.connections.entry(connection.id) {
+            Entry::Occupied(_) => return false,
+            Entry::Vacant(entry) => {
+                // Counted while the entry is locked so a concurrent set_country can't count it too
+                self.count_country(&connection, 1);
+                // Likewise indexed, so that a concurrent add_force or remove can't look for it
+                // there before it's added and leave it behind
+                self.add_by_user_id(connection.clone());
+                entry.insert(connection);
+            }
+        }
+        // The entry's shard is unlocked by now, which reading the length needs
+        self.update_peak_len();
+        true
+    }
+
+    /// Adds a connection, replacing any connection with the same ID. Returns whether that removed
+    /// the last connection of the replaced connection's user, who should then be reported offline.
+    /// The replaced connection's own [Self::remove] does nothing afterward, so it can't report it.
+    pub fn add_force(&self, connection: Connection) -> bool {
+        let replaced_last_for_user = match self.connections.entry(connection.id) {
+            Entry::Occupied(mut entry) => {
+                self.count_country(&connection, 1);
+                let old = entry.insert(connection.clone());
+                self.count_country(&old, -1);
+                let replaced_last_for_user =
+                    self.remove_by_user_id(&old) && old.user_uuid != connection.user_uuid;
+                self.add_by_user_id(connection);
+                replaced_last_for_user
+            }
+            Entry::Vacant(entry) => {
+                self.count_country(&connection, 1);
+                self.add_by_user_id(connection.clone());
+                entry.insert(connection);
+                false
+            }
+        };
+        self.update_peak_len();
+        replaced_last_for_user
+    }
+
+    /// Sets a connection's country, counting it in [Self::country_counts] if it's in this set.
+    /// Connections added later are counted when they're added.
+    pub fn set_country(&self, connection: &Connection, country: CountryCode) {
+        // Holding the entry keeps add and remove from counting the connection at the same time
+        let current = self.connections.get(&connection.id);
+        if connection.country.set(country).is_ok()
+            && current.is_some_and(|current| Arc::ptr_eq(&current, connection))
+        {
+            self.count_country(connection, 1);
+        }
+    }
+
+    /// Number of connections in this set from each country
+    pub fn country_counts(&self) -> HashMap<CountryCode, u32> {
+        self.countries
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value() as u32))
+            .collect()
+    }
+
+    fn count_country(&self, connection: &Connection, delta: isize) {
+        let Some(&country) = connection.country.get() else {
+            return;
+        };
+        match self.countries.entry(country) {
+            Entry::Occupied(mut entry) => {
+                let count = entry.get_mut();
+                *count = count.saturating_add_signed(delta);
+                if *count == 0 {
+                    entry.remove();
+                }
+            }
+            Entry::Vacant(entry) => {
+                if delta > 0 {
+                    entry.insert(delta as usize);
+                }
+            }
+        }
+    }
+
+    /// Called with the connection's entry in [Self::connections] locked
+    fn add_by_user_id(&self, connection: Connection) {
+        self.connections_by_user_id
             .entry(connection.user_uuid)
-            .or_insert_with(|| Arc::new(Mutex::new(Vec::new())))
-            .clone();
-        let mut by_uuid = by_uuid_arc.lock().unwrap();
-        if let Some(old) = old
-            && let Some(old_pos) = by_uuid.iter().position(|x| x.id == old.id)
+            .or_default()
+            .push(connection);
+    }
+
+    /// Locks every shard of [Self::connections], so no entry guard can be held while calling this
+    fn update_peak_len(&self) {
+        self.peak_len
+            .fetch_max(self.connections.len(), Ordering::Relaxed);
+    }
+
+    /// Returns whether this removed the user's last connection. Removing a connection that has
+    /// already been replaced by [`Self::add_force`] does nothing.
+    pub fn remove(&self, connection: &Connection) -> bool {
+        if self
+            .connections
+            .remove_if(&connection.id, |_, current| {
+                let matches = Arc::ptr_eq(current, connection);
+                if matches {
+                    self.count_country(connection, -1);
+                }
+                matches
+            })
+            .is_none()
         {
-            by_uuid.swap_remove(old_pos);
+            return false;
         }
-        by_uuid.push(connection);
-        true
+        let last_for_user = self.remove_by_user_id(connection);
+        self.removed.notify_waiters();
+        last_for_user
     }
 
-    pub fn remove(&mut self, connection: &Connection) {
-        self.connections.remove(&connection.id);
-        let remove =
-            if let Some(by_uuid_arc) = self.connections_by_user_id.get(&connection.user_uuid) {
-                let mut by_uuid = by_uuid_arc.lock().unwrap();
-                if let Some(old_pos) = by_uuid.iter().position(|x| x.id == connection.id) {
+    /// Completes the next time any connection is removed. Enable the future before checking the
+    /// set so that a removal in between isn't missed.
+    pub fn removed(&self) -> Notified<'_> {
+        self.removed.notified()
+    }
+
+    /// Returns whether the user has no connections left
+    fn remove_by_user_id(&self, connection: &Connection) -> bool {
+        match self.connections_by_user_id.entry(connection.user_uuid) {
+            Entry::Occupied(mut entry) => {
+                let by_uuid = entry.get_mut();
+                if let Some(old_pos) = by_uuid.iter().position(|x| Arc::ptr_eq(x, connection)) {
                     by_uuid.swap_remove(old_pos);
                 }
-                by_uuid.is_empty()
-            } else {
-                false
-            };
-        if remove {
-            self.connections_by_user_id.remove(&connection.user_uuid);
+                if by_uuid.is_empty() {
+                    entry.remove();
+                    true
+                } else {
+                    false
+                }
+            }
+            Entry::Vacant(_) => false,
         }
     }
 
+    pub fn user_count(&self) -> usize {
+        self.connections_by_user_id.len()
+    }
+
+    /// Returns the highest [Self::len] since the last call, and resets it to the current length
+    pub fn take_peak_len(&self) -> usize {
+        self.peak_len
+            .swap(self.connections.len(), Ordering::Relaxed)
+    }
+
     pub fn len(&self) -> usize {
         self.connections.len()
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = &Connection> {
-        self.connections.values()
+    /// Clones every connection. Parts of the set are locked while this runs, so the result
+    /// shouldn't be lazily consumed across an await.
+    pub fn snapshot(&self) -> Vec<Connection> {
+        self.connections
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::ConnectionInfo;
+    use crate::protocol::protocol_versions::CURRENT;
+    use rand::Rng;
+    use std::net::Ipv4Addr;
+
+    fn connection(id: u64, user: u128) -> Connection {
+        ConnectionInfo::for_test(
+            ConnectionId::new(id).unwrap(),
+            Uuid::from_u128(user),
+            Ipv4Addr::LOCALHOST.into(),
+            CURRENT,
+        )
+        .0
+    }
+
+    #[test]
+    fn add_force_without_conflict() {
+        let set = ConnectionSet::new();
+        assert!(!set.add_force(connection(1, 1)));
+        assert_eq!(set.user_connection_count(Uuid::from_u128(1)), 1);
+    }
+
+    #[test]
+    fn add_force_replacing_same_user() {
+        let set = ConnectionSet::new();
+        let old = connection(1, 1);
+        assert!(set.add(old.clone()));
+        let new = connection(1, 1);
+        assert!(!set.add_force(new.clone()));
+        assert!(Arc::ptr_eq(&set.by_id(new.id).unwrap(), &new));
+        assert_eq!(set.user_connection_count(Uuid::from_u128(1)), 1);
+        // The replaced connection's cleanup leaves the new one alone
+        assert!(!set.remove(&old));
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn add_force_replacing_other_users_last_connection() {
+        let set = ConnectionSet::new();
+        let old = connection(1, 1);
+        assert!(set.add(old.clone()));
+        assert!(set.add_force(connection(1, 2)));
+        assert_eq!(set.user_connection_count(Uuid::from_u128(1)), 0);
+        assert_eq!(set.user_connection_count(Uuid::from_u128(2)), 1);
+        assert!(!set.remove(&old));
+    }
+
+    #[test]
+    fn add_force_replacing_one_of_other_users_connections() {
+        let set = ConnectionSet::new();
+        assert!(set.add(connection(1, 1)));
+        assert!(set.add(connection(2, 1)));
+        assert!(!set.add_force(connection(1, 2)));
+        assert_eq!(set.user_connection_count(Uuid::from_u128(1)), 1);
+    }
+
+    #[test]
+    fn claim_or_assign() {
+        let set = ConnectionSet::new();
+        let preferred = ConnectionId::new(1).unwrap();
+        let here = Ipv4Addr::LOCALHOST.into();
+        let elsewhere = Ipv4Addr::new(192, 0, 2, 1).into();
+        assert_eq!(set.claim_or_assign(preferred, elsewhere), preferred);
+
+        assert!(set.add(connection(1, 2)));
+        // The same address can take over its own ID
+        assert_eq!(set.claim_or_assign(preferred, here), preferred);
+        let assigned = set.claim_or_assign(preferred, elsewhere);
+        assert_ne!(assigned, preferred);
+        assert!(set.by_id(assigned).is_none());
+        assert_eq!(
+            assigned.to_string().parse::<ConnectionId>().unwrap(),
+            assigned
+        );
+    }
+
+    fn in_country(id: u64, user: u128, country: CountryCode) -> Connection {
+        let connection = connection(id, user);
+        connection.country.set(country).unwrap();
+        connection
+    }
+
+    #[test]
+    fn country_counts_follow_replacements() {
+        let gb = CountryCode::new('G', 'B').unwrap();
+        let us = CountryCode::new('U', 'S').unwrap();
+        let set = ConnectionSet::new();
+        let old = in_country(1, 1, gb);
+        assert!(set.add(old.clone()));
+        assert!(set.add(in_country(2, 3, gb)));
+        assert_eq!(set.country_counts(), HashMap::from([(gb, 2)]));
+
+        // Same user, same country
+        let same_user = in_country(1, 1, gb);
+        assert!(!set.add_force(same_user.clone()));
+        assert_eq!(set.country_counts(), HashMap::from([(gb, 2)]));
+        assert!(!set.remove(&old));
+        assert_eq!(set.country_counts(), HashMap::from([(gb, 2)]));
+
+        // Another user from another country
+        let other_user = in_country(1, 2, us);
+        assert!(set.add_force(other_user.clone()));
+        assert_eq!(set.country_counts(), HashMap::from([(gb, 1), (us, 1)]));
+        assert!(!set.remove(&same_user));
+        assert_eq!(set.country_counts(), HashMap::from([(gb, 1), (us, 1)]));
+
+        // Setting the replaced connection's country again doesn't count it
+        set.set_country(&same_user, us);
+        assert_eq!(set.country_counts(), HashMap::from([(gb, 1), (us, 1)]));
+
+        assert!(set.remove(&other_user));
+        assert_eq!(set.country_counts(), HashMap::from([(gb, 1)]));
+        assert!(!set.remove(&other_user));
+        assert_eq!(set.country_counts(), HashMap::from([(gb, 1)]));
+        assert!(set.remove(&set.by_id(ConnectionId::new(2).unwrap()).unwrap()));
+        assert!(set.country_counts().is_empty());
+    }
+
+    #[test]
+    fn country_set_after_add() {
+        let gb = CountryCode::new('G', 'B').unwrap();
+        let set = ConnectionSet::new();
+        let old = connection(1, 1);
+        assert!(set.add(old.clone()));
+        let new = connection(1, 1);
+        assert!(!set.add_force(new.clone()));
+        // Only the connection that's still in the set is counted
+        set.set_country(&old, gb);
+        assert!(set.country_counts().is_empty());
+        set.set_country(&new, gb);
+        set.set_country(&new, gb);
+        assert_eq!(set.country_counts(), HashMap::from([(gb, 1)]));
+        assert!(set.remove(&new));
+        assert!(set.country_counts().is_empty());
+    }
+
+    #[test]
+    fn concurrent_changes_stay_consistent() {
+        let countries = [
+            CountryCode::new('G', 'B').unwrap(),
+            CountryCode::new('U', 'S').unwrap(),
+            CountryCode::new('J', 'P').unwrap(),
+        ];
+        let set = ConnectionSet::new();
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let mut rng = rand::thread_rng();
+                    let mut added = vec![];
+                    for _ in 0..2000 {
+                        let new = in_country(
+                            rng.gen_range(0..32),
+                            rng.gen_range(0..8),
+                            countries[rng.gen_range(0..countries.len())],
+                        );
+                        match rng.gen_range(0..3) {
+                            0 => {
+                                if set.add(new.clone()) {
+                                    added.push(new);
+                                }
+                            }
+                            1 => {
+                                set.add_force(new.clone());
+                                added.push(new);
+                            }
+                            _ if !added.is_empty() => {
+                                let old = added.swap_remove(rng.gen_range(0..added.len()));
+                                set.remove(&old);
+                            }
+                            _ => {}
+                        }
+                    }
+                });
+            }
+        });
+
+        let surviving = set.snapshot();
+        assert_eq!(set.len(), surviving.len());
+        let mut by_user = 0;
+        for user in 0..8 {
+            let user = Uuid::from_u128(user);
+            let connections = set.by_user_id(user);
+            assert_eq!(
+                connections.len(),
+                surviving.iter().filter(|c| c.user_uuid == user).count()
+            );
+            for connection in &connections {
+                assert!(Arc::ptr_eq(&set.by_id(connection.id).unwrap(), connection));
+            }
+            by_user += connections.len();
+        }
+        assert_eq!(by_user, surviving.len());
+        assert_eq!(
+            set.user_count(),
+            (0..8)
+                .filter(|&user| set.user_connection_count(Uuid::from_u128(user)) > 0)
+                .count()
+        );
+
+        let mut expected = HashMap::new();
+        for connection in &surviving {
+            *expected
+                .entry(*connection.country.get().unwrap())
+                .or_insert(0) += 1;
+        }
+        assert_eq!(set.country_counts(), expected);
+    }
+
+    #[test]
+    fn peak_len_survives_until_taken() {
+        let set = ConnectionSet::new();
+        let connections: Vec<_> = (0..3).map(|id| connection(id, 1)).collect();
+        for connection in &connections {
+            assert!(set.add(connection.clone()));
+        }
+        set.remove(&connections[0]);
+        set.remove(&connections[1]);
+        assert_eq!(set.len(), 1);
+        assert_eq!(set.take_peak_len(), 3);
+        // Reset to the current length
+        assert_eq!(set.take_peak_len(), 1);
+        assert!(!set.add_force(connection(5, 2)));
+        set.remove(&connections[2]);
+        assert_eq!(set.take_peak_len(), 2);
+        assert_eq!(set.take_peak_len(), 1);
     }
 }
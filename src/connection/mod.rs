@@ -1,38 +1,88 @@
 use crate::connection::connection_id::ConnectionId;
 use crate::country_code::CountryCode;
 use crate::json_data::ExternalProxy;
+use crate::lat_long::GridCell;
 use crate::minecraft_crypt::Aes128Cfb;
+use crate::modules::analytics::{IntervalCounters, SKIPPED_BY_TYPE};
 use crate::protocol::c2s_message::WorldHostC2SMessage;
-use crate::protocol::protocol_versions;
+use crate::protocol::protocol_versions::ProtocolCapabilities;
 use crate::protocol::s2c_message::WorldHostS2CMessage;
 use crate::protocol::security::SecurityLevel;
 use crate::socket_wrapper::{SocketReadWrapper, SocketWriteWrapper};
+use log::{debug, warn};
 use std::collections::HashSet;
 use std::io;
 use std::net::IpAddr;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::timeout;
 use uuid::Uuid;
 
+pub mod advisory_cache;
 pub mod connection_id;
 pub mod connection_set;
+pub mod proxy_connection_id;
 
 pub type Connection = Arc<ConnectionInfo>;
 
+/// How long a send can wait for the client to read before the connection is closed
+pub const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct ConnectionInfo {
     pub id: ConnectionId,
     pub addr: IpAddr,
     pub user_uuid: Uuid,
     pub protocol_version: u32,
+    pub capabilities: ProtocolCapabilities,
+    /// The client's mod version and loader, sanitized for logging. Only sent by clients that have
+    /// [ProtocolCapabilities::sends_brand].
+    pub brand: Option<String>,
+    /// Whether the server was in offline mode, so the UUID wasn't verified
+    pub offline_mode: bool,
+    /// Cleared as soon as the connection's read loop exits, before it's removed from the
+    /// [connection_set::ConnectionSet]. Closed connections are never returned from lookups and
+    /// silently drop any messages sent to them.
+    pub open: AtomicBool,
+    /// Notified by [ConnectionInfo::mark_closed], so the read loop stops for a client that's
+    /// stopped reading
+    pub closed: Notify,
+    /// Set once during setup with [connection_set::ConnectionSet::set_country], so that it's
+    /// counted in the set's country index
+    pub country: OnceLock<CountryCode>,
+    /// Only set with --analytics-grid
+    pub grid_cell: OnceLock<GridCell>,
+    /// The ASN of the hosting provider the connection comes from, if it's in --hosting-asns. Hosted
+    /// connections aren't sent an external proxy.
+    pub hosting_asn: Option<u32>,
     pub state: Mutex<ConnectionState>,
     pub read: Mutex<ConnectionRead>,
     pub write: Mutex<ConnectionWrite>,
 }
 
+/// What happened to a message passed to [ConnectionInfo::send_message]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SendOutcome {
+    Sent,
+    /// The message is newer than the connection's protocol version
+    SkippedOldProtocol,
+    /// The connection was closed
+    SkippedClosed,
+}
+
 pub struct ConnectionState {
-    pub country: Option<CountryCode>,
     pub external_proxy: Option<Arc<ExternalProxy>>,
     pub open_to_friends: HashSet<Uuid>,
+    pub presence_subscriptions: HashSet<Uuid>,
+    /// The friends named in the last ListOnline. Only these, the friends the connection subscribed
+    /// to, and the friends its world is open to are told about its presence.
+    pub listed_friends: HashSet<Uuid>,
+    /// Whether this connection was warned about sending the deprecated QueryResponse
+    pub warned_legacy_query_response: bool,
+    /// Whether this connection was told it hit --max-open-to-friends
+    pub warned_open_to_friends_cap: bool,
 }
 
 pub struct ConnectionRead {
@@ -43,16 +93,45 @@ pub struct ConnectionRead {
 pub struct ConnectionWrite {
     pub socket: SocketWriteWrapper,
     pub cipher: Option<Aes128Cfb>,
+    /// Encoded bytes that haven't been written yet. Keeping these here makes sending
+    /// cancellation-safe: a send that's cancelled leaves the rest of its messages queued for the
+    /// next send instead of a partial frame on the wire. Cleared when a send fails or times out,
+    /// since the connection is closed then.
+    pub unsent: Vec<u8>,
+    /// Whether the client supports compressed messages
+    pub compress: bool,
+    /// Selects the layout of messages whose fields changed between versions
+    pub protocol_version: u32,
 }
 
 impl ConnectionInfo {
     pub fn security_level(&self) -> SecurityLevel {
         SecurityLevel::from(
             self.user_uuid,
-            self.protocol_version >= protocol_versions::NEW_AUTH_PROTOCOL,
+            self.capabilities.supports_new_auth,
+            self.offline_mode,
         )
     }
 
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::Acquire)
+    }
+
+    pub fn mark_closed(&self) {
+        self.open.store(false, Ordering::Release);
+        self.closed.notify_waiters();
+    }
+
+    /// Completes once the connection is marked closed
+    pub async fn wait_closed(&self) {
+        let notified = self.closed.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if self.is_open() {
+            notified.await;
+        }
+    }
+
     pub async fn recv_message(&self) -> io::Result<WorldHostC2SMessage> {
         self.read
             .lock()
@@ -61,16 +140,73 @@ impl ConnectionInfo {
             .await
     }
 
-    pub async fn send_message(&self, message: &WorldHostS2CMessage) -> io::Result<()> {
-        if self.protocol_version >= message.first_protocol() {
-            self.write.lock().await.send_message(message).await
+    pub async fn send_message(&self, message: &WorldHostS2CMessage) -> io::Result<SendOutcome> {
+        if !self.supports_message(message) {
+            Ok(SendOutcome::SkippedOldProtocol)
+        } else if !self.is_open() {
+            Ok(SendOutcome::SkippedClosed)
         } else {
-            Ok(())
+            self.send_with_timeout(&[message]).await?;
+            Ok(SendOutcome::Sent)
+        }
+    }
+
+    /// Sends several messages with a single flush
+    pub async fn send_messages(&self, messages: &[WorldHostS2CMessage]) -> io::Result<()> {
+        let messages: Vec<_> = messages
+            .iter()
+            .filter(|message| self.supports_message(message))
+            .collect();
+        if messages.is_empty() || !self.is_open() {
+            return Ok(());
+        }
+        self.send_with_timeout(&messages).await
+    }
+
+    /// Closes the connection if the client doesn't read the messages within [SEND_TIMEOUT], or
+    /// the write fails
+    async fn send_with_timeout(&self, messages: &[&WorldHostS2CMessage]) -> io::Result<()> {
+        let mut write = self.write.lock().await;
+        let result = match timeout(SEND_TIMEOUT, write.send_messages(messages)).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Client stopped reading messages",
+            )),
+        };
+        if result.is_err() {
+            // Nothing more will be written, so don't hold on to what's queued
+            write.unsent = Vec::new();
+            self.mark_closed();
         }
+        result
     }
 
     pub async fn close_error(&self, message: String) {
-        self.write.lock().await.close_error(message).await
+        let mut write = self.write.lock().await;
+        if timeout(SEND_TIMEOUT, write.close_error(message))
+            .await
+            .is_err()
+        {
+            warn!("Timed out closing connection {}", self.id);
+        }
+        write.unsent = Vec::new();
+    }
+
+    /// Whether the message exists in this connection's protocol version. Messages that don't are
+    /// counted in [SKIPPED_BY_TYPE] and logged, and shouldn't be sent.
+    fn supports_message(&self, message: &WorldHostS2CMessage) -> bool {
+        if self.protocol_version >= message.first_protocol() {
+            return true;
+        }
+        IntervalCounters::increment(&SKIPPED_BY_TYPE[message.type_id() as usize]);
+        debug!(
+            "Not sending {} to {}, whose protocol version {} is too old",
+            message.name(),
+            self.id,
+            self.protocol_version
+        );
+        false
     }
 }
 
@@ -83,11 +219,201 @@ impl ConnectionRead {
 }
 
 impl ConnectionWrite {
-    async fn send_message(&mut self, message: &WorldHostS2CMessage) -> io::Result<()> {
-        self.socket.send_message(message, &mut self.cipher).await
+    async fn send_messages(&mut self, messages: &[&WorldHostS2CMessage]) -> io::Result<()> {
+        for message in messages {
+            self.unsent.extend(SocketWriteWrapper::encode_message(
+                message,
+                self.protocol_version,
+                &mut self.cipher,
+                self.compress,
+            ));
+        }
+        self.write_unsent().await
+    }
+
+    async fn write_unsent(&mut self) -> io::Result<()> {
+        while !self.unsent.is_empty() {
+            let written = self.socket.0.write(&self.unsent).await?;
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.unsent.drain(..written);
+        }
+        self.socket.0.flush().await
     }
 
     async fn close_error(&mut self, message: String) {
+        if let Err(error) = self.write_unsent().await {
+            warn!("Failed to send queued messages before closing: {error}");
+        }
         self.socket.close_error(message, &mut self.cipher).await
     }
 }
+
+#[cfg(test)]
+impl ConnectionInfo {
+    /// An open connection talking to the returned stream, which plays the client
+    pub fn for_test(
+        id: ConnectionId,
+        user_uuid: Uuid,
+        addr: IpAddr,
+        protocol_version: u32,
+    ) -> (Connection, tokio::io::DuplexStream) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (read, write) = tokio::io::split(server);
+        let capabilities = ProtocolCapabilities::from_version(protocol_version);
+        let connection = Arc::new(ConnectionInfo {
+            id,
+            addr,
+            user_uuid,
+            protocol_version,
+            capabilities,
+            brand: None,
+            offline_mode: false,
+            open: AtomicBool::new(true),
+            closed: Notify::new(),
+            country: OnceLock::new(),
+            grid_cell: OnceLock::new(),
+            hosting_asn: None,
+            state: Mutex::new(ConnectionState {
+                external_proxy: None,
+                open_to_friends: HashSet::new(),
+                presence_subscriptions: HashSet::new(),
+                listed_friends: HashSet::new(),
+                warned_legacy_query_response: false,
+                warned_open_to_friends_cap: false,
+            }),
+            read: Mutex::new(ConnectionRead {
+                socket: SocketReadWrapper(Box::new(read)),
+                cipher: None,
+            }),
+            write: Mutex::new(ConnectionWrite {
+                socket: SocketWriteWrapper(Box::new(write)),
+                cipher: None,
+                unsent: Vec::new(),
+                compress: false,
+                protocol_version,
+            }),
+        });
+        (connection, client)
+    }
+}
+
+/// Reads the next message sent to a [ConnectionInfo::for_test] connection
+#[cfg(test)]
+pub async fn read_test_message(
+    client: &mut tokio::io::DuplexStream,
+    protocol_version: u32,
+) -> io::Result<WorldHostS2CMessage> {
+    use tokio::io::AsyncReadExt;
+
+    let size = client.read_u32().await? as usize;
+    let mut data = vec![0; size];
+    client.read_exact(&mut data).await?;
+    WorldHostS2CMessage::parse(data[0], &data[1..], protocol_version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::protocol_versions::STABLE;
+    use crate::protocol::s2c_message::{
+        FRIEND_REQUEST_CANCELLED_ID, IS_OFFLINE_TO_ID, SERVER_CAPABILITIES_ID, WARNING_ID,
+    };
+    use std::net::Ipv4Addr;
+    use tokio::io::AsyncReadExt;
+
+    fn connection(protocol_version: u32) -> (Connection, tokio::io::DuplexStream) {
+        ConnectionInfo::for_test(
+            ConnectionId::new(1).unwrap(),
+            Uuid::from_u128(1),
+            Ipv4Addr::LOCALHOST.into(),
+            protocol_version,
+        )
+    }
+
+    fn skipped(type_id: u8) -> u64 {
+        SKIPPED_BY_TYPE[type_id as usize].load(Ordering::Relaxed)
+    }
+
+    /// Reads every frame the connection wrote, returning their type IDs
+    async fn received_types(
+        connection: Connection,
+        mut client: tokio::io::DuplexStream,
+    ) -> Vec<u8> {
+        drop(connection);
+        let mut data = vec![];
+        client.read_to_end(&mut data).await.unwrap();
+        let mut types = vec![];
+        let mut rest = data.as_slice();
+        while !rest.is_empty() {
+            let (size, body) = rest.split_at(4);
+            let size = u32::from_be_bytes(size.try_into().unwrap()) as usize;
+            types.push(body[0]);
+            rest = &body[size..];
+        }
+        types
+    }
+
+    #[tokio::test]
+    async fn send_message_skips_and_counts_newer_messages() {
+        let (connection, client) = connection(STABLE);
+        let before = skipped(IS_OFFLINE_TO_ID);
+        let outcome = connection
+            .send_message(&WorldHostS2CMessage::IsOfflineTo {
+                user: Uuid::from_u128(2),
+            })
+            .await
+            .unwrap();
+        assert_eq!(outcome, SendOutcome::SkippedOldProtocol);
+        assert_eq!(skipped(IS_OFFLINE_TO_ID), before + 1);
+        assert!(received_types(connection, client).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn send_messages_skips_and_counts_newer_messages() {
+        let (connection, client) = connection(STABLE);
+        let before_capabilities = skipped(SERVER_CAPABILITIES_ID);
+        let before_cancelled = skipped(FRIEND_REQUEST_CANCELLED_ID);
+        connection
+            .send_messages(&[
+                WorldHostS2CMessage::ServerCapabilities {
+                    allowed_join_types: 0,
+                },
+                WorldHostS2CMessage::Warning {
+                    message: "Old client".to_string(),
+                    important: false,
+                },
+                WorldHostS2CMessage::FriendRequestCancelled {
+                    from_user: Uuid::from_u128(2),
+                },
+            ])
+            .await
+            .unwrap();
+        assert_eq!(skipped(SERVER_CAPABILITIES_ID), before_capabilities + 1);
+        assert_eq!(skipped(FRIEND_REQUEST_CANCELLED_ID), before_cancelled + 1);
+        assert_eq!(received_types(connection, client).await, [WARNING_ID]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_client_is_closed() {
+        let (connection, _client) = connection(STABLE);
+        // Bigger than the test stream's buffer, so the client has to read to let it through
+        let message = WorldHostS2CMessage::Warning {
+            message: "x".repeat(128 * 1024),
+            important: false,
+        };
+        let error = connection.send_message(&message).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(!connection.is_open());
+        assert!(connection.write.lock().await.unsent.is_empty());
+        connection.wait_closed().await;
+
+        assert_eq!(
+            connection.send_message(&message).await.unwrap(),
+            SendOutcome::SkippedClosed
+        );
+        connection.close_error("Closed".to_string()).await;
+        assert!(connection.write.lock().await.unsent.is_empty());
+    }
+}
@@ -0,0 +1,26 @@
+use crate::serialization::serializable::PacketSerializable;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+/// ID of a connection to the proxy server. Unlike [super::connection_id::ConnectionId], these are
+/// a simple counter.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct ProxyConnectionId(pub u64);
+
+impl ProxyConnectionId {
+    pub fn next(self) -> Self {
+        ProxyConnectionId(self.0.wrapping_add(1))
+    }
+}
+
+impl Display for ProxyConnectionId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl PacketSerializable for ProxyConnectionId {
+    fn serialize_to(&self, buf: &mut Vec<u8>) {
+        self.0.serialize_to(buf)
+    }
+}
@@ -1,15 +1,56 @@
 use anyhow::bail;
+use lazy_static::lazy_static;
 use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashSet;
 use std::fmt::{Debug, Display, Formatter};
 use std::str::FromStr;
 
+// Officially assigned ISO 3166-1 alpha-2 codes, plus XK (Kosovo), which GeoLite uses
+const ASSIGNED_CODES: &str = "\
+    AD AE AF AG AI AL AM AO AQ AR AS AT AU AW AX AZ \
+    BA BB BD BE BF BG BH BI BJ BL BM BN BO BQ BR BS BT BV BW BY BZ \
+    CA CC CD CF CG CH CI CK CL CM CN CO CR CU CV CW CX CY CZ \
+    DE DJ DK DM DO DZ \
+    EC EE EG EH ER ES ET \
+    FI FJ FK FM FO FR \
+    GA GB GD GE GF GG GH GI GL GM GN GP GQ GR GS GT GU GW GY \
+    HK HM HN HR HT HU \
+    ID IE IL IM IN IO IQ IR IS IT \
+    JE JM JO JP \
+    KE KG KH KI KM KN KP KR KW KY KZ \
+    LA LB LC LI LK LR LS LT LU LV LY \
+    MA MC MD ME MF MG MH MK ML MM MN MO MP MQ MR MS MT MU MV MW MX MY MZ \
+    NA NC NE NF NG NI NL NO NP NR NU NZ \
+    OM \
+    PA PE PF PG PH PK PL PM PN PR PS PT PW PY \
+    QA \
+    RE RO RS RU RW \
+    SA SB SC SD SE SG SH SI SJ SK SL SM SN SO SR SS ST SV SX SY SZ \
+    TC TD TF TG TH TJ TK TL TM TN TO TR TT TV TW TZ \
+    UA UG UM US UY UZ \
+    VA VC VE VG VI VN VU \
+    WF WS \
+    XK \
+    YE YT \
+    ZA ZM ZW";
+
+lazy_static! {
+    static ref ASSIGNED: HashSet<[u8; 2]> = ASSIGNED_CODES
+        .split_whitespace()
+        .map(|code| code.as_bytes().try_into().unwrap())
+        .collect();
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct CountryCode {
     code: [u8; 2],
 }
 
 impl CountryCode {
+    /// Used in place of codes that aren't assigned to a country
+    pub const UNKNOWN: CountryCode = CountryCode { code: *b"XX" };
+
     pub fn new(a: char, b: char) -> anyhow::Result<Self> {
         Ok(Self {
             code: [Self::validate(a)?, Self::validate(b)?],
@@ -27,6 +68,10 @@ impl CountryCode {
     pub fn code(&self) -> [u8; 2] {
         self.code
     }
+
+    pub fn is_assigned(&self) -> bool {
+        ASSIGNED.contains(&self.code)
+    }
 }
 
 impl FromStr for CountryCode {
@@ -1,17 +1,35 @@
+use crate::connection::connection_id::ConnectionId;
 use crate::lat_long::LatitudeLongitude;
-use serde::{Deserialize, Serialize};
+use anyhow::bail;
+use log::error;
+use schemars::{JsonSchema, schema_for};
+use serde::de::{MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::{fs, io};
+use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Debug)]
+pub const EXTERNAL_PROXIES_PATH: &str = "external_proxies.json";
+pub const RESERVED_IDS_PATH: &str = "reserved_ids.json";
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct ExternalProxy {
+    /// Location of the proxy, used to pick the closest proxy for each client
     pub lat_long: LatitudeLongitude,
 
+    /// Address of the proxy. Omitted for the proxy built into this server.
     pub addr: Option<String>,
 
+    /// Port of the proxy's World Host protocol
     #[serde(default = "default_port")]
     pub port: u16,
 
+    /// Base address for proxied connections. Defaults to addr.
     pub base_addr: Option<String>,
 
+    /// Port of the proxy's Minecraft server
     #[serde(default = "default_mc_port")]
     pub mc_port: u16,
 }
@@ -23,3 +41,212 @@ fn default_port() -> u16 {
 fn default_mc_port() -> u16 {
     25565
 }
+
+pub fn external_proxies_schema() -> String {
+    serde_json::to_string_pretty(&schema_for!(Vec<ExternalProxy>)).unwrap()
+}
+
+#[derive(Debug)]
+pub enum ExternalProxiesError {
+    /// The file isn't a JSON array at all
+    Syntax(serde_json::Error),
+    /// The file is an array, but some of its entries are invalid
+    Entries(Vec<ExternalProxyEntryError>),
+    /// More than one entry is missing addr, so it's ambiguous which is this server
+    MultipleLocal,
+}
+
+#[derive(Debug)]
+pub struct ExternalProxyEntryError {
+    pub index: usize,
+    pub field: String,
+    pub message: String,
+}
+
+impl Display for ExternalProxyEntryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "entry {}", self.index)?;
+        if self.field != "." {
+            write!(f, ", field {}", self.field)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Parses each entry separately, so that every invalid entry is reported with its index and the
+/// path to the invalid field
+pub fn parse_external_proxies(json: &[u8]) -> Result<Vec<ExternalProxy>, ExternalProxiesError> {
+    let entries: Vec<serde_json::Value> =
+        serde_json::from_slice(json).map_err(ExternalProxiesError::Syntax)?;
+    let mut proxies: Vec<ExternalProxy> = Vec::with_capacity(entries.len());
+    let mut errors = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        match serde_path_to_error::deserialize(entry) {
+            Ok(proxy) => proxies.push(proxy),
+            Err(error) => errors.push(ExternalProxyEntryError {
+                index,
+                field: error.path().to_string(),
+                message: error.into_inner().to_string(),
+            }),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ExternalProxiesError::Entries(errors));
+    }
+    if proxies.iter().filter(|proxy| proxy.addr.is_none()).count() > 1 {
+        return Err(ExternalProxiesError::MultipleLocal);
+    }
+    Ok(proxies)
+}
+
+#[derive(Debug)]
+pub enum ReadExternalProxiesError {
+    Io(io::Error),
+    Parse(ExternalProxiesError),
+}
+
+impl ReadExternalProxiesError {
+    /// Logs the error, with a line for each invalid entry
+    pub fn log(&self) {
+        match self {
+            ReadExternalProxiesError::Io(error) => {
+                error!("Error reading {EXTERNAL_PROXIES_PATH}: {error}")
+            }
+            ReadExternalProxiesError::Parse(ExternalProxiesError::Syntax(error)) => {
+                error!("Error parsing {EXTERNAL_PROXIES_PATH}: {error}")
+            }
+            ReadExternalProxiesError::Parse(ExternalProxiesError::Entries(errors)) => {
+                error!(
+                    "{EXTERNAL_PROXIES_PATH} has {} invalid entries:",
+                    errors.len()
+                );
+                for error in errors {
+                    error!("  {error}");
+                }
+            }
+            ReadExternalProxiesError::Parse(ExternalProxiesError::MultipleLocal) => {
+                error!("{EXTERNAL_PROXIES_PATH} must have no more than one entry without addr.")
+            }
+        }
+    }
+}
+
+/// None if the file doesn't exist
+pub fn read_external_proxies(
+    path: &Path,
+) -> Result<Option<Vec<ExternalProxy>>, ReadExternalProxiesError> {
+    if !fs::exists(path).map_err(ReadExternalProxiesError::Io)? {
+        return Ok(None);
+    }
+    let json = fs::read(path).map_err(ReadExternalProxiesError::Io)?;
+    parse_external_proxies(&json)
+        .map(Some)
+        .map_err(ReadExternalProxiesError::Parse)
+}
+
+/// The entries of reserved_ids.json in file order, so that duplicate keys can be reported instead
+/// of silently overwriting each other
+struct ReservedIdEntries(Vec<(ConnectionId, Uuid)>);
+
+impl<'de> Deserialize<'de> for ReservedIdEntries {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntriesVisitor;
+
+        impl<'de> Visitor<'de> for EntriesVisitor {
+            type Value = ReservedIdEntries;
+
+            fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+                f.write_str("an object mapping connection IDs to UUIDs")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(ReservedIdEntries(entries))
+            }
+        }
+
+        deserializer.deserialize_map(EntriesVisitor)
+    }
+}
+
+/// Parses reserved_ids.json, an object mapping connection IDs to the UUID of the only user that
+/// may claim them. IDs that are written differently but parse to the same ID are duplicates.
+pub fn parse_reserved_ids(json: &[u8]) -> anyhow::Result<HashMap<ConnectionId, Uuid>> {
+    let ReservedIdEntries(entries) = serde_json::from_slice(json)?;
+    let mut result = HashMap::with_capacity(entries.len());
+    for (id, owner) in entries {
+        if result.insert(id, owner).is_some() {
+            bail!("{id} ({}) is listed more than once", id.short_string());
+        }
+    }
+    Ok(result)
+}
+
+/// Empty if the file doesn't exist
+pub fn read_reserved_ids(path: &Path) -> anyhow::Result<HashMap<ConnectionId, Uuid>> {
+    if !fs::exists(path)? {
+        return Ok(HashMap::new());
+    }
+    parse_reserved_ids(&fs::read(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry_errors(json: &str) -> Vec<(usize, String)> {
+        match parse_external_proxies(json.as_bytes()) {
+            Err(ExternalProxiesError::Entries(errors)) => errors
+                .into_iter()
+                .map(|error| (error.index, error.field))
+                .collect(),
+            result => panic!("Expected entry errors, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn valid_proxies() {
+        let proxies = parse_external_proxies(
+            br#"[{"lat_long": [51.5, -0.1], "addr": "london.example.com", "port": 1234}, {"lat_long": [0, 0], "base_addr": "example.com"}]"#,
+        )
+        .unwrap();
+        assert_eq!(proxies.len(), 2);
+        assert_eq!(proxies[0].port, 1234);
+        assert_eq!(proxies[0].mc_port, 25565);
+        assert_eq!(proxies[1].addr, None);
+        assert_eq!(proxies[1].base_addr.as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn every_invalid_entry_is_reported() {
+        assert_eq!(
+            entry_errors(
+                r#"[{"lat_long": [0, 0]}, {"addr": "a.example.com"}, {"lat_long": [0, 0], "port": "x"}, {"lat_long": [0, 0], "mc_port": 70000}]"#
+            ),
+            [
+                (1, ".".to_string()),
+                (2, "port".to_string()),
+                (3, "mc_port".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn not_an_array() {
+        assert!(matches!(
+            parse_external_proxies(br#"{"lat_long": [0, 0]}"#),
+            Err(ExternalProxiesError::Syntax(_))
+        ));
+    }
+
+    #[test]
+    fn only_one_local_proxy() {
+        assert!(matches!(
+            parse_external_proxies(br#"[{"lat_long": [0, 0]}, {"lat_long": [1, 1]}]"#),
+            Err(ExternalProxiesError::MultipleLocal)
+        ));
+    }
+}
@@ -1,6 +1,8 @@
-use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt::{Display, Formatter};
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Copy, Clone)]
 pub struct LatitudeLongitude(pub f64, pub f64);
 
 impl LatitudeLongitude {
@@ -15,4 +17,34 @@ impl LatitudeLongitude {
 
         2.0 * f64::min(1.0, a.sqrt()).asin()
     }
+
+    pub fn grid_cell(&self) -> GridCell {
+        GridCell {
+            lat: self.0.floor().clamp(-90.0, 89.0) as i16,
+            long: self.1.floor().clamp(-180.0, 179.0) as i16,
+        }
+    }
+}
+
+/// A 1-degree latitude/longitude cell, identified by its south-west corner. There are at most
+/// 180 * 360 of these.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GridCell {
+    pub lat: i16,
+    pub long: i16,
+}
+
+impl Display for GridCell {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.lat, self.long)
+    }
+}
+
+impl Serialize for GridCell {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
 }
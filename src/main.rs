@@ -15,62 +15,93 @@ mod socket_wrapper;
 mod util;
 
 use crate::cli::args::Args;
-use crate::json_data::ExternalProxy;
+use crate::connection::connection_id::WordList;
+use crate::json_data::{
+    EXTERNAL_PROXIES_PATH, RESERVED_IDS_PATH, external_proxies_schema, read_external_proxies,
+    read_reserved_ids,
+};
 use crate::server_state::{FullServerConfig, ServerState};
 use clap::Parser;
 use log::{error, info};
-use std::fs::File;
-use std::io::BufReader;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::fs;
 use std::path::Path;
 use std::process::exit;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::{fs, io};
-use tokio::time::sleep;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::ring;
 
 pub const SERVER_VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), '/', env!("CARGO_PKG_VERSION"));
 
 fn main() {
     let args = Args::parse();
-    logging::init_logging(args.log_config);
-    let mut base_addr = args.base_addr;
+    logging::init_logging(args.log_config.clone());
 
-    let external_servers = read_external_servers().unwrap_or_else(|error| {
-        error!("Error parsing external_proxies.json: {error}");
-        exit(1);
-    });
-    if let Some(servers) = &external_servers {
-        if servers.iter().filter(|s| s.addr.is_none()).count() > 1 {
-            error!("external_proxies.json defines must have no more than one missing addr field.");
+    if args.print_external_proxies_schema {
+        println!("{}", external_proxies_schema());
+        return;
+    }
+
+    let external_servers =
+        read_external_proxies(Path::new(EXTERNAL_PROXIES_PATH)).unwrap_or_else(|error| {
+            error.log();
             exit(1);
-        }
-        for server in servers {
-            if server.addr.is_none() && server.base_addr.is_some() {
-                if base_addr.is_none() {
-                    base_addr = server.base_addr.clone();
-                } else {
-                    info!(
-                        "Both the CLI and external_proxies.json specify baseAddr for the local server."
-                    );
-                    info!(
-                        "--base-addr from the CLI will override the value in external_proxies.json."
-                    );
-                }
-                break;
+        });
+
+    // Installed before anything parses or displays a connection ID, so that an invalid list is
+    // reported here instead of panicking later
+    let word_list = match &args.cid_wordlist {
+        Some(path) => fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| WordList::parse(&text, true))
+            .map_err(|error| format!("Error loading word list {}: {error}", path.display())),
+        None => WordList::embedded()
+            .map_err(|error| format!("The embedded word list is invalid: {error}")),
+    };
+    match word_list {
+        Ok(word_list) => {
+            if word_list.install().is_err() {
+                panic!("Word list used before it was installed");
             }
         }
+        Err(error) => {
+            error!("{error}");
+            exit(1);
+        }
+    }
+    if let Some(path) = &args.cid_wordlist {
+        info!("Using connection ID word list {}", path.display());
     }
 
-    if let Some(shutdown_time) = args.shutdown_time {
-        tokio::spawn(async move {
-            info!("Automatically shutting down after {shutdown_time:?}");
-            sleep(shutdown_time).await;
-            info!("Shutting down because shutdown_time ({shutdown_time:?}) was reached");
-            exit(0);
-        });
+    let reserved_ids = read_reserved_ids(Path::new(RESERVED_IDS_PATH)).unwrap_or_else(|error| {
+        error!("Error reading {RESERVED_IDS_PATH}: {error}");
+        exit(1);
+    });
+    if !reserved_ids.is_empty() {
+        info!("Loaded {} reserved connection IDs", reserved_ids.len());
+    }
+    if args.validate_config {
+        info!("external_proxies.json and reserved_ids.json are valid");
+        return;
     }
 
+    let tls_config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => match load_tls_config(cert, key) {
+            Ok(config) => {
+                info!("Loaded TLS certificate {}", cert.display());
+                Some(Arc::new(config))
+            }
+            Err(error) => {
+                error!("Error loading TLS certificate: {error}");
+                exit(1);
+            }
+        },
+        _ => None,
+    };
+
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .thread_name_fn(|| {
@@ -81,15 +112,12 @@ fn main() {
         .build()
         .unwrap();
     rt.block_on(async move {
-        ServerState::new(FullServerConfig {
-            port: args.port,
-            base_addr,
-            in_java_port: args.in_java_port,
-            ex_java_port: args.ex_java_port.unwrap_or(args.in_java_port),
-            analytics_time: args.analytics_time,
-            external_servers: external_servers
-                .map(|servers| servers.into_iter().map(Arc::new).collect()),
-        })
+        ServerState::new(FullServerConfig::from_args(
+            args,
+            tls_config,
+            external_servers,
+            reserved_ids,
+        ))
         .run()
         .await;
     });
@@ -108,12 +136,15 @@ pub fn init_logging(log_config: Option<String>) {
     }
 }
 
-fn read_external_servers() -> io::Result<Option<Vec<ExternalProxy>>> {
-    let path = Path::new("external_proxies.json");
-    if !fs::exists(path)? {
-        return Ok(None);
+fn load_tls_config(cert: &Path, key: &Path) -> anyhow::Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(cert)?.collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        anyhow::bail!("{} contains no certificates", cert.display());
     }
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-    Ok(serde_json::from_reader(reader)?)
+    let key = PrivateKeyDer::from_pem_file(key)?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(config)
 }
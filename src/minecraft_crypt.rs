@@ -1,12 +1,15 @@
 use aes::Aes128;
+use anyhow::bail;
 use cfb8::Cfb8;
 use cfb8::cipher::NewCipher;
 use log::error;
 use rsa::pkcs8::EncodePublicKey;
 use rsa::{Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
 use sha1::Digest;
+use std::hint::black_box;
 use std::ops::Deref;
 use std::process::exit;
+use zeroize::Zeroizing;
 
 pub struct RsaKeyPair {
     pub private: RsaPrivateKey,
@@ -25,6 +28,17 @@ pub fn generate_key_pair() -> RsaKeyPair {
     RsaKeyPair { public, private }
 }
 
+/// SHA-1 of the DER-encoded public key, in hex, for logging
+pub fn fingerprint(public_key: &RsaPublicKey) -> anyhow::Result<String> {
+    let digest = digest_data_parts(vec![public_key.to_public_key_der()?.as_bytes()]);
+    Ok(digest.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Plain SHA-1 of `data`, for remembering client-supplied bytes without keeping them
+pub fn sha1_digest(data: &[u8]) -> Vec<u8> {
+    digest_data_parts(vec![data])
+}
+
 pub fn digest_data(
     id: &str,
     public_key: &RsaPublicKey,
@@ -45,10 +59,82 @@ fn digest_data_parts(parts: Vec<&[u8]>) -> Vec<u8> {
     hasher.finalize().deref().to_owned()
 }
 
-pub fn decrypt_using_key(key: &RsaPrivateKey, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
-    Ok(key.decrypt(Pkcs1v15Encrypt, &data)?)
+/// The result is wiped from memory when it's dropped, since it's usually a secret
+pub fn decrypt_using_key(key: &RsaPrivateKey, data: Vec<u8>) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    Ok(Zeroizing::new(key.decrypt(Pkcs1v15Encrypt, &data)?))
+}
+
+/// Secret keys are AES-128 keys, which are also used as the IV
+pub const SECRET_KEY_LENGTH: usize = 16;
+
+/// Checked before the key is used for anything, so that a bad key gets a clear error
+pub fn validate_secret_key(key: &[u8]) -> anyhow::Result<()> {
+    if key.len() != SECRET_KEY_LENGTH {
+        bail!(
+            "Secret key must be {SECRET_KEY_LENGTH} bytes, but was {} bytes",
+            key.len()
+        );
+    }
+    if key.iter().all(|&b| b == 0) {
+        bail!("Secret key must not be all zeros");
+    }
+    Ok(())
 }
 
 pub fn get_cipher(key: &[u8]) -> anyhow::Result<Aes128Cfb> {
     Ok(Aes128Cfb::new_from_slices(key, key)?)
 }
+
+/// Compares secrets without stopping at the first difference. A client controls what it sends, so
+/// an early exit would let it learn how many leading bytes it got right from the response time.
+/// Lengths aren't secret, so a length mismatch returns immediately.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a
+        .iter()
+        .zip(b)
+        .fold(0, |difference, (x, y)| difference | (x ^ y));
+    // Keeps the compiler from turning the fold back into an early-exit comparison
+    black_box(difference) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_key_validation() {
+        for length in [0, 15, 32] {
+            assert_eq!(
+                validate_secret_key(&vec![1; length])
+                    .unwrap_err()
+                    .to_string(),
+                format!("Secret key must be 16 bytes, but was {length} bytes")
+            );
+        }
+        assert!(validate_secret_key(&[1; SECRET_KEY_LENGTH]).is_ok());
+        // A single nonzero byte is enough
+        let mut key = [0; SECRET_KEY_LENGTH];
+        assert_eq!(
+            validate_secret_key(&key).unwrap_err().to_string(),
+            "Secret key must not be all zeros"
+        );
+        key[SECRET_KEY_LENGTH - 1] = 1;
+        assert!(validate_secret_key(&key).is_ok());
+    }
+
+    #[test]
+    fn constant_time_comparison() {
+        let secret = [1, 2, 3, 4, 5, 6, 7, 8];
+        assert!(constant_time_eq(&secret, &secret.clone()));
+        assert!(constant_time_eq(&[], &[]));
+        assert!(!constant_time_eq(&secret, &[8, 7, 6, 5, 4, 3, 2, 1]));
+        assert!(!constant_time_eq(&secret, &[1, 2, 3, 4, 5, 6, 7, 9]));
+        assert!(!constant_time_eq(&secret, &[0, 2, 3, 4, 5, 6, 7, 8]));
+        assert!(!constant_time_eq(&secret, &secret[..7]));
+        assert!(!constant_time_eq(&secret[..7], &secret));
+        assert!(!constant_time_eq(&secret, &[]));
+    }
+}
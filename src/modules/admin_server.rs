@@ -0,0 +1,499 @@
+//! Admin HTTP API, only bound on localhost with --admin-port. Each connection handles one
+//! HTTP/1.1 request, and every action is logged to the `audit` log target.
+
+use crate::modules::main_server::dequeue_friend_requests;
+use crate::protocol::message_handler::blocks;
+use crate::protocol::s2c_message::WorldHostS2CMessage;
+use crate::protocol::security::SecurityLevel;
+use crate::server_state::ServerState;
+use log::{error, info, warn};
+use serde_json::json;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::process::exit;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+const AUDIT_TARGET: &str = "audit";
+/// Longest request line and headers accepted. Bodies are ignored.
+const MAX_REQUEST_HEAD_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn run_admin_server(server: Arc<ServerState>) {
+    let Some(port) = server.config.admin_port else {
+        return;
+    };
+    info!("Starting admin server on port {port}");
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+        .await
+        .unwrap_or_else(|error| {
+            error!("Failed to start admin server: {error}");
+            exit(1);
+        });
+    info!("Started admin server on {}", listener.local_addr().unwrap());
+
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                warn!("Failed to accept admin connection: {error}");
+                continue;
+            }
+        };
+        let server = server.clone();
+        tokio::spawn(async move {
+            let result = timeout(REQUEST_TIMEOUT, handle_request(socket, addr, &server))
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+            if let Err(error) = result {
+                warn!("Admin request from {addr} failed: {error}");
+            }
+        });
+    }
+}
+
+/// Friend requests sent by [redeliver_friend_requests]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Redelivered {
+    /// Requests that were still queued for the user, which are now forgotten
+    pub queued: usize,
+    /// Requests that were already delivered within --friend-request-retention
+    pub replayed: usize,
+}
+
+#[derive(Debug)]
+pub enum RedeliverError {
+    NotOnline,
+    Send(io::Error),
+}
+
+impl RedeliverError {
+    fn status(&self) -> u16 {
+        match self {
+            RedeliverError::NotOnline => 409,
+            RedeliverError::Send(_) => 500,
+        }
+    }
+}
+
+impl Display for RedeliverError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RedeliverError::NotOnline => f.write_str("User is not online"),
+            RedeliverError::Send(error) => write!(f, "Failed to send friend requests: {error}"),
+        }
+    }
+}
+
+/// Sends an online user's queued friend requests to all of their connections, followed by the
+/// requests they were sent within --friend-request-retention, for requests the client lost
+pub async fn redeliver_friend_requests(
+    server: &ServerState,
+    user: Uuid,
+) -> Result<Redelivered, RedeliverError> {
+    let connections = server.connections.by_user_id(user);
+    if connections.is_empty() {
+        return Err(RedeliverError::NotOnline);
+    }
+    // Taken first, since dequeuing records the queued requests as delivered
+    let recent = server.delivered_friend_requests.lock().await.recent(user);
+    let queued = dequeue_friend_requests(user, &connections, server)
+        .await
+        .map_err(RedeliverError::Send)?;
+    for connection in &connections {
+        let mut messages = Vec::with_capacity(recent.len());
+        for &from_user in &recent {
+            if !blocks(server, user, from_user).await {
+                messages.push(WorldHostS2CMessage::FriendRequest {
+                    from_user,
+                    security: SecurityLevel::from(from_user, true, server.config.offline_mode),
+                });
+            }
+        }
+        connection
+            .send_messages(&messages)
+            .await
+            .map_err(RedeliverError::Send)?;
+    }
+    Ok(Redelivered {
+        queued,
+        replayed: recent.len(),
+    })
+}
+
+struct Response {
+    status: u16,
+    body: serde_json::Value,
+}
+
+impl Response {
+    fn error(status: u16, message: impl Display) -> Self {
+        Self {
+            status,
+            body: json!({ "error": message.to_string() }),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            _ => "Internal Server Error",
+        };
+        let body = self.body.to_string();
+        format!(
+            "HTTP/1.1 {} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            self.status,
+            body.len()
+        )
+        .into_bytes()
+    }
+}
+
+async fn handle_request<S: AsyncRead + AsyncWrite + Unpin>(
+    mut socket: S,
+    addr: SocketAddr,
+    server: &ServerState,
+) -> io::Result<()> {
+    let response = match read_request_line(&mut socket).await? {
+        Some((method, path)) => route(&method, &path, addr, server).await,
+        None => Response::error(400, "Malformed request"),
+    };
+    socket.write_all(&response.encode()).await?;
+    socket.shutdown().await
+}
+
+/// Reads the request's head, returning its method and path. None if it isn't a valid HTTP/1.x
+/// request.
+async fn read_request_line<S: AsyncRead + Unpin>(
+    socket: &mut S,
+) -> io::Result<Option<(String, String)>> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD_SIZE {
+            return Ok(None);
+        }
+        let read = socket.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+    let Some(line) = head
+        .split(|&b| b == b'\r')
+        .next()
+        .and_then(|line| str::from_utf8(line).ok())
+    else {
+        return Ok(None);
+    };
+    let mut parts = line.split(' ');
+    Ok(
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+                let path = target.split('?').next().unwrap_or_default();
+                Some((method.to_string(), path.to_string()))
+            }
+            _ => None,
+        },
+    )
+}
+
+async fn route(method: &str, path: &str, addr: SocketAddr, server: &ServerState) -> Response {
+    let segments: Vec<_> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["users", user, "redeliver-friend-requests"] => {
+            if method != "POST" {
+                return Response::error(405, format!("{method} is not allowed"));
+            }
+            let Ok(user) = Uuid::parse_str(user) else {
+                return Response::error(400, format!("Invalid UUID {user}"));
+            };
+            match redeliver_friend_requests(server, user).await {
+                Ok(redelivered) => {
+                    info!(
+                        target: AUDIT_TARGET,
+                        "{addr} redelivered {} queued and {} recent friend requests to {user}",
+                        redelivered.queued,
+                        redelivered.replayed
+                    );
+                    Response {
+                        status: 200,
+                        body: json!({
+                            "queued": redelivered.queued,
+                            "replayed": redelivered.replayed,
+                        }),
+                    }
+                }
+                Err(error) => {
+                    info!(
+                        target: AUDIT_TARGET,
+                        "{addr} failed to redeliver friend requests to {user}: {error}"
+                    );
+                    Response::error(error.status(), error)
+                }
+            }
+        }
+        _ => Response::error(404, format!("No such endpoint {path}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::connection_id::ConnectionId;
+    use crate::connection::{Connection, ConnectionInfo, read_test_message};
+    use crate::protocol::c2s_message::WorldHostC2SMessage;
+    use crate::protocol::message_handler::handle_message;
+    use crate::protocol::protocol_versions::CURRENT;
+    use crate::server_state::FullServerConfig;
+    use linked_hash_set::LinkedHashSet;
+    use tokio::io::DuplexStream;
+
+    const USER: Uuid = Uuid::from_u128(0x1000);
+    const SENDER: Uuid = Uuid::from_u128(0x2000);
+    const OTHER_SENDER: Uuid = Uuid::from_u128(0x3000);
+
+    fn server() -> ServerState {
+        ServerState::new(FullServerConfig::for_test())
+    }
+
+    fn connect(server: &ServerState, id: u64, user: Uuid) -> (Connection, DuplexStream) {
+        let (connection, client) = ConnectionInfo::for_test(
+            ConnectionId::new(id).unwrap(),
+            user,
+            Ipv4Addr::LOCALHOST.into(),
+            CURRENT,
+        );
+        assert!(server.connections.add(connection.clone()));
+        (connection, client)
+    }
+
+    async fn next_friend_request(client: &mut DuplexStream) -> Uuid {
+        match read_test_message(client, CURRENT).await.unwrap() {
+            WorldHostS2CMessage::FriendRequest { from_user, .. } => from_user,
+            message => panic!("Expected FriendRequest, got {message:?}"),
+        }
+    }
+
+    async fn assert_nothing_sent(client: &mut DuplexStream) {
+        let next = timeout(
+            Duration::from_millis(50),
+            read_test_message(client, CURRENT),
+        )
+        .await;
+        assert!(next.is_err(), "Unexpected message {next:?}");
+    }
+
+    async fn queue(server: &ServerState, from_user: Uuid, to_user: Uuid) {
+        server
+            .received_friend_requests
+            .lock()
+            .await
+            .entry(to_user)
+            .or_insert_with(LinkedHashSet::new)
+            .insert(from_user);
+        server
+            .remembered_friend_requests
+            .lock()
+            .await
+            .entry(from_user)
+            .or_insert_with(LinkedHashSet::new)
+            .insert(to_user);
+    }
+
+    async fn request(server: &ServerState, request: &str) -> String {
+        let (mut client, socket) = tokio::io::duplex(4096);
+        client.write_all(request.as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 1234));
+        handle_request(socket, addr, server).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn redelivers_queued_requests() {
+        let server = server();
+        queue(&server, SENDER, USER).await;
+        queue(&server, OTHER_SENDER, USER).await;
+        let (_connection, mut client) = connect(&server, 1, USER);
+
+        let redelivered = redeliver_friend_requests(&server, USER).await.unwrap();
+        assert_eq!(
+            redelivered,
+            Redelivered {
+                queued: 2,
+                replayed: 0
+            }
+        );
+        assert_eq!(next_friend_request(&mut client).await, SENDER);
+        assert_eq!(next_friend_request(&mut client).await, OTHER_SENDER);
+        assert_nothing_sent(&mut client).await;
+        assert!(server.received_friend_requests.lock().await.is_empty());
+        assert!(
+            !server
+                .remembered_friend_requests
+                .lock()
+                .await
+                .contains_key(&SENDER)
+        );
+    }
+
+    #[tokio::test]
+    async fn replays_delivered_requests() {
+        let server = server();
+        let (sender, _sender_client) = connect(&server, 1, SENDER);
+        let (_connection, mut client) = connect(&server, 2, USER);
+        let (_other_connection, mut other_client) = connect(&server, 3, USER);
+        handle_message(
+            WorldHostC2SMessage::FriendRequest { to_user: USER },
+            &sender,
+            &server,
+        )
+        .await
+        .unwrap();
+        assert_eq!(next_friend_request(&mut client).await, SENDER);
+        assert_eq!(next_friend_request(&mut other_client).await, SENDER);
+
+        let redelivered = redeliver_friend_requests(&server, USER).await.unwrap();
+        assert_eq!(
+            redelivered,
+            Redelivered {
+                queued: 0,
+                replayed: 1
+            }
+        );
+        assert_eq!(next_friend_request(&mut client).await, SENDER);
+        assert_eq!(next_friend_request(&mut other_client).await, SENDER);
+
+        // Queued requests are replayable once they've been redelivered
+        queue(&server, OTHER_SENDER, USER).await;
+        redeliver_friend_requests(&server, USER).await.unwrap();
+        let redelivered = redeliver_friend_requests(&server, USER).await.unwrap();
+        assert_eq!(redelivered.replayed, 2);
+    }
+
+    #[tokio::test]
+    async fn cancelled_requests_are_not_replayed() {
+        let server = server();
+        let (sender, _sender_client) = connect(&server, 1, SENDER);
+        let (_connection, mut client) = connect(&server, 2, USER);
+        handle_message(
+            WorldHostC2SMessage::FriendRequest { to_user: USER },
+            &sender,
+            &server,
+        )
+        .await
+        .unwrap();
+        handle_message(
+            WorldHostC2SMessage::CancelFriendRequest { to_user: USER },
+            &sender,
+            &server,
+        )
+        .await
+        .unwrap();
+        assert_eq!(next_friend_request(&mut client).await, SENDER);
+        read_test_message(&mut client, CURRENT).await.unwrap();
+
+        let redelivered = redeliver_friend_requests(&server, USER).await.unwrap();
+        assert_eq!(redelivered.replayed, 0);
+        assert_nothing_sent(&mut client).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retention_expires() {
+        let server = server();
+        let (sender, _sender_client) = connect(&server, 1, SENDER);
+        let (_connection, _client) = connect(&server, 2, USER);
+        handle_message(
+            WorldHostC2SMessage::FriendRequest { to_user: USER },
+            &sender,
+            &server,
+        )
+        .await
+        .unwrap();
+        tokio::time::advance(server.config.friend_request_retention).await;
+        let redelivered = redeliver_friend_requests(&server, USER).await.unwrap();
+        assert_eq!(redelivered.replayed, 0);
+        assert_eq!(server.delivered_friend_requests.lock().await.shrink(), 0);
+    }
+
+    #[tokio::test]
+    async fn offline_user_is_an_error() {
+        let server = server();
+        queue(&server, SENDER, USER).await;
+        let result = redeliver_friend_requests(&server, USER).await;
+        assert!(matches!(result, Err(RedeliverError::NotOnline)));
+        // The queue is kept for when they connect
+        assert!(server.received_friend_requests.lock().await[&USER].contains(&SENDER));
+
+        let response = request(
+            &server,
+            &format!("POST /users/{USER}/redeliver-friend-requests HTTP/1.1\r\n\r\n"),
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 409 Conflict\r\n"),
+            "{response}"
+        );
+        assert!(
+            response.ends_with(r#"{"error":"User is not online"}"#),
+            "{response}"
+        );
+    }
+
+    #[tokio::test]
+    async fn http_redelivery() {
+        let server = server();
+        queue(&server, SENDER, USER).await;
+        let (_connection, mut client) = connect(&server, 1, USER);
+        let response = request(
+            &server,
+            &format!(
+                "POST /users/{USER}/redeliver-friend-requests HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n"
+            ),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(
+            response.ends_with(r#"{"queued":1,"replayed":0}"#),
+            "{response}"
+        );
+        assert_eq!(next_friend_request(&mut client).await, SENDER);
+    }
+
+    #[tokio::test]
+    async fn http_errors() {
+        let server = server();
+        for (request_text, status) in [
+            (
+                format!("GET /users/{USER}/redeliver-friend-requests HTTP/1.1\r\n\r\n"),
+                "405 Method Not Allowed",
+            ),
+            (
+                "POST /users/nobody/redeliver-friend-requests HTTP/1.1\r\n\r\n".to_string(),
+                "400 Bad Request",
+            ),
+            ("POST /users HTTP/1.1\r\n\r\n".to_string(), "404 Not Found"),
+            ("nonsense\r\n\r\n".to_string(), "400 Bad Request"),
+            ("POST / HTTP/1.1".to_string(), "400 Bad Request"),
+        ] {
+            let response = request(&server, &request_text).await;
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {status}\r\n")),
+                "{request_text:?}: {response}"
+            );
+        }
+    }
+}
@@ -1,66 +1,777 @@
-use crate::server_state::ServerState;
-use chrono::Local;
-use log::{error, info};
-use std::collections::HashMap;
-use std::path::Path;
-use std::sync::Arc;
+use crate::country_code::CountryCode;
+use crate::lat_long::GridCell;
+use crate::protocol::s2c_message::message_name;
+use crate::server_state::{FullServerConfig, ServerState};
+use crate::util::Redacted;
+use crate::{SERVER_VERSION, USER_AGENT};
+use chrono::{DateTime, Local, NaiveDate};
+use clap::ValueEnum;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use log::{error, info, warn};
+use reqwest::Url;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::hash::Hash;
+use std::io::Write;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fs as std_fs, io};
 use tokio::fs;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::task::spawn_blocking;
 use tokio::time::{Instant, MissedTickBehavior, interval_at};
 use try_catch::catch;
+use uuid::Uuid;
+
+/// Columns are only ever appended to, so that existing consumers keep working
+pub const CSV_HEADER: &str = "timestamp,total,countries,proxy_connections,proxy_opened,signals,port_lookups_completed,users,users_seen,peak_connections,peak_proxy_connections,final,joins_upnp,joins_proxy,joins_punch,joins_rejected,join_requests,direct_join_requests,grid_cells,legacy_query_responses,skipped_old_protocol,skipped_by_type,brands,auth_retries,auth_fallbacks,handshakes,auth_verified,auth_rejected,offline_mismatches,reserved_uuid_rejections,rate_limited\n";
+
+/// Counters incremented by the other modules and reset every analytics interval
+#[derive(Default)]
+pub struct IntervalCounters {
+    pub proxy_opened: AtomicU64,
+    pub signals: AtomicU64,
+    pub port_lookups_completed: AtomicU64,
+    pub peak_proxy_connections: AtomicUsize,
+    pub joins_upnp: AtomicU64,
+    pub joins_proxy: AtomicU64,
+    pub joins_punch: AtomicU64,
+    pub joins_rejected: AtomicU64,
+    pub join_requests: AtomicU64,
+    pub direct_join_requests: AtomicU64,
+    pub legacy_query_responses: AtomicU64,
+    pub skipped_old_protocol: AtomicU64,
+    pub auth_retries: AtomicU64,
+    pub auth_fallbacks: AtomicU64,
+    pub handshakes: AtomicU64,
+    pub auth_verified: AtomicU64,
+    pub auth_rejected: AtomicU64,
+    pub offline_mismatches: AtomicU64,
+    pub reserved_uuid_rejections: AtomicU64,
+    /// Connections refused by each rate limit bucket
+    pub rate_limited: Mutex<HashMap<String, u64>>,
+}
+
+impl IntervalCounters {
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn take(counter: &AtomicU64) -> u64 {
+        counter.swap(0, Ordering::Relaxed)
+    }
+
+    pub fn increment_keyed(counters: &Mutex<HashMap<String, u64>>, key: &str) {
+        let mut counters = counters.lock().unwrap();
+        match counters.get_mut(key) {
+            Some(count) => *count += 1,
+            None => {
+                counters.insert(key.to_string(), 1);
+            }
+        }
+    }
+
+    fn take_keyed(counters: &Mutex<HashMap<String, u64>>) -> HashMap<String, u64> {
+        mem::take(&mut counters.lock().unwrap())
+    }
+
+    pub fn record_peak(counter: &AtomicUsize, value: usize) {
+        counter.fetch_max(value, Ordering::Relaxed);
+    }
+
+    fn take_peak(counter: &AtomicUsize, current: usize) -> usize {
+        counter.swap(current, Ordering::Relaxed)
+    }
+}
+
+/// Messages dropped by [ConnectionInfo::send_message] because the recipient's protocol version is
+/// too old, indexed by type ID. This is global because send_message can't reach the server state.
+///
+/// [ConnectionInfo::send_message]: crate::connection::ConnectionInfo::send_message
+pub static SKIPPED_BY_TYPE: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum AnalyticsRotation {
+    /// Never rotate the analytics file
+    Off,
+    /// Rotate the analytics file when the local date changes
+    Daily,
+    /// Rotate the analytics file when it exceeds --analytics-rotation-size
+    Size,
+}
 
 pub async fn run_analytics(server: Arc<ServerState>) {
     let analytics_time = server.config.analytics_time;
     if analytics_time.is_zero() {
         return info!("Analytics disabled by request");
     }
-    info!("Starting analytics system to update every {analytics_time:?}");
-    let path = Path::new("analytics.csv");
+    let path = server.config.analytics_file.as_path();
+    info!(
+        "Starting analytics system to update {} every {analytics_time:?}",
+        path.display()
+    );
+    if let Err(error) = check_writable(path).await {
+        error!("Cannot write analytics to {}: {error}", path.display());
+        exit(1);
+    }
     let mut interval = interval_at(Instant::now() + analytics_time, analytics_time);
     interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
     loop {
         interval.tick().await;
-        catch! {
-            try {
-                if !fs::try_exists(path).await? || fs::metadata(path).await?.len() == 0 {
-                    info!("Creating new analytics.csv");
-                    fs::write(path, "timestamp,total,countries\n").await?;
-                }
-            } catch error {
-                error!("Failed to create analytics.csv: {error}");
+        write_analytics_sample(&server, false).await;
+    }
+}
+
+/// State kept between analytics samples
+pub struct AnalyticsWriter {
+    /// Date of the last write to the analytics file, used for daily rotation
+    file_date: Option<NaiveDate>,
+    webhook: Option<AnalyticsWebhook>,
+}
+
+impl AnalyticsWriter {
+    pub fn new(config: &FullServerConfig) -> Self {
+        let webhook = config.analytics_webhook.clone().and_then(|url| {
+            info!("Pushing analytics to {url}");
+            let secret = config.analytics_webhook_secret.clone();
+            AnalyticsWebhook::new(url, secret.map(|Redacted(secret)| secret))
+                .inspect_err(|error| error!("Failed to create analytics webhook client: {error}"))
+                .ok()
+        });
+        Self {
+            file_date: None,
+            webhook,
+        }
+    }
+}
+
+/// Collects a sample and writes it to the analytics file and webhook. `final_sample` marks
+/// samples written on shutdown, which cover less than a full interval. Those wait for the webhook
+/// push to finish.
+pub async fn write_analytics_sample(server: &ServerState, final_sample: bool) {
+    let mut writer = server.analytics_writer.lock().await;
+    let path = server.config.analytics_file.as_path();
+    let today = Local::now().date_naive();
+    let file_date = match writer.file_date {
+        Some(file_date) => file_date,
+        None => match fs::metadata(path).await.and_then(|meta| meta.modified()) {
+            Ok(modified) => DateTime::<Local>::from(modified).date_naive(),
+            Err(_) => today,
+        },
+    };
+    // Rotation happens before the header check, so the sample below always lands in a file
+    catch! {
+        try {
+            if should_rotate(server, path, file_date, today).await?
+                || has_old_header(path).await?
+            {
+                rotate(path, file_date, server.config.analytics_gzip).await?;
             }
+        } catch error {
+            error!("Failed to rotate {}: {error}", path.display());
         }
-        info!("Updating analytics.csv");
-        let timestamp = Local::now().format("%+");
-        let mut total = 0;
-        let mut by_country = HashMap::new();
-        {
-            for connection in server.connections.lock().await.iter() {
-                if let Some(country) = connection.state.lock().await.country {
-                    by_country
-                        .entry(country)
-                        .and_modify(|count| *count += 1)
-                        .or_insert(1);
-                }
-                total += 1;
+    }
+    writer.file_date = Some(today);
+    catch! {
+        try {
+            if !fs::try_exists(path).await? || fs::metadata(path).await?.len() == 0 {
+                info!("Creating new {}", path.display());
+                fs::write(path, CSV_HEADER).await?;
             }
+        } catch error {
+            error!("Failed to create {}: {error}", path.display());
+        }
+    }
+    info!("Updating {}", path.display());
+    let sample = AnalyticsSample::collect(server, final_sample).await;
+    catch! {
+        try {
+            let mut file = fs::OpenOptions::new().append(true).open(path).await?;
+            file.write_all(sample.to_csv_row().as_bytes()).await?;
+            // tokio finishes writes in the background unless flushed, and the final sample is
+            // followed by an exit
+            file.flush().await?;
+        } catch error {
+            error!("Failed to write to {}: {error}", path.display());
+        }
+    }
+    if let Some(webhook) = &writer.webhook {
+        if final_sample {
+            webhook.push(&sample).await;
+        } else {
+            let webhook = webhook.clone();
+            tokio::spawn(async move {
+                webhook.push(&sample).await;
+            });
+        }
+    }
+}
+
+/// Writes a final sample without awaiting, for when the process is about to end without the
+/// runtime getting to finish [write_analytics_sample]. Best effort: nothing is written if a sample
+/// is already being written or a lock the sample needs is held, and there's no rotation or webhook
+/// push. Returns whether a sample was written.
+pub fn write_final_sample_now(server: &ServerState) -> bool {
+    // Held until the sample is written, so that it can't interleave with an async write
+    let Ok(_writer) = server.analytics_writer.try_lock() else {
+        warn!("An analytics sample is already being written");
+        return false;
+    };
+    let Some(sample) = AnalyticsSample::try_collect(server, true) else {
+        warn!("Couldn't collect a final analytics sample without waiting");
+        return false;
+    };
+    let path = server.config.analytics_file.as_path();
+    match append_sample_now(path, &sample) {
+        Ok(()) => true,
+        Err(error) => {
+            error!("Failed to write to {}: {error}", path.display());
+            false
         }
-        let country_string = by_country
-            .into_iter()
-            .map(|(country, count)| format!("{country}:{count}"))
-            .collect::<Vec<String>>()
-            .join(";");
-        catch! {
-            try {
-                fs::OpenOptions::new()
-                    .append(true)
-                    .open(path)
-                    .await?
-                    .write_all(format!("{timestamp},{total},{country_string}\n").as_bytes())
-                    .await?;
-            } catch error {
-                error!("Failed to write to analytics.csv: {error}");
+    }
+}
+
+fn append_sample_now(path: &Path, sample: &AnalyticsSample) -> io::Result<()> {
+    let mut file = std_fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(CSV_HEADER.as_bytes())?;
+    }
+    file.write_all(sample.to_csv_row().as_bytes())?;
+    file.sync_data()
+}
+
+#[derive(Serialize, Debug)]
+pub struct AnalyticsSample {
+    pub timestamp: String,
+    pub total: u32,
+    /// The --analytics-max-countries countries with the most connections
+    pub countries: HashMap<CountryCode, u32>,
+    /// Connections from countries that didn't fit in [Self::countries]
+    pub other_countries: u32,
+    pub server_version: &'static str,
+    pub base_addr: Option<String>,
+    pub proxy_connections: usize,
+    pub proxy_opened: u64,
+    pub signals: u64,
+    pub port_lookups_completed: u64,
+    pub users: usize,
+    pub users_seen: usize,
+    pub peak_connections: usize,
+    pub peak_proxy_connections: usize,
+    /// Whether this sample was written on shutdown, and so covers a partial interval
+    #[serde(rename = "final")]
+    pub final_sample: bool,
+    pub joins_upnp: u64,
+    pub joins_proxy: u64,
+    pub joins_punch: u64,
+    /// JoinGranted messages with a join type that's disallowed or unsupported
+    pub joins_rejected: u64,
+    pub join_requests: u64,
+    pub direct_join_requests: u64,
+    /// The --analytics-max-grid-cells cells with the most connections. Empty unless
+    /// --analytics-grid is passed.
+    pub grid_cells: HashMap<GridCell, u32>,
+    /// Connections from cells that didn't fit in [Self::grid_cells]
+    pub other_grid_cells: u32,
+    /// Deprecated QueryResponse messages from protocol 5+ clients
+    pub legacy_query_responses: u64,
+    /// Messages between connections that weren't sent because the recipient's protocol is too old
+    pub skipped_old_protocol: u64,
+    /// All messages not sent because the recipient's protocol is too old, by message name
+    pub skipped_by_type: HashMap<&'static str, u64>,
+    /// The --analytics-max-brands client brands with the most connections
+    pub brands: HashMap<String, u32>,
+    /// Connections from brands that didn't fit in [Self::brands]
+    pub other_brands: u32,
+    /// Session server requests retried after a transient error
+    pub auth_retries: u64,
+    /// Players that couldn't be verified because the session server couldn't be reached. They're
+    /// allowed anyway unless --strict-auth is passed.
+    pub auth_fallbacks: u64,
+    /// Handshakes started by supported clients
+    pub handshakes: u64,
+    /// Online players confirmed by the session server, or by a cached verification
+    pub auth_verified: u64,
+    /// Online players the session server didn't confirm, or confirmed with a different UUID
+    pub auth_rejected: u64,
+    /// Offline players allowed with a UUID that doesn't match their username
+    pub offline_mismatches: u64,
+    /// Handshakes rejected for using the nil or max UUID
+    pub reserved_uuid_rejections: u64,
+    /// Connections refused by each rate limit bucket
+    pub rate_limited: HashMap<String, u64>,
+}
+
+impl AnalyticsSample {
+    async fn collect(server: &ServerState, final_sample: bool) -> Self {
+        let proxy_connections = {
+            let proxy_connections = server.proxy_connections.lock().await;
+            Self::take_proxy_connections(server, proxy_connections.len())
+        };
+        let users_seen = Self::take_users_seen(&mut *server.users_seen.lock().await);
+        Self::collect_from(server, final_sample, proxy_connections, users_seen)
+    }
+
+    /// Like [Self::collect], but without waiting. None if a lock it needs is held.
+    fn try_collect(server: &ServerState, final_sample: bool) -> Option<Self> {
+        let proxy_connections = {
+            let proxy_connections = server.proxy_connections.try_lock().ok()?;
+            Self::take_proxy_connections(server, proxy_connections.len())
+        };
+        let users_seen = Self::take_users_seen(&mut *server.users_seen.try_lock().ok()?);
+        Some(Self::collect_from(
+            server,
+            final_sample,
+            proxy_connections,
+            users_seen,
+        ))
+    }
+
+    /// The number of proxy connections and their peak. Called with the proxy connections locked.
+    fn take_proxy_connections(server: &ServerState, len: usize) -> (usize, usize) {
+        let counters = &server.analytics_counters;
+        (
+            len,
+            IntervalCounters::take_peak(&counters.peak_proxy_connections, len),
+        )
+    }
+
+    fn take_users_seen(users_seen: &mut HashSet<Uuid>) -> usize {
+        let count = users_seen.len();
+        // Replaced rather than cleared so that the capacity is released after a spike
+        *users_seen = HashSet::new();
+        count
+    }
+
+    fn collect_from(
+        server: &ServerState,
+        final_sample: bool,
+        (proxy_connections, peak_proxy_connections): (usize, usize),
+        users_seen: usize,
+    ) -> Self {
+        let timestamp = Local::now().format("%+").to_string();
+        let counters = &server.analytics_counters;
+        let connections = server.connections.snapshot();
+        let users = server.connections.user_count();
+        let peak_connections = server.connections.take_peak_len();
+        let total = connections.len() as u32;
+        let countries = server.connections.country_counts();
+        let mut grid_cells = HashMap::new();
+        let mut brands = HashMap::new();
+        for connection in connections {
+            if let Some(&cell) = connection.grid_cell.get() {
+                *grid_cells.entry(cell).or_insert(0) += 1;
+            }
+            if let Some(brand) = &connection.brand {
+                *brands.entry(brand.clone()).or_insert(0) += 1;
             }
         }
+        let (countries, other_countries) = cap_counts(
+            countries,
+            server.config.analytics_max_countries,
+            CountryCode::code,
+        );
+        let (grid_cells, other_grid_cells) = cap_counts(
+            grid_cells,
+            server.config.analytics_max_grid_cells,
+            |&cell| cell,
+        );
+        let (brands, other_brands) =
+            cap_counts(brands, server.config.analytics_max_brands, |brand| {
+                brand.clone()
+            });
+        Self {
+            timestamp,
+            total,
+            countries,
+            other_countries,
+            server_version: SERVER_VERSION,
+            base_addr: server.config.base_addr.clone(),
+            proxy_connections,
+            proxy_opened: IntervalCounters::take(&counters.proxy_opened),
+            signals: IntervalCounters::take(&counters.signals),
+            port_lookups_completed: IntervalCounters::take(&counters.port_lookups_completed),
+            users,
+            users_seen,
+            peak_connections,
+            peak_proxy_connections,
+            final_sample,
+            joins_upnp: IntervalCounters::take(&counters.joins_upnp),
+            joins_proxy: IntervalCounters::take(&counters.joins_proxy),
+            joins_punch: IntervalCounters::take(&counters.joins_punch),
+            joins_rejected: IntervalCounters::take(&counters.joins_rejected),
+            join_requests: IntervalCounters::take(&counters.join_requests),
+            direct_join_requests: IntervalCounters::take(&counters.direct_join_requests),
+            grid_cells,
+            other_grid_cells,
+            legacy_query_responses: IntervalCounters::take(&counters.legacy_query_responses),
+            skipped_old_protocol: IntervalCounters::take(&counters.skipped_old_protocol),
+            skipped_by_type: SKIPPED_BY_TYPE
+                .iter()
+                .enumerate()
+                .map(|(id, counter)| (message_name(id as u8), IntervalCounters::take(counter)))
+                .filter(|&(_, count)| count > 0)
+                .collect(),
+            brands,
+            other_brands,
+            auth_retries: IntervalCounters::take(&counters.auth_retries),
+            auth_fallbacks: IntervalCounters::take(&counters.auth_fallbacks),
+            handshakes: IntervalCounters::take(&counters.handshakes),
+            auth_verified: IntervalCounters::take(&counters.auth_verified),
+            auth_rejected: IntervalCounters::take(&counters.auth_rejected),
+            offline_mismatches: IntervalCounters::take(&counters.offline_mismatches),
+            reserved_uuid_rejections: IntervalCounters::take(&counters.reserved_uuid_rejections),
+            rate_limited: IntervalCounters::take_keyed(&counters.rate_limited),
+        }
+    }
+
+    fn to_csv_row(&self) -> String {
+        let country_string = format_counts(&self.countries, self.other_countries, |country| {
+            country.code()
+        });
+        let grid_string = format_counts(&self.grid_cells, self.other_grid_cells, |&cell| cell);
+        let skipped_string = format_counts(&self.skipped_by_type, 0, |&name| name);
+        let brand_string = format_counts(&self.brands, self.other_brands, |brand| brand.clone());
+        let rate_limited_string = format_counts(&self.rate_limited, 0, |bucket| bucket.clone());
+        format!(
+            "{},{},{country_string},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{grid_string},{},{},{skipped_string},{brand_string},{},{},{},{},{},{},{},{rate_limited_string}\n",
+            self.timestamp,
+            self.total,
+            self.proxy_connections,
+            self.proxy_opened,
+            self.signals,
+            self.port_lookups_completed,
+            self.users,
+            self.users_seen,
+            self.peak_connections,
+            self.peak_proxy_connections,
+            self.final_sample as u8,
+            self.joins_upnp,
+            self.joins_proxy,
+            self.joins_punch,
+            self.joins_rejected,
+            self.join_requests,
+            self.direct_join_requests,
+            self.legacy_query_responses,
+            self.skipped_old_protocol,
+            self.auth_retries,
+            self.auth_fallbacks,
+            self.handshakes,
+            self.auth_verified,
+            self.auth_rejected,
+            self.offline_mismatches,
+            self.reserved_uuid_rejections,
+        )
+    }
+}
+
+/// Formats counts as `;`-separated `key:count` pairs, most connections first, followed by
+/// `other:count` if anything was capped off
+fn format_counts<K: Display, C: Ord + Display + Default, O: Ord>(
+    counts: &HashMap<K, C>,
+    other: C,
+    order: impl Fn(&K) -> O,
+) -> String {
+    let mut counts: Vec<_> = counts.iter().collect();
+    counts.sort_unstable_by(|a, b| b.1.cmp(a.1).then(order(a.0).cmp(&order(b.0))));
+    let mut result = counts
+        .into_iter()
+        .map(|(key, count)| format!("{key}:{count}"))
+        .collect::<Vec<String>>()
+        .join(";");
+    if other > C::default() {
+        if !result.is_empty() {
+            result.push(';');
+        }
+        result.push_str(&format!("other:{other}"));
+    }
+    result
+}
+
+/// Keeps the `max` keys with the most connections, returning the total count of the rest
+fn cap_counts<K: Hash + Eq, O: Ord>(
+    counts: HashMap<K, u32>,
+    max: usize,
+    order: impl Fn(&K) -> O,
+) -> (HashMap<K, u32>, u32) {
+    if counts.len() <= max {
+        return (counts, 0);
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(order(&a.0).cmp(&order(&b.0))));
+    let other = counts[max..].iter().map(|(_, count)| count).sum();
+    counts.truncate(max);
+    (counts.into_iter().collect(), other)
+}
+
+#[derive(Clone)]
+pub struct AnalyticsWebhook {
+    client: reqwest::Client,
+    url: Url,
+    secret: Option<Arc<str>>,
+}
+
+impl AnalyticsWebhook {
+    fn new(url: Url, secret: Option<String>) -> reqwest::Result<Self> {
+        let client = reqwest::ClientBuilder::new()
+            .timeout(Duration::from_secs(5))
+            .user_agent(USER_AGENT)
+            .build()?;
+        Ok(Self {
+            client,
+            url,
+            secret: secret.map(Arc::from),
+        })
+    }
+
+    async fn push(&self, sample: &AnalyticsSample) {
+        let mut result = self.try_push(sample).await;
+        if let Err(error) = &result {
+            warn!(
+                "Failed to push analytics to {}, retrying: {error}",
+                self.url
+            );
+            result = self.try_push(sample).await;
+        }
+        if let Err(error) = result {
+            error!("Failed to push analytics to {}: {error}", self.url);
+        }
+    }
+
+    async fn try_push(&self, sample: &AnalyticsSample) -> reqwest::Result<()> {
+        let mut request = self.client.post(self.url.clone()).json(sample);
+        if let Some(secret) = &self.secret {
+            request = request.bearer_auth(secret);
+        }
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Creates the analytics file's parent directories and checks that the file can be appended to
+async fn check_writable(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .await?;
+    Ok(())
+}
+
+/// Files written before the current set of columns are rotated out rather than appended to
+async fn has_old_header(path: &Path) -> io::Result<bool> {
+    let file = match fs::File::open(path).await {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(error) => return Err(error),
+    };
+    let mut header = Vec::with_capacity(CSV_HEADER.len());
+    file.take(CSV_HEADER.len() as u64)
+        .read_to_end(&mut header)
+        .await?;
+    Ok(!header.is_empty() && header != CSV_HEADER.as_bytes())
+}
+
+async fn should_rotate(
+    server: &ServerState,
+    path: &Path,
+    file_date: NaiveDate,
+    today: NaiveDate,
+) -> io::Result<bool> {
+    let len = match fs::metadata(path).await {
+        Ok(meta) => meta.len(),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(error) => return Err(error),
+    };
+    Ok(len > 0
+        && match server.config.analytics_rotation {
+            AnalyticsRotation::Off => false,
+            AnalyticsRotation::Daily => file_date != today,
+            AnalyticsRotation::Size => len >= server.config.analytics_rotation_size,
+        })
+}
+
+async fn rotate(path: &Path, file_date: NaiveDate, gzip: bool) -> io::Result<()> {
+    let rotated = rotated_path(path, file_date).await?;
+    info!("Rotating {} to {}", path.display(), rotated.display());
+    fs::rename(path, &rotated).await?;
+    if gzip {
+        spawn_blocking(move || {
+            if let Err(error) = gzip_file(&rotated) {
+                error!("Failed to gzip {}: {error}", rotated.display());
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Rotated files are placed next to the analytics file, named `<stem>-YYYY-MM-DD.csv`
+async fn rotated_path(path: &Path, file_date: NaiveDate) -> io::Result<PathBuf> {
+    let stem = path
+        .file_stem()
+        .map_or("analytics".into(), |stem| stem.to_string_lossy());
+    let base = format!("{stem}-{}", file_date.format("%Y-%m-%d"));
+    let mut index = 0;
+    loop {
+        let name = if index == 0 {
+            base.clone()
+        } else {
+            format!("{base}.{index}")
+        };
+        let csv = path.with_file_name(format!("{name}.csv"));
+        let gz = path.with_file_name(format!("{name}.csv.gz"));
+        if !fs::try_exists(&csv).await? && !fs::try_exists(&gz).await? {
+            return Ok(csv);
+        }
+        index += 1;
+    }
+}
+
+fn gzip_file(path: &Path) -> io::Result<()> {
+    let mut gz_path = path.as_os_str().to_owned();
+    gz_path.push(".gz");
+    let mut input = std_fs::File::open(path)?;
+    let mut encoder = GzEncoder::new(std_fs::File::create(&gz_path)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    std_fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::ConnectionInfo;
+    use crate::connection::connection_id::ConnectionId;
+    use crate::protocol::protocol_versions::CURRENT;
+    use std::net::Ipv4Addr;
+
+    fn server(name: &str) -> ServerState {
+        let mut config = FullServerConfig::for_test();
+        config.analytics_file = std::env::temp_dir().join(format!(
+            "world-host-server-{}-{name}.csv",
+            std::process::id()
+        ));
+        let _ = std_fs::remove_file(&config.analytics_file);
+        ServerState::new(config)
+    }
+
+    #[tokio::test]
+    async fn final_sample_now() {
+        let server = server("final_sample_now");
+        let path = &server.config.analytics_file;
+        assert!(write_final_sample_now(&server));
+        assert!(write_final_sample_now(&server));
+        let written = std_fs::read_to_string(path).unwrap();
+        std_fs::remove_file(path).unwrap();
+        let mut lines = written.lines();
+        assert_eq!(lines.next(), Some(CSV_HEADER.trim_end()));
+        assert_eq!(lines.count(), 2);
+    }
+
+    #[tokio::test]
+    async fn final_sample_now_skipped_while_locked() {
+        let server = server("final_sample_now_skipped_while_locked");
+        let path = &server.config.analytics_file;
+        {
+            let _writer = server.analytics_writer.lock().await;
+            assert!(!write_final_sample_now(&server));
+        }
+        {
+            let _proxy_connections = server.proxy_connections.lock().await;
+            assert!(!write_final_sample_now(&server));
+        }
+        {
+            let _users_seen = server.users_seen.lock().await;
+            assert!(!write_final_sample_now(&server));
+        }
+        assert!(!path.exists());
+    }
+
+    /// Every two letter code, where each count is shared by about a seventh of them
+    fn country_counts(reverse: bool) -> HashMap<CountryCode, u32> {
+        let mut pairs: Vec<_> = ('A'..='Z')
+            .flat_map(|a| ('A'..='Z').map(move |b| CountryCode::new(a, b).unwrap()))
+            .enumerate()
+            .map(|(i, code)| (code, i as u32 % 7 + 1))
+            .collect();
+        if reverse {
+            pairs.reverse();
+        }
+        pairs.into_iter().collect()
+    }
+
+    fn capped(counts: HashMap<CountryCode, u32>, max: usize) -> String {
+        let (counts, other) = cap_counts(counts, max, CountryCode::code);
+        assert!(counts.len() <= max);
+        format_counts(&counts, other, CountryCode::code)
+    }
+
+    #[test]
+    fn capped_counts() {
+        let total: u32 = country_counts(false).values().sum();
+        // Ties are broken by code, so the first codes with the highest count are kept
+        let expected = format!("AG:7;AN:7;AU:7;BB:7;BI:7;other:{}", total - 5 * 7);
+        assert_eq!(capped(country_counts(false), 5), expected);
+        assert_eq!(capped(country_counts(true), 5), expected);
+        for _ in 0..10 {
+            assert_eq!(capped(country_counts(false), 5), expected);
+        }
+
+        let all = capped(country_counts(false), 26 * 26);
+        assert!(!all.contains("other"));
+        let entries: Vec<_> = all.split(';').collect();
+        assert_eq!(entries.len(), 26 * 26);
+        assert_eq!(entries[..2], ["AG:7", "AN:7"]);
+        assert_eq!(entries[entries.len() - 1], "ZW:1");
+
+        assert_eq!(capped(country_counts(false), 0), format!("other:{total}"));
+        assert_eq!(capped(HashMap::new(), 5), "");
+    }
+
+    #[tokio::test]
+    async fn peaks_survive_until_sampled() {
+        let server = server("peaks_survive_until_sampled");
+        let counters = &server.analytics_counters;
+        for len in [1, 4, 2] {
+            IntervalCounters::record_peak(&counters.peak_proxy_connections, len);
+        }
+        let connections: Vec<_> = (0..3)
+            .map(|id| {
+                ConnectionInfo::for_test(
+                    ConnectionId::new(id).unwrap(),
+                    Uuid::from_u128(1),
+                    Ipv4Addr::LOCALHOST.into(),
+                    CURRENT,
+                )
+                .0
+            })
+            .collect();
+        for connection in &connections {
+            assert!(server.connections.add(connection.clone()));
+        }
+        server.connections.remove(&connections[0]);
+        server.connections.remove(&connections[1]);
+
+        let sample = AnalyticsSample::collect(&server, false).await;
+        assert_eq!(sample.peak_connections, 3);
+        assert_eq!(sample.peak_proxy_connections, 4);
+        // Both reset to the current count, as there are no proxy connections left
+        let sample = AnalyticsSample::collect(&server, false).await;
+        assert_eq!(sample.peak_connections, 1);
+        assert_eq!(sample.peak_proxy_connections, 0);
     }
 }
@@ -1,23 +1,40 @@
+use crate::SERVER_VERSION;
 use crate::authlib::auth_service::YggdrasilAuthenticationService;
 use crate::authlib::session_service::YggdrasilMinecraftSessionService;
+use crate::connection::advisory_cache::Advisory;
 use crate::connection::connection_id::ConnectionId;
 use crate::connection::{
     Connection, ConnectionInfo, ConnectionRead, ConnectionState, ConnectionWrite,
 };
 use crate::minecraft_crypt;
 use crate::minecraft_crypt::{Aes128Cfb, RsaKeyPair};
+use crate::modules::analytics::IntervalCounters;
 use crate::protocol::c2s_message::WorldHostC2SMessage;
+use crate::protocol::compat::CompatMode;
 use crate::protocol::data_ext::WHAsyncReadExt;
+use crate::protocol::delivered_friend_requests::record_delivered;
+use crate::protocol::join_type::JoinTypeKind;
+use crate::protocol::message_handler::HandleError;
+use crate::protocol::protocol_versions::ProtocolCapabilities;
 use crate::protocol::s2c_message::WorldHostS2CMessage;
 use crate::protocol::security::SecurityLevel;
-use crate::protocol::{message_handler, protocol_versions};
+use crate::protocol::{message_handler, presence, protocol_versions};
 use crate::ratelimit::bucket::RateLimitBucket;
+use crate::ratelimit::error::RateLimited;
 use crate::ratelimit::limiter::RateLimiter;
-use crate::server_state::ServerState;
+use crate::server_state::{FullServerConfig, ServerState};
 use crate::socket_wrapper::{SocketReadWrapper, SocketWriteWrapper};
+use crate::util::asn_map::AsnMap;
+use crate::util::geo_lookup::GeoLookup;
+use crate::util::ip_info::IpInfo;
 use crate::util::ip_info_map::IpInfoMap;
+use crate::util::ip_range_map::CsvSource;
 use crate::util::java_util::java_name_uuid_from_bytes;
 use crate::util::remove_double_key;
+use anyhow::{anyhow, bail};
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
 use log::{debug, error, info, warn};
 use num_bigint::BigInt;
 use rand::RngCore;
@@ -27,39 +44,69 @@ use std::io;
 use std::net::IpAddr;
 use std::ops::DerefMut;
 use std::process::exit;
-use std::sync::Arc;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
-use tokio::sync::Mutex;
-use tokio::task::yield_now;
-use tokio::time::{Instant, MissedTickBehavior, interval_at};
+use tokio::pin;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{Instant, MissedTickBehavior, interval_at, sleep, timeout, timeout_at};
+use tokio_rustls::TlsAcceptor;
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
 pub async fn run_main_server(server: Arc<ServerState>) {
-    let session_service = YggdrasilAuthenticationService::new().create_session_service();
-    let ip_info_map = load_ip_info_map().await;
+    let session_service = if server.config.offline_mode {
+        warn!(
+            "Running in offline mode! Players are not verified, so anyone can impersonate anyone."
+        );
+        None
+    } else {
+        Some(YggdrasilAuthenticationService::new(&server.config).create_session_service())
+    };
+    let (ip_info_map, ip_info_retry_sources) = load_ip_info_map(&server.config).await;
+    let asn_map = load_asn_map(&server.config).await;
 
     info!("Generating key pair");
     let key_pair = minecraft_crypt::generate_key_pair();
+    log_key_fingerprint(&key_pair, "Generated");
 
     info!("Staring World Host server on port {}", server.config.port);
-    let rate_limiter = Arc::new(RateLimiter::<IpAddr>::new(vec![
-        RateLimitBucket::new("per_minute".to_string(), 20, Duration::from_secs(60)),
-        RateLimitBucket::new("per_hour".to_string(), 400, Duration::from_secs(60 * 60)),
-    ]));
+    let rate_limiter = Arc::new(RateLimiter::<IpAddr>::new(
+        server
+            .config
+            .rate_limits
+            .iter()
+            .map(RateLimitBucket::from_spec)
+            .collect(),
+    ));
     {
         let rate_limiter = rate_limiter.clone();
         tokio::spawn(async move {
             const PUMP_TIME: Duration = Duration::from_secs(60);
             let mut interval = interval_at(Instant::now() + PUMP_TIME, PUMP_TIME);
             interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut last_checks = 0;
             loop {
                 interval.tick().await;
                 let rate_limiter = rate_limiter.clone();
-                tokio::task::spawn_blocking(move || rate_limiter.pump_limits())
-                    .await
-                    .unwrap();
+                let (reclaimed, stats) = tokio::task::spawn_blocking(move || {
+                    (rate_limiter.pump_limits(), rate_limiter.stats())
+                })
+                .await
+                .unwrap();
+                if reclaimed > 0 {
+                    info!("Reclaimed {reclaimed} slots from the rate limiter");
+                }
+                // Only logged when there's been a connection since the last summary
+                let checks: u64 = stats.iter().map(|stats| stats.checks).sum();
+                if checks != last_checks {
+                    last_checks = checks;
+                    let summary: Vec<_> = stats.iter().map(ToString::to_string).collect();
+                    info!("Rate limiter totals: {}", summary.join("; "));
+                }
             }
         });
     }
@@ -75,12 +122,103 @@ pub async fn run_main_server(server: Arc<ServerState>) {
         listener.local_addr().unwrap()
     );
 
+    let geo_lookup = server.config.geo_lookup_url.clone().map(|url| {
+        Arc::new(GeoLookup::new(
+            url,
+            server.config.geo_lookup_timeout,
+            server.config.geo_lookup_concurrency,
+        ))
+    });
     let state = MainServerState {
         server,
-        session_service: Arc::new(session_service),
-        key_pair: Arc::new(key_pair),
-        ip_info_map: Arc::new(ip_info_map),
+        session_service: session_service.map(Arc::new),
+        key_pair: Arc::new(ArcSwap::from_pointee(key_pair)),
+        ip_info_map: Arc::new(ArcSwap::from_pointee(ip_info_map)),
+        verified_profiles: Arc::new(VerifiedProfiles::new()),
+        handshake_counter: Arc::new(AtomicU64::new(0)),
+        seen_secret_keys: Arc::new(SeenSecretKeys::new()),
+        geo_lookup,
+        asn_map: asn_map.map(Arc::new),
     };
+    if let Some(sources) = ip_info_retry_sources {
+        tokio::spawn(retry_ip_info_map(
+            state.ip_info_map.clone(),
+            sources,
+            state.server.config.precise_locations,
+        ));
+    }
+    #[cfg(unix)]
+    {
+        let key_pair = state.key_pair.clone();
+        tokio::spawn(rotate_key_pair_on_signal(key_pair));
+    }
+    if state.session_service.is_some() && !state.server.config.auth_cache_time.is_zero() {
+        let verified_profiles = state.verified_profiles.clone();
+        let cache_time = state.server.config.auth_cache_time;
+        tokio::spawn(async move {
+            let mut interval = interval_at(Instant::now() + cache_time, cache_time);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                verified_profiles.retain(|_, verified_at| verified_at.elapsed() < cache_time);
+            }
+        });
+    }
+    {
+        let seen_secret_keys = state.seen_secret_keys.clone();
+        let window = state.server.config.setup_timeout;
+        tokio::spawn(async move {
+            let mut interval = interval_at(Instant::now() + window, window);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                seen_secret_keys.retain(|_, seen_at| seen_at.elapsed() < window);
+            }
+        });
+    }
+    if let Some(geo_lookup) = &state.geo_lookup {
+        let geo_lookup = geo_lookup.clone();
+        tokio::spawn(async move {
+            const PRUNE_TIME: Duration = Duration::from_secs(10 * 60);
+            let mut interval = interval_at(Instant::now() + PRUNE_TIME, PRUNE_TIME);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let pruned = geo_lookup.prune();
+                if pruned > 0 {
+                    debug!("Pruned {pruned} expired geo lookups");
+                }
+            }
+        });
+    }
+    if let Some(tls_config) = &state.server.config.tls_config {
+        let tls_listener = TcpListener::bind(("0.0.0.0", state.server.config.tls_port))
+            .await
+            .unwrap_or_else(|error| {
+                error!("Failed to start World Host TLS listener: {error}");
+                exit(1);
+            });
+        info!(
+            "Started World Host TLS listener on {}",
+            tls_listener.local_addr().unwrap()
+        );
+        let acceptor = TlsAcceptor::from(tls_config.0.clone());
+        tokio::spawn(accept_connections(
+            tls_listener,
+            Some(acceptor),
+            state.clone(),
+            rate_limiter.clone(),
+        ));
+    }
+    accept_connections(listener, None, state, rate_limiter).await;
+}
+
+async fn accept_connections(
+    listener: TcpListener,
+    tls_acceptor: Option<TlsAcceptor>,
+    state: MainServerState,
+    rate_limiter: Arc<RateLimiter<IpAddr>>,
+) {
     loop {
         let result = listener.accept().await;
         if let Err(error) = result {
@@ -88,27 +226,74 @@ pub async fn run_main_server(server: Arc<ServerState>) {
             continue;
         }
         let (socket, addr) = result.unwrap();
+        // Dual-stack listeners report IPv4 clients as IPv4-mapped IPv6 addresses. Normalized so
+        // that rate limiting, IP info, and logs all see the same address.
+        let ip = addr.ip().to_canonical();
         if let Err(error) = socket2::SockRef::from(&socket).set_keepalive(true) {
             warn!("Failed to set SO_KEEPALIVE on socket for {addr}: {error}");
         }
 
         let rate_limiter = rate_limiter.clone();
+        let tls_acceptor = tls_acceptor.clone();
         let state = state.clone();
         tokio::spawn(async move {
-            let (read, write) = socket.into_split();
-            let read = SocketReadWrapper(read);
-            let mut write = SocketWriteWrapper(write);
-            if let Some(limited) = rate_limiter.ratelimit(addr.ip()).await {
-                warn!("{} is reconnecting too quickly! {limited}", addr.ip());
+            let limited = rate_limiter.ratelimit(ip).await.map(|limited| {
+                let limited =
+                    reported_rate_limit(&rate_limiter, ip, limited, state.server.config.compat);
+                warn!("{ip} is reconnecting too quickly! {limited}");
+                IntervalCounters::increment_keyed(
+                    &state.server.analytics_counters.rate_limited,
+                    &limited.bucket,
+                );
+                limited
+            });
+            let (read, mut write) = match tls_acceptor {
+                // Checked before the handshake so that reconnecting quickly can't make the server
+                // do TLS work. The client isn't told why, since that would need a handshake.
+                Some(_) if limited.is_some() => return,
+                Some(acceptor) => {
+                    let setup_timeout = state.server.config.setup_timeout;
+                    let stream = match timeout(setup_timeout, acceptor.accept(socket)).await {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(error)) => {
+                            info!("TLS handshake with {addr} failed: {error}");
+                            return;
+                        }
+                        Err(_) => {
+                            info!("TLS handshake with {addr} timed out");
+                            return;
+                        }
+                    };
+                    let (read, write) = tokio::io::split(stream);
+                    (
+                        SocketReadWrapper(Box::new(read)),
+                        SocketWriteWrapper(Box::new(write)),
+                    )
+                }
+                None => {
+                    let (read, write) = socket.into_split();
+                    (
+                        SocketReadWrapper(Box::new(read)),
+                        SocketWriteWrapper(Box::new(write)),
+                    )
+                }
+            };
+            if let Some(limited) = limited {
                 let message = format!("Ratelimit exceeded! {limited}");
                 write.close_error(message, &mut None).await;
                 return;
             }
+            if let Some(remaining) = rate_limiter.remaining(ip) {
+                debug!("{ip} can connect {remaining} more times before it's rate limited");
+            }
 
             let mut connection = None;
-            if let Err(error) =
-                handle_connection(&state, read, write, addr.ip(), &mut connection).await
-            {
+            let result = handle_connection(&state, read, write, ip, &mut connection).await;
+            if let Some(connection) = &connection {
+                // Stop other tasks from sending to this connection before it's removed
+                connection.mark_closed();
+            }
+            if let Err(error) = result {
                 info!("Connection {addr} closed due to {error}");
                 if let Some(connection) = &connection {
                     connection.close_error(error.to_string()).await;
@@ -116,68 +301,300 @@ pub async fn run_main_server(server: Arc<ServerState>) {
             }
             if let Some(connection) = connection {
                 info!("Connection {} from {} closed", connection.id, addr);
-                state.server.connections.lock().await.remove(&connection);
-                // Inlining this variable will cause the lock to not be dropped, causing a deadlock in handle_message
-                let friends: Vec<Uuid> = connection
-                    .state
-                    .lock()
-                    .await
-                    .open_to_friends
-                    .iter()
-                    .copied()
-                    .collect();
-                message_handler::handle_message(
-                    WorldHostC2SMessage::ClosedWorld { friends },
-                    &connection,
-                    &state.server,
-                )
-                .await;
+                clean_up_connection(&state, &connection).await;
                 info!(
                     "There are {} open connections.",
-                    state.server.connections.lock().await.len()
+                    state.server.connections.len()
                 );
             }
         });
     }
 }
 
+/// Removes a closed connection and closes its world for the friends it was open to
+async fn clean_up_connection(state: &MainServerState, connection: &Connection) {
+    let was_last = state.server.connections.remove(connection);
+    // Notified first, since who may be notified depends on the connection's subscriptions
+    if was_last {
+        presence::notify_offline(connection, &state.server).await;
+    }
+    presence::unsubscribe_all(connection, &state.server).await;
+    // Inlining this variable will cause the lock to not be dropped, causing a deadlock in handle_message
+    let friends: Vec<Uuid> = connection
+        .state
+        .lock()
+        .await
+        .open_to_friends
+        .iter()
+        .copied()
+        .collect();
+    // ClosedWorld only sends to other connections, so it can't fail
+    let _ = message_handler::handle_message(
+        WorldHostC2SMessage::ClosedWorld { friends },
+        connection,
+        &state.server,
+    )
+    .await;
+}
+
+/// What a rate limited client is told. [RateLimiter::ratelimit] reports the last bucket that was
+/// exceeded, which is what the Kotlin server reported, but otherwise the client should be told how
+/// long it has to wait for all of them.
+fn reported_rate_limit(
+    rate_limiter: &RateLimiter<IpAddr>,
+    ip: IpAddr,
+    limited: RateLimited,
+    compat: Option<CompatMode>,
+) -> RateLimited {
+    match compat {
+        Some(CompatMode::Kotlin) => limited,
+        None => rate_limiter.check(ip).unwrap_or(limited),
+    }
+}
+
+/// Records a connection's country and location, and picks its nearest external proxy unless it's
+/// hosted in a datacenter. Returns the ExternalProxyServer message to send if a proxy was picked.
+async fn apply_ip_info(
+    state: &MainServerState,
+    connection: &Connection,
+    ip_info: IpInfo,
+) -> Option<WorldHostS2CMessage> {
+    state
+        .server
+        .connections
+        .set_country(connection, ip_info.country);
+    // Country-only entries can't place the connection
+    if !ip_info.has_location() {
+        return None;
+    }
+    if state.server.config.analytics_grid {
+        let _ = connection.grid_cell.set(ip_info.lat_long.grid_cell());
+    }
+    if connection.hosting_asn.is_some() {
+        return None;
+    }
+    let external_servers = state.server.config.external_servers.load_full()?;
+    let proxy = external_servers.iter().min_by(|a, b| {
+        f64::total_cmp(
+            &a.lat_long.haversine_distance(&ip_info.lat_long),
+            &b.lat_long.haversine_distance(&ip_info.lat_long),
+        )
+    })?;
+    let addr = proxy.addr.as_ref()?;
+    connection.state.lock().await.external_proxy = Some(proxy.clone());
+    Some(WorldHostS2CMessage::ExternalProxyServer {
+        host: addr.clone(),
+        port: proxy.port,
+        base_addr: proxy.base_addr.clone().unwrap_or_else(|| addr.clone()),
+        mc_port: proxy.mc_port,
+    })
+}
+
+/// Moves the open world from a connection being replaced by a quick reconnect, so friends don't see
+/// it close. The old connection is left with nothing to broadcast ClosedWorld to when it's cleaned
+/// up.
+async fn inherit_world_state(old: &Connection, new: &Connection) {
+    let (open_to_friends, external_proxy) = {
+        let mut old_state = old.state.lock().await;
+        (
+            std::mem::take(&mut old_state.open_to_friends),
+            old_state.external_proxy.take(),
+        )
+    };
+    let mut new_state = new.state.lock().await;
+    new_state.open_to_friends.extend(open_to_friends);
+    if new_state.external_proxy.is_none() {
+        new_state.external_proxy = external_proxy;
+    }
+}
+
 #[derive(Clone)]
 struct MainServerState {
     server: Arc<ServerState>,
-    session_service: Arc<YggdrasilMinecraftSessionService>,
-    key_pair: Arc<RsaKeyPair>,
-    ip_info_map: Arc<IpInfoMap>,
+    /// None in offline mode
+    session_service: Option<Arc<YggdrasilMinecraftSessionService>>,
+    /// Replaced on SIGUSR1. Handshakes keep the key pair they started with.
+    key_pair: Arc<ArcSwap<RsaKeyPair>>,
+    /// Replaced once every source loads if some failed at startup
+    ip_info_map: Arc<ArcSwap<IpInfoMap>>,
+    verified_profiles: Arc<VerifiedProfiles>,
+    /// Mixed into every handshake challenge, so that no two connections get the same one
+    handshake_counter: Arc<AtomicU64>,
+    seen_secret_keys: Arc<SeenSecretKeys>,
+    /// None unless --geo-lookup-url is passed
+    geo_lookup: Option<Arc<GeoLookup>>,
+    /// None unless --asn-source is passed
+    asn_map: Option<Arc<AsnMap>>,
 }
 
-async fn load_ip_info_map() -> IpInfoMap {
-    info!("Downloading IP info map...");
-    let start = Instant::now();
-    let result = IpInfoMap::load_from_compressed_geolite_city_files(
-        if !cfg!(debug_assertions) { // This takes a whopping 15 seconds (on my computer) under the dev target!
-            vec![
-                "https://github.com/sapics/ip-location-db/raw/main/geolite2-city/geolite2-city-ipv4-num.csv.gz",
-                "https://github.com/sapics/ip-location-db/raw/main/geolite2-city/geolite2-city-ipv6-num.csv.gz",
-            ]
+/// SHA-1s of the encrypted secret keys received in the last --setup-timeout, with when they were
+/// received. PKCS#1 v1.5 encryption is randomized, so a real client never sends the same
+/// ciphertext twice, and a repeat can only be a replay.
+type SeenSecretKeys = DashMap<Vec<u8>, Instant>;
+
+fn log_key_fingerprint(key_pair: &RsaKeyPair, action: &str) {
+    match minecraft_crypt::fingerprint(&key_pair.public) {
+        Ok(fingerprint) => info!("{action} key pair with fingerprint {fingerprint}"),
+        Err(error) => warn!("{action} key pair, but failed to fingerprint it: {error}"),
+    }
+}
+
+/// Generates a new handshake key pair whenever SIGUSR1 is received
+#[cfg(unix)]
+async fn rotate_key_pair_on_signal(key_pair: Arc<ArcSwap<RsaKeyPair>>) {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut rotate = match signal(SignalKind::user_defined1()) {
+        Ok(rotate) => rotate,
+        Err(error) => {
+            error!("Failed to listen for SIGUSR1: {error}");
+            return;
+        }
+    };
+    while rotate.recv().await.is_some() {
+        info!("Rotating key pair because SIGUSR1 was received");
+        let new_key_pair = tokio::task::spawn_blocking(minecraft_crypt::generate_key_pair)
+            .await
+            .unwrap();
+        rotate_key_pair(&key_pair, new_key_pair);
+    }
+}
+
+/// Handshakes that already loaded the old key pair keep using it
+#[cfg(any(unix, test))]
+fn rotate_key_pair(key_pair: &ArcSwap<RsaKeyPair>, new_key_pair: RsaKeyPair) {
+    log_key_fingerprint(&new_key_pair, "Rotated to");
+    key_pair.store(Arc::new(new_key_pair));
+}
+
+/// Also returns the sources to reload in the background if any of them failed
+async fn load_ip_info_map(config: &FullServerConfig) -> (IpInfoMap, Option<Vec<CsvSource>>) {
+    #[cfg(feature = "maxminddb")]
+    if let Some(path) = &config.ip_info_mmdb {
+        info!("Loading IP info database {}...", path.display());
+        let start = Instant::now();
+        return match IpInfoMap::load_from_mmdb(path) {
+            Ok(map) => {
+                info!(
+                    "Loaded IP info database in {:?} ({} nodes)",
+                    start.elapsed(),
+                    map.len()
+                );
+                (map, None)
+            }
+            Err(err) => {
+                error!("Failed to load IP info database {}: {err}", path.display());
+                exit(1);
+            }
+        };
+    }
+    let sources = config.ip_info_sources.clone().unwrap_or_else(|| {
+        if !cfg!(debug_assertions) {
+            DEFAULT_IP_INFO_SOURCES
+                .iter()
+                .map(|url| CsvSource::parse(url))
+                .collect()
         } else {
+            // This takes a whopping 15 seconds (on my computer) under the dev target!
             vec![]
         }
-    ).await;
+    });
+    info!("Loading IP info map...");
+    let start = Instant::now();
+    let (map, failed) = IpInfoMap::load_from_geolite_city_csvs(
+        &sources,
+        config.precise_locations,
+        config.strict_geo,
+    )
+    .await;
     let duration = start.elapsed();
-    match result {
-        Ok(map) => {
-            info!(
-                "Downloaded IP info map in {duration:?} ({} entries)",
-                map.len()
-            );
-            map
-        }
-        Err(err) => {
-            error!("Failed to download IP info map in {duration:?}: {err}");
-            IpInfoMap::default()
+    if config.strict_geo && !failed.is_empty() {
+        error!(
+            "Failed to load {} of {} IP info sources with --strict-geo",
+            failed.len(),
+            sources.len()
+        );
+        exit(1);
+    }
+    #[cfg(feature = "embedded-geo")]
+    let map = if map.len() == 0 {
+        warn!("No IP info loaded, using embedded country-only fallback");
+        let map = IpInfoMap::load_embedded_countries().await;
+        info!(
+            "Loaded embedded country-only IP info ({} entries)",
+            map.len()
+        );
+        map
+    } else {
+        map
+    };
+    if failed.is_empty() {
+        info!("Loaded IP info map in {duration:?} ({} entries)", map.len());
+        return (map, None);
+    }
+    warn!(
+        "Loaded IP info map without {} of {} sources in {duration:?} ({} entries). Retrying in the background.",
+        failed.len(),
+        sources.len(),
+        map.len()
+    );
+    (map, Some(sources))
+}
+
+/// Reloads the IP info map until every source loads, then swaps it in. Each attempt reuses the
+/// allocations of the last one.
+async fn retry_ip_info_map(
+    ip_info_map: Arc<ArcSwap<IpInfoMap>>,
+    sources: Vec<CsvSource>,
+    precise_locations: bool,
+) {
+    const RETRY_TIME: Duration = Duration::from_secs(10 * 60);
+    let mut map = IpInfoMap::default();
+    loop {
+        sleep(RETRY_TIME).await;
+        info!("Reloading IP info map...");
+        // Never reached with --strict-geo, which exits if a source fails at startup
+        let failed = map
+            .rebuild_from_geolite_city_csvs(&sources, precise_locations, false)
+            .await;
+        if failed.is_empty() {
+            info!("Reloaded IP info map ({} entries)", map.len());
+            ip_info_map.store(Arc::new(map));
+            return;
         }
+        warn!(
+            "IP info map is still missing {} sources. Retrying in {RETRY_TIME:?}.",
+            failed.len()
+        );
+    }
+}
+
+/// None if --asn-source wasn't passed
+async fn load_asn_map(config: &FullServerConfig) -> Option<AsnMap> {
+    if config.asn_sources.is_empty() {
+        return None;
+    }
+    info!("Loading ASN map...");
+    let start = Instant::now();
+    let (map, failed) = AsnMap::load_from_csvs(&config.asn_sources).await;
+    let duration = start.elapsed();
+    if failed.is_empty() {
+        info!("Loaded ASN map in {duration:?} ({} entries)", map.len());
+    } else {
+        warn!(
+            "Loaded ASN map without {} of {} sources in {duration:?} ({} entries)",
+            failed.len(),
+            config.asn_sources.len(),
+            map.len()
+        );
     }
+    Some(map)
 }
 
+const DEFAULT_IP_INFO_SOURCES: [&str; 2] = [
+    "https://github.com/sapics/ip-location-db/raw/main/geolite2-city/geolite2-city-ipv4-num.csv.gz",
+    "https://github.com/sapics/ip-location-db/raw/main/geolite2-city/geolite2-city-ipv6-num.csv.gz",
+];
+
 async fn handle_connection(
     state: &MainServerState,
     mut read: SocketReadWrapper,
@@ -198,9 +615,9 @@ async fn handle_connection(
         return Ok(());
     }
 
-    let connection =
+    let (connection, handshake_warning) =
         match create_connection(read, write, remote_addr, state, protocol_version).await {
-            Some(conn) => conn,
+            Some(result) => result,
             None => {
                 return Ok(());
             }
@@ -208,8 +625,16 @@ async fn handle_connection(
     *connection_out = Some(connection.clone());
 
     info!(
-        "Connection opened: {} ({}) from {}",
-        connection.id, connection.user_uuid, connection.addr
+        "Connection opened: {} [{}] ({}) from {}{} using {}",
+        connection.id,
+        connection.id.short_string(),
+        connection.user_uuid,
+        connection.addr,
+        connection
+            .hosting_asn
+            .map(|asn| format!(" (hosted in AS{asn})"))
+            .unwrap_or_default(),
+        connection.brand.as_deref().unwrap_or("an unknown client")
     );
 
     let latest_visible_protocol_version = if protocol_version <= protocol_versions::STABLE {
@@ -217,85 +642,108 @@ async fn handle_connection(
     } else {
         protocol_versions::CURRENT
     };
-    connection
-        .send_message(&WorldHostS2CMessage::ConnectionInfo {
-            connection_id: connection.id,
-            base_ip: state.server.config.base_addr.clone().unwrap_or_default(),
-            base_port: state.server.config.ex_java_port,
-            user_ip: remote_addr.to_string(),
-            protocol_version: latest_visible_protocol_version,
-            punch_port: 0,
-        })
-        .await?;
-    if protocol_version < latest_visible_protocol_version {
-        warn!(
-            "Client {} has an outdated client! Client version: {}. Server version: {} (stable {})",
-            connection.id,
-            protocol_version,
-            protocol_versions::CURRENT,
-            protocol_versions::STABLE
-        );
-        connection
-            .send_message(&WorldHostS2CMessage::OutdatedWorldHost {
-                recommended_version: protocol_versions::get_version_name(
-                    latest_visible_protocol_version,
-                )
-                .to_string(),
-            })
-            .await?
+    let setup_deadline = Instant::now() + state.server.config.setup_timeout;
+    let connection_info = WorldHostS2CMessage::ConnectionInfo {
+        connection_id: connection.id,
+        base_ip: state.server.config.base_addr.clone().unwrap_or_default(),
+        base_port: state.server.config.ex_java_port,
+        user_ip: remote_addr.to_string(),
+        protocol_version: latest_visible_protocol_version,
+        punch_port: 0,
+        server_version: SERVER_VERSION.to_string(),
+    };
+    let advisories = setup_advisories(
+        &connection,
+        handshake_warning,
+        latest_visible_protocol_version,
+    );
+    let capabilities = WorldHostS2CMessage::ServerCapabilities {
+        allowed_join_types: JoinTypeKind::mask(&state.server.config.allowed_join_types),
+    };
+    match state.server.config.compat {
+        Some(CompatMode::Kotlin) => {
+            timeout_at(
+                setup_deadline,
+                send_kotlin_setup_messages(
+                    state,
+                    &connection,
+                    connection_info,
+                    capabilities,
+                    advisories,
+                ),
+            )
+            .await
+            .map_err(|_| anyhow!("Timed out sending setup messages"))??;
+        }
+        None => {
+            send_setup_messages(
+                state,
+                &connection,
+                setup_deadline,
+                connection_info,
+                capabilities,
+                advisories,
+            )
+            .await?;
+        }
     }
 
-    if connection.security_level() == SecurityLevel::Insecure
-        && connection.user_uuid.get_version_num() == 4
+    // Done in the background, so that a slow API never holds up setup. The external proxy is
+    // sent late if the lookup finds a location. Connections without a country weren't in the IP
+    // info map.
+    if connection.country.get().is_none()
+        && let Some(geo_lookup) = &state.geo_lookup
     {
-        // Using Error because Warning was added in the same protocol version that Secure was
-        connection.send_message(&WorldHostS2CMessage::Error {
-            message: format!("You are using an old insecure version of World Host. It is highly recommended that you update to {} or later.", protocol_versions::get_version_name(protocol_versions::NEW_AUTH_PROTOCOL)),
-            critical: false,
-        }).await?;
-    }
-
-    if let Some(ip_info) = state.ip_info_map.get(remote_addr) {
-        connection.state.lock().await.country = Some(ip_info.country);
-        if let Some(external_servers) = &state.server.config.external_servers
-            && let Some(proxy) = external_servers.iter().min_by(|a, b| {
-                f64::total_cmp(
-                    &a.lat_long.haversine_distance(&ip_info.lat_long),
-                    &b.lat_long.haversine_distance(&ip_info.lat_long),
-                )
-            })
-            && let Some(addr) = &proxy.addr
-        {
-            connection.state.lock().await.external_proxy = Some(proxy.clone());
-            connection
-                .send_message(&WorldHostS2CMessage::ExternalProxyServer {
-                    host: addr.clone(),
-                    port: proxy.port,
-                    base_addr: proxy.base_addr.clone().unwrap_or_else(|| addr.clone()),
-                    mc_port: proxy.mc_port,
-                })
-                .await?;
-        }
+        let geo_lookup = geo_lookup.clone();
+        let state = state.clone();
+        let connection = connection.clone();
+        tokio::spawn(async move {
+            let Some(ip_info) = geo_lookup.get(remote_addr).await else {
+                return;
+            };
+            if let Some(message) = apply_ip_info(&state, &connection, ip_info).await
+                && let Err(error) = connection.send_message(&message).await
+            {
+                warn!(
+                    "Failed to send late external proxy to {}: {error}",
+                    connection.id
+                );
+            }
+        });
     }
 
     {
-        let start = Instant::now();
+        let deadline = Instant::now() + ID_CONFLICT_TIMEOUT;
         let connections = &state.server.connections;
-        while !connections.lock().await.add(connection.clone()) {
+        let owns_reserved_id = state.server.config.reserved_ids.load().get(&connection.id)
+            == Some(&connection.user_uuid);
+        loop {
+            let removed = connections.removed();
+            pin!(removed);
+            removed.as_mut().enable();
+            if connections.add(connection.clone()) {
+                break;
+            }
+            if let Some(other) = connections.by_id(connection.id)
+                && (other.addr == connection.addr || owns_reserved_id)
             {
-                let mut connections = connections.lock().await;
-                let other = connections.by_id(connection.id);
-                if let Some(other) = other
-                    && other.addr == connection.addr
-                {
-                    other
-                        .close_error("Connection ID taken by same IP".to_string())
-                        .await;
-                    connections.add_force(connection.clone());
-                    break;
+                let same_addr = other.addr == connection.addr;
+                if same_addr && other.user_uuid == connection.user_uuid {
+                    inherit_world_state(&other, &connection).await;
                 }
+                let reason = if same_addr {
+                    "Connection ID taken by same IP"
+                } else {
+                    "Connection ID reclaimed by its reserved owner"
+                };
+                other.close_error(reason.to_string()).await;
+                if connections.add_force(connection.clone()) {
+                    presence::notify_offline(&other, &state.server).await;
+                }
+                break;
             }
-            if start.elapsed() > Duration::from_millis(500) {
+            // The ID may belong to a connection that's already closing, so wait for it to go away
+            if timeout_at(deadline, removed).await.is_err() {
                 warn!(
                     "ID {} used twice. Disconnecting new connection.",
                     connection.id
@@ -305,53 +753,265 @@ async fn handle_connection(
                     .await;
                 return Ok(());
             }
-            yield_now().await;
         }
     }
 
+    let first_for_user = state
+        .server
+        .connections
+        .user_connection_count(connection.user_uuid)
+        == 1;
+    if first_for_user {
+        presence::notify_online(&connection, &state.server).await;
+    }
+    if !state.server.config.analytics_time.is_zero() {
+        state
+            .server
+            .users_seen
+            .lock()
+            .await
+            .insert(connection.user_uuid);
+    }
+
     info!(
         "There are {} open connections",
-        state.server.connections.lock().await.len()
+        state.server.connections.len()
     );
 
-    dequeue_friend_requests(&connection, &state.server).await?;
+    dequeue_friend_requests(
+        connection.user_uuid,
+        slice::from_ref(&connection),
+        &state.server,
+    )
+    .await?;
+    state
+        .server
+        .setup_advisories
+        .lock()
+        .await
+        .complete(connection.user_uuid);
 
     loop {
-        let message = connection.recv_message().await;
+        let message = tokio::select! {
+            message = connection.recv_message() => message,
+            // A send timed out or failed
+            () = connection.wait_closed() => return Ok(()),
+        };
         if message.is_err() {
             return Ok(());
         }
         let message = message?;
-        debug!("Received message {message:?}");
-        message_handler::handle_message(message, &connection, state.server.as_ref()).await;
+        if state
+            .server
+            .config
+            .debug_messages
+            .as_ref()
+            .is_none_or(|names| names.contains(message.name()))
+        {
+            debug!(
+                "Received {} from {}: {}",
+                message.name(),
+                connection.id,
+                message.summary()
+            );
+        }
+        match message_handler::handle_message(message, &connection, state.server.as_ref()).await {
+            Ok(()) => {}
+            Err(HandleError::Recoverable(message)) => {
+                debug!("Failed to handle message from {}: {message}", connection.id);
+                connection
+                    .send_message(&WorldHostS2CMessage::Error {
+                        message,
+                        critical: false,
+                    })
+                    .await?;
+            }
+            Err(HandleError::Fatal(error)) => return Err(error),
+        }
+    }
+}
+
+/// Advisories for a new connection, in the order they're sent
+fn setup_advisories(
+    connection: &Connection,
+    handshake_warning: Option<String>,
+    latest_visible_protocol_version: u32,
+) -> Vec<(Advisory, WorldHostS2CMessage)> {
+    let mut advisories = Vec::new();
+
+    if let Some(warning) = handshake_warning {
+        advisories.push((
+            Advisory::HandshakeWarning,
+            WorldHostS2CMessage::Warning {
+                message: warning,
+                important: false,
+            },
+        ));
     }
+
+    if connection.protocol_version < latest_visible_protocol_version {
+        warn!(
+            "Client {} has an outdated client! Client version: {}. Server version: {} (stable {})",
+            connection.id,
+            connection.protocol_version,
+            protocol_versions::CURRENT,
+            protocol_versions::STABLE
+        );
+        advisories.push((
+            Advisory::OutdatedWorldHost,
+            WorldHostS2CMessage::OutdatedWorldHost {
+                recommended_version: protocol_versions::get_version_name(
+                    latest_visible_protocol_version,
+                )
+                .to_string(),
+            },
+        ));
+    }
+
+    if connection.security_level() == SecurityLevel::Insecure
+        && connection.user_uuid.get_version_num() == 4
+    {
+        // Using Error because Warning was added in the same protocol version that Secure was
+        advisories.push((
+            Advisory::InsecureAuth,
+            WorldHostS2CMessage::Error {
+                message: format!("You are using an old insecure version of World Host. It is highly recommended that you update to {} or later.", protocol_versions::get_version_name(protocol_versions::NEW_AUTH_PROTOCOL)),
+                critical: false,
+            },
+        ));
+    }
+
+    advisories
 }
 
-async fn dequeue_friend_requests(connection: &Connection, server: &ServerState) -> io::Result<()> {
-    let received = server
-        .received_friend_requests
+/// ConnectionInfo is flushed on its own so that clients on high-latency links get it as quickly as
+/// possible. The rest of the required setup messages are sent together in a second flush, followed
+/// by the advisories in a third. Advisories that were already delivered to a connection that
+/// dropped mid-setup are skipped.
+async fn send_setup_messages(
+    state: &MainServerState,
+    connection: &Connection,
+    setup_deadline: Instant,
+    connection_info: WorldHostS2CMessage,
+    capabilities: WorldHostS2CMessage,
+    advisories: Vec<(Advisory, WorldHostS2CMessage)>,
+) -> anyhow::Result<()> {
+    timeout_at(setup_deadline, connection.send_message(&connection_info))
+        .await
+        .map_err(|_| anyhow!("Timed out sending ConnectionInfo"))??;
+    let already_delivered = state
+        .server
+        .setup_advisories
         .lock()
         .await
-        .remove(&connection.user_uuid);
-    if received.is_none() {
+        .delivered(connection.user_uuid);
+    let (advisories, advisory_messages): (Vec<Advisory>, Vec<_>) = advisories
+        .into_iter()
+        .filter(|(advisory, _)| !already_delivered.contains(advisory))
+        .unzip();
+
+    let mut setup_messages = vec![capabilities];
+    if let Some(ip_info) = state.ip_info_map.load().get(connection.addr)
+        && let Some(message) = apply_ip_info(state, connection, ip_info).await
+    {
+        setup_messages.push(message);
+    }
+    timeout_at(setup_deadline, connection.send_messages(&setup_messages))
+        .await
+        .map_err(|_| anyhow!("Timed out sending setup messages"))??;
+
+    if advisories.is_empty() {
         return Ok(());
     }
+    // Advisories are optional unless --require-setup-advisories is passed. If they can't be sent
+    // before the deadline, whatever wasn't written stays queued on the connection.
+    let result =
+        match timeout_at(setup_deadline, connection.send_messages(&advisory_messages)).await {
+            Ok(result) => result.map_err(anyhow::Error::from),
+            Err(_) => Err(anyhow!("Timed out sending setup advisories")),
+        };
+    match result {
+        Ok(()) => state
+            .server
+            .setup_advisories
+            .lock()
+            .await
+            .record(connection.user_uuid, advisories),
+        Err(error) if state.server.config.require_setup_advisories => return Err(error),
+        Err(error) => warn!(
+            "Continuing setup of {} without advisories: {error}",
+            connection.id
+        ),
+    }
+    Ok(())
+}
+
+/// The Kotlin server's setup, for --compat kotlin. Every message is flushed on its own, the
+/// handshake warning comes before ConnectionInfo, the external proxy comes after the other
+/// advisories, and advisories are sent on every connection. ServerCapabilities didn't exist in
+/// the Kotlin server, so it comes last.
+async fn send_kotlin_setup_messages(
+    state: &MainServerState,
+    connection: &Connection,
+    connection_info: WorldHostS2CMessage,
+    capabilities: WorldHostS2CMessage,
+    advisories: Vec<(Advisory, WorldHostS2CMessage)>,
+) -> io::Result<()> {
+    let (warning, advisories): (Vec<_>, Vec<_>) = advisories
+        .into_iter()
+        .partition(|(advisory, _)| *advisory == Advisory::HandshakeWarning);
+    let mut messages: Vec<_> = warning.into_iter().map(|(_, message)| message).collect();
+    messages.push(connection_info);
+    messages.extend(advisories.into_iter().map(|(_, message)| message));
+    if let Some(ip_info) = state.ip_info_map.load().get(connection.addr)
+        && let Some(message) = apply_ip_info(state, connection, ip_info).await
+    {
+        messages.push(message);
+    }
+    messages.push(capabilities);
+    for message in &messages {
+        connection.send_message(message).await?;
+    }
+    Ok(())
+}
+
+/// Sends the friend requests queued for `user` while they were offline to `connections`, which
+/// should be theirs, and forgets them. Returns how many requests were sent.
+pub async fn dequeue_friend_requests(
+    user: Uuid,
+    connections: &[Connection],
+    server: &ServerState,
+) -> io::Result<usize> {
+    let received = server.received_friend_requests.lock().await.remove(&user);
+    if received.is_none() {
+        return Ok(0);
+    }
     let received = received.unwrap();
+    // Requests from users that were blocked after the request was queued are dropped
+    let delivered: Vec<Uuid> = {
+        let block_lists = server.block_lists.lock().await;
+        received
+            .iter()
+            .copied()
+            .filter(|&received_from| !block_lists.blocks(user, received_from))
+            .collect()
+    };
+    let messages: Vec<_> = delivered
+        .iter()
+        .map(|&received_from| WorldHostS2CMessage::FriendRequest {
+            from_user: received_from,
+            security: SecurityLevel::from(received_from, true, server.config.offline_mode),
+        })
+        .collect();
+    for connection in connections {
+        connection.send_messages(&messages).await?;
+    }
+    record_delivered(server, user, delivered.iter().copied()).await;
     let mut remembered = server.remembered_friend_requests.lock().await;
-    for received_from in received {
-        connection
-            .send_message(&WorldHostS2CMessage::FriendRequest {
-                from_user: received_from,
-                security: SecurityLevel::from(received_from, true),
-            })
-            .await?;
-        remove_double_key(
-            remembered.deref_mut(),
-            &received_from,
-            &connection.user_uuid,
-        );
+    for received_from in &received {
+        remove_double_key(remembered.deref_mut(), received_from, &user);
     }
-    Ok(())
+    Ok(delivered.len())
 }
 
 async fn create_connection(
@@ -360,9 +1020,10 @@ async fn create_connection(
     remote_addr: IpAddr,
     state: &MainServerState,
     protocol_version: u32,
-) -> Option<Connection> {
+) -> Option<(Connection, Option<String>)> {
     let handshake_result =
-        perform_versioned_handshake(&mut read, &mut write, state, protocol_version).await;
+        perform_versioned_handshake(&mut read, &mut write, remote_addr, state, protocol_version)
+            .await;
     if let Err(error) = handshake_result {
         warn!("Failed to perform handshake from {remote_addr}: {error}");
         let message = error.to_string();
@@ -372,39 +1033,80 @@ async fn create_connection(
     let handshake_result = handshake_result.unwrap();
     let mut encrypt_cipher = handshake_result.encrypt_cipher;
 
-    if handshake_result.success {
-        if let Some(warning) = handshake_result.message {
+    // The warning is sent by handle_connection, after ConnectionInfo
+    let warning = if handshake_result.success {
+        if let Some(warning) = &handshake_result.message {
             warn!("Warning in handshake from {remote_addr}: {warning}");
-            if let Err(error) = write
-                .send_message(
-                    &WorldHostS2CMessage::Warning {
-                        message: warning,
-                        important: false,
-                    },
-                    &mut encrypt_cipher,
-                )
-                .await
-            {
-                error!("Failed to send warning to {remote_addr}: {error}");
-                return None;
-            }
         }
+        handshake_result.message
     } else {
         let message = handshake_result.message.unwrap();
         warn!("Handshake from {remote_addr} failed: {message}");
         write.close_error(message, &mut encrypt_cipher).await;
         return None;
-    }
+    };
 
-    Some(Arc::new(ConnectionInfo {
-        id: handshake_result.connection_id,
-        addr: remote_addr,
-        user_uuid: handshake_result.user_id,
-        protocol_version,
-        state: Mutex::new(ConnectionState {
-            country: None,
+    let capabilities = ProtocolCapabilities::from_version(protocol_version);
+    let mut connection_id = handshake_result.connection_id;
+    let reserved_owner = state
+        .server
+        .config
+        .reserved_ids
+        .load()
+        .get(&connection_id)
+        .copied();
+    if let Some(owner) = reserved_owner
+        && owner != handshake_result.user_id
+    {
+        warn!(
+            "{remote_addr} ({}) requested connection ID {connection_id}, which is reserved for {owner}",
+            handshake_result.user_id
+        );
+        write
+            .close_error(
+                "That connection ID is reserved for another player.".to_string(),
+                &mut encrypt_cipher,
+            )
+            .await;
+        return None;
+    }
+    // Owners keep their reserved ID and take it over from any address in handle_connection
+    if capabilities.accepts_assigned_id && reserved_owner.is_none() {
+        connection_id = state
+            .server
+            .connections
+            .claim_or_assign(connection_id, remote_addr);
+        if connection_id != handshake_result.connection_id {
+            info!(
+                "Connection ID {} requested by {remote_addr} is taken. Assigned {connection_id} instead.",
+                handshake_result.connection_id
+            );
+        }
+    }
+    let connection = Arc::new(ConnectionInfo {
+        id: connection_id,
+        addr: remote_addr,
+        user_uuid: handshake_result.user_id,
+        protocol_version,
+        capabilities,
+        brand: handshake_result.brand,
+        offline_mode: state.server.config.offline_mode,
+        open: AtomicBool::new(true),
+        closed: Notify::new(),
+        country: OnceLock::new(),
+        grid_cell: OnceLock::new(),
+        hosting_asn: state
+            .asn_map
+            .as_ref()
+            .and_then(|asn_map| asn_map.get(remote_addr))
+            .filter(|asn| state.server.config.hosting_asns.contains(asn)),
+        state: Mutex::new(ConnectionState {
             external_proxy: None,
             open_to_friends: HashSet::new(),
+            presence_subscriptions: HashSet::new(),
+            listed_friends: HashSet::new(),
+            warned_legacy_query_response: false,
+            warned_open_to_friends_cap: false,
         }),
         read: Mutex::new(ConnectionRead {
             socket: read,
@@ -413,33 +1115,35 @@ async fn create_connection(
         write: Mutex::new(ConnectionWrite {
             socket: write,
             cipher: encrypt_cipher,
+            unsent: Vec::new(),
+            compress: capabilities.supports_compression,
+            protocol_version,
         }),
-    }))
+    });
+    Some((connection, warning))
 }
 
 async fn perform_versioned_handshake(
     read: &mut SocketReadWrapper,
     write: &mut SocketWriteWrapper,
+    remote_addr: IpAddr,
     state: &MainServerState,
     protocol_version: u32,
 ) -> anyhow::Result<HandshakeResult> {
-    if protocol_version < protocol_versions::NEW_AUTH_PROTOCOL {
+    IntervalCounters::increment(&state.server.analytics_counters.handshakes);
+    let capabilities = ProtocolCapabilities::from_version(protocol_version);
+    if !capabilities.supports_new_auth {
         Ok(HandshakeResult {
             user_id: read.0.read_uuid().await?,
-            connection_id: ConnectionId::new(read.0.read_u64().await?)?,
+            connection_id: ConnectionId::try_from(read.0.read_u64().await?)?,
             encrypt_cipher: None,
             decrypt_cipher: None,
             success: true,
             message: None,
+            brand: None,
         })
     } else {
-        perform_handshake(
-            read,
-            write,
-            state,
-            protocol_version >= protocol_versions::ENCRYPTED_PROTOCOL,
-        )
-        .await
+        perform_handshake(read, write, remote_addr, state, capabilities).await
     }
 }
 
@@ -450,21 +1154,37 @@ struct HandshakeResult {
     decrypt_cipher: Option<Aes128Cfb>,
     success: bool,
     message: Option<String>,
+    brand: Option<String>,
 }
 
 async fn perform_handshake(
     read: &mut SocketReadWrapper,
     write: &mut SocketWriteWrapper,
+    remote_addr: IpAddr,
     state: &MainServerState,
-    supports_encryption: bool,
+    capabilities: ProtocolCapabilities,
 ) -> anyhow::Result<HandshakeResult> {
     const KEY_PREFIX: u32 = 0xFAFA0000;
     write.0.write_u32(KEY_PREFIX).await?;
     write.0.flush().await?;
 
-    let encoded_public_key = state.key_pair.public.to_public_key_der()?;
-    let mut challenge = vec![0; 16];
-    rand::thread_rng().fill_bytes(&mut challenge);
+    // Threat model: an attacker who recorded a successful handshake, and maybe can also win the
+    // race against the session server's serverId check, replays the client's half of it on a new
+    // connection. That fails in three independent ways:
+    // - The challenge starts with a counter that's never reused while the server runs, followed by
+    //   16 random bytes, so a recorded encrypted challenge never matches a new connection's
+    //   challenge, and the challenges of a restarted server can't be predicted from an old one.
+    //   The key pair is generated at startup, so recordings from before a restart don't decrypt.
+    // - Exact repeats of an encrypted secret key seen within --setup-timeout are rejected outright.
+    // - The response has to arrive within --setup-timeout of the challenge, so a stalled
+    //   connection can't hold its challenge open while the attacker works on it.
+    let key_pair = state.key_pair.load_full();
+    let encoded_public_key = key_pair.public.to_public_key_der()?;
+    // Secrets are wrapped in Zeroizing so that they're wiped once the handshake is done
+    let mut challenge = Zeroizing::new(vec![0; 24]);
+    let handshake_number = state.handshake_counter.fetch_add(1, Ordering::Relaxed);
+    challenge[..8].copy_from_slice(&handshake_number.to_be_bytes());
+    rand::thread_rng().fill_bytes(&mut challenge[8..]);
 
     write
         .0
@@ -474,31 +1194,57 @@ async fn perform_handshake(
     write.0.write_u16(challenge.len() as u16).await?;
     write.0.write_all(&challenge).await?;
     write.0.flush().await?;
+    let response_deadline = Instant::now() + state.server.config.setup_timeout;
 
-    let mut encrypted_challenge = vec![0; read.0.read_u16().await? as usize];
-    read.0.read_exact(&mut encrypted_challenge).await?;
+    let (encrypted_challenge, encrypted_secret_key) = timeout_at(response_deadline, async {
+        let mut encrypted_challenge = vec![0; read.0.read_u16().await? as usize];
+        read.0.read_exact(&mut encrypted_challenge).await?;
 
-    let mut encrypted_secret_key = vec![0; read.0.read_u16().await? as usize];
-    read.0.read_exact(&mut encrypted_secret_key).await?;
+        let mut encrypted_secret_key = vec![0; read.0.read_u16().await? as usize];
+        read.0.read_exact(&mut encrypted_secret_key).await?;
+        io::Result::Ok((encrypted_challenge, encrypted_secret_key))
+    })
+    .await
+    .map_err(|_| anyhow!("Timed out waiting for the handshake response"))??;
+
+    match state
+        .seen_secret_keys
+        .entry(minecraft_crypt::sha1_digest(&encrypted_secret_key))
+    {
+        Entry::Occupied(_) => {
+            warn!("{remote_addr} replayed the encrypted secret key of an earlier handshake");
+            bail!("Handshake replayed");
+        }
+        Entry::Vacant(entry) => {
+            entry.insert(Instant::now());
+        }
+    }
 
-    let secret_key =
-        minecraft_crypt::decrypt_using_key(&state.key_pair.private, encrypted_secret_key)?;
+    let secret_key = minecraft_crypt::decrypt_using_key(&key_pair.private, encrypted_secret_key)?;
+    minecraft_crypt::validate_secret_key(&secret_key)?;
     let auth_key = BigInt::from_signed_bytes_be(&minecraft_crypt::digest_data(
         "",
-        &state.key_pair.public,
+        &key_pair.public,
         &secret_key,
     )?)
     .to_str_radix(16);
 
     let requested_uuid = read.0.read_uuid().await?;
     let requested_username = read.0.read_string().await?;
-    let connection_id = ConnectionId::new(read.0.read_u64().await?)?;
+    let connection_id = ConnectionId::try_from(read.0.read_u64().await?)?;
+    let brand = if capabilities.sends_brand {
+        Some(sanitize_brand(
+            &read.0.read_bounded_string(MAX_BRAND_LENGTH).await?,
+        ))
+    } else {
+        None
+    };
 
     struct CipherPair {
         encrypt: Option<Aes128Cfb>,
         decrypt: Option<Aes128Cfb>,
     }
-    let ciphers = if supports_encryption {
+    let ciphers = if capabilities.supports_encryption {
         CipherPair {
             encrypt: Some(minecraft_crypt::get_cipher(&secret_key)?),
             decrypt: Some(minecraft_crypt::get_cipher(&secret_key)?),
@@ -510,9 +1256,9 @@ async fn perform_handshake(
         }
     };
 
-    if challenge
-        != minecraft_crypt::decrypt_using_key(&state.key_pair.private, encrypted_challenge)?
-    {
+    let decrypted_challenge =
+        minecraft_crypt::decrypt_using_key(&key_pair.private, encrypted_challenge)?;
+    if !minecraft_crypt::constant_time_eq(&challenge, &decrypted_challenge) {
         return Ok(HandshakeResult {
             user_id: requested_uuid,
             connection_id,
@@ -520,11 +1266,37 @@ async fn perform_handshake(
             decrypt_cipher: ciphers.decrypt,
             success: false,
             message: Some("Challenge failed".to_string()),
+            brand,
+        });
+    }
+
+    // Checked before verify_profile so that invalid profiles never reach the session service
+    if let Err(message) =
+        validate_username(&requested_username, state.server.config.relaxed_usernames).and_then(
+            |()| {
+                validate_uuid(
+                    requested_uuid,
+                    &requested_username,
+                    state.server.config.compat,
+                    &state.server.analytics_counters,
+                )
+            },
+        )
+    {
+        return Ok(HandshakeResult {
+            user_id: requested_uuid,
+            connection_id,
+            encrypt_cipher: ciphers.encrypt,
+            decrypt_cipher: ciphers.decrypt,
+            success: false,
+            message: Some(message),
+            brand,
         });
     }
 
     let verify_result = verify_profile(
-        state.session_service.as_ref(),
+        state,
+        remote_addr,
         requested_uuid,
         requested_username,
         auth_key,
@@ -541,9 +1313,84 @@ async fn perform_handshake(
         } else {
             None
         },
+        brand,
     })
 }
 
+/// How long a new connection waits for a connection from another address to release its ID
+const ID_CONFLICT_TIMEOUT: Duration = Duration::from_millis(500);
+
+const MAX_USERNAME_LENGTH: usize = 16;
+const MAX_BRAND_LENGTH: usize = 64;
+
+/// Replaces control characters, and the separators used in analytics, with `?`
+fn sanitize_brand(brand: &str) -> String {
+    brand
+        .chars()
+        .map(|c| {
+            if c.is_control() || ",;:".contains(c) {
+                '?'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// Checks a username before it's sent to the session service. Relaxed usernames may use any
+/// characters except control characters.
+fn validate_username(username: &str, relaxed: bool) -> Result<(), String> {
+    let length = username.chars().count();
+    if length == 0 || length > MAX_USERNAME_LENGTH {
+        return Err(format!(
+            "Usernames must be between 1 and {MAX_USERNAME_LENGTH} characters long"
+        ));
+    }
+    let valid = if relaxed {
+        !username.chars().any(char::is_control)
+    } else {
+        username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    if !valid {
+        return Err("Username contains invalid characters".to_string());
+    }
+    Ok(())
+}
+
+/// Only online (v4) and offline (v3) UUIDs are accepted. The nil and max UUIDs are reserved.
+fn validate_uuid(
+    uuid: Uuid,
+    username: &str,
+    compat: Option<CompatMode>,
+    counters: &IntervalCounters,
+) -> Result<(), String> {
+    if uuid.is_nil() || uuid.is_max() {
+        IntervalCounters::increment(&counters.reserved_uuid_rejections);
+        const MESSAGE: &str = "Reserved special UUID not allowed.";
+        return Err(match compat {
+            // The Kotlin server treated these as mismatched offline UUIDs
+            Some(CompatMode::Kotlin) => format!(
+                "{MESSAGE} Client gave UUID {uuid}. Expected UUID {}.",
+                offline_uuid(username)
+            ),
+            None => MESSAGE.to_string(),
+        });
+    }
+    match uuid.get_version_num() {
+        3 | 4 => Ok(()),
+        version => Err(format!(
+            "UUID {uuid} has version {version}. Only version 4 (online) and version 3 (offline) UUIDs are allowed."
+        )),
+    }
+}
+
+/// The UUID offline-mode Minecraft gives a player
+fn offline_uuid(username: &str) -> Uuid {
+    java_name_uuid_from_bytes(format!("OfflinePlayer:{username}").as_bytes())
+}
+
 #[derive(Clone, Debug)]
 struct VerifyProfileResult {
     requested_uuid: Uuid,
@@ -570,19 +1417,81 @@ impl VerifyProfileResult {
     }
 }
 
+/// Recent successful verifications, so that quick reconnects skip the session service. Keyed by
+/// address as well, so that knowing a player's name and UUID isn't enough to reuse an entry.
+type VerifiedProfiles = DashMap<(Uuid, String, IpAddr), Instant>;
+
 async fn verify_profile(
-    session_service: &YggdrasilMinecraftSessionService,
+    state: &MainServerState,
+    remote_addr: IpAddr,
     requested_uuid: Uuid,
     requested_username: String,
     auth_key: String,
 ) -> VerifyProfileResult {
-    if requested_uuid.get_version_num() == 4 {
-        let profile = session_service.has_joined_server(&requested_username, &auth_key)
-            .await
-            .unwrap_or_else(|_| {
-                warn!("Authentication servers are down. Unable to verify {requested_username}. Will allow anyway.");
-                Some(requested_uuid)
-            });
+    // In offline mode, v4 UUIDs are treated like offline ones
+    if requested_uuid.get_version_num() == 4
+        && let Some(session_service) = &state.session_service
+    {
+        let cache_time = state.server.config.auth_cache_time;
+        let verify_ip = state.server.config.verify_client_ip;
+        let cache_key = (requested_uuid, requested_username, remote_addr);
+        let cached = !cache_time.is_zero()
+            && state
+                .verified_profiles
+                .get(&cache_key)
+                .is_some_and(|verified_at| verified_at.elapsed() < cache_time);
+        let requested_username = &cache_key.1;
+        let counters = &state.server.analytics_counters;
+        let profile = if cached {
+            debug!("Using cached verification of {requested_username} from {remote_addr}");
+            IntervalCounters::increment(&counters.auth_verified);
+            Some(requested_uuid)
+        } else {
+            match session_service
+                .has_joined_server(
+                    requested_username,
+                    &auth_key,
+                    verify_ip.then_some(remote_addr),
+                    &counters.auth_retries,
+                )
+                .await
+            {
+                Ok(profile) => {
+                    if profile == Some(requested_uuid) {
+                        IntervalCounters::increment(&counters.auth_verified);
+                        // Only genuine matches are cached, never failures or outages
+                        if !cache_time.is_zero() {
+                            state
+                                .verified_profiles
+                                .insert(cache_key.clone(), Instant::now());
+                        }
+                    } else {
+                        IntervalCounters::increment(&counters.auth_rejected);
+                    }
+                    profile
+                }
+                Err(error) if state.server.config.strict_auth => {
+                    IntervalCounters::increment(&counters.auth_fallbacks);
+                    warn!(
+                        "Authentication servers are down. Unable to verify {requested_username}. Rejecting because of --strict-auth. {error}"
+                    );
+                    return VerifyProfileResult {
+                        requested_uuid,
+                        expected_uuid: Uuid::nil(),
+                        mismatch_message: "Unable to reach the authentication servers to verify you. Please try again later.",
+                        mismatch_is_error: true,
+                        include_uuid_info: false,
+                    };
+                }
+                Err(error) => {
+                    IntervalCounters::increment(&counters.auth_fallbacks);
+                    warn!(
+                        "Authentication servers are down. Unable to verify {requested_username}. Will allow anyway. {error}"
+                    );
+                    Some(requested_uuid)
+                }
+            }
+        };
         match profile {
             Some(uuid) => VerifyProfileResult {
                 requested_uuid,
@@ -591,6 +1500,17 @@ async fn verify_profile(
                 mismatch_is_error: true,
                 include_uuid_info: true,
             },
+            None if verify_ip => VerifyProfileResult {
+                requested_uuid,
+                expected_uuid: Uuid::nil(),
+                mismatch_message: concat!(
+                    "Failed to verify username. ",
+                    "If you have prevent-proxy-connections enabled, this server requires you to connect from the same IP address you logged in to Minecraft from. ",
+                    "Otherwise, please restart your game and the launcher.",
+                ),
+                mismatch_is_error: true,
+                include_uuid_info: false,
+            },
             None => VerifyProfileResult {
                 requested_uuid,
                 expected_uuid: Uuid::nil(),
@@ -605,24 +1525,1026 @@ async fn verify_profile(
             },
         }
     } else {
-        let offline_uuid =
-            java_name_uuid_from_bytes(format!("OfflinePlayer:{requested_username}").as_bytes());
-        if requested_uuid.is_nil() || requested_uuid.is_max() {
-            VerifyProfileResult {
-                requested_uuid,
-                expected_uuid: offline_uuid,
-                mismatch_message: "Reserved special UUID not allowed.",
-                mismatch_is_error: true,
-                include_uuid_info: true,
+        let offline_uuid = offline_uuid(&requested_username);
+        if requested_uuid != offline_uuid {
+            IntervalCounters::increment(&state.server.analytics_counters.offline_mismatches);
+        }
+        VerifyProfileResult {
+            requested_uuid,
+            expected_uuid: offline_uuid,
+            mismatch_message: "Mismatched offline UUID. Some features may not work as intended.",
+            mismatch_is_error: false,
+            include_uuid_info: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authlib::mock_session_server::{MockResponse, MockSessionServer};
+    use crate::cli::args::Args;
+    use crate::connection::read_test_message;
+    use crate::json_data::ExternalProxy;
+    use crate::lat_long::LatitudeLongitude;
+    use crate::modules::reload::reload_reserved_ids;
+    use arc_swap::ArcSwapOption;
+    use cfb8::cipher::AsyncStreamCipher;
+    use clap::Parser;
+    use rsa::pkcs8::DecodePublicKey;
+    use rsa::{Pkcs1v15Encrypt, RsaPublicKey};
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use std::sync::LazyLock;
+    use tokio::io::{AsyncWrite, DuplexStream};
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+    use tokio_rustls::rustls::sign::CertifiedKey;
+
+    /// In testdata/geolite2-city-ipv4-num.csv as London
+    const LONDON: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 5));
+    /// Not Steve's offline UUID, so that handshakes on protocol 6+ get a warning in offline mode
+    const USER: Uuid = Uuid::from_u128(0x12345678_1234_4234_8234_123456789abc);
+    const USERNAME: &str = "Steve";
+    const SECRET_KEY: [u8; minecraft_crypt::SECRET_KEY_LENGTH] = [7; 16];
+
+    /// Generating a key pair takes a while in debug builds, so every test shares one
+    static KEY_PAIR: LazyLock<RsaKeyPair> = LazyLock::new(minecraft_crypt::generate_key_pair);
+
+    async fn state(configure: impl FnOnce(&mut FullServerConfig)) -> MainServerState {
+        let mut config = FullServerConfig::for_test();
+        config.external_servers = ArcSwapOption::from_pointee(vec![Arc::new(ExternalProxy {
+            lat_long: LatitudeLongitude(51.5, -0.1),
+            addr: Some("london.example.com".to_string()),
+            port: 9656,
+            base_addr: None,
+            mc_port: 25565,
+        })]);
+        configure(&mut config);
+        let source = CsvSource::File(
+            concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/testdata/geolite2-city-ipv4-num.csv"
+            )
+            .into(),
+        );
+        let (ip_info_map, failed) =
+            IpInfoMap::load_from_geolite_city_csvs(&[source], false, true).await;
+        assert!(failed.is_empty());
+        MainServerState {
+            server: Arc::new(ServerState::new(config)),
+            session_service: None,
+            key_pair: Arc::new(ArcSwap::from_pointee(RsaKeyPair {
+                private: KEY_PAIR.private.clone(),
+                public: KEY_PAIR.public.clone(),
+            })),
+            ip_info_map: Arc::new(ArcSwap::from_pointee(ip_info_map)),
+            verified_profiles: Arc::new(VerifiedProfiles::new()),
+            handshake_counter: Arc::new(AtomicU64::new(0)),
+            seen_secret_keys: Arc::new(SeenSecretKeys::new()),
+            geo_lookup: None,
+            asn_map: None,
+        }
+    }
+
+    async fn write_string(client: &mut (impl AsyncWrite + Unpin), string: &str) {
+        client.write_u16(string.len() as u16).await.unwrap();
+        client.write_all(string.as_bytes()).await.unwrap();
+    }
+
+    /// Plays the client's half of the handshake
+    async fn handshake(client: &mut DuplexStream, protocol_version: u32, user: Uuid) {
+        client.write_u32(protocol_version).await.unwrap();
+        let capabilities = ProtocolCapabilities::from_version(protocol_version);
+        if !capabilities.supports_new_auth {
+            client.write_u128(user.as_u128()).await.unwrap();
+            client.write_u64(1).await.unwrap();
+            return;
+        }
+        let (public_key, challenge) = read_challenge(client).await;
+        let response = handshake_response(&public_key, &challenge, protocol_version, user).await;
+        client.write_all(&response).await.unwrap();
+    }
+
+    async fn read_challenge(client: &mut DuplexStream) -> (RsaPublicKey, Vec<u8>) {
+        assert_eq!(client.read_u32().await.unwrap(), 0xFAFA0000);
+        let mut public_key = vec![0; client.read_u16().await.unwrap() as usize];
+        client.read_exact(&mut public_key).await.unwrap();
+        let mut challenge = vec![0; client.read_u16().await.unwrap() as usize];
+        client.read_exact(&mut challenge).await.unwrap();
+        let public_key = RsaPublicKey::from_public_key_der(&public_key).unwrap();
+        (public_key, challenge)
+    }
+
+    /// Everything a client sends after receiving the challenge, so that it can be replayed
+    async fn handshake_response(
+        public_key: &RsaPublicKey,
+        challenge: &[u8],
+        protocol_version: u32,
+        user: Uuid,
+    ) -> Vec<u8> {
+        let mut response = vec![];
+        for data in [challenge, &SECRET_KEY] {
+            let encrypted = public_key
+                .encrypt(&mut rand::thread_rng(), Pkcs1v15Encrypt, data)
+                .unwrap();
+            response.write_u16(encrypted.len() as u16).await.unwrap();
+            response.write_all(&encrypted).await.unwrap();
+        }
+        response.write_u128(user.as_u128()).await.unwrap();
+        write_string(&mut response, USERNAME).await;
+        response.write_u64(1).await.unwrap();
+        if ProtocolCapabilities::from_version(protocol_version).sends_brand {
+            write_string(&mut response, "world-host/test").await;
+        }
+        response
+    }
+
+    /// Connects a client from [LONDON] with connection ID 1 that disconnects right after setup.
+    /// The connection is left in the set, as if it hadn't been cleaned up yet.
+    async fn connect(
+        state: &MainServerState,
+        protocol_version: u32,
+        user: Uuid,
+    ) -> (Connection, DuplexStream) {
+        let (connection, client) = try_connect(state, protocol_version, user).await;
+        (connection.unwrap(), client)
+    }
+
+    /// Like [connect], but None if the connection was rejected during setup
+    async fn try_connect(
+        state: &MainServerState,
+        protocol_version: u32,
+        user: Uuid,
+    ) -> (Option<Connection>, DuplexStream) {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let (read, write) = tokio::io::split(server);
+        let handler = {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let mut connection = None;
+                let result = handle_connection(
+                    &state,
+                    SocketReadWrapper(Box::new(read)),
+                    SocketWriteWrapper(Box::new(write)),
+                    LONDON,
+                    &mut connection,
+                )
+                .await;
+                (result, connection)
+            })
+        };
+        handshake(&mut client, protocol_version, user).await;
+        client.shutdown().await.unwrap();
+        let (result, connection) = handler.await.unwrap();
+        result.unwrap();
+        (connection, client)
+    }
+
+    /// Connects a client that disconnects right after setup, returning every message it was sent
+    async fn setup_messages(
+        state: &MainServerState,
+        protocol_version: u32,
+    ) -> Vec<WorldHostS2CMessage> {
+        let (connection, mut client) = connect(state, protocol_version, USER).await;
+        // Drops the last reference to the connection's write half, ending the stream
+        state.server.connections.remove(&connection);
+        drop(connection);
+
+        let mut data = vec![];
+        client.read_to_end(&mut data).await.unwrap();
+        if ProtocolCapabilities::from_version(protocol_version).supports_encryption {
+            minecraft_crypt::get_cipher(&SECRET_KEY)
+                .unwrap()
+                .decrypt(&mut data);
+        }
+        let mut messages = vec![];
+        let mut rest = data.as_slice();
+        while !rest.is_empty() {
+            let (size, body) = rest.split_at(4);
+            let size = u32::from_be_bytes(size.try_into().unwrap()) as usize;
+            messages.push(
+                WorldHostS2CMessage::parse(body[0], &body[1..size], protocol_version).unwrap(),
+            );
+            rest = &body[size..];
+        }
+        messages
+    }
+
+    async fn setup_names(state: &MainServerState, protocol_version: u32) -> Vec<&'static str> {
+        setup_messages(state, protocol_version)
+            .await
+            .iter()
+            .map(WorldHostS2CMessage::name)
+            .collect()
+    }
+
+    /// Runs the server's half of the handshake on a new connection
+    fn start_handshake(
+        state: &MainServerState,
+        protocol_version: u32,
+    ) -> (
+        DuplexStream,
+        tokio::task::JoinHandle<anyhow::Result<HandshakeResult>>,
+    ) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (read, write) = tokio::io::split(server);
+        let state = state.clone();
+        let handshake = tokio::spawn(async move {
+            perform_versioned_handshake(
+                &mut SocketReadWrapper(Box::new(read)),
+                &mut SocketWriteWrapper(Box::new(write)),
+                LONDON,
+                &state,
+                protocol_version,
+            )
+            .await
+        });
+        (client, handshake)
+    }
+
+    #[tokio::test]
+    async fn challenge_layout() {
+        let state = state(|config| config.offline_mode = true).await;
+        let mut challenges = vec![];
+        for handshake_number in 0..3u64 {
+            let (mut client, handshake) = start_handshake(&state, 8);
+            let (_, challenge) = read_challenge(&mut client).await;
+            assert_eq!(challenge.len(), 24);
+            assert_eq!(challenge[..8], handshake_number.to_be_bytes());
+            challenges.push(challenge[8..].to_vec());
+            drop(client);
+            assert!(handshake.await.unwrap().is_err());
+        }
+        challenges.dedup();
+        assert_eq!(challenges.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn key_rotation_during_handshake() {
+        let state = state(|config| config.offline_mode = true).await;
+        let (mut old_client, old_handshake) = start_handshake(&state, 8);
+        let (old_public_key, old_challenge) = read_challenge(&mut old_client).await;
+
+        rotate_key_pair(&state.key_pair, minecraft_crypt::generate_key_pair());
+        let (mut new_client, new_handshake) = start_handshake(&state, 8);
+        let (new_public_key, new_challenge) = read_challenge(&mut new_client).await;
+        assert_ne!(new_public_key, old_public_key);
+        assert_eq!(new_public_key, state.key_pair.load().public);
+
+        // The handshake started before the rotation still finishes with the old key
+        let response = handshake_response(&old_public_key, &old_challenge, 8, USER).await;
+        old_client.write_all(&response).await.unwrap();
+        assert!(old_handshake.await.unwrap().unwrap().success);
+
+        let response = handshake_response(&new_public_key, &new_challenge, 8, USER).await;
+        new_client.write_all(&response).await.unwrap();
+        assert!(new_handshake.await.unwrap().unwrap().success);
+    }
+
+    #[tokio::test]
+    async fn replayed_handshake() {
+        let state = state(|config| config.offline_mode = true).await;
+
+        let (mut client, handshake) = start_handshake(&state, 8);
+        let (public_key, challenge) = read_challenge(&mut client).await;
+        let transcript = handshake_response(&public_key, &challenge, 8, USER).await;
+        client.write_all(&transcript).await.unwrap();
+        let result = handshake.await.unwrap().unwrap();
+        assert!(result.success);
+        assert_eq!(result.user_id, USER);
+
+        // The encrypted secret key is recognized as a repeat
+        let (mut client, handshake) = start_handshake(&state, 8);
+        let (_, new_challenge) = read_challenge(&mut client).await;
+        assert_ne!(new_challenge, challenge);
+        client.write_all(&transcript).await.unwrap();
+        let error = handshake.await.unwrap().err().unwrap();
+        assert_eq!(error.to_string(), "Handshake replayed");
+
+        // Once the secret key has been forgotten, the recorded challenge still doesn't match
+        state.seen_secret_keys.clear();
+        let (mut client, handshake) = start_handshake(&state, 8);
+        read_challenge(&mut client).await;
+        client.write_all(&transcript).await.unwrap();
+        let result = handshake.await.unwrap().unwrap();
+        assert!(!result.success);
+        assert_eq!(result.message.as_deref(), Some("Challenge failed"));
+    }
+
+    const FRIEND: Uuid = Uuid::from_u128(0x87654321_4321_4321_8321_cba987654321);
+    const OTHER_USER: Uuid = Uuid::from_u128(0xabcdef01_2345_4678_9abc_def012345678);
+
+    /// A friend's connection, and an earlier connection with ID 1 whose world is open to them
+    async fn open_world(
+        state: &MainServerState,
+        user: Uuid,
+        addr: IpAddr,
+    ) -> (Connection, Connection, DuplexStream) {
+        let (friend, friend_client) = ConnectionInfo::for_test(
+            ConnectionId::new(2).unwrap(),
+            FRIEND,
+            IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7)),
+            protocol_versions::CURRENT,
+        );
+        let (old, _) = ConnectionInfo::for_test(
+            ConnectionId::new(1).unwrap(),
+            user,
+            addr,
+            protocol_versions::CURRENT,
+        );
+        old.state.lock().await.open_to_friends.insert(FRIEND);
+        assert!(state.server.connections.add(friend.clone()));
+        assert!(state.server.connections.add(old.clone()));
+        (friend, old, friend_client)
+    }
+
+    /// Whether a message was already waiting for the friend
+    async fn next_message(friend_client: &mut DuplexStream) -> Option<WorldHostS2CMessage> {
+        timeout(
+            Duration::from_millis(50),
+            read_test_message(friend_client, protocol_versions::CURRENT),
+        )
+        .await
+        .ok()
+        .map(Result::unwrap)
+    }
+
+    #[tokio::test]
+    async fn quick_reconnect_inherits_world() {
+        let state = state(|_| {}).await;
+        let (_friend, old, mut friend_client) = open_world(&state, USER, LONDON).await;
+
+        let (new, _client) = connect(&state, 4, USER).await;
+        assert!(Arc::ptr_eq(
+            &state.server.connections.by_id(new.id).unwrap(),
+            &new
+        ));
+        assert!(old.state.lock().await.open_to_friends.is_empty());
+        assert!(new.state.lock().await.open_to_friends.contains(&FRIEND));
+
+        // The replaced connection finishing doesn't close the world
+        clean_up_connection(&state, &old).await;
+        assert_eq!(next_message(&mut friend_client).await, None);
+
+        clean_up_connection(&state, &new).await;
+        assert_eq!(
+            next_message(&mut friend_client).await,
+            Some(WorldHostS2CMessage::ClosedWorld { user: USER })
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnect_as_another_user_doesnt_inherit_world() {
+        let state = state(|_| {}).await;
+        let (_friend, old, mut friend_client) = open_world(&state, OTHER_USER, LONDON).await;
+
+        let (new, _client) = connect(&state, 4, USER).await;
+        assert!(Arc::ptr_eq(
+            &state.server.connections.by_id(new.id).unwrap(),
+            &new
+        ));
+        assert!(new.state.lock().await.open_to_friends.is_empty());
+
+        clean_up_connection(&state, &old).await;
+        assert_eq!(
+            next_message(&mut friend_client).await,
+            Some(WorldHostS2CMessage::ClosedWorld { user: OTHER_USER })
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnect_from_another_ip_doesnt_inherit_world() {
+        let id = ConnectionId::new(1).unwrap();
+        let state = state(|config| {
+            config.reserved_ids = ArcSwap::from_pointee(HashMap::from([(id, USER)]));
+        })
+        .await;
+        let elsewhere = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let (_friend, old, mut friend_client) = open_world(&state, USER, elsewhere).await;
+
+        // Reclaimed by its reserved owner, so it's still replaced
+        let (new, _client) = connect(&state, 4, USER).await;
+        assert!(Arc::ptr_eq(
+            &state.server.connections.by_id(id).unwrap(),
+            &new
+        ));
+        assert!(new.state.lock().await.open_to_friends.is_empty());
+
+        clean_up_connection(&state, &old).await;
+        assert_eq!(
+            next_message(&mut friend_client).await,
+            Some(WorldHostS2CMessage::ClosedWorld { user: USER })
+        );
+    }
+
+    #[tokio::test]
+    async fn reserved_id_owner() {
+        let id = ConnectionId::new(1).unwrap();
+        let state = state(|config| {
+            config.reserved_ids = ArcSwap::from_pointee(HashMap::from([(id, USER)]));
+        })
+        .await;
+        let (connection, _client) = connect(&state, 4, USER).await;
+        assert_eq!(connection.id, id);
+    }
+
+    #[tokio::test]
+    async fn reserved_id_squatter() {
+        let id = ConnectionId::new(1).unwrap();
+        let state = state(|config| {
+            config.reserved_ids = ArcSwap::from_pointee(HashMap::from([(id, OTHER_USER)]));
+        })
+        .await;
+        let (connection, mut client) = try_connect(&state, 4, USER).await;
+        assert!(connection.is_none());
+        assert_eq!(
+            read_test_message(&mut client, 4).await.unwrap(),
+            WorldHostS2CMessage::Error {
+                message: "That connection ID is reserved for another player.".to_string(),
+                critical: true,
             }
-        } else {
-            VerifyProfileResult {
-                requested_uuid,
-                expected_uuid: offline_uuid,
-                mismatch_message: "Mismatched offline UUID. Some features may not work as intended.",
-                mismatch_is_error: false,
-                include_uuid_info: true,
+        );
+    }
+
+    #[tokio::test]
+    async fn unreserved_id() {
+        let state = state(|_| {}).await;
+        let (connection, _client) = connect(&state, 4, USER).await;
+        assert_eq!(connection.id, ConnectionId::new(1).unwrap());
+    }
+
+    /// A connection from another user on another address holding ID 1
+    fn hold_id(state: &MainServerState) -> Connection {
+        let (other, _) = ConnectionInfo::for_test(
+            ConnectionId::new(1).unwrap(),
+            OTHER_USER,
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            protocol_versions::CURRENT,
+        );
+        assert!(state.server.connections.add(other.clone()));
+        other
+    }
+
+    /// Skips setup messages until the connection is closed with an error
+    async fn closing_error(client: &mut DuplexStream, protocol_version: u32) -> String {
+        loop {
+            if let WorldHostS2CMessage::Error {
+                message,
+                critical: true,
+            } = read_test_message(client, protocol_version).await.unwrap()
+            {
+                return message;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn taken_id_assigned_to_new_client() {
+        let state = state(|config| config.offline_mode = true).await;
+        let other = hold_id(&state);
+
+        let (connection, _client) =
+            connect(&state, protocol_versions::ASSIGNED_ID_PROTOCOL, USER).await;
+        assert_ne!(connection.id, other.id);
+        let connections = &state.server.connections;
+        assert!(Arc::ptr_eq(&connections.by_id(other.id).unwrap(), &other));
+        assert!(Arc::ptr_eq(
+            &connections.by_id(connection.id).unwrap(),
+            &connection
+        ));
+        assert_eq!(
+            connection.id.to_string().parse::<ConnectionId>().unwrap(),
+            connection.id
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn taken_id_rejected_for_old_client() {
+        let state = state(|_| {}).await;
+        let other = hold_id(&state);
+
+        let (_, mut client) = try_connect(&state, 4, USER).await;
+        assert_eq!(
+            closing_error(&mut client, 4).await,
+            "That connection ID is taken."
+        );
+        assert_eq!(state.server.connections.len(), 1);
+        assert!(state.server.connections.by_id(other.id).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn taken_id_freed_before_deadline() {
+        let state = state(|_| {}).await;
+        let other = hold_id(&state);
+        let start = Instant::now();
+        tokio::spawn({
+            let state = state.clone();
+            async move {
+                sleep(ID_CONFLICT_TIMEOUT / 2).await;
+                state.server.connections.remove(&other);
+            }
+        });
+
+        let (connection, _client) = connect(&state, 4, USER).await;
+        assert_eq!(connection.id, ConnectionId::new(1).unwrap());
+        assert_eq!(start.elapsed(), ID_CONFLICT_TIMEOUT / 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn taken_id_deadline() {
+        let state = state(|_| {}).await;
+        hold_id(&state);
+        let (unrelated, _) = ConnectionInfo::for_test(
+            ConnectionId::new(3).unwrap(),
+            FRIEND,
+            LONDON,
+            protocol_versions::CURRENT,
+        );
+        assert!(state.server.connections.add(unrelated.clone()));
+        let start = Instant::now();
+        // Other connections going away doesn't extend the deadline
+        tokio::spawn({
+            let state = state.clone();
+            async move {
+                sleep(ID_CONFLICT_TIMEOUT / 2).await;
+                state.server.connections.remove(&unrelated);
+            }
+        });
+
+        let (_, mut client) = try_connect(&state, 4, USER).await;
+        assert_eq!(start.elapsed(), ID_CONFLICT_TIMEOUT);
+        assert_eq!(
+            closing_error(&mut client, 4).await,
+            "That connection ID is taken."
+        );
+        assert_eq!(state.server.connections.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reloaded_reserved_ids_apply_to_new_connections() {
+        let state = state(|_| {}).await;
+        let path = std::env::temp_dir().join(format!(
+            "world-host-server-{}-reserved_ids.json",
+            std::process::id()
+        ));
+        let id = ConnectionId::new(1).unwrap();
+        std::fs::write(&path, format!(r#"{{"{id}": "{OTHER_USER}"}}"#)).unwrap();
+        let reloaded = reload_reserved_ids(&state.server.config, &path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(reloaded);
+
+        let (connection, _client) = try_connect(&state, 4, USER).await;
+        assert!(connection.is_none());
+    }
+
+    /// Verifies players with the mock session servers, tried in order
+    async fn online_state(
+        session_servers: &[&MockSessionServer],
+        configure: impl FnOnce(&mut FullServerConfig),
+    ) -> MainServerState {
+        let state = state(|config| {
+            configure(config);
+            config.session_hosts = session_servers
+                .iter()
+                .map(|server| server.url.clone())
+                .collect();
+        })
+        .await;
+        let session_service =
+            YggdrasilAuthenticationService::new(&state.server.config).create_session_service();
+        MainServerState {
+            session_service: Some(Arc::new(session_service)),
+            ..state
+        }
+    }
+
+    /// Whether USER is verified when connecting from `addr`
+    async fn verify(state: &MainServerState, addr: IpAddr) -> bool {
+        !verify_profile(
+            state,
+            addr,
+            USER,
+            USERNAME.to_string(),
+            "server-id".to_string(),
+        )
+        .await
+        .is_mismatch()
+    }
+
+    #[tokio::test]
+    async fn verification_cache_hit() {
+        let session_server = MockSessionServer::start(vec![MockResponse::Joined(USER)]).await;
+        let state = online_state(&[&session_server], |_| {}).await;
+        assert!(verify(&state, LONDON).await);
+        assert!(verify(&state, LONDON).await);
+        assert_eq!(session_server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn verification_cache_miss_from_other_address() {
+        let session_server = MockSessionServer::start(vec![MockResponse::Joined(USER)]).await;
+        let state = online_state(&[&session_server], |_| {}).await;
+        assert!(verify(&state, LONDON).await);
+        assert!(verify(&state, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))).await);
+        assert_eq!(session_server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn verification_cache_expires() {
+        let session_server = MockSessionServer::start(vec![MockResponse::Joined(USER)]).await;
+        let state = online_state(&[&session_server], |config| {
+            config.auth_cache_time = Duration::from_millis(100);
+        })
+        .await;
+        assert!(verify(&state, LONDON).await);
+        sleep(Duration::from_millis(200)).await;
+        assert!(verify(&state, LONDON).await);
+        assert_eq!(session_server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_verification_not_cached() {
+        let session_server =
+            MockSessionServer::start(vec![MockResponse::NotJoined, MockResponse::Joined(USER)])
+                .await;
+        let state = online_state(&[&session_server], |_| {}).await;
+        assert!(!verify(&state, LONDON).await);
+        assert!(verify(&state, LONDON).await);
+        assert_eq!(session_server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn verification_cache_disabled() {
+        for args in [
+            &["world-host-server", "--auth-cache-time", "0s"][..],
+            &[
+                "world-host-server",
+                "--strict-auth",
+                "--auth-cache-time",
+                "60s",
+            ],
+        ] {
+            let session_server = MockSessionServer::start(vec![MockResponse::Joined(USER)]).await;
+            let state = online_state(&[&session_server], |config| {
+                let args = Args::try_parse_from(args).unwrap();
+                *config = FullServerConfig::from_args(args, None, None, HashMap::new());
+            })
+            .await;
+            assert!(verify(&state, LONDON).await);
+            assert!(verify(&state, LONDON).await);
+            assert_eq!(session_server.requests().len(), 2, "{args:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_client_ip() {
+        for verify_client_ip in [false, true] {
+            let session_server = MockSessionServer::start(vec![MockResponse::NotJoined]).await;
+            let state = online_state(&[&session_server], |config| {
+                config.verify_client_ip = verify_client_ip;
+            })
+            .await;
+            let result = verify_profile(
+                &state,
+                LONDON,
+                USER,
+                USERNAME.to_string(),
+                "server-id".to_string(),
+            )
+            .await;
+            assert!(result.is_mismatch());
+            assert_eq!(
+                result
+                    .mismatch_message
+                    .contains("prevent-proxy-connections"),
+                verify_client_ip
+            );
+            assert_eq!(
+                session_server.requests()[0].ends_with("&ip=203.0.113.5"),
+                verify_client_ip
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn strict_auth_when_session_servers_down() {
+        for strict_auth in [false, true] {
+            let session_server = MockSessionServer::start(vec![MockResponse::Status(403)]).await;
+            let state = online_state(&[&session_server], |config| {
+                config.strict_auth = strict_auth;
+            })
+            .await;
+            let result = verify_profile(
+                &state,
+                LONDON,
+                USER,
+                USERNAME.to_string(),
+                "server-id".to_string(),
+            )
+            .await;
+            assert_eq!(result.is_mismatch(), strict_auth);
+            if strict_auth {
+                assert!(
+                    result
+                        .mismatch_message
+                        .starts_with("Unable to reach the authentication servers")
+                );
             }
+            assert_eq!(session_server.requests().len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn setup_order() {
+        let state = state(|config| config.offline_mode = true).await;
+        // ConnectionInfo is flushed first, then the required messages, then the advisories
+        assert_eq!(
+            setup_names(&state, 8).await,
+            [
+                "ConnectionInfo",
+                "ServerCapabilities",
+                "ExternalProxyServer",
+                "Warning"
+            ]
+        );
+        assert_eq!(
+            setup_names(&state, 6).await,
+            [
+                "ConnectionInfo",
+                "ExternalProxyServer",
+                "Warning",
+                "OutdatedWorldHost"
+            ]
+        );
+        assert_eq!(
+            setup_names(&state, 4).await,
+            [
+                "ConnectionInfo",
+                "ExternalProxyServer",
+                "OutdatedWorldHost",
+                "Error"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sent_allowed_join_types() {
+        for (allowed, mask) in [
+            (vec![], 0),
+            (vec![JoinTypeKind::Proxy], 0b010),
+            (vec![JoinTypeKind::UPnP, JoinTypeKind::Punch], 0b101),
+            (
+                vec![JoinTypeKind::UPnP, JoinTypeKind::Proxy, JoinTypeKind::Punch],
+                0b111,
+            ),
+        ] {
+            let state = state(|config| {
+                config.offline_mode = true;
+                config.allowed_join_types = allowed;
+            })
+            .await;
+            let capabilities = setup_messages(&state, 8)
+                .await
+                .into_iter()
+                .find(|message| matches!(message, WorldHostS2CMessage::ServerCapabilities { .. }));
+            assert_eq!(
+                capabilities,
+                Some(WorldHostS2CMessage::ServerCapabilities {
+                    allowed_join_types: mask
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn kotlin_setup_order() {
+        let state = state(|config| {
+            config.offline_mode = true;
+            config.compat = Some(CompatMode::Kotlin);
+        })
+        .await;
+        assert_eq!(
+            setup_names(&state, 8).await,
+            [
+                "Warning",
+                "ConnectionInfo",
+                "ExternalProxyServer",
+                "ServerCapabilities"
+            ]
+        );
+        assert_eq!(
+            setup_names(&state, 6).await,
+            [
+                "Warning",
+                "ConnectionInfo",
+                "OutdatedWorldHost",
+                "ExternalProxyServer"
+            ]
+        );
+        assert_eq!(
+            setup_names(&state, 4).await,
+            [
+                "ConnectionInfo",
+                "OutdatedWorldHost",
+                "Error",
+                "ExternalProxyServer"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn delivered_advisories() {
+        for (compat, expected) in [
+            (None, &["ConnectionInfo", "ExternalProxyServer"][..]),
+            (
+                Some(CompatMode::Kotlin),
+                &[
+                    "ConnectionInfo",
+                    "OutdatedWorldHost",
+                    "Error",
+                    "ExternalProxyServer",
+                ],
+            ),
+        ] {
+            let state = state(|config| config.compat = compat).await;
+            // As if a connection that dropped mid-setup had delivered them
+            state
+                .server
+                .setup_advisories
+                .lock()
+                .await
+                .record(USER, [Advisory::OutdatedWorldHost, Advisory::InsecureAuth]);
+            assert_eq!(setup_names(&state, 4).await, expected, "{compat:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn setup_messages_match() {
+        for compat in [None, Some(CompatMode::Kotlin)] {
+            let state = state(|config| config.compat = compat).await;
+            let messages = setup_messages(&state, 4).await;
+            assert!(
+                messages.contains(&WorldHostS2CMessage::ExternalProxyServer {
+                    host: "london.example.com".to_string(),
+                    port: 9656,
+                    base_addr: "london.example.com".to_string(),
+                    mc_port: 25565,
+                })
+            );
+            assert!(messages.contains(&WorldHostS2CMessage::Error {
+                message: "You are using an old insecure version of World Host. It is highly recommended that you update to 0.4.14 or later.".to_string(),
+                critical: false,
+            }));
+        }
+    }
+
+    /// Fails every handshake, which is enough for tests that never get that far
+    #[derive(Debug)]
+    struct NoCertificate;
+
+    impl ResolvesServerCert for NoCertificate {
+        fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn tls_rate_limited_before_handshake() {
+        let state = state(|_| {}).await;
+        let tls_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(NoCertificate));
+        let rate_limiter = Arc::new(RateLimiter::new(vec![RateLimitBucket::new(
+            "test".to_string(),
+            1,
+            Duration::from_secs(60),
+        )]));
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(accept_connections(
+            listener,
+            Some(TlsAcceptor::from(Arc::new(tls_config))),
+            state.clone(),
+            rate_limiter,
+        ));
+
+        // The first connection is allowed, so the server waits for its ClientHello
+        let mut allowed = TcpStream::connect(addr).await.unwrap();
+        assert!(
+            timeout(Duration::from_millis(200), allowed.read(&mut [0; 1]))
+                .await
+                .is_err()
+        );
+        // The second is closed without starting a handshake
+        let mut limited = TcpStream::connect(addr).await.unwrap();
+        let read = timeout(Duration::from_secs(5), limited.read(&mut [0; 1])).await;
+        assert_eq!(read.unwrap().unwrap(), 0);
+        assert_eq!(
+            state
+                .server
+                .analytics_counters
+                .rate_limited
+                .lock()
+                .unwrap()
+                .get("test"),
+            Some(&1)
+        );
+    }
+
+    #[tokio::test]
+    async fn reported_rate_limit_wait() {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let rate_limiter = RateLimiter::new(vec![
+            RateLimitBucket::new("long".to_string(), 1, Duration::from_secs(3600)),
+            RateLimitBucket::new("short".to_string(), 1, Duration::from_secs(60)),
+        ]);
+        rate_limiter.ratelimit(ip).await;
+        let limited = rate_limiter.ratelimit(ip).await.unwrap();
+        assert_eq!(limited.bucket, "short");
+        let reported = reported_rate_limit(&rate_limiter, ip, limited.clone(), None);
+        assert_eq!(reported.bucket, "long");
+        let reported = reported_rate_limit(&rate_limiter, ip, limited, Some(CompatMode::Kotlin));
+        assert_eq!(reported.bucket, "short");
+    }
+
+    #[test]
+    fn username_validation() {
+        for relaxed in [false, true] {
+            assert!(validate_username("a", relaxed).is_ok());
+            assert!(validate_username("Steve_123", relaxed).is_ok());
+            assert!(validate_username(&"a".repeat(16), relaxed).is_ok());
+            assert!(validate_username("", relaxed).is_err());
+            assert!(validate_username(&"a".repeat(17), relaxed).is_err());
+            assert!(validate_username("Steve\n", relaxed).is_err());
+            assert!(validate_username("\0", relaxed).is_err());
+        }
+        // Length is counted in characters, not bytes
+        assert!(validate_username(&"é".repeat(16), true).is_ok());
+        assert!(validate_username(&"é".repeat(17), true).is_err());
+        for username in ["Steve Jobs", "Steve-Jobs", "Stéve", "Steve.", "§cSteve"] {
+            assert_eq!(
+                validate_username(username, false),
+                Err("Username contains invalid characters".to_string())
+            );
+            assert!(validate_username(username, true).is_ok());
+        }
+    }
+
+    #[test]
+    fn uuid_validation() {
+        let counters = IntervalCounters::default();
+        let online = Uuid::from_u128(0x12345678_1234_4234_8234_123456789abc);
+        assert!(validate_uuid(online, USERNAME, None, &counters).is_ok());
+        assert!(validate_uuid(offline_uuid(USERNAME), USERNAME, None, &counters).is_ok());
+        for reserved in [Uuid::nil(), Uuid::max()] {
+            assert!(validate_uuid(reserved, USERNAME, None, &counters).is_err());
+        }
+        assert_eq!(counters.reserved_uuid_rejections.load(Ordering::Relaxed), 2);
+        for (uuid, version) in [
+            (Uuid::from_u128(0x12345678_1234_1234_8234_123456789abc), 1),
+            (Uuid::from_u128(0x12345678_1234_5234_8234_123456789abc), 5),
+        ] {
+            assert_eq!(
+                validate_uuid(uuid, USERNAME, None, &counters),
+                Err(format!(
+                    "UUID {uuid} has version {version}. Only version 4 (online) and version 3 (offline) UUIDs are allowed."
+                ))
+            );
+        }
+        assert_eq!(counters.reserved_uuid_rejections.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn reserved_uuid_message() {
+        let counters = IntervalCounters::default();
+        assert_eq!(
+            validate_uuid(Uuid::nil(), USERNAME, None, &counters),
+            Err("Reserved special UUID not allowed.".to_string())
+        );
+        assert_eq!(
+            validate_uuid(Uuid::max(), USERNAME, Some(CompatMode::Kotlin), &counters),
+            Err(format!(
+                "Reserved special UUID not allowed. Client gave UUID {}. Expected UUID {}.",
+                Uuid::max(),
+                offline_uuid(USERNAME)
+            ))
+        );
+    }
+
+    /// Checks that protect the server aren't relaxed by --compat kotlin
+    #[test]
+    fn kotlin_still_validates_profiles() {
+        let counters = IntervalCounters::default();
+        let v5 = Uuid::from_u128(0x12345678_1234_5234_8234_123456789abc);
+        for compat in [None, Some(CompatMode::Kotlin)] {
+            assert!(validate_uuid(v5, USERNAME, compat, &counters).is_err());
+            assert!(validate_uuid(USER, USERNAME, compat, &counters).is_ok());
         }
+        assert!(validate_username("Steve Jobs", false).is_err());
     }
 }
@@ -0,0 +1,117 @@
+use crate::server_state::ServerState;
+use crate::util::shrink_if_sparse;
+use log::info;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{MissedTickBehavior, interval};
+
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Periodically shrinks long-lived maps so capacity left over from a traffic spike is released.
+pub async fn run_maintenance(server: Arc<ServerState>) {
+    let mut interval = interval(MAINTENANCE_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        for (name, reclaimed) in shrink_maps(&server).await {
+            if reclaimed > 0 {
+                info!("Reclaimed {reclaimed} slots from {name}");
+            }
+        }
+    }
+}
+
+/// Returns how many slots were reclaimed from each map
+async fn shrink_maps(server: &ServerState) -> [(&'static str, usize); 10] {
+    [
+        (
+            "proxy connections",
+            shrink_if_sparse(&mut *server.proxy_connections.lock().await),
+        ),
+        (
+            "remembered friend requests",
+            shrink_if_sparse(&mut *server.remembered_friend_requests.lock().await),
+        ),
+        (
+            "received friend requests",
+            shrink_if_sparse(&mut *server.received_friend_requests.lock().await),
+        ),
+        (
+            "delivered friend requests",
+            server.delivered_friend_requests.lock().await.shrink(),
+        ),
+        (
+            "port lookups",
+            shrink_if_sparse(&mut *server.port_lookups.lock().await),
+        ),
+        (
+            "active punches",
+            shrink_if_sparse(&mut *server.active_punches.lock().await),
+        ),
+        (
+            "presence subscriptions",
+            server.presence_subscriptions.lock().await.shrink(),
+        ),
+        ("block lists", server.block_lists.lock().await.shrink()),
+        (
+            "setup advisories",
+            server.setup_advisories.lock().await.shrink(),
+        ),
+        ("pending joins", server.pending_joins.lock().await.shrink()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::connection_id::ConnectionId;
+    use crate::connection::proxy_connection_id::ProxyConnectionId;
+    use crate::modules::proxy_server::ProxyWrite;
+    use crate::server_state::FullServerConfig;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::Mutex;
+
+    #[tokio::test]
+    async fn live_proxy_connections_survive() {
+        let server = ServerState::new(FullServerConfig::for_test());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut clients = vec![];
+        {
+            let mut proxy_connections = server.proxy_connections.lock().await;
+            // Left over from a spike
+            proxy_connections.reserve(1000);
+            for id in 0..3 {
+                let client = TcpStream::connect(listener.local_addr().unwrap())
+                    .await
+                    .unwrap();
+                let (socket, _) = listener.accept().await.unwrap();
+                let write = ProxyWrite {
+                    socket: socket.into_split().1,
+                    next_state: 2,
+                    host_responded: false,
+                };
+                let dest = ConnectionId::new(id).unwrap();
+                proxy_connections.insert(ProxyConnectionId(id), (dest, Mutex::new(write)));
+                clients.push(client);
+            }
+        }
+        let capacity = server.proxy_connections.lock().await.capacity();
+
+        let reclaimed = shrink_maps(&server).await;
+        assert_eq!(reclaimed[0].0, "proxy connections");
+        let proxy_connections = server.proxy_connections.lock().await;
+        assert_eq!(reclaimed[0].1, capacity - proxy_connections.capacity());
+        assert!(reclaimed[0].1 > 0);
+        assert_eq!(proxy_connections.len(), 3);
+        for (id, client) in clients.iter_mut().enumerate() {
+            let (dest, write) = &proxy_connections[&ProxyConnectionId(id as u64)];
+            assert_eq!(dest.value(), id as u64);
+            write.lock().await.forward(b"still open").await.unwrap();
+            let mut received = [0; 10];
+            client.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"still open");
+        }
+    }
+}
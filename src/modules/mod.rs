@@ -1,4 +1,8 @@
+pub mod admin_server;
 pub mod analytics;
 pub mod main_server;
+pub mod maintenance;
 pub mod proxy_server;
+pub mod reload;
+pub mod shutdown;
 pub mod signalling_server;
@@ -1,6 +1,9 @@
 use crate::connection::Connection;
 use crate::connection::connection_id::ConnectionId;
+use crate::connection::proxy_connection_id::ProxyConnectionId;
+use crate::invalid_data;
 use crate::json_data::ExternalProxy;
+use crate::modules::analytics::IntervalCounters;
 use crate::protocol::s2c_message::WorldHostS2CMessage;
 use crate::server_state::{FullServerConfig, ServerState};
 use crate::util::mc_packet::{MinecraftPacketAsyncRead, MinecraftPacketRead, MinecraftPacketWrite};
@@ -11,9 +14,11 @@ use std::process::exit;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::io;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
+use tokio::task::yield_now;
 use tokio::time::{Instant, sleep};
 use tokio_util::bytes::Buf;
 
@@ -22,8 +27,8 @@ pub async fn run_proxy_server(server: Arc<ServerState>) {
         info!("Proxy server disabled by request");
         return;
     }
-    if let Some(servers) = &server.config.external_servers {
-        check_for_fallback_message(servers);
+    if let Some(servers) = server.config.external_servers.load_full() {
+        check_for_fallback_message(&servers);
     }
     info!(
         "Starting proxy server on port {}",
@@ -37,7 +42,7 @@ pub async fn run_proxy_server(server: Arc<ServerState>) {
             exit(1);
         });
 
-    let mut next_connection_id = 0u64;
+    let mut next_connection_id = ProxyConnectionId::default();
     info!("Started proxy server on {}", listener.local_addr().unwrap());
     loop {
         let result = listener.accept().await;
@@ -48,7 +53,7 @@ pub async fn run_proxy_server(server: Arc<ServerState>) {
         let (proxy_socket, addr) = result.unwrap();
 
         let connection_id = next_connection_id;
-        next_connection_id = next_connection_id.wrapping_add(1);
+        next_connection_id = next_connection_id.next();
         info!("Accepted proxy connection {connection_id} from {addr}");
 
         let server = server.clone();
@@ -73,7 +78,7 @@ fn check_for_fallback_message(servers: &[Arc<ExternalProxy>]) {
 async fn handle_proxy_connection(
     socket: TcpStream,
     remote_addr: IpAddr,
-    connection_id: u64,
+    connection_id: ProxyConnectionId,
     server: &ServerState,
 ) {
     let mut connection = None;
@@ -96,7 +101,7 @@ async fn handle_proxy_connection(
 async fn handle_inner(
     mut socket: TcpStream,
     remote_addr: IpAddr,
-    connection_id: u64,
+    connection_id: ProxyConnectionId,
     server: &ServerState,
     connection_out: &mut Option<Connection>,
 ) -> io::Result<()> {
@@ -110,27 +115,31 @@ async fn handle_inner(
         handshake_data,
     } = handshake_result.unwrap();
 
-    let mut connection = {
-        let connections = server.connections.lock().await;
-        let connection = connections.by_id(dest_cid);
-        if connection.is_none() {
-            return disconnect(
-                &mut socket,
-                next_state,
-                format!("Couldn't find server with ID {dest_cid}"),
-            )
-            .await;
-        }
-        connection.unwrap().clone()
+    let Some(mut connection) = server.connections.by_id(dest_cid) else {
+        return disconnect(
+            &mut socket,
+            next_state,
+            format!("Couldn't find server with ID {dest_cid}"),
+        )
+        .await;
     };
     *connection_out = Some(connection.clone());
 
     let (mut read, write) = socket.into_split();
-    server
-        .proxy_connections
-        .lock()
-        .await
-        .insert(connection_id, (dest_cid, Mutex::new(write)));
+    {
+        let mut proxy_connections = server.proxy_connections.lock().await;
+        let write = ProxyWrite {
+            socket: write,
+            next_state,
+            host_responded: false,
+        };
+        proxy_connections.insert(connection_id, (dest_cid, Mutex::new(write)));
+        IntervalCounters::record_peak(
+            &server.analytics_counters.peak_proxy_connections,
+            proxy_connections.len(),
+        );
+    }
+    IntervalCounters::increment(&server.analytics_counters.proxy_opened);
 
     connection
         .send_message(&WorldHostS2CMessage::ProxyConnect {
@@ -171,9 +180,7 @@ async fn handle_inner(
             drop(result);
             let failed = loop {
                 sleep(Duration::from_millis(50)).await;
-                if let Some(new_connection) =
-                    server.connections.lock().await.by_id(dest_cid).cloned()
-                {
+                if let Some(new_connection) = server.connections.by_id(dest_cid) {
                     *connection_out = Some(new_connection.clone());
                     connection = new_connection;
                     break false;
@@ -187,6 +194,13 @@ async fn handle_inner(
             }
         };
         if failed {
+            let write = server.proxy_connections.lock().await.remove(&connection_id);
+            if let Some((_, write)) = write {
+                let mut write = write.into_inner();
+                write
+                    .disconnect("Lost connection to the host".to_string())
+                    .await?;
+            }
             break;
         }
     }
@@ -194,26 +208,63 @@ async fn handle_inner(
     Ok(())
 }
 
+/// The client side of a proxy connection, written to with data from the host
+pub struct ProxyWrite {
+    pub socket: OwnedWriteHalf,
+    pub next_state: u8,
+    /// Set once any data from the host has been forwarded. After that, the client may be in play
+    /// state, and the stream may be encrypted or compressed, so a disconnect packet can't be
+    /// injected.
+    pub host_responded: bool,
+}
+
+/// Large proxy packets are written in chunks of this size, yielding in between
+const FORWARD_CHUNK_SIZE: usize = 16 * 1024;
+
+impl ProxyWrite {
+    pub async fn forward(&mut self, data: &[u8]) -> io::Result<()> {
+        self.host_responded = true;
+        for (i, chunk) in data.chunks(FORWARD_CHUNK_SIZE).enumerate() {
+            if i > 0 {
+                yield_now().await;
+            }
+            self.socket.write_all(chunk).await?;
+        }
+        self.socket.flush().await
+    }
+
+    /// Kicks the client with `message` if the host hasn't responded yet, and otherwise only shuts
+    /// the connection down. Whether a responding host left the client in a state where a kick can
+    /// still be injected isn't tracked, since that would mean parsing the host's packets.
+    pub async fn disconnect(&mut self, message: String) -> io::Result<()> {
+        if self.host_responded {
+            return self.socket.shutdown().await;
+        }
+        disconnect(&mut self.socket, self.next_state, message).await
+    }
+}
+
 struct HandshakeResult {
     connection_id: ConnectionId,
     next_state: u8,
     handshake_data: Vec<u8>,
 }
 
+/// A handshake holds two VarInts, an address of at most 255 bytes, a port, and a VarInt
+const MAX_HANDSHAKE_SIZE: i32 = 512;
+
 async fn handshake(
     socket: &mut TcpStream,
     config: &FullServerConfig,
 ) -> io::Result<Option<HandshakeResult>> {
-    let packet_size = socket.read_var_int().await? as usize;
-    let mut handshake_data = vec![0; packet_size];
+    let packet_size = socket.read_var_int().await?;
+    if !(0..=MAX_HANDSHAKE_SIZE).contains(&packet_size) {
+        invalid_data!("Invalid handshake packet size {packet_size}");
+    }
+    let mut handshake_data = vec![0; packet_size as usize];
     socket.read_exact(&mut handshake_data).await?;
 
-    let mut handshake_cursor = Cursor::new(handshake_data.as_slice());
-    handshake_cursor.get_var_int()?; // Packet ID
-    handshake_cursor.get_var_int()?; // Protocol version
-    let this_addr = handshake_cursor.get_mc_string(255)?;
-    let this_port = handshake_cursor.get_u16();
-    let next_state = handshake_cursor.get_var_int()? as u8;
+    let (this_addr, this_port, next_state) = parse_handshake(&handshake_data)?;
 
     let cid_str = &this_addr[..this_addr.find('.').unwrap_or(this_addr.len())];
     Ok(match cid_str.parse() {
@@ -243,7 +294,35 @@ async fn handshake(
     })
 }
 
-async fn disconnect(socket: &mut TcpStream, next_state: u8, message: String) -> io::Result<()> {
+/// Returns the address, port, and next state from a handshake packet's body
+fn parse_handshake(handshake_data: &[u8]) -> io::Result<(String, u16, u8)> {
+    let mut handshake_cursor = Cursor::new(handshake_data);
+    handshake_cursor.get_var_int()?; // Packet ID
+    handshake_cursor.get_var_int()?; // Protocol version
+    let this_addr = handshake_cursor.get_mc_string(255)?;
+    if handshake_cursor.remaining() < 2 {
+        invalid_data!("Handshake packet is missing its port");
+    }
+    let this_port = handshake_cursor.get_u16();
+    let next_state = handshake_cursor.get_var_int()? as u8;
+    Ok((this_addr, this_port, next_state))
+}
+
+/// Sends a kick for a client that hasn't received anything from the host yet. Only the status and
+/// login states are supported, as nothing can be injected once the host has responded.
+async fn disconnect<W: AsyncWrite + Unpin>(
+    socket: &mut W,
+    next_state: u8,
+    message: String,
+) -> io::Result<()> {
+    socket
+        .write_all(&disconnect_packets(next_state, message)?)
+        .await?;
+    socket.flush().await?;
+    socket.shutdown().await
+}
+
+fn disconnect_packets(next_state: u8, message: String) -> io::Result<Vec<u8>> {
     let json_message = format!(r#"{{"text":"{message}","color":"red"}}"#);
 
     let mut packet_data = vec![0x00];
@@ -252,22 +331,114 @@ async fn disconnect(socket: &mut TcpStream, next_state: u8, message: String) ->
     } else if next_state == 2 {
         packet_data.write_mc_string(json_message, 262144)?;
     }
-    let mut packet = Vec::new();
-    packet.write_var_int(packet_data.len() as i32)?;
-    packet.extend_from_slice(&packet_data);
-    socket.write_all(&packet).await?;
-    socket.flush().await?;
+    let mut packets = Vec::new();
+    packets.write_var_int(packet_data.len() as i32)?;
+    packets.extend_from_slice(&packet_data);
 
     if next_state == 1 {
         packet_data.clear();
         packet_data.push(0x01);
         packet_data.extend_from_slice(&[0; 8]);
-        packet.clear();
-        packet.write_var_int(packet_data.len() as i32)?;
-        packet.extend_from_slice(&packet_data);
-        socket.write_all(&packet).await?;
-        socket.flush().await?;
+        packets.write_var_int(packet_data.len() as i32)?;
+        packets.extend_from_slice(&packet_data);
     }
 
-    socket.shutdown().await
+    Ok(packets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::alloc_tracker::largest_allocation;
+    use crate::util::mc_packet::MinecraftPacketWrite;
+    use proptest::prelude::*;
+
+    fn handshake_body(addr: &str, port: u16, next_state: i32) -> Vec<u8> {
+        let mut data = vec![];
+        data.write_var_int(0x00).unwrap();
+        data.write_var_int(767).unwrap();
+        data.write_mc_string(addr.to_string(), 255).unwrap();
+        data.extend(port.to_be_bytes());
+        data.write_var_int(next_state).unwrap();
+        data
+    }
+
+    #[test]
+    fn parses_handshake() {
+        let data = handshake_body("my-id.example.com", 25565, 2);
+        let (addr, port, next_state) = parse_handshake(&data).unwrap();
+        assert_eq!(addr, "my-id.example.com");
+        assert_eq!(port, 25565);
+        assert_eq!(next_state, 2);
+    }
+
+    #[test]
+    fn rejects_truncated_handshakes() {
+        let data = handshake_body("my-id.example.com", 25565, 2);
+        for len in 0..data.len() {
+            assert!(parse_handshake(&data[..len]).is_err(), "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn rejects_long_address() {
+        let mut data = vec![0x00, 0x01];
+        data.write_var_int(i32::MAX).unwrap();
+        assert!(parse_handshake(&data).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn parse_arbitrary_handshake(
+            data in prop::collection::vec(any::<u8>(), 0..=MAX_HANDSHAKE_SIZE as usize),
+        ) {
+            let (_, allocated) = largest_allocation(|| {
+                let _ = parse_handshake(&data);
+            });
+            prop_assert!(allocated <= MAX_HANDSHAKE_SIZE as usize, "{allocated} bytes");
+        }
+    }
+
+    /// A [ProxyWrite] to a connected client
+    async fn proxy_write(next_state: u8) -> (ProxyWrite, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (_, socket) = server.into_split();
+        let write = ProxyWrite {
+            socket,
+            next_state,
+            host_responded: false,
+        };
+        (write, client)
+    }
+
+    #[tokio::test]
+    async fn disconnect_before_host_responded() {
+        for next_state in [1, 2] {
+            let (mut write, mut client) = proxy_write(next_state).await;
+            write.disconnect("Gone".to_string()).await.unwrap();
+            let mut received = vec![];
+            client.read_to_end(&mut received).await.unwrap();
+            assert_eq!(
+                received,
+                disconnect_packets(next_state, "Gone".to_string()).unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnect_after_host_responded() {
+        for next_state in [1, 2] {
+            let (mut write, mut client) = proxy_write(next_state).await;
+            write.forward(b"from the host").await.unwrap();
+            assert!(write.host_responded);
+            write.disconnect("Gone".to_string()).await.unwrap();
+            let mut received = vec![];
+            client.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, b"from the host");
+        }
+    }
 }
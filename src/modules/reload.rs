@@ -0,0 +1,176 @@
+use crate::json_data::{
+    EXTERNAL_PROXIES_PATH, RESERVED_IDS_PATH, read_external_proxies, read_reserved_ids,
+};
+use crate::server_state::{FullServerConfig, ServerState};
+use log::{error, info, warn};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Rereads external_proxies.json and reserved_ids.json whenever SIGHUP is received. An invalid
+/// file is reported and its previous contents are kept.
+pub async fn run_reload_handler(server: Arc<ServerState>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut reload = match signal(SignalKind::hangup()) {
+            Ok(reload) => reload,
+            Err(error) => {
+                error!("Failed to listen for SIGHUP: {error}");
+                return;
+            }
+        };
+        while reload.recv().await.is_some() {
+            info!("Reloading config files because SIGHUP was received");
+            reload_external_proxies(&server.config, Path::new(EXTERNAL_PROXIES_PATH));
+            reload_reserved_ids(&server.config, Path::new(RESERVED_IDS_PATH));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = server;
+}
+
+/// Connections keep the proxy they were already sent. The local entry's baseAddr is only read at
+/// startup. Returns whether the file was valid.
+pub fn reload_external_proxies(config: &FullServerConfig, path: &Path) -> bool {
+    let servers = match read_external_proxies(path) {
+        Ok(servers) => servers,
+        Err(error) => {
+            error.log();
+            warn!("Keeping the previous external proxies");
+            return false;
+        }
+    };
+    match &servers {
+        Some(servers) => info!("Reloaded {} external proxies", servers.len()),
+        None => info!("{EXTERNAL_PROXIES_PATH} was removed, so no external proxies will be used"),
+    }
+    config
+        .external_servers
+        .store(servers.map(|servers| Arc::new(servers.into_iter().map(Arc::new).collect())));
+    true
+}
+
+/// Connections already using a newly reserved ID keep it until they disconnect. Returns whether
+/// the file was valid.
+pub fn reload_reserved_ids(config: &FullServerConfig, path: &Path) -> bool {
+    match read_reserved_ids(path) {
+        Ok(reserved_ids) => {
+            info!("Reloaded {} reserved connection IDs", reserved_ids.len());
+            config.reserved_ids.store(Arc::new(reserved_ids));
+            true
+        }
+        Err(error) => {
+            error!("Error reading {RESERVED_IDS_PATH}: {error}");
+            warn!("Keeping the previous reserved connection IDs");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::connection_id::ConnectionId;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+    use uuid::Uuid;
+
+    /// A file in the temp directory that's deleted when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            Self(
+                std::env::temp_dir()
+                    .join(format!("world-host-server-{}-{name}", std::process::id())),
+            )
+        }
+
+        fn write(&self, contents: &str) {
+            fs::write(&self.0, contents).unwrap();
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn proxy_addrs(config: &FullServerConfig) -> Option<Vec<Option<String>>> {
+        config
+            .external_servers
+            .load()
+            .as_ref()
+            .map(|servers| servers.iter().map(|proxy| proxy.addr.clone()).collect())
+    }
+
+    #[test]
+    fn reload_replaces_proxies() {
+        let config = FullServerConfig::for_test();
+        let file = TempFile::new("reload_replaces_proxies.json");
+        file.write(
+            r#"[{"lat_long": [51.5, -0.1], "addr": "london.example.com"}, {"lat_long": [0, 0]}]"#,
+        );
+        assert!(reload_external_proxies(&config, &file.0));
+        assert_eq!(
+            proxy_addrs(&config),
+            Some(vec![Some("london.example.com".to_string()), None])
+        );
+
+        fs::remove_file(&file.0).unwrap();
+        assert!(reload_external_proxies(&config, &file.0));
+        assert_eq!(proxy_addrs(&config), None);
+    }
+
+    #[test]
+    fn invalid_reload_keeps_proxies() {
+        let config = FullServerConfig::for_test();
+        let file = TempFile::new("invalid_reload_keeps_proxies.json");
+        file.write(r#"[{"lat_long": [51.5, -0.1], "addr": "london.example.com"}]"#);
+        assert!(reload_external_proxies(&config, &file.0));
+
+        for invalid in [
+            "{",
+            r#"[{"addr": "paris.example.com"}]"#,
+            r#"[{"lat_long": [0, 0]}, {"lat_long": [1, 1]}]"#,
+        ] {
+            file.write(invalid);
+            assert!(!reload_external_proxies(&config, &file.0));
+            assert_eq!(
+                proxy_addrs(&config),
+                Some(vec![Some("london.example.com".to_string())])
+            );
+        }
+    }
+
+    #[test]
+    fn reload_replaces_reserved_ids() {
+        let config = FullServerConfig::for_test();
+        let file = TempFile::new("reload_replaces_reserved_ids.json");
+        let id = ConnectionId::new(1).unwrap();
+        let owner = Uuid::from_u128(2);
+        file.write(&format!(r#"{{"{id}": "{owner}"}}"#));
+        assert!(reload_reserved_ids(&config, &file.0));
+        assert_eq!(
+            *config.reserved_ids.load_full(),
+            HashMap::from([(id, owner)])
+        );
+
+        // Duplicates keep the previous IDs
+        file.write(&format!(
+            r#"{{"{id}": "{owner}", "{}": "{owner}"}}"#,
+            id.short_string()
+        ));
+        assert!(!reload_reserved_ids(&config, &file.0));
+        assert_eq!(
+            *config.reserved_ids.load_full(),
+            HashMap::from([(id, owner)])
+        );
+
+        fs::remove_file(&file.0).unwrap();
+        assert!(reload_reserved_ids(&config, &file.0));
+        assert!(config.reserved_ids.load().is_empty());
+    }
+}
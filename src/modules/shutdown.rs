@@ -0,0 +1,93 @@
+use crate::modules::analytics::{write_analytics_sample, write_final_sample_now};
+use crate::server_state::ServerState;
+use log::{error, info};
+use std::future::pending;
+use std::process::exit;
+use std::sync::{Arc, mpsc};
+use std::time::Duration;
+use std::{panic, thread};
+use tokio::signal;
+use tokio::time::sleep;
+
+/// How long a panic waits for the final analytics sample before letting the process end
+const PANIC_SAMPLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Waits for shutdown_time, Ctrl+C, or SIGTERM, then writes a final analytics sample and exits.
+/// Also writes one if a panic ends the process.
+pub async fn run_shutdown_handler(server: Arc<ServerState>) {
+    if !server.config.analytics_time.is_zero() {
+        install_panic_hook(server.clone());
+    }
+    let shutdown_time = async {
+        match server.config.shutdown_time {
+            Some(shutdown_time) => {
+                info!("Automatically shutting down after {shutdown_time:?}");
+                sleep(shutdown_time).await;
+                format!("shutdown_time ({shutdown_time:?}) was reached")
+            }
+            None => pending().await,
+        }
+    };
+    let reason = tokio::select! {
+        reason = shutdown_time => reason,
+        reason = wait_for_signal() => reason,
+    };
+    info!("Shutting down because {reason}");
+    if !server.config.analytics_time.is_zero() {
+        info!("Writing final analytics sample");
+        write_analytics_sample(&server, true).await;
+    }
+    exit(0);
+}
+
+/// Writes a final analytics sample when a panic is about to end the process. That's a panic on the
+/// main thread, which runs the main server, or any panic when built with panic=abort. tokio catches
+/// panics in other tasks and the server keeps running, so those are only reported as usual.
+fn install_panic_hook(server: Arc<ServerState>) {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        if !cfg!(panic = "abort") && thread::current().name() != Some("main") {
+            return;
+        }
+        // Written from another thread, since this one may hold a lock that the sample needs
+        let (written, wait) = mpsc::channel();
+        let server = server.clone();
+        thread::spawn(move || {
+            info!("Writing final analytics sample after a panic");
+            let _ = written.send(write_final_sample_now(&server));
+        });
+        if wait.recv_timeout(PANIC_SAMPLE_TIMEOUT).is_err() {
+            error!("Timed out writing final analytics sample");
+        }
+    }));
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> String {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(error) => {
+            error!("Failed to listen for SIGTERM: {error}");
+            return wait_for_ctrl_c().await;
+        }
+    };
+    tokio::select! {
+        reason = wait_for_ctrl_c() => reason,
+        _ = terminate.recv() => "SIGTERM was received".to_string(),
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> String {
+    wait_for_ctrl_c().await
+}
+
+async fn wait_for_ctrl_c() -> String {
+    if let Err(error) = signal::ctrl_c().await {
+        error!("Failed to listen for Ctrl+C: {error}");
+        pending::<()>().await;
+    }
+    "Ctrl+C was pressed".to_string()
+}
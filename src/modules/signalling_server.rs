@@ -1,3 +1,5 @@
+use crate::modules::analytics::IntervalCounters;
+use crate::protocol::message_handler::count_skipped;
 use crate::protocol::s2c_message::WorldHostS2CMessage;
 use crate::server_state::ServerState;
 use crate::util::copy_to_fixed_size;
@@ -33,6 +35,7 @@ pub async fn run_signalling_server(server: Arc<ServerState>) {
             loop {
                 interval.tick().await;
                 cleanup_expired_punch_requests(server.as_ref()).await;
+                cleanup_expired_punches(server.as_ref()).await;
             }
         });
     }
@@ -50,22 +53,26 @@ pub async fn run_signalling_server(server: Arc<ServerState>) {
             continue;
         }
 
+        IntervalCounters::increment(&server.analytics_counters.signals);
         let signal = copy_to_fixed_size(&signal);
         let server = server.clone();
         tokio::spawn(async move {
             let lookup_id = Uuid::from_bytes(signal);
             if let Some(request) = server.port_lookups.lock().await.remove(&lookup_id)
-                && let Some(connection) =
-                    server.connections.lock().await.by_id(request.source_client)
+                && let Some(connection) = server.connections.by_id(request.source_client)
             {
+                IntervalCounters::increment(&server.analytics_counters.port_lookups_completed);
                 // If it's already been closed, well there's nothing we can do about it
-                let _ = connection
+                if let Ok(outcome) = connection
                     .send_message(&WorldHostS2CMessage::PortLookupSuccess {
                         lookup_id,
                         host: addr.ip().to_string(),
                         port: addr.port(),
                     })
-                    .await;
+                    .await
+                {
+                    count_skipped(&server, outcome);
+                }
             }
         });
     }
@@ -86,15 +93,127 @@ async fn cleanup_expired_punch_requests(server: &ServerState) {
             {
                 continue;
             }
-            if let Some(connection) = server.connections.lock().await.by_id(request.source_client) {
-                let _ = connection
+            if let Some(connection) = server.connections.by_id(request.source_client)
+                && let Ok(outcome) = connection
                     .send_message(&WorldHostS2CMessage::CancelPortLookup {
                         lookup_id: request.lookup_id,
                     })
-                    .await;
+                    .await
+            {
+                count_skipped(server, outcome);
             }
         } else {
             break;
         }
     }
 }
+
+async fn cleanup_expired_punches(server: &ServerState) {
+    let time = Instant::now();
+    let mut punches = server.punch_by_expiry.lock().await;
+    while let Ok((expiry, punch_id)) = punches.peek() {
+        if time <= expiry {
+            break;
+        }
+        punches.remove().unwrap();
+        let mut active_punches = server.active_punches.lock().await;
+        // The punch may have finished already, and its ID been reused by a newer one
+        if active_punches
+            .get(&punch_id)
+            .is_some_and(|punch| punch.expiry == expiry)
+        {
+            active_punches.remove(&punch_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::connection_id::ConnectionId;
+    use crate::connection::{Connection, ConnectionInfo};
+    use crate::protocol::c2s_message::WorldHostC2SMessage;
+    use crate::protocol::message_handler::handle_message;
+    use crate::protocol::protocol_versions::CURRENT;
+    use crate::protocol::punch::PUNCH_EXPIRY;
+    use crate::server_state::FullServerConfig;
+    use std::net::Ipv4Addr;
+    use tokio::io::DuplexStream;
+    use tokio::time::advance;
+
+    const PUNCH_ID: Uuid = Uuid::from_u128(0x9000);
+
+    fn connect(server: &ServerState, id: u64) -> (Connection, DuplexStream) {
+        let (connection, client) = ConnectionInfo::for_test(
+            ConnectionId::new(id).unwrap(),
+            Uuid::from_u128(id as u128),
+            Ipv4Addr::LOCALHOST.into(),
+            CURRENT,
+        );
+        assert!(server.connections.add(connection.clone()));
+        (connection, client)
+    }
+
+    async fn request_punch(server: &ServerState, initiator: &Connection, target: &Connection) {
+        handle_message(
+            WorldHostC2SMessage::RequestPunchOpen {
+                target_connection: target.id,
+                purpose: "test".to_string(),
+                punch_id: PUNCH_ID,
+                my_host: "example.com".to_string(),
+                my_port: 25565,
+                my_local_host: "example.com".to_string(),
+                my_local_port: 25565,
+            },
+            initiator,
+            server,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn punches_expire() {
+        let server = ServerState::new(FullServerConfig::for_test());
+        let (initiator, _initiator_client) = connect(&server, 1);
+        let (target, _target_client) = connect(&server, 2);
+        request_punch(&server, &initiator, &target).await;
+
+        advance(PUNCH_EXPIRY - Duration::from_secs(1)).await;
+        cleanup_expired_punches(&server).await;
+        assert!(server.active_punches.lock().await.contains_key(&PUNCH_ID));
+
+        advance(Duration::from_secs(2)).await;
+        cleanup_expired_punches(&server).await;
+        assert!(server.active_punches.lock().await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_expiry_keeps_reused_id() {
+        let server = ServerState::new(FullServerConfig::for_test());
+        let (initiator, _initiator_client) = connect(&server, 1);
+        let (target, _target_client) = connect(&server, 2);
+        request_punch(&server, &initiator, &target).await;
+        handle_message(
+            WorldHostC2SMessage::PunchFailed {
+                target_connection: initiator.id,
+                punch_id: PUNCH_ID,
+            },
+            &target,
+            &server,
+        )
+        .await
+        .unwrap();
+
+        advance(Duration::from_secs(10)).await;
+        request_punch(&server, &initiator, &target).await;
+        advance(PUNCH_EXPIRY - Duration::from_secs(5)).await;
+        // Only the first punch's queue entry is due
+        cleanup_expired_punches(&server).await;
+        assert!(server.active_punches.lock().await.contains_key(&PUNCH_ID));
+
+        advance(Duration::from_secs(10)).await;
+        cleanup_expired_punches(&server).await;
+        assert!(server.active_punches.lock().await.is_empty());
+    }
+}
@@ -0,0 +1,94 @@
+use crate::util::shrink_if_sparse;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+pub const MAX_BLOCKED_PER_USER: usize = 1024;
+pub const MAX_TOTAL_BLOCKED: usize = 1 << 20;
+
+/// The users each user blocked with BlockUser. Keyed by user rather than connection, so that blocks
+/// apply to all of a user's connections and survive reconnects.
+#[derive(Default)]
+pub struct BlockLists {
+    by_user: HashMap<Uuid, HashSet<Uuid>>,
+    total: usize,
+}
+
+impl BlockLists {
+    /// Fails if `blocker` already blocked [MAX_BLOCKED_PER_USER] users, or the server is at
+    /// [MAX_TOTAL_BLOCKED]
+    pub fn block(&mut self, blocker: Uuid, user: Uuid) -> Result<(), String> {
+        if self.blocks(blocker, user) {
+            return Ok(());
+        }
+        if self.total >= MAX_TOTAL_BLOCKED {
+            return Err("Too many users are blocked on this server".to_string());
+        }
+        let blocked = self.by_user.entry(blocker).or_default();
+        if blocked.len() >= MAX_BLOCKED_PER_USER {
+            return Err(format!(
+                "Can't block more than {MAX_BLOCKED_PER_USER} users"
+            ));
+        }
+        blocked.insert(user);
+        self.total += 1;
+        Ok(())
+    }
+
+    pub fn unblock(&mut self, blocker: Uuid, user: Uuid) {
+        if let Some(blocked) = self.by_user.get_mut(&blocker) {
+            if blocked.remove(&user) {
+                self.total -= 1;
+            }
+            if blocked.is_empty() {
+                self.by_user.remove(&blocker);
+            }
+        }
+    }
+
+    pub fn blocks(&self, blocker: Uuid, user: Uuid) -> bool {
+        self.by_user
+            .get(&blocker)
+            .is_some_and(|blocked| blocked.contains(&user))
+    }
+
+    /// Returns how many slots were reclaimed
+    pub fn shrink(&mut self) -> usize {
+        shrink_if_sparse(&mut self.by_user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCKER: Uuid = Uuid::from_u128(1);
+
+    #[test]
+    fn per_user_cap() {
+        let mut block_lists = BlockLists::default();
+        for i in 0..MAX_BLOCKED_PER_USER as u128 {
+            block_lists
+                .block(BLOCKER, Uuid::from_u128(0x1000 + i))
+                .unwrap();
+        }
+        let over = Uuid::from_u128(0x1000 + MAX_BLOCKED_PER_USER as u128);
+        assert!(block_lists.block(BLOCKER, over).is_err());
+        // Blocking someone already blocked isn't an error
+        block_lists.block(BLOCKER, Uuid::from_u128(0x1000)).unwrap();
+
+        block_lists.unblock(BLOCKER, Uuid::from_u128(0x1000));
+        block_lists.block(BLOCKER, over).unwrap();
+        assert_eq!(block_lists.total, MAX_BLOCKED_PER_USER);
+    }
+
+    #[test]
+    fn unblocking_everyone_forgets_the_user() {
+        let mut block_lists = BlockLists::default();
+        block_lists.block(BLOCKER, Uuid::from_u128(2)).unwrap();
+        assert!(block_lists.blocks(BLOCKER, Uuid::from_u128(2)));
+        assert!(!block_lists.blocks(Uuid::from_u128(2), BLOCKER));
+        block_lists.unblock(BLOCKER, Uuid::from_u128(2));
+        assert!(block_lists.by_user.is_empty());
+        assert_eq!(block_lists.total, 0);
+    }
+}
@@ -1,7 +1,10 @@
 use crate::connection::connection_id::ConnectionId;
+use crate::connection::proxy_connection_id::ProxyConnectionId;
 use crate::invalid_data;
 use crate::protocol::data_ext::WHReadBytesExt;
 use crate::protocol::join_type::JoinType;
+use crate::protocol::message_table::{MessageInfo, check_table, lookup};
+use crate::protocol::protocol_versions::ProtocolCapabilities;
 use byteorder::{BigEndian, ReadBytesExt};
 use std::io;
 use std::io::{Cursor, Read};
@@ -24,6 +27,13 @@ pub const REQUEST_PUNCH_OPEN_ID: u8 = 12;
 pub const PUNCH_FAILED_ID: u8 = 13;
 pub const BEGIN_PORT_LOOKUP_ID: u8 = 14;
 pub const PUNCH_SUCCESS_ID: u8 = 15;
+pub const SUBSCRIBE_PRESENCE_ID: u8 = 16;
+pub const CANCEL_FRIEND_REQUEST_ID: u8 = 17;
+pub const BLOCK_USER_ID: u8 = 18;
+pub const UNBLOCK_USER_ID: u8 = 19;
+
+/// Maximum number of friends in a single message. --max-friends can only be set lower than this.
+pub const MAX_FRIENDS: usize = 8192;
 
 #[derive(Clone, Debug)]
 pub enum WorldHostC2SMessage {
@@ -54,11 +64,11 @@ pub enum WorldHostC2SMessage {
         data: Vec<u8>,
     },
     ProxyS2CPacket {
-        connection_id: u64,
+        connection_id: ProxyConnectionId,
         data: Vec<u8>,
     },
     ProxyDisconnect {
-        connection_id: u64,
+        connection_id: ProxyConnectionId,
     },
     RequestDirectJoin {
         connection_id: ConnectionId,
@@ -91,9 +101,79 @@ pub enum WorldHostC2SMessage {
         host: String,
         port: u16,
     },
+    SubscribePresence {
+        friends: Vec<Uuid>,
+    },
+    CancelFriendRequest {
+        to_user: Uuid,
+    },
+    BlockUser {
+        user: Uuid,
+    },
+    UnblockUser {
+        user: Uuid,
+    },
 }
 
 impl WorldHostC2SMessage {
+    pub fn type_id(&self) -> u8 {
+        use WorldHostC2SMessage::*;
+        match self {
+            ListOnline { .. } => LIST_ONLINE_ID,
+            FriendRequest { .. } => FRIEND_REQUEST_ID,
+            PublishedWorld { .. } => PUBLISHED_WORLD_ID,
+            ClosedWorld { .. } => CLOSED_WORLD_ID,
+            RequestJoin { .. } => REQUEST_JOIN_ID,
+            JoinGranted { .. } => JOIN_GRANTED_ID,
+            QueryRequest { .. } => QUERY_REQUEST_ID,
+            QueryResponse { .. } => QUERY_RESPONSE_ID,
+            ProxyS2CPacket { .. } => PROXY_S2C_PACKET_ID,
+            ProxyDisconnect { .. } => PROXY_DISCONNECT_ID,
+            RequestDirectJoin { .. } => REQUEST_DIRECT_JOIN_ID,
+            NewQueryResponse { .. } => NEW_QUERY_RESPONSE_ID,
+            RequestPunchOpen { .. } => REQUEST_PUNCH_OPEN_ID,
+            PunchFailed { .. } => PUNCH_FAILED_ID,
+            BeginPortLookup { .. } => BEGIN_PORT_LOOKUP_ID,
+            PunchSuccess { .. } => PUNCH_SUCCESS_ID,
+            SubscribePresence { .. } => SUBSCRIBE_PRESENCE_ID,
+            CancelFriendRequest { .. } => CANCEL_FRIEND_REQUEST_ID,
+            BlockUser { .. } => BLOCK_USER_ID,
+            UnblockUser { .. } => UNBLOCK_USER_ID,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        message_name(self.type_id())
+    }
+
+    /// Like the Debug output, but with payloads replaced by their length
+    pub fn summary(&self) -> String {
+        use WorldHostC2SMessage::*;
+        match self {
+            QueryResponse {
+                connection_id,
+                data,
+            }
+            | NewQueryResponse {
+                connection_id,
+                data,
+            } => format!(
+                "{} {{ connection_id: {connection_id:?}, data: <{} bytes> }}",
+                self.name(),
+                data.len()
+            ),
+            ProxyS2CPacket {
+                connection_id,
+                data,
+            } => format!(
+                "{} {{ connection_id: {connection_id:?}, data: <{} bytes> }}",
+                self.name(),
+                data.len()
+            ),
+            _ => format!("{self:?}"),
+        }
+    }
+
     pub fn parse(id: u8, data: &[u8], max_protocol_version: Option<u32>) -> io::Result<Self> {
         let first_protocol = first_protocol_version(id);
         if first_protocol.is_none() {
@@ -107,7 +187,20 @@ impl WorldHostC2SMessage {
                 "Received too new message from client. Client has version {max_protocol}, but message ID {id} was added in {first_protocol}."
             );
         }
-        Self::parse_raw(id, &mut Cursor::new(data))
+        let mut cursor = Cursor::new(data);
+        let message = Self::parse_raw(id, &mut cursor)?;
+        if cursor.has_remaining()
+            && !consumes_remaining(id)
+            && max_protocol_version
+                .is_none_or(|version| ProtocolCapabilities::from_version(version).strict_parsing)
+        {
+            invalid_data!(
+                "Received {} message with {} trailing bytes",
+                message_name(id),
+                cursor.remaining()
+            );
+        }
+        Ok(message)
     }
 
     pub fn parse_raw(id: u8, cursor: &mut Cursor<&[u8]>) -> io::Result<Self> {
@@ -138,6 +231,9 @@ impl WorldHostC2SMessage {
             QUERY_RESPONSE_ID => {
                 let connection_id = cursor.read_connection_id()?;
                 let len = cursor.read_u32::<BigEndian>()? as usize;
+                if len > cursor.remaining() {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
                 let mut data = vec![0; len];
                 cursor.read_exact(&mut data)?;
                 Ok(QueryResponse {
@@ -146,11 +242,11 @@ impl WorldHostC2SMessage {
                 })
             }
             PROXY_S2C_PACKET_ID => Ok(ProxyS2CPacket {
-                connection_id: cursor.read_u64::<BigEndian>()?,
+                connection_id: cursor.read_proxy_connection_id()?,
                 data: Self::read_remaining(cursor)?,
             }),
             PROXY_DISCONNECT_ID => Ok(ProxyDisconnect {
-                connection_id: cursor.read_u64::<BigEndian>()?,
+                connection_id: cursor.read_proxy_connection_id()?,
             }),
             REQUEST_DIRECT_JOIN_ID => Ok(RequestDirectJoin {
                 connection_id: cursor.read_connection_id()?,
@@ -181,12 +277,31 @@ impl WorldHostC2SMessage {
                 host: cursor.read_string()?,
                 port: cursor.read_u16::<BigEndian>()?,
             }),
+            SUBSCRIBE_PRESENCE_ID => Ok(SubscribePresence {
+                friends: Self::read_uuid_vec(cursor)?,
+            }),
+            CANCEL_FRIEND_REQUEST_ID => Ok(CancelFriendRequest {
+                to_user: cursor.read_uuid()?,
+            }),
+            BLOCK_USER_ID => Ok(BlockUser {
+                user: cursor.read_uuid()?,
+            }),
+            UNBLOCK_USER_ID => Ok(UnblockUser {
+                user: cursor.read_uuid()?,
+            }),
             _ => invalid_data!("Unknown message ID {id}"),
         }
     }
 
     fn read_uuid_vec(cursor: &mut Cursor<&[u8]>) -> io::Result<Vec<Uuid>> {
-        cursor.read_vec(|c| c.read_uuid())
+        // A list that's longer than the rest of the message is rejected before allocating for it
+        let start = cursor.position();
+        let len = cursor.read_u32::<BigEndian>()? as usize;
+        if len <= MAX_FRIENDS && len * size_of::<Uuid>() > cursor.remaining() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        cursor.set_position(start);
+        cursor.read_vec(MAX_FRIENDS, |c| c.read_uuid())
     }
 
     fn read_remaining(cursor: &mut Cursor<&[u8]>) -> io::Result<Vec<u8>> {
@@ -196,24 +311,219 @@ impl WorldHostC2SMessage {
     }
 }
 
+/// Every C2S message, indexed by type ID
+pub const C2S_MESSAGES: &[MessageInfo] = &[
+    MessageInfo::new("ListOnline", LIST_ONLINE_ID, 2),
+    MessageInfo::new("FriendRequest", FRIEND_REQUEST_ID, 2),
+    MessageInfo::new("PublishedWorld", PUBLISHED_WORLD_ID, 2),
+    MessageInfo::new("ClosedWorld", CLOSED_WORLD_ID, 2),
+    MessageInfo::new("RequestJoin", REQUEST_JOIN_ID, 2),
+    MessageInfo::new("JoinGranted", JOIN_GRANTED_ID, 2),
+    MessageInfo::new("QueryRequest", QUERY_REQUEST_ID, 2),
+    MessageInfo::new("QueryResponse", QUERY_RESPONSE_ID, 2),
+    MessageInfo::new("ProxyS2CPacket", PROXY_S2C_PACKET_ID, 2),
+    MessageInfo::new("ProxyDisconnect", PROXY_DISCONNECT_ID, 2),
+    MessageInfo::new("RequestDirectJoin", REQUEST_DIRECT_JOIN_ID, 4),
+    MessageInfo::new("NewQueryResponse", NEW_QUERY_RESPONSE_ID, 5),
+    MessageInfo::new("RequestPunchOpen", REQUEST_PUNCH_OPEN_ID, 7),
+    MessageInfo::new("PunchFailed", PUNCH_FAILED_ID, 7),
+    MessageInfo::new("BeginPortLookup", BEGIN_PORT_LOOKUP_ID, 7),
+    MessageInfo::new("PunchSuccess", PUNCH_SUCCESS_ID, 7),
+    MessageInfo::new("SubscribePresence", SUBSCRIBE_PRESENCE_ID, 8),
+    MessageInfo::new("CancelFriendRequest", CANCEL_FRIEND_REQUEST_ID, 8),
+    MessageInfo::new("BlockUser", BLOCK_USER_ID, 8),
+    MessageInfo::new("UnblockUser", UNBLOCK_USER_ID, 8),
+];
+
+const _: () = check_table(C2S_MESSAGES);
+
 pub fn first_protocol_version(id: u8) -> Option<u32> {
-    match id {
-        LIST_ONLINE_ID => Some(2),
-        FRIEND_REQUEST_ID => Some(2),
-        PUBLISHED_WORLD_ID => Some(2),
-        CLOSED_WORLD_ID => Some(2),
-        REQUEST_JOIN_ID => Some(2),
-        JOIN_GRANTED_ID => Some(2),
-        QUERY_REQUEST_ID => Some(2),
-        QUERY_RESPONSE_ID => Some(2),
-        PROXY_S2C_PACKET_ID => Some(2),
-        PROXY_DISCONNECT_ID => Some(2),
-        REQUEST_DIRECT_JOIN_ID => Some(4),
-        NEW_QUERY_RESPONSE_ID => Some(5),
-        REQUEST_PUNCH_OPEN_ID => Some(7),
-        PUNCH_FAILED_ID => Some(7),
-        BEGIN_PORT_LOOKUP_ID => Some(7),
-        PUNCH_SUCCESS_ID => Some(7),
-        _ => None,
+    lookup(C2S_MESSAGES, id).map(|info| info.first_protocol)
+}
+
+pub fn message_name(id: u8) -> &'static str {
+    lookup(C2S_MESSAGES, id).map_or("Unknown", |info| info.name)
+}
+
+/// Messages that end in a variable-length payload. The deprecated QueryResponse is length-prefixed,
+/// but is left lenient since only old clients send it.
+fn consumes_remaining(id: u8) -> bool {
+    matches!(
+        id,
+        PROXY_S2C_PACKET_ID | NEW_QUERY_RESPONSE_ID | QUERY_RESPONSE_ID
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::protocol_versions::{CURRENT, STABLE};
+    use crate::util::alloc_tracker::largest_allocation;
+    use proptest::prelude::*;
+
+    /// The most any message may allocate: a full friends list, or a copy of the message
+    fn max_allocation(data: &[u8]) -> usize {
+        (MAX_FRIENDS * size_of::<Uuid>()).max(data.len())
+    }
+
+    fn check_parse(id: u8, data: &[u8], max_protocol_version: Option<u32>) {
+        let (_, allocated) = largest_allocation(|| {
+            let _ = WorldHostC2SMessage::parse(id, data, max_protocol_version);
+        });
+        assert!(
+            allocated <= max_allocation(data),
+            "Parsing {} bytes of {id} allocated {allocated} bytes",
+            data.len()
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn parse_arbitrary_bytes(
+            id in any::<u8>(),
+            data in prop::collection::vec(any::<u8>(), 0..1024),
+            max_protocol_version in prop::option::of(0..16u32),
+        ) {
+            check_parse(id, &data, max_protocol_version);
+        }
+
+        /// Puts an arbitrary u32 where list and QueryResponse lengths are read
+        #[test]
+        fn parse_arbitrary_lengths(
+            id in 0..C2S_MESSAGES.len() as u8,
+            head in prop::collection::vec(any::<u8>(), 0..=8),
+            length in any::<u32>(),
+            tail in prop::collection::vec(any::<u8>(), 0..64),
+        ) {
+            let data = [head.as_slice(), &length.to_be_bytes(), &tail].concat();
+            check_parse(id, &data, None);
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_friends_list() {
+        let data = u32::MAX.to_be_bytes();
+        let error = WorldHostC2SMessage::parse(LIST_ONLINE_ID, &data, None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_query_response_longer_than_message() {
+        let mut data = 1u64.to_be_bytes().to_vec();
+        data.extend(u32::MAX.to_be_bytes());
+        let error = WorldHostC2SMessage::parse(QUERY_RESPONSE_ID, &data, None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    /// Like the Kotlin server, the length field decides how much is data, and anything after it is
+    /// ignored even from clients that can't send trailing bytes
+    #[test]
+    fn query_response_length_is_authoritative() {
+        let mut data = 1u64.to_be_bytes().to_vec();
+        data.extend(2u32.to_be_bytes());
+        data.extend([1, 2, 3, 4]);
+        for max_protocol_version in [None, Some(CURRENT)] {
+            match WorldHostC2SMessage::parse(QUERY_RESPONSE_ID, &data, max_protocol_version) {
+                Ok(WorldHostC2SMessage::QueryResponse { data, .. }) => assert_eq!(data, [1, 2]),
+                result => panic!("Expected QueryResponse, got {result:?}"),
+            }
+        }
+    }
+
+    /// Far below the [max_allocation] of a full friends list
+    const SMALL_ALLOCATION: usize = 1024;
+
+    fn parse_hostile(id: u8, data: &[u8]) -> io::ErrorKind {
+        let (result, allocated) =
+            largest_allocation(|| WorldHostC2SMessage::parse(id, data, Some(CURRENT)));
+        assert!(
+            allocated < SMALL_ALLOCATION,
+            "Parsing {} allocated {allocated} bytes",
+            message_name(id)
+        );
+        result.unwrap_err().kind()
+    }
+
+    #[test]
+    fn hostile_list_lengths_rejected_before_allocating() {
+        for id in [LIST_ONLINE_ID, PUBLISHED_WORLD_ID, SUBSCRIBE_PRESENCE_ID] {
+            assert_eq!(
+                parse_hostile(id, &u32::MAX.to_be_bytes()),
+                io::ErrorKind::InvalidData
+            );
+            // Within the cap, but longer than the message
+            let mut data = (MAX_FRIENDS as u32).to_be_bytes().to_vec();
+            data.extend(1u128.to_be_bytes());
+            assert_eq!(parse_hostile(id, &data), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn hostile_query_response_length_rejected_before_allocating() {
+        for len in [u32::MAX, 1 << 20] {
+            let mut data = 1u64.to_be_bytes().to_vec();
+            data.extend(len.to_be_bytes());
+            data.extend([0; 16]);
+            assert_eq!(
+                parse_hostile(QUERY_RESPONSE_ID, &data),
+                io::ErrorKind::UnexpectedEof
+            );
+        }
+    }
+
+    /// The shortest valid payload of each message that can't end in variable-length data
+    fn minimal_payload(id: u8) -> Vec<u8> {
+        let connection_id = 1u64.to_be_bytes();
+        let uuid = 1u128.to_be_bytes();
+        let empty_list = 0u32.to_be_bytes();
+        let empty_string = 0u16.to_be_bytes();
+        let port = 25565u16.to_be_bytes();
+        match id {
+            LIST_ONLINE_ID
+            | PUBLISHED_WORLD_ID
+            | CLOSED_WORLD_ID
+            | QUERY_REQUEST_ID
+            | SUBSCRIBE_PRESENCE_ID => empty_list.to_vec(),
+            FRIEND_REQUEST_ID
+            | REQUEST_JOIN_ID
+            | BEGIN_PORT_LOOKUP_ID
+            | CANCEL_FRIEND_REQUEST_ID
+            | BLOCK_USER_ID
+            | UNBLOCK_USER_ID => uuid.to_vec(),
+            JOIN_GRANTED_ID => [&connection_id[..], &[1]].concat(),
+            PROXY_DISCONNECT_ID | REQUEST_DIRECT_JOIN_ID => connection_id.to_vec(),
+            REQUEST_PUNCH_OPEN_ID => [
+                &connection_id[..],
+                &empty_string,
+                &uuid,
+                &empty_string,
+                &port,
+                &empty_string,
+                &port,
+            ]
+            .concat(),
+            PUNCH_FAILED_ID => [&connection_id[..], &uuid].concat(),
+            PUNCH_SUCCESS_ID => [&connection_id[..], &uuid, &empty_string, &port].concat(),
+            _ => panic!("No payload for {}", message_name(id)),
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_rejected_on_strict_versions() {
+        for info in C2S_MESSAGES {
+            let id = info.id;
+            if consumes_remaining(id) {
+                continue;
+            }
+            let payload = minimal_payload(id);
+            WorldHostC2SMessage::parse(id, &payload, Some(CURRENT)).unwrap();
+            let padded = [payload.as_slice(), &[0]].concat();
+            let error = WorldHostC2SMessage::parse(id, &padded, Some(CURRENT)).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", info.name);
+            if info.first_protocol <= STABLE {
+                WorldHostC2SMessage::parse(id, &padded, Some(STABLE))
+                    .unwrap_or_else(|error| panic!("{} rejected on {STABLE}: {error}", info.name));
+            }
+        }
     }
 }
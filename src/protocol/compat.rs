@@ -0,0 +1,23 @@
+use clap::ValueEnum;
+
+/// Another server implementation whose observable behavior is mirrored with --compat, for clients
+/// with workarounds tuned to it.
+///
+/// With [CompatMode::Kotlin]:
+/// - Setup messages are flushed one at a time, in the order Warning (from the handshake),
+///   ConnectionInfo, OutdatedWorldHost, Error (insecure authentication), ExternalProxyServer.
+///   Advisories are sent on every connection, even if a connection that dropped mid-setup already
+///   delivered them.
+/// - A rate limited connection is told the wait of the last bucket it exceeded, rather than the
+///   longest wait.
+/// - Reserved UUIDs are rejected with the client's UUID and the expected offline UUID, like other
+///   UUID mismatches.
+/// - Legacy QueryResponse messages don't get a deprecation warning.
+///
+/// The length field of a legacy QueryResponse is honored the same way in every mode. Checks that
+/// protect the server, like username and UUID validation and size limits, still apply.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum CompatMode {
+    /// The original Kotlin world-host-server
+    Kotlin,
+}
@@ -1,4 +1,6 @@
 use crate::connection::connection_id::ConnectionId;
+use crate::connection::proxy_connection_id::ProxyConnectionId;
+use crate::invalid_data;
 use byteorder::{BigEndian, ReadBytesExt};
 use std::io;
 use tokio::io::AsyncReadExt;
@@ -7,6 +9,9 @@ use uuid::Uuid;
 pub trait WHAsyncReadExt {
     async fn read_string(&mut self) -> io::Result<String>;
 
+    /// Like [Self::read_string], but rejects strings over `max_len` bytes before allocating
+    async fn read_bounded_string(&mut self, max_len: usize) -> io::Result<String>;
+
     async fn read_uuid(&mut self) -> io::Result<Uuid>;
 }
 
@@ -17,6 +22,16 @@ impl<T: AsyncReadExt + Unpin> WHAsyncReadExt for T {
         String::from_utf8(result).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
     }
 
+    async fn read_bounded_string(&mut self, max_len: usize) -> io::Result<String> {
+        let len = self.read_u16().await? as usize;
+        if len > max_len {
+            invalid_data!("String of {len} bytes is longer than the maximum of {max_len}");
+        }
+        let mut result = vec![0; len];
+        self.read_exact(&mut result).await?;
+        String::from_utf8(result).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
     async fn read_uuid(&mut self) -> io::Result<Uuid> {
         Ok(Uuid::from_u128(self.read_u128().await?))
     }
@@ -29,7 +44,10 @@ pub trait WHReadBytesExt {
 
     fn read_connection_id(&mut self) -> io::Result<ConnectionId>;
 
-    fn read_vec<V: Copy, F>(&mut self, reader: F) -> io::Result<Vec<V>>
+    fn read_proxy_connection_id(&mut self) -> io::Result<ProxyConnectionId>;
+
+    /// Reads a u32-prefixed list, rejecting lengths over `max_len` before allocating
+    fn read_vec<V: Copy, F>(&mut self, max_len: usize, reader: F) -> io::Result<Vec<V>>
     where
         F: Fn(&mut Self) -> io::Result<V>;
 }
@@ -46,15 +64,22 @@ impl<T: ReadBytesExt> WHReadBytesExt for T {
     }
 
     fn read_connection_id(&mut self) -> io::Result<ConnectionId> {
-        ConnectionId::new(self.read_u64::<BigEndian>()?)
+        ConnectionId::try_from(self.read_u64::<BigEndian>()?)
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
     }
 
-    fn read_vec<V: Copy, F>(&mut self, reader: F) -> io::Result<Vec<V>>
+    fn read_proxy_connection_id(&mut self) -> io::Result<ProxyConnectionId> {
+        Ok(ProxyConnectionId(self.read_u64::<BigEndian>()?))
+    }
+
+    fn read_vec<V: Copy, F>(&mut self, max_len: usize, reader: F) -> io::Result<Vec<V>>
     where
         F: Fn(&mut Self) -> io::Result<V>,
     {
         let len = self.read_u32::<BigEndian>()? as usize;
+        if len > max_len {
+            invalid_data!("List of {len} items is longer than the maximum of {max_len}");
+        }
         let mut result = Vec::with_capacity(len);
         for _ in 0..len {
             result.push(reader(self)?);
@@ -0,0 +1,101 @@
+use crate::server_state::ServerState;
+use crate::util::shrink_if_sparse;
+use std::collections::{HashMap, VecDeque};
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// Most delivered requests kept per recipient. Older ones are dropped first.
+pub const MAX_RETAINED_PER_USER: usize = 32;
+pub const MAX_RETAINED_TOTAL: usize = 1 << 16;
+
+struct DeliveredRequest {
+    from_user: Uuid,
+    expiry: Instant,
+}
+
+/// Friend requests that were delivered to an online user, kept for --friend-request-retention so
+/// that requests the client lost can be replayed by the admin API
+#[derive(Default)]
+pub struct DeliveredFriendRequests {
+    by_recipient: HashMap<Uuid, VecDeque<DeliveredRequest>>,
+    total: usize,
+}
+
+impl DeliveredFriendRequests {
+    /// Records a delivered request until `expiry`, replacing an older one from the same sender.
+    /// Nothing is recorded once [MAX_RETAINED_TOTAL] is reached.
+    pub fn record(&mut self, to_user: Uuid, from_user: Uuid, expiry: Instant) {
+        self.remove(to_user, from_user);
+        if self.total >= MAX_RETAINED_TOTAL {
+            return;
+        }
+        let requests = self.by_recipient.entry(to_user).or_default();
+        if requests.len() >= MAX_RETAINED_PER_USER {
+            requests.pop_front();
+            self.total -= 1;
+        }
+        requests.push_back(DeliveredRequest { from_user, expiry });
+        self.total += 1;
+    }
+
+    /// Forgets a request that was cancelled, returning whether there was one
+    pub fn remove(&mut self, to_user: Uuid, from_user: Uuid) -> bool {
+        let Some(requests) = self.by_recipient.get_mut(&to_user) else {
+            return false;
+        };
+        let len = requests.len();
+        requests.retain(|request| request.from_user != from_user);
+        let removed = len - requests.len();
+        self.total -= removed;
+        if requests.is_empty() {
+            self.by_recipient.remove(&to_user);
+        }
+        removed > 0
+    }
+
+    /// Senders of the unexpired requests delivered to `to_user`, oldest first
+    pub fn recent(&mut self, to_user: Uuid) -> Vec<Uuid> {
+        let now = Instant::now();
+        let Some(requests) = self.by_recipient.get_mut(&to_user) else {
+            return Vec::new();
+        };
+        let len = requests.len();
+        requests.retain(|request| request.expiry > now);
+        self.total -= len - requests.len();
+        let senders = requests.iter().map(|request| request.from_user).collect();
+        if requests.is_empty() {
+            self.by_recipient.remove(&to_user);
+        }
+        senders
+    }
+
+    /// Drops expired requests and returns how many slots were reclaimed
+    pub fn shrink(&mut self) -> usize {
+        let now = Instant::now();
+        let mut total = 0;
+        self.by_recipient.retain(|_, requests| {
+            requests.retain(|request| request.expiry > now);
+            total += requests.len();
+            !requests.is_empty()
+        });
+        self.total = total;
+        shrink_if_sparse(&mut self.by_recipient)
+    }
+}
+
+/// Records requests delivered to `to_user`, unless --friend-request-retention is 0s
+pub async fn record_delivered(
+    server: &ServerState,
+    to_user: Uuid,
+    from_users: impl IntoIterator<Item = Uuid>,
+) {
+    let retention = server.config.friend_request_retention;
+    if retention.is_zero() {
+        return;
+    }
+    let expiry = Instant::now() + retention;
+    let mut delivered = server.delivered_friend_requests.lock().await;
+    for from_user in from_users {
+        delivered.record(to_user, from_user, expiry);
+    }
+}
@@ -2,6 +2,7 @@ use crate::connection::Connection;
 use crate::protocol::s2c_message::WorldHostS2CMessage;
 use crate::server_state::FullServerConfig;
 use byteorder::{BigEndian, ReadBytesExt};
+use clap::ValueEnum;
 use std::io;
 use std::io::Cursor;
 
@@ -12,6 +13,30 @@ pub enum JoinType {
     Punch,
 }
 
+/// A [JoinType] without its data, used for configuring which join types are allowed
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum JoinTypeKind {
+    #[value(name = "upnp")]
+    UPnP,
+    Proxy,
+    Punch,
+}
+
+impl JoinTypeKind {
+    pub fn id(self) -> u8 {
+        match self {
+            JoinTypeKind::UPnP => 0,
+            JoinTypeKind::Proxy => 1,
+            JoinTypeKind::Punch => 2,
+        }
+    }
+
+    /// Bitmask of the join type ids in `kinds`, as sent in ServerCapabilities
+    pub fn mask(kinds: &[JoinTypeKind]) -> u8 {
+        kinds.iter().fold(0, |mask, kind| mask | 1 << kind.id())
+    }
+}
+
 impl JoinType {
     pub fn decode(cursor: &mut Cursor<&[u8]>) -> io::Result<JoinType> {
         use JoinType::*;
@@ -27,6 +52,30 @@ impl JoinType {
         }
     }
 
+    pub fn kind(&self) -> JoinTypeKind {
+        match self {
+            JoinType::UPnP(_) => JoinTypeKind::UPnP,
+            JoinType::Proxy => JoinTypeKind::Proxy,
+            JoinType::Punch => JoinTypeKind::Punch,
+        }
+    }
+
+    /// Checks this join type against `--allowed-join-types`, substituting UPnP with Proxy if
+    /// `--substitute-join-types` is passed. Returns an error message for the host otherwise.
+    pub fn check_allowed(self, config: &FullServerConfig) -> Result<JoinType, String> {
+        let kind = self.kind();
+        if config.allowed_join_types.contains(&kind) {
+            return Ok(self);
+        }
+        if config.substitute_join_types
+            && kind == JoinTypeKind::UPnP
+            && config.allowed_join_types.contains(&JoinTypeKind::Proxy)
+        {
+            return Ok(JoinType::Proxy);
+        }
+        Err(format!("This server does not allow {kind:?} joins"))
+    }
+
     pub async fn to_online_game(
         &self,
         connection: &Connection,
@@ -37,9 +86,11 @@ impl JoinType {
                 host: connection.addr.to_string(),
                 port: *port,
                 owner_cid: connection.id,
+                punch_transfer: false,
+                owner_uuid: connection.user_uuid,
             }),
             JoinType::Proxy => {
-                let external_proxy = if connection.protocol_version >= 3 {
+                let external_proxy = if connection.capabilities.prefers_external_proxy {
                     connection.state.lock().await.external_proxy.clone()
                 } else {
                     None
@@ -59,9 +110,139 @@ impl JoinType {
                     host: format!("{}.{}", connection.id, base_addr),
                     port,
                     owner_cid: connection.id,
+                    punch_transfer: false,
+                    owner_uuid: connection.user_uuid,
                 })
             }
             JoinType::Punch => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn decode(data: &[u8]) -> io::Result<JoinType> {
+        JoinType::decode(&mut Cursor::new(data))
+    }
+
+    #[test]
+    fn decodes_every_kind() {
+        assert!(matches!(
+            decode(&[0, 0x63, 0xdd]),
+            Ok(JoinType::UPnP(25565))
+        ));
+        assert!(matches!(decode(&[1]), Ok(JoinType::Proxy)));
+        assert!(matches!(decode(&[2]), Ok(JoinType::Punch)));
+    }
+
+    #[test]
+    fn rejects_invalid_join_types() {
+        assert_eq!(
+            decode(&[]).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            decode(&[0, 1]).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert_eq!(decode(&[3]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn config(allowed: &[JoinTypeKind], substitute: bool) -> FullServerConfig {
+        let mut config = FullServerConfig::for_test();
+        config.allowed_join_types = allowed.to_vec();
+        config.substitute_join_types = substitute;
+        config
+    }
+
+    fn check(
+        join_type: JoinType,
+        allowed: &[JoinTypeKind],
+        substitute: bool,
+    ) -> Result<JoinTypeKind, String> {
+        join_type
+            .check_allowed(&config(allowed, substitute))
+            .map(|join_type| join_type.kind())
+    }
+
+    #[test]
+    fn allowed_join_types() {
+        use JoinTypeKind::*;
+        for join_type in [JoinType::UPnP(25565), JoinType::Proxy, JoinType::Punch] {
+            let kind = join_type.kind();
+            assert_eq!(
+                check(join_type.clone(), &[UPnP, Proxy, Punch], false),
+                Ok(kind)
+            );
+            assert_eq!(check(join_type.clone(), &[kind], false), Ok(kind));
+            let others: Vec<_> = [UPnP, Proxy, Punch]
+                .into_iter()
+                .filter(|&other| other != kind)
+                .collect();
+            for substitute in [false, true] {
+                if kind == UPnP && substitute {
+                    continue;
+                }
+                assert_eq!(
+                    check(join_type.clone(), &others, substitute),
+                    Err(format!("This server does not allow {kind:?} joins"))
+                );
+            }
+        }
+        assert!(matches!(
+            JoinType::UPnP(25565).check_allowed(&config(&[UPnP], true)),
+            Ok(JoinType::UPnP(25565))
+        ));
+    }
+
+    #[test]
+    fn substituted_join_types() {
+        use JoinTypeKind::*;
+        assert_eq!(check(JoinType::UPnP(25565), &[Proxy], true), Ok(Proxy));
+        assert_eq!(
+            check(JoinType::UPnP(25565), &[Proxy, Punch], true),
+            Ok(Proxy)
+        );
+        // Nothing to substitute with
+        assert_eq!(
+            check(JoinType::UPnP(25565), &[Punch], true),
+            Err("This server does not allow UPnP joins".to_string())
+        );
+        assert_eq!(
+            check(JoinType::UPnP(25565), &[], true),
+            Err("This server does not allow UPnP joins".to_string())
+        );
+        // Only UPnP is substituted
+        assert_eq!(
+            check(JoinType::Punch, &[Proxy], true),
+            Err("This server does not allow Punch joins".to_string())
+        );
+    }
+
+    #[test]
+    fn masks() {
+        use JoinTypeKind::*;
+        assert_eq!(JoinTypeKind::mask(&[]), 0);
+        assert_eq!(JoinTypeKind::mask(&[UPnP]), 0b001);
+        assert_eq!(JoinTypeKind::mask(&[Proxy]), 0b010);
+        assert_eq!(JoinTypeKind::mask(&[Punch, UPnP]), 0b101);
+        assert_eq!(JoinTypeKind::mask(&[UPnP, Proxy, Punch, Proxy]), 0b111);
+    }
+
+    proptest! {
+        #[test]
+        fn decode_arbitrary_bytes(data in prop::collection::vec(any::<u8>(), 0..8)) {
+            let mut cursor = Cursor::new(data.as_slice());
+            if let Ok(join_type) = JoinType::decode(&mut cursor) {
+                let expected_len = match join_type {
+                    JoinType::UPnP(_) => 3,
+                    _ => 1,
+                };
+                prop_assert_eq!(cursor.position(), expected_len);
+            }
+        }
+    }
+}
@@ -1,25 +1,68 @@
-use crate::connection::Connection;
+use crate::connection::{Connection, SendOutcome};
+use crate::modules::analytics::IntervalCounters;
 use crate::protocol::c2s_message::WorldHostC2SMessage;
+use crate::protocol::compat::CompatMode;
+use crate::protocol::delivered_friend_requests::record_delivered;
+use crate::protocol::join_type::JoinType;
 use crate::protocol::port_lookup::{ActivePortLookup, PORT_LOOKUP_EXPIRY};
-use crate::protocol::s2c_message::WorldHostS2CMessage;
+use crate::protocol::presence;
+use crate::protocol::punch::{ActivePunch, PUNCH_EXPIRY};
+use crate::protocol::s2c_message::{OnlineFriend, WorldHostS2CMessage};
 use crate::protocol::security::SecurityLevel;
 use crate::server_state::ServerState;
 use crate::util::{add_with_circle_limit, remove_double_key};
 use log::warn;
 use queues::IsQueue;
+use std::collections::hash_map::Entry;
+use std::io;
+use std::net::IpAddr;
 use std::ops::DerefMut;
 use tokio::io::AsyncWriteExt;
 use tokio::time::Instant;
 use uuid::Uuid;
 
+/// Why a message couldn't be handled. Failures to deliver to other connections aren't errors.
+#[derive(Debug)]
+pub enum HandleError {
+    /// The sender's own connection is unusable. The connection is closed.
+    Fatal(anyhow::Error),
+    /// Only this message failed. The sender is sent a non-critical error and the connection stays
+    /// open.
+    Recoverable(String),
+}
+
+impl From<io::Error> for HandleError {
+    fn from(error: io::Error) -> Self {
+        Self::Fatal(error.into())
+    }
+}
+
 pub async fn handle_message(
     message: WorldHostC2SMessage,
     connection: &Connection,
     server: &ServerState,
-) {
+) -> Result<(), HandleError> {
     use WorldHostC2SMessage::*;
     match message {
         ListOnline { friends } => {
+            check_friends_len(&friends, server)?;
+            connection.state.lock().await.listed_friends = friends.iter().copied().collect();
+            // Only friends that would answer the IsOnlineTo broadcast are reported
+            let mut online = Vec::new();
+            for other in friends
+                .iter()
+                .flat_map(|&friend| server.connections.by_user_id(friend))
+            {
+                if other.id != connection.id
+                    && presence::shares_presence_with(server, &other, connection.user_uuid).await
+                {
+                    online.push(OnlineFriend {
+                        user: other.user_uuid,
+                        connection_id: other.id,
+                        security: other.security_level(),
+                    });
+                }
+            }
             broadcast_to_friends(
                 connection,
                 server,
@@ -29,19 +72,31 @@ pub async fn handle_message(
                 },
             )
             .await;
+            connection
+                .send_message(&WorldHostS2CMessage::FriendsOnline { friends: online })
+                .await?;
         }
         FriendRequest { to_user } => {
+            // Dropped like a request to an offline user that can't be queued
+            if blocks(server, to_user, connection.user_uuid).await {
+                return Ok(());
+            }
             let response = WorldHostS2CMessage::FriendRequest {
                 from_user: connection.user_uuid,
                 security: connection.security_level(),
             };
-            let other_connections = server.connections.lock().await.by_user_id(to_user);
+            let other_connections = server.connections.user_connections(to_user);
             if !other_connections.is_empty() {
+                let mut delivered = false;
                 for other in other_connections {
                     if other.id != connection.id {
-                        send_safely(connection, &other, &response).await;
+                        delivered |= send_safely(server, connection, &other, &response).await
+                            == Some(SendOutcome::Sent);
                     }
                 }
+                if delivered {
+                    record_delivered(server, to_user, [connection.user_uuid]).await;
+                }
             } else if connection.security_level() > SecurityLevel::Insecure {
                 let removed_remembered = {
                     let mut remembered = server.remembered_friend_requests.lock().await;
@@ -69,13 +124,84 @@ pub async fn handle_message(
                 }
             }
         }
-        PublishedWorld { friends } => {
-            connection
-                .state
+        CancelFriendRequest { to_user } => {
+            let removed_remembered = remove_double_key(
+                server.remembered_friend_requests.lock().await.deref_mut(),
+                &connection.user_uuid,
+                &to_user,
+            );
+            let removed_received = remove_double_key(
+                server.received_friend_requests.lock().await.deref_mut(),
+                &to_user,
+                &connection.user_uuid,
+            );
+            let removed_delivered = server
+                .delivered_friend_requests
+                .lock()
+                .await
+                .remove(to_user, connection.user_uuid);
+            // Otherwise there's no request to cancel, and this would be an unsolicited message
+            if !(removed_remembered || removed_received || removed_delivered)
+                || blocks(server, to_user, connection.user_uuid).await
+            {
+                return Ok(());
+            }
+            let response = WorldHostS2CMessage::FriendRequestCancelled {
+                from_user: connection.user_uuid,
+            };
+            let other_connections = server.connections.by_user_id(to_user);
+            for other in other_connections {
+                if other.id != connection.id {
+                    send_safely(server, connection, &other, &response).await;
+                }
+            }
+        }
+        BlockUser { user } => {
+            server
+                .block_lists
+                .lock()
+                .await
+                .block(connection.user_uuid, user)
+                .map_err(HandleError::Recoverable)?;
+        }
+        UnblockUser { user } => {
+            server
+                .block_lists
                 .lock()
                 .await
-                .open_to_friends
-                .extend(friends.iter());
+                .unblock(connection.user_uuid, user);
+        }
+        PublishedWorld { friends } => {
+            check_friends_len(&friends, server)?;
+            let max_open = server.config.max_open_to_friends;
+            let (friends, warn_cap) = {
+                let state = &mut *connection.state.lock().await;
+                let open = &mut state.open_to_friends;
+                let requested = friends.len();
+                let friends: Vec<_> = friends
+                    .into_iter()
+                    .filter(|&friend| {
+                        open.contains(&friend) || (open.len() < max_open && open.insert(friend))
+                    })
+                    .collect();
+                let warn_cap = friends.len() < requested
+                    && !std::mem::replace(&mut state.warned_open_to_friends_cap, true);
+                (friends, warn_cap)
+            };
+            if warn_cap {
+                warn!(
+                    "Connection {} reached the limit of {max_open} friends its world is open to",
+                    connection.id
+                );
+                connection
+                    .send_message(&WorldHostS2CMessage::Error {
+                        message: format!(
+                            "Worlds can be published to at most {max_open} friends. Further friends were ignored."
+                        ),
+                        critical: false,
+                    })
+                    .await?;
+            }
             broadcast_to_friends(
                 connection,
                 server,
@@ -89,12 +215,24 @@ pub async fn handle_message(
             .await;
         }
         ClosedWorld { friends } => {
-            {
+            let friends = {
                 let open = &mut connection.state.lock().await.open_to_friends;
-                for friend in friends.iter() {
-                    open.remove(friend);
+                if friends.is_empty() && connection.capabilities.supports_close_all {
+                    open.drain().collect()
+                } else {
+                    for friend in friends.iter() {
+                        open.remove(friend);
+                    }
+                    if open.len() > CLOSED_WORLD_LEFTOVER_WARNING {
+                        warn!(
+                            "Connection {} closed its world, but is still open to {} friends",
+                            connection.id,
+                            open.len()
+                        );
+                    }
+                    friends
                 }
-            }
+            };
             broadcast_to_friends(
                 connection,
                 server,
@@ -106,22 +244,28 @@ pub async fn handle_message(
             .await;
         }
         RequestJoin { friend } => {
-            if connection.protocol_version >= 4 {
+            if connection.capabilities.supports_direct_join {
                 warn!(
                     "Connection {} tried to use unsupported RequestJoin message",
                     connection.id
                 );
-                send_safely(connection, connection, &WorldHostS2CMessage::Error {
-                    message: "Please use the v4+ RequestDirectJoin message instead of the unsupported RequestJoin message".to_string(),
-                    critical: false
-                }).await;
-                return;
+                return Err(HandleError::Recoverable(
+                    "Please use the v4+ RequestDirectJoin message instead of the unsupported RequestJoin message".to_string(),
+                ));
             }
-            let online = server.connections.lock().await.by_user_id(friend);
+            IntervalCounters::increment(&server.analytics_counters.join_requests);
+            let online = server.connections.user_connections(friend);
             if !online.is_empty()
                 && let Some(last) = online.last()
+                && !blocks(server, last.user_uuid, connection.user_uuid).await
             {
+                server
+                    .pending_joins
+                    .lock()
+                    .await
+                    .add(connection.id, last.id);
                 send_safely(
+                    server,
                     connection,
                     last,
                     &WorldHostS2CMessage::RequestJoin {
@@ -137,26 +281,48 @@ pub async fn handle_message(
             connection_id,
             join_type,
         } => {
+            let requested = server
+                .pending_joins
+                .lock()
+                .await
+                .take(connection_id, connection.id);
+            if !requested && !server.config.allow_unrequested_joins {
+                warn!(
+                    "Connection {} granted a join to {connection_id}, which didn't request one",
+                    connection.id
+                );
+                return Err(HandleError::Recoverable(format!(
+                    "Connection {connection_id} hasn't requested to join"
+                )));
+            }
+            let join_type = match join_type.check_allowed(&server.config) {
+                Ok(join_type) => join_type,
+                Err(message) => {
+                    IntervalCounters::increment(&server.analytics_counters.joins_rejected);
+                    return Err(HandleError::Recoverable(message));
+                }
+            };
             let response = join_type.to_online_game(connection, &server.config).await;
+            let counters = &server.analytics_counters;
+            IntervalCounters::increment(match (&join_type, &response) {
+                (_, None) => &counters.joins_rejected,
+                (JoinType::UPnP(_), _) => &counters.joins_upnp,
+                (JoinType::Proxy, _) => &counters.joins_proxy,
+                (JoinType::Punch, _) => &counters.joins_punch,
+            });
             if response.is_none() {
-                send_safely(
-                    connection,
-                    connection,
-                    &WorldHostS2CMessage::Error {
-                        message: format!("This server does not support JoinType {join_type:?}"),
-                        critical: false,
-                    },
-                )
-                .await;
-                return;
+                return Err(HandleError::Recoverable(format!(
+                    "This server does not support JoinType {join_type:?}"
+                )));
             }
             if connection_id != connection.id
-                && let Some(other) = server.connections.lock().await.by_id(connection_id)
+                && let Some(other) = server.connections.by_id(connection_id)
             {
-                send_safely(connection, other, &response.unwrap()).await;
+                send_safely(server, connection, &other, &response.unwrap()).await;
             }
         }
         QueryRequest { friends } => {
+            check_friends_len(&friends, server)?;
             broadcast_to_friends(
                 connection,
                 server,
@@ -173,7 +339,24 @@ pub async fn handle_message(
             connection_id,
             data,
         } => {
-            Box::pin(handle_message(
+            if connection.capabilities.supports_new_query_response {
+                IntervalCounters::increment(&server.analytics_counters.legacy_query_responses);
+                // The Kotlin server accepted these silently
+                let warn = server.config.compat != Some(CompatMode::Kotlin)
+                    && !std::mem::replace(
+                        &mut connection.state.lock().await.warned_legacy_query_response,
+                        true,
+                    );
+                if warn {
+                    connection
+                        .send_message(&WorldHostS2CMessage::Warning {
+                            message: "QueryResponse is deprecated. NewQueryResponse should be used instead.".to_string(),
+                            important: false,
+                        })
+                        .await?;
+                }
+            }
+            return Box::pin(handle_message(
                 NewQueryResponse {
                     connection_id,
                     data,
@@ -187,13 +370,29 @@ pub async fn handle_message(
             connection_id,
             data,
         } => {
+            let max_size = server.config.max_proxy_packet_size;
+            if data.len() > max_size {
+                warn!(
+                    "Connection {} sent a {} byte proxy packet to {connection_id}, over the limit of {max_size}",
+                    connection.id,
+                    data.len()
+                );
+                connection
+                    .send_message(&WorldHostS2CMessage::Warning {
+                        message: format!(
+                            "Dropped a {} byte proxy packet. Proxy packets can be at most {max_size} bytes.",
+                            data.len()
+                        ),
+                        important: false,
+                    })
+                    .await?;
+                return Ok(());
+            }
             if let Some((cid, socket)) = server.proxy_connections.lock().await.get(&connection_id)
                 && *cid == connection.id
             {
-                let mut socket = socket.lock().await;
                 // Socket may be disconnected. Let the receiver deal with that.
-                let _ = socket.write_all(&data).await;
-                let _ = socket.flush().await;
+                let _ = socket.lock().await.forward(&data).await;
             }
         }
         ProxyDisconnect { connection_id } => {
@@ -201,16 +400,27 @@ pub async fn handle_message(
                 && *cid == connection.id
             {
                 // Socket may already be shutdown. That's the receiver's job to handle.
-                let _ = socket.lock().await.shutdown().await;
+                let _ = socket.lock().await.socket.shutdown().await;
             }
         }
         RequestDirectJoin { connection_id } => {
+            IntervalCounters::increment(&server.analytics_counters.direct_join_requests);
+            let other = server.connections.by_id(connection_id);
             if connection_id != connection.id
-                && let Some(other) = server.connections.lock().await.by_id(connection_id)
+                && let Some(other) = other
+                && !blocks(server, other.user_uuid, connection.user_uuid).await
+                && (!server.config.friends_only_direct_joins
+                    || is_friend_or_unpublished(&other, connection).await)
             {
+                server
+                    .pending_joins
+                    .lock()
+                    .await
+                    .add(connection.id, connection_id);
                 send_safely(
+                    server,
                     connection,
-                    other,
+                    &other,
                     &WorldHostS2CMessage::RequestJoin {
                         user: connection.user_uuid,
                         connection_id: connection.id,
@@ -218,27 +428,25 @@ pub async fn handle_message(
                     },
                 )
                 .await;
-                return;
+                return Ok(());
             }
-            send_safely(
-                connection,
-                connection,
-                &WorldHostS2CMessage::ConnectionNotFound { connection_id },
-            )
-            .await;
+            connection
+                .send_message(&WorldHostS2CMessage::ConnectionNotFound { connection_id })
+                .await?;
         }
         NewQueryResponse {
             connection_id,
             data,
         } => {
             if connection_id == connection.id {
-                return;
+                return Ok(());
             }
-            if let Some(other) = server.connections.lock().await.by_id(connection_id) {
+            if let Some(other) = server.connections.by_id(connection_id) {
                 send_safely(
+                    server,
                     connection,
-                    other,
-                    &if other.protocol_version < 5 {
+                    &other,
+                    &if !other.capabilities.supports_new_query_response {
                         #[allow(deprecated)]
                         WorldHostS2CMessage::QueryResponse {
                             friend: connection.user_uuid,
@@ -264,47 +472,102 @@ pub async fn handle_message(
             my_local_host: _,
             my_local_port: _,
         } => {
-            if let Some(target_client) = server.connections.lock().await.by_id(target_connection) {
-                if target_client.protocol_version < 7 {
-                    send_safely(
-                        connection,
-                        connection,
-                        &WorldHostS2CMessage::PunchRequestCancelled { punch_id },
-                    )
-                    .await;
-                    return;
+            if purpose.len() > MAX_PUNCH_PURPOSE_LENGTH || purpose.chars().any(|c| c.is_control()) {
+                return Err(HandleError::Recoverable(format!(
+                    "Punch purpose must be at most {MAX_PUNCH_PURPOSE_LENGTH} bytes without control characters"
+                )));
+            }
+            validate_punch_address(&my_host, my_port)?;
+            let target_client = server.connections.by_id(target_connection);
+            let target_client = match target_client {
+                Some(target) if !blocks(server, target.user_uuid, connection.user_uuid).await => {
+                    Some(target)
                 }
-                send_safely(
-                    connection,
-                    target_client,
-                    &WorldHostS2CMessage::PunchOpenRequest {
-                        punch_id,
-                        purpose,
-                        from_host: my_host,
-                        from_port: my_port,
-                        connection_id: connection.id,
-                        user: connection.user_uuid,
-                        security: connection.security_level(),
-                    },
-                )
-                .await;
-            } else {
-                send_safely(
-                    connection,
-                    connection,
-                    &WorldHostS2CMessage::PunchRequestCancelled { punch_id },
-                )
-                .await;
+                _ => None,
+            };
+            let Some(target_client) = target_client else {
+                connection
+                    .send_message(&WorldHostS2CMessage::PunchRequestCancelled { punch_id })
+                    .await?;
+                return Ok(());
+            };
+            let expiry = Instant::now() + PUNCH_EXPIRY;
+            let added = match server.active_punches.lock().await.entry(punch_id) {
+                Entry::Occupied(entry)
+                    if (entry.get().initiator, entry.get().target)
+                        != (connection.id, target_connection) =>
+                {
+                    return Err(HandleError::Recoverable(format!(
+                        "Punch ID {punch_id} is already in use"
+                    )));
+                }
+                Entry::Occupied(_) => false,
+                Entry::Vacant(entry) => {
+                    entry.insert(ActivePunch {
+                        initiator: connection.id,
+                        target: target_connection,
+                        expiry,
+                    });
+                    true
+                }
+            };
+            // Queued after active_punches is unlocked, since the sweep locks them the other way
+            if added {
+                server
+                    .punch_by_expiry
+                    .lock()
+                    .await
+                    .add((expiry, punch_id))
+                    .unwrap();
+            }
+            let outcome = send_safely(
+                server,
+                connection,
+                &target_client,
+                &WorldHostS2CMessage::PunchOpenRequest {
+                    punch_id,
+                    purpose,
+                    from_host: my_host,
+                    from_port: my_port,
+                    connection_id: connection.id,
+                    user: connection.user_uuid,
+                    security: connection.security_level(),
+                },
+            )
+            .await;
+            if outcome == Some(SendOutcome::SkippedOldProtocol) {
+                server.active_punches.lock().await.remove(&punch_id);
+                connection
+                    .send_message(&WorldHostS2CMessage::PunchRequestCancelled { punch_id })
+                    .await?;
             }
         }
         PunchFailed {
             target_connection,
             punch_id,
         } => {
-            if let Some(target) = server.connections.lock().await.by_id(target_connection) {
+            let known = {
+                let mut punches = server.active_punches.lock().await;
+                let known = punches
+                    .get(&punch_id)
+                    .is_some_and(|punch| punch.is_between(connection.id, target_connection));
+                if known {
+                    punches.remove(&punch_id);
+                }
+                known
+            };
+            if !known {
+                warn!(
+                    "Connection {} sent PunchFailed to {target_connection} for unknown punch {punch_id}",
+                    connection.id
+                );
+                return Ok(());
+            }
+            if let Some(target) = server.connections.by_id(target_connection) {
                 send_safely(
+                    server,
                     connection,
-                    target,
+                    &target,
                     &WorldHostS2CMessage::PunchRequestCancelled { punch_id },
                 )
                 .await;
@@ -329,10 +592,30 @@ pub async fn handle_message(
             host,
             port,
         } => {
-            if let Some(target) = server.connections.lock().await.by_id(connection_id) {
+            validate_punch_address(&host, port)?;
+            // Consumed, so that the sender can't keep redirecting its peer until the punch expires
+            let known = {
+                let mut punches = server.active_punches.lock().await;
+                let known = punches
+                    .get(&punch_id)
+                    .is_some_and(|punch| punch.is_between(connection.id, connection_id));
+                if known {
+                    punches.remove(&punch_id);
+                }
+                known
+            };
+            if !known {
+                warn!(
+                    "Connection {} sent PunchSuccess to {connection_id} for unknown punch {punch_id}",
+                    connection.id
+                );
+                return Ok(());
+            }
+            if let Some(target) = server.connections.by_id(connection_id) {
                 send_safely(
+                    server,
                     connection,
-                    target,
+                    &target,
                     &WorldHostS2CMessage::PunchSuccess {
                         punch_id,
                         host,
@@ -342,9 +625,110 @@ pub async fn handle_message(
                 .await;
             }
         }
+        SubscribePresence { friends } => {
+            presence::subscribe(connection, server, friends).await?;
+        }
+    }
+    Ok(())
+}
+
+fn check_friends_len(friends: &[Uuid], server: &ServerState) -> Result<(), HandleError> {
+    let max_friends = server.config.max_friends;
+    if friends.len() > max_friends {
+        return Err(HandleError::Recoverable(format!(
+            "Friend lists can have at most {max_friends} friends"
+        )));
+    }
+    Ok(())
+}
+
+/// Whether `blocker` has blocked `user` with BlockUser. Blocked users are treated as if the blocker
+/// were offline.
+pub async fn blocks(server: &ServerState, blocker: Uuid, user: Uuid) -> bool {
+    server.block_lists.lock().await.blocks(blocker, user)
+}
+
+/// Whether either user blocked the other. Neither sees the other's presence then.
+pub async fn either_blocks(server: &ServerState, a: Uuid, b: Uuid) -> bool {
+    let block_lists = server.block_lists.lock().await;
+    block_lists.blocks(a, b) || block_lists.blocks(b, a)
+}
+
+/// Whether `requester` is in the friends `host` published its world to. Hosts that haven't
+/// published to anyone accept requests from everyone.
+async fn is_friend_or_unpublished(host: &Connection, requester: &Connection) -> bool {
+    let open_to_friends = &host.state.lock().await.open_to_friends;
+    open_to_friends.is_empty() || open_to_friends.contains(&requester.user_uuid)
+}
+
+/// Friends left in open_to_friends after a ClosedWorld before it's considered drift worth logging
+const CLOSED_WORLD_LEFTOVER_WARNING: usize = 32;
+const MAX_PUNCH_PURPOSE_LENGTH: usize = 64;
+const MAX_HOST_LENGTH: usize = 255;
+
+/// Loosely checks that a host relayed to other clients is a hostname or IP literal. IP literals
+/// and hostnames that can only reach the peer's own machine or network are rejected, so that a
+/// client can't point its peer at something local to the peer.
+fn validate_punch_address(host: &str, port: u16) -> Result<(), HandleError> {
+    let valid_host = !host.is_empty()
+        && host.len() <= MAX_HOST_LENGTH
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ".-_:[]%".contains(c));
+    if !valid_host {
+        return Err(HandleError::Recoverable(format!(
+            "Punch host must be a hostname or IP address of at most {MAX_HOST_LENGTH} bytes"
+        )));
+    }
+    if is_local_host(host) {
+        return Err(HandleError::Recoverable(
+            "Punch host must be a public address".to_string(),
+        ));
+    }
+    if port == 0 {
+        return Err(HandleError::Recoverable(
+            "Punch port must not be 0".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether the host is localhost, or an IP literal that's loopback, private, link-local,
+/// multicast, broadcast, or unspecified
+fn is_local_host(host: &str) -> bool {
+    if host.eq_ignore_ascii_case("localhost") {
+        return true;
+    }
+    let literal = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    // Zone IDs are only meaningful on the sender's machine
+    let literal = literal.split_once('%').map_or(literal, |(ip, _)| ip);
+    let Ok(ip) = literal.parse::<IpAddr>() else {
+        return false;
+    };
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || ip.is_broadcast()
+                || ip.is_unspecified()
+        }
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                || ip.is_multicast()
+                || ip.is_unspecified()
+        }
     }
 }
 
+/// Friends that blocked the sender, or that the sender blocked, are skipped. Each of them is
+/// offline as far as the other can tell.
 async fn broadcast_to_friends(
     connection: &Connection,
     server: &ServerState,
@@ -352,19 +736,1098 @@ async fn broadcast_to_friends(
     message: WorldHostS2CMessage,
 ) {
     for friend in friends {
-        for other in server.connections.lock().await.by_user_id(friend) {
+        if either_blocks(server, friend, connection.user_uuid).await {
+            continue;
+        }
+        let others = server.connections.user_connections(friend);
+        for other in others {
             if other.id != connection.id {
-                send_safely(connection, &other, &message).await;
+                send_safely(server, connection, &other, &message).await;
             }
         }
     }
 }
 
-async fn send_safely(from: &Connection, to: &Connection, message: &WorldHostS2CMessage) {
-    if let Err(error) = to.send_message(message).await {
-        warn!(
-            "Failed to broadcast {message:?} from {} to {}: {error}",
-            from.id, to.id
+/// Sends a message from one connection to another, logging failures instead of returning them.
+/// Returns None if sending failed.
+pub async fn send_safely(
+    server: &ServerState,
+    from: &Connection,
+    to: &Connection,
+    message: &WorldHostS2CMessage,
+) -> Option<SendOutcome> {
+    match to.send_message(message).await {
+        Ok(outcome) => {
+            count_skipped(server, outcome);
+            Some(outcome)
+        }
+        Err(error) => {
+            warn!(
+                "Failed to broadcast {} from {} to {}: {error}",
+                message.name(),
+                from.id,
+                to.id
+            );
+            None
+        }
+    }
+}
+
+pub fn count_skipped(server: &ServerState, outcome: SendOutcome) {
+    if outcome == SendOutcome::SkippedOldProtocol {
+        IntervalCounters::increment(&server.analytics_counters.skipped_old_protocol);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::ConnectionInfo;
+    use crate::connection::connection_id::ConnectionId;
+    use crate::connection::read_test_message;
+    use crate::modules::main_server::dequeue_friend_requests;
+    use crate::protocol::pending_joins::JOIN_REQUEST_EXPIRY;
+    use crate::protocol::protocol_versions::{CURRENT, DIRECT_JOIN_PROTOCOL, STABLE};
+    use crate::server_state::FullServerConfig;
+    use crate::util::alloc_tracker::allocation_count;
+    use std::collections::HashSet;
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use tokio::io::DuplexStream;
+    use tokio::time::timeout;
+
+    const SENDER: Uuid = Uuid::from_u128(0x1000);
+    const RECIPIENT: Uuid = Uuid::from_u128(0x2000);
+    const OTHER_USER: Uuid = Uuid::from_u128(0x3000);
+
+    fn server(compat: Option<CompatMode>) -> ServerState {
+        let mut config = FullServerConfig::for_test();
+        config.compat = compat;
+        ServerState::new(config)
+    }
+
+    fn connect(
+        server: &ServerState,
+        id: u64,
+        user: Uuid,
+        protocol_version: u32,
+    ) -> (Connection, DuplexStream) {
+        let (connection, client) = ConnectionInfo::for_test(
+            ConnectionId::new(id).unwrap(),
+            user,
+            Ipv4Addr::LOCALHOST.into(),
+            protocol_version,
+        );
+        assert!(server.connections.add(connection.clone()));
+        (connection, client)
+    }
+
+    async fn assert_nothing_sent(client: &mut DuplexStream, protocol_version: u32) {
+        let next = timeout(
+            Duration::from_millis(50),
+            read_test_message(client, protocol_version),
+        )
+        .await;
+        assert!(next.is_err(), "Unexpected message {next:?}");
+    }
+
+    async fn list_online(connection: &Connection, server: &ServerState, friends: Vec<Uuid>) {
+        handle_message(
+            WorldHostC2SMessage::ListOnline { friends },
+            connection,
+            server,
+        )
+        .await
+        .unwrap();
+    }
+
+    fn online_friend(connection: &Connection) -> OnlineFriend {
+        OnlineFriend {
+            user: connection.user_uuid,
+            connection_id: connection.id,
+            security: connection.security_level(),
+        }
+    }
+
+    fn legacy_query_response(to: &Connection) -> WorldHostC2SMessage {
+        WorldHostC2SMessage::QueryResponse {
+            connection_id: to.id,
+            data: vec![1, 2, 3],
+        }
+    }
+
+    #[tokio::test]
+    async fn legacy_query_response_is_relayed_with_its_length() {
+        for compat in [None, Some(CompatMode::Kotlin)] {
+            let server = server(compat);
+            let (sender, _sender_client) = connect(&server, 1, SENDER, DIRECT_JOIN_PROTOCOL);
+            let (old, mut old_client) = connect(&server, 2, RECIPIENT, DIRECT_JOIN_PROTOCOL);
+            let (new, mut new_client) = connect(&server, 3, RECIPIENT, CURRENT);
+
+            handle_message(legacy_query_response(&old), &sender, &server)
+                .await
+                .unwrap();
+            #[allow(deprecated)]
+            let expected = WorldHostS2CMessage::QueryResponse {
+                friend: SENDER,
+                length: 3,
+                data: vec![1, 2, 3],
+            };
+            assert_eq!(
+                read_test_message(&mut old_client, DIRECT_JOIN_PROTOCOL)
+                    .await
+                    .unwrap(),
+                expected
+            );
+
+            handle_message(legacy_query_response(&new), &sender, &server)
+                .await
+                .unwrap();
+            assert_eq!(
+                read_test_message(&mut new_client, CURRENT).await.unwrap(),
+                WorldHostS2CMessage::NewQueryResponse {
+                    friend: SENDER,
+                    data: vec![1, 2, 3],
+                }
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn legacy_query_response_deprecation_warning() {
+        let server = server(None);
+        let (sender, mut sender_client) = connect(&server, 1, SENDER, CURRENT);
+        let (recipient, _recipient_client) = connect(&server, 2, RECIPIENT, CURRENT);
+        for _ in 0..2 {
+            handle_message(legacy_query_response(&recipient), &sender, &server)
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            read_test_message(&mut sender_client, CURRENT)
+                .await
+                .unwrap(),
+            WorldHostS2CMessage::Warning {
+                message: "QueryResponse is deprecated. NewQueryResponse should be used instead."
+                    .to_string(),
+                important: false,
+            }
+        );
+        // Only the first one is warned about
+        assert_nothing_sent(&mut sender_client, CURRENT).await;
+    }
+
+    #[tokio::test]
+    async fn kotlin_legacy_query_response_is_silent() {
+        let server = server(Some(CompatMode::Kotlin));
+        let (sender, mut sender_client) = connect(&server, 1, SENDER, CURRENT);
+        let (recipient, _recipient_client) = connect(&server, 2, RECIPIENT, CURRENT);
+        handle_message(legacy_query_response(&recipient), &sender, &server)
+            .await
+            .unwrap();
+        assert_nothing_sent(&mut sender_client, CURRENT).await;
+        assert_eq!(
+            server
+                .analytics_counters
+                .legacy_query_responses
+                .load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn friends_online_for_current_asker_with_mixed_friends() {
+        let server = server(None);
+        let (old, mut old_client) = connect(&server, 2, RECIPIENT, STABLE);
+        let (new, mut new_client) = connect(&server, 3, OTHER_USER, CURRENT);
+        list_online(&old, &server, vec![SENDER]).await;
+        list_online(&new, &server, vec![SENDER]).await;
+        assert_eq!(
+            read_test_message(&mut new_client, CURRENT).await.unwrap(),
+            WorldHostS2CMessage::FriendsOnline { friends: vec![] }
+        );
+
+        let (asker, mut asker_client) = connect(&server, 1, SENDER, CURRENT);
+        list_online(&asker, &server, vec![RECIPIENT, OTHER_USER]).await;
+        assert_eq!(
+            read_test_message(&mut asker_client, CURRENT).await.unwrap(),
+            WorldHostS2CMessage::FriendsOnline {
+                friends: vec![online_friend(&old), online_friend(&new)],
+            }
+        );
+        // Both still get the broadcast
+        for (client, protocol_version) in [(&mut old_client, STABLE), (&mut new_client, CURRENT)] {
+            assert_eq!(
+                read_test_message(client, protocol_version).await.unwrap(),
+                WorldHostS2CMessage::IsOnlineTo { user: SENDER }
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn friends_online_isnt_sent_to_stable_asker() {
+        let server = server(None);
+        let (asker, mut asker_client) = connect(&server, 1, SENDER, STABLE);
+        let (friend, mut friend_client) = connect(&server, 2, RECIPIENT, CURRENT);
+
+        list_online(&asker, &server, vec![RECIPIENT]).await;
+        assert_eq!(
+            read_test_message(&mut friend_client, CURRENT)
+                .await
+                .unwrap(),
+            WorldHostS2CMessage::IsOnlineTo { user: SENDER }
+        );
+        // The friend answers the broadcast, which is how stable clients learn who's online
+        list_online(&friend, &server, vec![SENDER]).await;
+        assert_eq!(
+            read_test_message(&mut friend_client, CURRENT)
+                .await
+                .unwrap(),
+            WorldHostS2CMessage::FriendsOnline {
+                friends: vec![online_friend(&asker)],
+            }
+        );
+        assert_eq!(
+            read_test_message(&mut asker_client, STABLE).await.unwrap(),
+            WorldHostS2CMessage::IsOnlineTo { user: RECIPIENT }
+        );
+        assert_nothing_sent(&mut asker_client, STABLE).await;
+    }
+
+    #[tokio::test]
+    async fn friends_online_omits_friends_that_didnt_name_the_asker() {
+        let server = server(None);
+        let (asker, mut asker_client) = connect(&server, 1, SENDER, CURRENT);
+        let (_stranger, _stranger_client) = connect(&server, 2, RECIPIENT, STABLE);
+        let (friend, _friend_client) = connect(&server, 3, OTHER_USER, CURRENT);
+        handle_message(
+            WorldHostC2SMessage::SubscribePresence {
+                friends: vec![SENDER],
+            },
+            &friend,
+            &server,
+        )
+        .await
+        .unwrap();
+
+        list_online(&asker, &server, vec![RECIPIENT, OTHER_USER]).await;
+        assert_eq!(
+            read_test_message(&mut asker_client, CURRENT).await.unwrap(),
+            WorldHostS2CMessage::FriendsOnline {
+                friends: vec![online_friend(&friend)],
+            }
+        );
+    }
+
+    async fn friend_request(from: &Connection, server: &ServerState, to_user: Uuid) {
+        handle_message(WorldHostC2SMessage::FriendRequest { to_user }, from, server)
+            .await
+            .unwrap();
+    }
+
+    async fn cancel_friend_request(from: &Connection, server: &ServerState, to_user: Uuid) {
+        handle_message(
+            WorldHostC2SMessage::CancelFriendRequest { to_user },
+            from,
+            server,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancel_queued_friend_request() {
+        let server = server(None);
+        let (sender, _sender_client) = connect(&server, 1, SENDER, CURRENT);
+        friend_request(&sender, &server, RECIPIENT).await;
+        assert!(server.received_friend_requests.lock().await[&RECIPIENT].contains(&SENDER));
+
+        cancel_friend_request(&sender, &server, RECIPIENT).await;
+        assert!(server.received_friend_requests.lock().await.is_empty());
+        assert!(server.remembered_friend_requests.lock().await.is_empty());
+
+        // Nothing is delivered when the recipient comes online
+        let (recipient, _recipient_client) = connect(&server, 2, RECIPIENT, CURRENT);
+        let dequeued = dequeue_friend_requests(RECIPIENT, &[recipient], &server)
+            .await
+            .unwrap();
+        assert_eq!(dequeued, 0);
+    }
+
+    #[tokio::test]
+    async fn cancel_delivered_friend_request() {
+        let server = server(None);
+        let (sender, _sender_client) = connect(&server, 1, SENDER, CURRENT);
+        let (_recipient, mut recipient_client) = connect(&server, 2, RECIPIENT, CURRENT);
+        friend_request(&sender, &server, RECIPIENT).await;
+        assert_eq!(
+            read_test_message(&mut recipient_client, CURRENT)
+                .await
+                .unwrap(),
+            WorldHostS2CMessage::FriendRequest {
+                from_user: SENDER,
+                security: sender.security_level(),
+            }
+        );
+
+        cancel_friend_request(&sender, &server, RECIPIENT).await;
+        assert_eq!(
+            read_test_message(&mut recipient_client, CURRENT)
+                .await
+                .unwrap(),
+            WorldHostS2CMessage::FriendRequestCancelled { from_user: SENDER }
+        );
+        // It was already cancelled
+        cancel_friend_request(&sender, &server, RECIPIENT).await;
+        assert_nothing_sent(&mut recipient_client, CURRENT).await;
+    }
+
+    #[tokio::test]
+    async fn cancel_without_request_sends_nothing() {
+        let server = server(None);
+        let (sender, _sender_client) = connect(&server, 1, SENDER, CURRENT);
+        let (_recipient, mut recipient_client) = connect(&server, 2, RECIPIENT, CURRENT);
+        cancel_friend_request(&sender, &server, RECIPIENT).await;
+        assert_nothing_sent(&mut recipient_client, CURRENT).await;
+    }
+
+    /// Every message still waiting for a client
+    async fn drain(client: &mut DuplexStream, protocol_version: u32) -> Vec<WorldHostS2CMessage> {
+        let mut messages = vec![];
+        while let Ok(message) = timeout(
+            Duration::from_millis(50),
+            read_test_message(client, protocol_version),
+        )
+        .await
+        {
+            messages.push(message.unwrap());
+        }
+        messages
+    }
+
+    fn request_punch_open(target_connection: ConnectionId) -> WorldHostC2SMessage {
+        WorldHostC2SMessage::RequestPunchOpen {
+            target_connection,
+            purpose: "test".to_string(),
+            punch_id: Uuid::from_u128(0x9000),
+            my_host: "example.com".to_string(),
+            my_port: 25565,
+            my_local_host: "example.com".to_string(),
+            my_local_port: 25565,
+        }
+    }
+
+    /// Everything SENDER is sent while interacting with RECIPIENT, who's either offline or online
+    /// and blocking SENDER. RECIPIENT also tries to reveal itself to SENDER.
+    async fn seen_by_blocked_sender(recipient_online: bool) -> Vec<WorldHostS2CMessage> {
+        let server = server(None);
+        let (sender, mut sender_client) = connect(&server, 1, SENDER, CURRENT);
+        let recipient_id = ConnectionId::new(2).unwrap();
+        let mut recipient = None;
+        if recipient_online {
+            let (connection, client) = connect(&server, 2, RECIPIENT, CURRENT);
+            handle_message(
+                WorldHostC2SMessage::BlockUser { user: SENDER },
+                &connection,
+                &server,
+            )
+            .await
+            .unwrap();
+            recipient = Some((connection, client));
+        }
+
+        for message in [
+            WorldHostC2SMessage::ListOnline {
+                friends: vec![RECIPIENT],
+            },
+            WorldHostC2SMessage::SubscribePresence {
+                friends: vec![RECIPIENT],
+            },
+            WorldHostC2SMessage::FriendRequest { to_user: RECIPIENT },
+            WorldHostC2SMessage::CancelFriendRequest { to_user: RECIPIENT },
+            WorldHostC2SMessage::QueryRequest {
+                friends: vec![RECIPIENT],
+            },
+            WorldHostC2SMessage::RequestDirectJoin {
+                connection_id: recipient_id,
+            },
+            request_punch_open(recipient_id),
+        ] {
+            handle_message(message, &sender, &server).await.unwrap();
+        }
+
+        if let Some((recipient, mut recipient_client)) = recipient {
+            for message in [
+                WorldHostC2SMessage::ListOnline {
+                    friends: vec![SENDER],
+                },
+                WorldHostC2SMessage::SubscribePresence {
+                    friends: vec![SENDER],
+                },
+                WorldHostC2SMessage::PublishedWorld {
+                    friends: vec![SENDER],
+                },
+                WorldHostC2SMessage::ClosedWorld {
+                    friends: vec![SENDER],
+                },
+            ] {
+                handle_message(message, &recipient, &server).await.unwrap();
+            }
+            presence::notify_online(&recipient, &server).await;
+            recipient.mark_closed();
+            server.connections.remove(&recipient);
+            presence::notify_offline(&recipient, &server).await;
+            // Nothing from SENDER reached RECIPIENT either
+            assert_eq!(
+                drain(&mut recipient_client, CURRENT).await,
+                [WorldHostS2CMessage::FriendsOnline { friends: vec![] }]
+            );
+        }
+        drain(&mut sender_client, CURRENT).await
+    }
+
+    #[tokio::test]
+    async fn block_is_indistinguishable_from_offline() {
+        let offline = seen_by_blocked_sender(false).await;
+        assert_eq!(
+            offline,
+            [
+                WorldHostS2CMessage::FriendsOnline { friends: vec![] },
+                WorldHostS2CMessage::ConnectionNotFound {
+                    connection_id: ConnectionId::new(2).unwrap(),
+                },
+                WorldHostS2CMessage::PunchRequestCancelled {
+                    punch_id: Uuid::from_u128(0x9000),
+                },
+            ]
+        );
+        assert_eq!(seen_by_blocked_sender(true).await, offline);
+    }
+
+    #[tokio::test]
+    async fn blocks_apply_to_every_connection_and_survive_reconnects() {
+        let server = server(None);
+        let (sender, mut sender_client) = connect(&server, 1, SENDER, CURRENT);
+        let (first, _first_client) = connect(&server, 2, RECIPIENT, CURRENT);
+        handle_message(
+            WorldHostC2SMessage::BlockUser { user: SENDER },
+            &first,
+            &server,
+        )
+        .await
+        .unwrap();
+        first.mark_closed();
+        server.connections.remove(&first);
+
+        let (second, mut second_client) = connect(&server, 3, RECIPIENT, CURRENT);
+        handle_message(
+            WorldHostC2SMessage::RequestDirectJoin {
+                connection_id: second.id,
+            },
+            &sender,
+            &server,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            read_test_message(&mut sender_client, CURRENT)
+                .await
+                .unwrap(),
+            WorldHostS2CMessage::ConnectionNotFound {
+                connection_id: second.id,
+            }
+        );
+        assert_nothing_sent(&mut second_client, CURRENT).await;
+
+        handle_message(
+            WorldHostC2SMessage::UnblockUser { user: SENDER },
+            &second,
+            &server,
+        )
+        .await
+        .unwrap();
+        handle_message(
+            WorldHostC2SMessage::RequestDirectJoin {
+                connection_id: second.id,
+            },
+            &sender,
+            &server,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            read_test_message(&mut second_client, CURRENT)
+                .await
+                .unwrap(),
+            WorldHostS2CMessage::RequestJoin {
+                user: SENDER,
+                connection_id: sender.id,
+                security: sender.security_level(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn queued_requests_from_blocked_users_are_dropped() {
+        let server = server(None);
+        let (sender, _sender_client) = connect(&server, 1, SENDER, CURRENT);
+        friend_request(&sender, &server, RECIPIENT).await;
+        server
+            .block_lists
+            .lock()
+            .await
+            .block(RECIPIENT, SENDER)
+            .unwrap();
+
+        let (recipient, mut recipient_client) = connect(&server, 2, RECIPIENT, CURRENT);
+        let dequeued = dequeue_friend_requests(RECIPIENT, &[recipient], &server)
+            .await
+            .unwrap();
+        assert_eq!(dequeued, 0);
+        assert_nothing_sent(&mut recipient_client, CURRENT).await;
+    }
+
+    const PUNCH_ID: Uuid = Uuid::from_u128(0x9000);
+
+    fn punch_success(connection_id: ConnectionId, host: &str) -> WorldHostC2SMessage {
+        WorldHostC2SMessage::PunchSuccess {
+            connection_id,
+            punch_id: PUNCH_ID,
+            host: host.to_string(),
+            port: 25565,
+        }
+    }
+
+    /// Connects an initiator and a target, and forwards a punch between them
+    async fn open_punch(
+        server: &ServerState,
+    ) -> (Connection, DuplexStream, Connection, DuplexStream) {
+        let (initiator, initiator_client) = connect(server, 1, SENDER, CURRENT);
+        let (target, mut target_client) = connect(server, 2, RECIPIENT, CURRENT);
+        handle_message(request_punch_open(target.id), &initiator, server)
+            .await
+            .unwrap();
+        assert!(matches!(
+            read_test_message(&mut target_client, CURRENT)
+                .await
+                .unwrap(),
+            WorldHostS2CMessage::PunchOpenRequest {
+                punch_id: PUNCH_ID,
+                ..
+            }
+        ));
+        (initiator, initiator_client, target, target_client)
+    }
+
+    #[tokio::test]
+    async fn punch_success_is_relayed_once() {
+        let server = server(None);
+        let (initiator, mut initiator_client, target, _target_client) = open_punch(&server).await;
+        handle_message(punch_success(initiator.id, "example.com"), &target, &server)
+            .await
+            .unwrap();
+        assert_eq!(
+            read_test_message(&mut initiator_client, CURRENT)
+                .await
+                .unwrap(),
+            WorldHostS2CMessage::PunchSuccess {
+                punch_id: PUNCH_ID,
+                host: "example.com".to_string(),
+                port: 25565,
+            }
+        );
+        assert!(server.active_punches.lock().await.is_empty());
+
+        handle_message(punch_success(initiator.id, "example.net"), &target, &server)
+            .await
+            .unwrap();
+        assert_nothing_sent(&mut initiator_client, CURRENT).await;
+    }
+
+    #[tokio::test]
+    async fn punch_failed_cancels_the_punch() {
+        let server = server(None);
+        let (initiator, mut initiator_client, target, _target_client) = open_punch(&server).await;
+        handle_message(
+            WorldHostC2SMessage::PunchFailed {
+                target_connection: initiator.id,
+                punch_id: PUNCH_ID,
+            },
+            &target,
+            &server,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            read_test_message(&mut initiator_client, CURRENT)
+                .await
+                .unwrap(),
+            WorldHostS2CMessage::PunchRequestCancelled { punch_id: PUNCH_ID }
+        );
+        handle_message(punch_success(initiator.id, "example.com"), &target, &server)
+            .await
+            .unwrap();
+        assert_nothing_sent(&mut initiator_client, CURRENT).await;
+    }
+
+    #[tokio::test]
+    async fn spoofed_punch_results_are_dropped() {
+        let server = server(None);
+        let (initiator, mut initiator_client, target, _target_client) = open_punch(&server).await;
+        let (spoofer, _spoofer_client) = connect(&server, 3, OTHER_USER, CURRENT);
+
+        handle_message(
+            punch_success(initiator.id, "example.net"),
+            &spoofer,
+            &server,
+        )
+        .await
+        .unwrap();
+        handle_message(
+            WorldHostC2SMessage::PunchFailed {
+                target_connection: initiator.id,
+                punch_id: PUNCH_ID,
+            },
+            &spoofer,
+            &server,
+        )
+        .await
+        .unwrap();
+        // An ID that was never forwarded
+        handle_message(
+            WorldHostC2SMessage::PunchSuccess {
+                connection_id: initiator.id,
+                punch_id: Uuid::from_u128(0x9001),
+                host: "example.net".to_string(),
+                port: 25565,
+            },
+            &target,
+            &server,
+        )
+        .await
+        .unwrap();
+        assert_nothing_sent(&mut initiator_client, CURRENT).await;
+
+        // The spoofed attempts didn't use up the real punch
+        handle_message(punch_success(initiator.id, "example.com"), &target, &server)
+            .await
+            .unwrap();
+        assert!(matches!(
+            read_test_message(&mut initiator_client, CURRENT)
+                .await
+                .unwrap(),
+            WorldHostS2CMessage::PunchSuccess { .. }
+        ));
+    }
+
+    #[test]
+    fn public_punch_addresses_accepted() {
+        for host in [
+            "example.com",
+            "mc-1.example.com",
+            "203.0.113.5",
+            "2001:db8::1",
+            "[2001:db8::1]",
+        ] {
+            assert!(validate_punch_address(host, 25565).is_ok(), "{host}");
+        }
+        assert!(validate_punch_address("example.com", 1).is_ok());
+    }
+
+    #[test]
+    fn local_punch_addresses_rejected() {
+        for host in [
+            // Loopback
+            "localhost",
+            "LOCALHOST",
+            "127.0.0.1",
+            "127.1.2.3",
+            "::1",
+            "[::1]",
+            "::ffff:127.0.0.1",
+            // Private and link-local
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.1.1",
+            "fd00::1",
+            "fe80::1%eth0",
+            "[fe80::1%25eth0]",
+            // Multicast and broadcast
+            "224.0.0.1",
+            "239.255.255.250",
+            "ff02::1",
+            "255.255.255.255",
+            // Unspecified
+            "0.0.0.0",
+            "::",
+        ] {
+            assert!(validate_punch_address(host, 25565).is_err(), "{host}");
+        }
+    }
+
+    #[test]
+    fn malformed_punch_addresses_rejected() {
+        for host in [
+            "",
+            &"a".repeat(MAX_HOST_LENGTH + 1),
+            "example.com\n",
+            "exa mple.com",
+            "example.com/path",
+        ] {
+            assert!(validate_punch_address(host, 25565).is_err(), "{host:?}");
+        }
+        assert!(validate_punch_address("example.com", 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn invalid_punch_requests_not_forwarded() {
+        let server = server(None);
+        let (initiator, _initiator_client) = connect(&server, 1, SENDER, CURRENT);
+        let (target, mut target_client) = connect(&server, 2, RECIPIENT, CURRENT);
+        let with =
+            |purpose: &str, my_host: &str, my_port: u16| WorldHostC2SMessage::RequestPunchOpen {
+                target_connection: target.id,
+                purpose: purpose.to_string(),
+                punch_id: PUNCH_ID,
+                my_host: my_host.to_string(),
+                my_port,
+                my_local_host: "example.com".to_string(),
+                my_local_port: 25565,
+            };
+        for message in [
+            with(
+                &"x".repeat(MAX_PUNCH_PURPOSE_LENGTH + 1),
+                "example.com",
+                25565,
+            ),
+            with("te\u{7}st", "example.com", 25565),
+            with("test", "127.0.0.1", 25565),
+            with("test", "192.168.1.1", 25565),
+            with("test", "224.0.0.1", 25565),
+            with("test", "example.com", 0),
+        ] {
+            assert!(matches!(
+                handle_message(message, &initiator, &server).await,
+                Err(HandleError::Recoverable(_))
+            ));
+        }
+        assert_nothing_sent(&mut target_client, CURRENT).await;
+        assert!(server.active_punches.lock().await.is_empty());
+    }
+
+    fn grant_join(connection_id: ConnectionId) -> WorldHostC2SMessage {
+        WorldHostC2SMessage::JoinGranted {
+            connection_id,
+            join_type: JoinType::UPnP(25565),
+        }
+    }
+
+    async fn request_direct_join(joiner: &Connection, host: &Connection, server: &ServerState) {
+        handle_message(
+            WorldHostC2SMessage::RequestDirectJoin {
+                connection_id: host.id,
+            },
+            joiner,
+            server,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn unrequested_join_dropped() {
+        let server = server(None);
+        let (host, _host_client) = connect(&server, 1, SENDER, CURRENT);
+        let (joiner, mut joiner_client) = connect(&server, 2, RECIPIENT, CURRENT);
+        assert!(matches!(
+            handle_message(grant_join(joiner.id), &host, &server).await,
+            Err(HandleError::Recoverable(_))
+        ));
+        assert_nothing_sent(&mut joiner_client, CURRENT).await;
+    }
+
+    #[tokio::test]
+    async fn unrequested_join_allowed_by_config() {
+        let mut config = FullServerConfig::for_test();
+        config.allow_unrequested_joins = true;
+        let server = ServerState::new(config);
+        let (host, _host_client) = connect(&server, 1, SENDER, CURRENT);
+        let (joiner, mut joiner_client) = connect(&server, 2, RECIPIENT, CURRENT);
+        handle_message(grant_join(joiner.id), &host, &server)
+            .await
+            .unwrap();
+        assert!(matches!(
+            read_test_message(&mut joiner_client, CURRENT)
+                .await
+                .unwrap(),
+            WorldHostS2CMessage::OnlineGame { port: 25565, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn requested_join_granted_once() {
+        let server = server(None);
+        let (host, mut host_client) = connect(&server, 1, SENDER, CURRENT);
+        let (joiner, mut joiner_client) = connect(&server, 2, RECIPIENT, CURRENT);
+        request_direct_join(&joiner, &host, &server).await;
+        assert!(matches!(
+            read_test_message(&mut host_client, CURRENT).await.unwrap(),
+            WorldHostS2CMessage::RequestJoin { .. }
+        ));
+
+        handle_message(grant_join(joiner.id), &host, &server)
+            .await
+            .unwrap();
+        assert!(matches!(
+            read_test_message(&mut joiner_client, CURRENT)
+                .await
+                .unwrap(),
+            WorldHostS2CMessage::OnlineGame { .. }
+        ));
+        assert!(
+            handle_message(grant_join(joiner.id), &host, &server)
+                .await
+                .is_err()
+        );
+        assert_nothing_sent(&mut joiner_client, CURRENT).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn join_request_expires() {
+        let server = server(None);
+        let (host, _host_client) = connect(&server, 1, SENDER, CURRENT);
+        let (joiner, mut joiner_client) = connect(&server, 2, RECIPIENT, CURRENT);
+        request_direct_join(&joiner, &host, &server).await;
+        tokio::time::advance(JOIN_REQUEST_EXPIRY).await;
+        assert!(matches!(
+            handle_message(grant_join(joiner.id), &host, &server).await,
+            Err(HandleError::Recoverable(_))
+        ));
+        assert_nothing_sent(&mut joiner_client, CURRENT).await;
+    }
+
+    #[tokio::test]
+    async fn friend_or_unpublished() {
+        let server = server(None);
+        let (host, _host_client) = connect(&server, 1, SENDER, CURRENT);
+        let (friend, _friend_client) = connect(&server, 2, RECIPIENT, CURRENT);
+        let (stranger, _stranger_client) = connect(&server, 3, OTHER_USER, CURRENT);
+        // Hosts that haven't published accept everyone
+        assert!(is_friend_or_unpublished(&host, &friend).await);
+        assert!(is_friend_or_unpublished(&host, &stranger).await);
+
+        host.state
+            .lock()
+            .await
+            .open_to_friends
+            .insert(friend.user_uuid);
+        assert!(is_friend_or_unpublished(&host, &friend).await);
+        assert!(!is_friend_or_unpublished(&host, &stranger).await);
+    }
+
+    #[tokio::test]
+    async fn friends_only_direct_joins_from_strangers_dropped() {
+        let mut config = FullServerConfig::for_test();
+        config.friends_only_direct_joins = true;
+        let server = ServerState::new(config);
+        let (host, mut host_client) = connect(&server, 1, SENDER, CURRENT);
+        let (friend, _friend_client) = connect(&server, 2, RECIPIENT, CURRENT);
+        let (stranger, _stranger_client) = connect(&server, 3, OTHER_USER, CURRENT);
+        handle_message(
+            WorldHostC2SMessage::PublishedWorld {
+                friends: vec![friend.user_uuid],
+            },
+            &host,
+            &server,
+        )
+        .await
+        .unwrap();
+        drain(&mut host_client, CURRENT).await;
+
+        request_direct_join(&stranger, &host, &server).await;
+        assert_nothing_sent(&mut host_client, CURRENT).await;
+        request_direct_join(&friend, &host, &server).await;
+        assert!(matches!(
+            read_test_message(&mut host_client, CURRENT).await.unwrap(),
+            WorldHostS2CMessage::RequestJoin {
+                user: RECIPIENT,
+                ..
+            }
+        ));
+    }
+
+    fn friends(count: usize) -> Vec<Uuid> {
+        (0..count as u128)
+            .map(|i| Uuid::from_u128(0x10000 + i))
+            .collect()
+    }
+
+    #[test]
+    fn friends_len_limit() {
+        let mut config = FullServerConfig::for_test();
+        config.max_friends = 4;
+        let server = ServerState::new(config);
+        assert!(check_friends_len(&friends(0), &server).is_ok());
+        assert!(check_friends_len(&friends(4), &server).is_ok());
+        assert!(matches!(
+            check_friends_len(&friends(5), &server),
+            Err(HandleError::Recoverable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversized_friend_lists_rejected() {
+        let mut config = FullServerConfig::for_test();
+        config.max_friends = 4;
+        let server = ServerState::new(config);
+        let (sender, mut sender_client) = connect(&server, 1, SENDER, CURRENT);
+        let (recipient, mut recipient_client) = connect(&server, 2, RECIPIENT, CURRENT);
+        list_online(&recipient, &server, vec![SENDER]).await;
+        drain(&mut sender_client, CURRENT).await;
+        drain(&mut recipient_client, CURRENT).await;
+
+        let mut over = friends(4);
+        over.push(RECIPIENT);
+        for message in [
+            WorldHostC2SMessage::ListOnline {
+                friends: over.clone(),
+            },
+            WorldHostC2SMessage::PublishedWorld {
+                friends: over.clone(),
+            },
+            WorldHostC2SMessage::QueryRequest {
+                friends: over.clone(),
+            },
+        ] {
+            assert!(matches!(
+                handle_message(message, &sender, &server).await,
+                Err(HandleError::Recoverable(_))
+            ));
+        }
+        assert_nothing_sent(&mut recipient_client, CURRENT).await;
+        assert!(sender.state.lock().await.open_to_friends.is_empty());
+
+        // Exactly at the limit is fine
+        let mut at_limit = friends(3);
+        at_limit.push(RECIPIENT);
+        handle_message(
+            WorldHostC2SMessage::QueryRequest { friends: at_limit },
+            &sender,
+            &server,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            read_test_message(&mut recipient_client, CURRENT)
+                .await
+                .unwrap(),
+            WorldHostS2CMessage::QueryRequest { .. }
+        ));
+    }
+
+    async fn publish(connection: &Connection, server: &ServerState, friends: Vec<Uuid>) {
+        handle_message(
+            WorldHostC2SMessage::PublishedWorld { friends },
+            connection,
+            server,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn published_world_truncated_to_max_open_to_friends() {
+        let mut config = FullServerConfig::for_test();
+        config.max_open_to_friends = 2;
+        let server = ServerState::new(config);
+        let (host, mut host_client) = connect(&server, 1, SENDER, CURRENT);
+        let (_first, mut first_client) = connect(&server, 2, RECIPIENT, CURRENT);
+        let (_second, mut second_client) = connect(&server, 3, OTHER_USER, CURRENT);
+        let third_user = Uuid::from_u128(0x4000);
+        let (_third, mut third_client) = connect(&server, 4, third_user, CURRENT);
+
+        publish(&host, &server, vec![RECIPIENT, OTHER_USER, third_user]).await;
+        for client in [&mut first_client, &mut second_client] {
+            assert!(matches!(
+                read_test_message(client, CURRENT).await.unwrap(),
+                WorldHostS2CMessage::PublishedWorld { user: SENDER, .. }
+            ));
+        }
+        assert_nothing_sent(&mut third_client, CURRENT).await;
+        assert!(matches!(
+            read_test_message(&mut host_client, CURRENT).await.unwrap(),
+            WorldHostS2CMessage::Error {
+                critical: false,
+                ..
+            }
+        ));
+        assert_eq!(
+            host.state.lock().await.open_to_friends,
+            HashSet::from([RECIPIENT, OTHER_USER])
+        );
+
+        // Friends it's already open to can be republished to, and the warning isn't repeated
+        publish(&host, &server, vec![third_user, RECIPIENT]).await;
+        assert!(matches!(
+            read_test_message(&mut first_client, CURRENT).await.unwrap(),
+            WorldHostS2CMessage::PublishedWorld { user: SENDER, .. }
+        ));
+        assert_nothing_sent(&mut third_client, CURRENT).await;
+        assert_nothing_sent(&mut host_client, CURRENT).await;
+
+        // Closing to a friend frees a slot
+        handle_message(
+            WorldHostC2SMessage::ClosedWorld {
+                friends: vec![OTHER_USER],
+            },
+            &host,
+            &server,
+        )
+        .await
+        .unwrap();
+        publish(&host, &server, vec![third_user]).await;
+        assert!(matches!(
+            read_test_message(&mut third_client, CURRENT).await.unwrap(),
+            WorldHostS2CMessage::PublishedWorld { user: SENDER, .. }
+        ));
+    }
+
+    #[test]
+    fn broadcast_doesnt_allocate_per_friend() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let server = server(None);
+        let (sender, _sender_client) = connect(&server, 0, SENDER, CURRENT);
+        let friends: Vec<_> = (1..=1000).map(|i| Uuid::from_u128(0x10000 + i)).collect();
+        // Too old for the message, so nothing is sent, and any allocation would come from looking
+        // up their connections
+        let _clients: Vec<_> = friends
+            .iter()
+            .enumerate()
+            .map(|(i, &friend)| connect(&server, i as u64 + 1, friend, STABLE))
+            .collect();
+        let message = WorldHostS2CMessage::IsOfflineTo { user: SENDER };
+        assert!(message.first_protocol() > STABLE);
+
+        let ((), count) = allocation_count(|| {
+            runtime.block_on(broadcast_to_friends(
+                &sender,
+                &server,
+                friends.clone(),
+                message.clone(),
+            ))
+        });
+        assert!(count < 10, "{count} allocations");
+        assert_eq!(
+            server
+                .analytics_counters
+                .skipped_old_protocol
+                .load(Ordering::Relaxed),
+            1000
         );
     }
 }
@@ -0,0 +1,44 @@
+//! Tables of every message type in each direction. Name and first protocol lookups are generated
+//! from these, and they're checked for consistency at compile time.
+
+use crate::protocol::protocol_versions;
+
+#[derive(Copy, Clone, Debug)]
+pub struct MessageInfo {
+    pub name: &'static str,
+    pub id: u8,
+    pub first_protocol: u32,
+}
+
+impl MessageInfo {
+    pub const fn new(name: &'static str, id: u8, first_protocol: u32) -> Self {
+        Self {
+            name,
+            id,
+            first_protocol,
+        }
+    }
+}
+
+/// Panics (at compile time when used in a const) unless every entry's ID matches its index and its
+/// first protocol is supported
+pub const fn check_table(table: &[MessageInfo]) {
+    let mut i = 0;
+    while i < table.len() {
+        let info = &table[i];
+        assert!(
+            info.id as usize == i,
+            "Message IDs must be unique, dense, and in order"
+        );
+        assert!(
+            info.first_protocol >= *protocol_versions::SUPPORTED.start()
+                && info.first_protocol <= protocol_versions::CURRENT,
+            "Message first protocols must be supported"
+        );
+        i += 1;
+    }
+}
+
+pub fn lookup(table: &'static [MessageInfo], id: u8) -> Option<&'static MessageInfo> {
+    table.get(id as usize)
+}
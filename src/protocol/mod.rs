@@ -1,8 +1,17 @@
+pub mod block_lists;
 pub mod c2s_message;
+pub mod compat;
 pub mod data_ext;
+pub mod delivered_friend_requests;
 pub mod join_type;
 pub mod message_handler;
+pub mod message_table;
+pub mod pending_joins;
 pub mod port_lookup;
+pub mod presence;
 pub mod protocol_versions;
+pub mod punch;
 pub mod s2c_message;
+#[cfg(any(test, feature = "client"))]
+pub mod s2c_parser;
 pub mod security;
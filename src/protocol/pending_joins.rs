@@ -0,0 +1,89 @@
+use crate::connection::connection_id::ConnectionId;
+use crate::util::shrink_if_sparse;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How long a host has to grant a join request
+pub const JOIN_REQUEST_EXPIRY: Duration = Duration::from_secs(60);
+
+/// Expired requests are pruned when adding would go over this many
+const PRUNE_THRESHOLD: usize = 1 << 16;
+
+/// Join requests that were forwarded to hosts, keyed by (requester, host). Hosts may only grant
+/// joins to connections that asked them.
+#[derive(Default)]
+pub struct PendingJoins {
+    requests: HashMap<(ConnectionId, ConnectionId), Instant>,
+}
+
+impl PendingJoins {
+    pub fn add(&mut self, requester: ConnectionId, host: ConnectionId) {
+        if self.requests.len() >= PRUNE_THRESHOLD {
+            self.prune();
+        }
+        self.requests
+            .insert((requester, host), Instant::now() + JOIN_REQUEST_EXPIRY);
+    }
+
+    /// Removes the request from `requester` to `host`, returning whether it hadn't expired
+    pub fn take(&mut self, requester: ConnectionId, host: ConnectionId) -> bool {
+        self.requests
+            .remove(&(requester, host))
+            .is_some_and(|expiry| expiry > Instant::now())
+    }
+
+    /// Drops expired requests and returns how many slots were reclaimed
+    pub fn shrink(&mut self) -> usize {
+        self.prune();
+        shrink_if_sparse(&mut self.requests)
+    }
+
+    fn prune(&mut self) {
+        let now = Instant::now();
+        self.requests.retain(|_, expiry| *expiry > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::advance;
+
+    fn id(id: u64) -> ConnectionId {
+        ConnectionId::new(id).unwrap()
+    }
+
+    #[test]
+    fn taken_once() {
+        let mut pending_joins = PendingJoins::default();
+        pending_joins.add(id(1), id(2));
+        assert!(!pending_joins.take(id(2), id(1)));
+        assert!(!pending_joins.take(id(1), id(3)));
+        assert!(pending_joins.take(id(1), id(2)));
+        assert!(!pending_joins.take(id(1), id(2)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expires() {
+        let mut pending_joins = PendingJoins::default();
+        pending_joins.add(id(1), id(2));
+        pending_joins.add(id(1), id(3));
+        advance(JOIN_REQUEST_EXPIRY - Duration::from_secs(1)).await;
+        assert!(pending_joins.take(id(1), id(2)));
+
+        advance(Duration::from_secs(1)).await;
+        assert!(!pending_joins.take(id(1), id(3)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shrink_drops_expired() {
+        let mut pending_joins = PendingJoins::default();
+        pending_joins.add(id(1), id(2));
+        advance(JOIN_REQUEST_EXPIRY).await;
+        pending_joins.add(id(1), id(3));
+        pending_joins.shrink();
+        assert_eq!(pending_joins.requests.len(), 1);
+        assert!(pending_joins.take(id(1), id(3)));
+    }
+}
@@ -0,0 +1,502 @@
+use crate::connection::Connection;
+use crate::connection::connection_id::ConnectionId;
+use crate::protocol::message_handler::{either_blocks, send_safely};
+use crate::protocol::s2c_message::WorldHostS2CMessage;
+use crate::server_state::ServerState;
+use crate::util::shrink_if_sparse;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::Arc;
+use uuid::Uuid;
+
+pub const MAX_SUBSCRIPTIONS_PER_CONNECTION: usize = 1024;
+pub const MAX_TOTAL_SUBSCRIPTIONS: usize = 1 << 20;
+
+/// Reverse index of presence subscriptions: which connections want to be told when a user comes
+/// online or goes offline.
+pub struct PresenceSubscriptions {
+    subscribers: HashMap<Uuid, HashSet<ConnectionId>>,
+    total: usize,
+    max_total: usize,
+}
+
+impl Default for PresenceSubscriptions {
+    fn default() -> Self {
+        Self::with_max_total(MAX_TOTAL_SUBSCRIPTIONS)
+    }
+}
+
+impl PresenceSubscriptions {
+    pub fn with_max_total(max_total: usize) -> Self {
+        Self {
+            subscribers: HashMap::new(),
+            total: 0,
+            max_total,
+        }
+    }
+
+    fn add(&mut self, user: Uuid, subscriber: ConnectionId) -> bool {
+        if self.total >= self.max_total {
+            return false;
+        }
+        if self.subscribers.entry(user).or_default().insert(subscriber) {
+            self.total += 1;
+        }
+        true
+    }
+
+    fn remove(&mut self, user: &Uuid, subscriber: ConnectionId) {
+        if let Some(subscribers) = self.subscribers.get_mut(user) {
+            if subscribers.remove(&subscriber) {
+                self.total -= 1;
+            }
+            if subscribers.is_empty() {
+                self.subscribers.remove(user);
+            }
+        }
+    }
+
+    /// Returns how many slots were reclaimed
+    pub fn shrink(&mut self) -> usize {
+        shrink_if_sparse(&mut self.subscribers)
+    }
+
+    fn subscribers_of(&self, user: &Uuid) -> Vec<ConnectionId> {
+        match self.subscribers.get(user) {
+            Some(subscribers) => subscribers.iter().copied().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+pub async fn subscribe(
+    connection: &Connection,
+    server: &ServerState,
+    mut friends: Vec<Uuid>,
+) -> io::Result<()> {
+    if friends.len() > MAX_SUBSCRIPTIONS_PER_CONNECTION {
+        warn!(
+            "Connection {} tried to subscribe to {} users. Only the first {MAX_SUBSCRIPTIONS_PER_CONNECTION} will be used.",
+            connection.id,
+            friends.len()
+        );
+        friends.truncate(MAX_SUBSCRIPTIONS_PER_CONNECTION);
+    }
+    let new_friends = {
+        let mut state = connection.state.lock().await;
+        let mut presence = server.presence_subscriptions.lock().await;
+        let old_friends = std::mem::take(&mut state.presence_subscriptions);
+        for friend in old_friends.difference(&friends.iter().copied().collect()) {
+            presence.remove(friend, connection.id);
+        }
+        let mut new_friends = Vec::new();
+        for friend in friends {
+            if friend == connection.user_uuid || state.presence_subscriptions.contains(&friend) {
+                continue;
+            }
+            if !old_friends.contains(&friend) {
+                if !presence.add(friend, connection.id) {
+                    warn!(
+                        "Global presence subscription limit reached. Ignoring further subscriptions from {}.",
+                        connection.id
+                    );
+                    break;
+                }
+                new_friends.push(friend);
+            }
+            state.presence_subscriptions.insert(friend);
+        }
+        new_friends
+    };
+    for friend in new_friends {
+        let mut online = false;
+        for other in server.connections.user_connections(friend) {
+            if shares_presence_with(server, &other, connection.user_uuid).await {
+                online = true;
+                break;
+            }
+        }
+        if online {
+            connection
+                .send_message(&WorldHostS2CMessage::IsOnlineTo { user: friend })
+                .await?;
+        }
+        // Subscribing is consent for the friend to be told about this connection, so friends that
+        // were already subscribed to it learn that it's online now
+        let message = WorldHostS2CMessage::IsOnlineTo {
+            user: connection.user_uuid,
+        };
+        if either_blocks(server, connection.user_uuid, friend).await {
+            continue;
+        }
+        for other in server.connections.user_connections(friend) {
+            if other.id != connection.id
+                && other
+                    .state
+                    .lock()
+                    .await
+                    .presence_subscriptions
+                    .contains(&connection.user_uuid)
+            {
+                send_safely(server, connection, &other, &message).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `user` may be told about `target`'s presence. Anyone could subscribe to anyone, so this
+/// is only the case if `target`'s client named `user` as a friend, in ListOnline,
+/// SubscribePresence, or PublishedWorld, or if either of them has a friend request pending for the
+/// other. Never if either of them blocked the other.
+pub async fn shares_presence_with(server: &ServerState, target: &Connection, user: Uuid) -> bool {
+    if either_blocks(server, target.user_uuid, user).await {
+        return false;
+    }
+    {
+        let state = target.state.lock().await;
+        if state.listed_friends.contains(&user)
+            || state.presence_subscriptions.contains(&user)
+            || state.open_to_friends.contains(&user)
+        {
+            return true;
+        }
+    }
+    let remembered = server.remembered_friend_requests.lock().await;
+    [(target.user_uuid, user), (user, target.user_uuid)]
+        .iter()
+        .any(|(from, to)| remembered.get(from).is_some_and(|sent| sent.contains(to)))
+}
+
+/// Removes a closed connection's subscriptions. If its ID has been taken over by another
+/// connection, subscriptions that the new connection also holds are kept, since they're keyed by
+/// ID.
+pub async fn unsubscribe_all(connection: &Connection, server: &ServerState) {
+    let friends = std::mem::take(&mut connection.state.lock().await.presence_subscriptions);
+    let replacement = server
+        .connections
+        .by_id(connection.id)
+        .filter(|current| !Arc::ptr_eq(current, connection));
+    // Locked before the presence subscriptions, like subscribe does, so that the replacement can't
+    // subscribe in between
+    let replacement_state = match &replacement {
+        Some(replacement) => Some(replacement.state.lock().await),
+        None => None,
+    };
+    let mut presence = server.presence_subscriptions.lock().await;
+    for friend in friends {
+        if replacement_state
+            .as_ref()
+            .is_none_or(|state| !state.presence_subscriptions.contains(&friend))
+        {
+            presence.remove(&friend, connection.id);
+        }
+    }
+}
+
+pub async fn notify_online(connection: &Connection, server: &ServerState) {
+    broadcast_presence(
+        connection,
+        server,
+        WorldHostS2CMessage::IsOnlineTo {
+            user: connection.user_uuid,
+        },
+    )
+    .await;
+}
+
+pub async fn notify_offline(connection: &Connection, server: &ServerState) {
+    broadcast_presence(
+        connection,
+        server,
+        WorldHostS2CMessage::IsOfflineTo {
+            user: connection.user_uuid,
+        },
+    )
+    .await;
+}
+
+async fn broadcast_presence(
+    connection: &Connection,
+    server: &ServerState,
+    message: WorldHostS2CMessage,
+) {
+    let subscribers = server
+        .presence_subscriptions
+        .lock()
+        .await
+        .subscribers_of(&connection.user_uuid);
+    if subscribers.is_empty() {
+        return;
+    }
+    let subscribers: Vec<Connection> = subscribers
+        .into_iter()
+        .filter_map(|id| server.connections.by_id(id))
+        .collect();
+    for subscriber in subscribers {
+        if shares_presence_with(server, connection, subscriber.user_uuid).await {
+            send_safely(server, connection, &subscriber, &message).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{ConnectionInfo, read_test_message};
+    use crate::protocol::protocol_versions::CURRENT;
+    use crate::server_state::FullServerConfig;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+    use tokio::io::DuplexStream;
+    use tokio::time::timeout;
+
+    const USER: Uuid = Uuid::from_u128(1);
+    const FRIEND: Uuid = Uuid::from_u128(2);
+    const OTHER_FRIEND: Uuid = Uuid::from_u128(3);
+
+    fn connection(id: u64, user: Uuid) -> Connection {
+        ConnectionInfo::for_test(
+            ConnectionId::new(id).unwrap(),
+            user,
+            Ipv4Addr::LOCALHOST.into(),
+            CURRENT,
+        )
+        .0
+    }
+
+    /// An open connection in the server's set, and the stream its client reads
+    fn connect(server: &ServerState, id: u64, user: Uuid) -> (Connection, DuplexStream) {
+        let (connection, client) = ConnectionInfo::for_test(
+            ConnectionId::new(id).unwrap(),
+            user,
+            Ipv4Addr::LOCALHOST.into(),
+            CURRENT,
+        );
+        assert!(server.connections.add(connection.clone()));
+        (connection, client)
+    }
+
+    /// The next message sent to a client, if one was already waiting
+    async fn next_message(client: &mut DuplexStream) -> Option<WorldHostS2CMessage> {
+        timeout(
+            Duration::from_millis(50),
+            read_test_message(client, CURRENT),
+        )
+        .await
+        .ok()
+        .map(Result::unwrap)
+    }
+
+    /// Closes a connection the way the main server does
+    async fn disconnect(server: &ServerState, connection: &Connection) {
+        connection.mark_closed();
+        if server.connections.remove(connection) {
+            notify_offline(connection, server).await;
+        }
+        unsubscribe_all(connection, server).await;
+    }
+
+    async fn subscribers_of(server: &ServerState, user: Uuid) -> Vec<ConnectionId> {
+        server
+            .presence_subscriptions
+            .lock()
+            .await
+            .subscribers_of(&user)
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_all_removes_every_subscription() {
+        let server = ServerState::new(FullServerConfig::for_test());
+        let connection = connection(1, USER);
+        server.connections.add(connection.clone());
+        subscribe(&connection, &server, vec![FRIEND, OTHER_FRIEND])
+            .await
+            .unwrap();
+        assert_eq!(subscribers_of(&server, FRIEND).await, [connection.id]);
+
+        connection.mark_closed();
+        server.connections.remove(&connection);
+        unsubscribe_all(&connection, &server).await;
+        assert!(subscribers_of(&server, FRIEND).await.is_empty());
+        assert!(subscribers_of(&server, OTHER_FRIEND).await.is_empty());
+        assert_eq!(server.presence_subscriptions.lock().await.total, 0);
+    }
+
+    #[tokio::test]
+    async fn takeover_keeps_replacement_subscriptions() {
+        let server = ServerState::new(FullServerConfig::for_test());
+        let old = connection(1, USER);
+        server.connections.add(old.clone());
+        subscribe(&old, &server, vec![FRIEND, OTHER_FRIEND])
+            .await
+            .unwrap();
+
+        let new = connection(1, USER);
+        old.mark_closed();
+        server.connections.add_force(new.clone());
+        subscribe(&new, &server, vec![FRIEND]).await.unwrap();
+
+        // The old connection is cleaned up after the new one subscribed
+        server.connections.remove(&old);
+        unsubscribe_all(&old, &server).await;
+        assert_eq!(subscribers_of(&server, FRIEND).await, [new.id]);
+        assert!(subscribers_of(&server, OTHER_FRIEND).await.is_empty());
+        assert_eq!(server.presence_subscriptions.lock().await.total, 1);
+
+        new.mark_closed();
+        server.connections.remove(&new);
+        unsubscribe_all(&new, &server).await;
+        assert!(subscribers_of(&server, FRIEND).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscribe_reports_friends_that_named_the_subscriber() {
+        let server = ServerState::new(FullServerConfig::for_test());
+        let (friend, _friend_client) = connect(&server, 2, FRIEND);
+        let (_stranger, _stranger_client) = connect(&server, 3, OTHER_FRIEND);
+        friend.state.lock().await.listed_friends.insert(USER);
+
+        let (connection, mut client) = connect(&server, 1, USER);
+        subscribe(&connection, &server, vec![FRIEND, OTHER_FRIEND])
+            .await
+            .unwrap();
+        assert_eq!(
+            next_message(&mut client).await,
+            Some(WorldHostS2CMessage::IsOnlineTo { user: FRIEND })
+        );
+        // OTHER_FRIEND never named USER, so it looks offline
+        assert_eq!(next_message(&mut client).await, None);
+    }
+
+    #[tokio::test]
+    async fn online_push_once_both_subscribed() {
+        let server = ServerState::new(FullServerConfig::for_test());
+        let (subscriber, mut subscriber_client) = connect(&server, 1, USER);
+        subscribe(&subscriber, &server, vec![FRIEND]).await.unwrap();
+
+        // A connection that hasn't named anyone yet isn't announced
+        let (friend, mut friend_client) = connect(&server, 2, FRIEND);
+        notify_online(&friend, &server).await;
+        assert_eq!(next_message(&mut subscriber_client).await, None);
+
+        subscribe(&friend, &server, vec![USER]).await.unwrap();
+        assert_eq!(
+            next_message(&mut subscriber_client).await,
+            Some(WorldHostS2CMessage::IsOnlineTo { user: FRIEND })
+        );
+        assert_eq!(
+            next_message(&mut friend_client).await,
+            Some(WorldHostS2CMessage::IsOnlineTo { user: USER })
+        );
+    }
+
+    #[tokio::test]
+    async fn online_push_for_pending_friend_request() {
+        let server = ServerState::new(FullServerConfig::for_test());
+        let (subscriber, mut subscriber_client) = connect(&server, 1, USER);
+        subscribe(&subscriber, &server, vec![FRIEND]).await.unwrap();
+        server
+            .remembered_friend_requests
+            .lock()
+            .await
+            .entry(USER)
+            .or_default()
+            .insert(FRIEND);
+
+        let (friend, _friend_client) = connect(&server, 2, FRIEND);
+        notify_online(&friend, &server).await;
+        assert_eq!(
+            next_message(&mut subscriber_client).await,
+            Some(WorldHostS2CMessage::IsOnlineTo { user: FRIEND })
+        );
+    }
+
+    #[tokio::test]
+    async fn offline_push_after_last_connection() {
+        let server = ServerState::new(FullServerConfig::for_test());
+        let (subscriber, mut subscriber_client) = connect(&server, 1, USER);
+        let (friend, _friend_client) = connect(&server, 2, FRIEND);
+        let (second, _second_client) = connect(&server, 3, FRIEND);
+        subscribe(&friend, &server, vec![USER]).await.unwrap();
+        subscribe(&second, &server, vec![USER]).await.unwrap();
+        subscribe(&subscriber, &server, vec![FRIEND]).await.unwrap();
+        assert_eq!(
+            next_message(&mut subscriber_client).await,
+            Some(WorldHostS2CMessage::IsOnlineTo { user: FRIEND })
+        );
+
+        disconnect(&server, &friend).await;
+        assert_eq!(next_message(&mut subscriber_client).await, None);
+        disconnect(&server, &second).await;
+        assert_eq!(
+            next_message(&mut subscriber_client).await,
+            Some(WorldHostS2CMessage::IsOfflineTo { user: FRIEND })
+        );
+    }
+
+    #[tokio::test]
+    async fn no_offline_push_to_strangers() {
+        let server = ServerState::new(FullServerConfig::for_test());
+        let (subscriber, mut subscriber_client) = connect(&server, 1, USER);
+        let (friend, _friend_client) = connect(&server, 2, FRIEND);
+        subscribe(&subscriber, &server, vec![FRIEND]).await.unwrap();
+
+        disconnect(&server, &friend).await;
+        assert_eq!(next_message(&mut subscriber_client).await, None);
+    }
+
+    #[tokio::test]
+    async fn per_connection_cap() {
+        let server = ServerState::new(FullServerConfig::for_test());
+        let (connection, _client) = connect(&server, 1, USER);
+        let friends = (0..MAX_SUBSCRIPTIONS_PER_CONNECTION as u128 + 10)
+            .map(|i| Uuid::from_u128(0x10000 + i))
+            .collect();
+        subscribe(&connection, &server, friends).await.unwrap();
+        assert_eq!(
+            connection.state.lock().await.presence_subscriptions.len(),
+            MAX_SUBSCRIPTIONS_PER_CONNECTION
+        );
+        assert_eq!(
+            server.presence_subscriptions.lock().await.total,
+            MAX_SUBSCRIPTIONS_PER_CONNECTION
+        );
+    }
+
+    #[tokio::test]
+    async fn global_cap() {
+        let server = ServerState::new(FullServerConfig::for_test());
+        *server.presence_subscriptions.lock().await = PresenceSubscriptions::with_max_total(3);
+        let (first, _first_client) = connect(&server, 1, USER);
+        let (second, _second_client) = connect(&server, 2, FRIEND);
+        subscribe(
+            &first,
+            &server,
+            vec![Uuid::from_u128(10), Uuid::from_u128(11)],
+        )
+        .await
+        .unwrap();
+        subscribe(
+            &second,
+            &server,
+            vec![Uuid::from_u128(10), Uuid::from_u128(11)],
+        )
+        .await
+        .unwrap();
+        assert_eq!(server.presence_subscriptions.lock().await.total, 3);
+        assert_eq!(second.state.lock().await.presence_subscriptions.len(), 1);
+
+        // Room is made when subscriptions are cleaned up
+        disconnect(&server, &first).await;
+        subscribe(
+            &second,
+            &server,
+            vec![Uuid::from_u128(10), Uuid::from_u128(11)],
+        )
+        .await
+        .unwrap();
+        assert_eq!(server.presence_subscriptions.lock().await.total, 2);
+    }
+}
@@ -1,11 +1,73 @@
 use std::ops::RangeInclusive;
 
-pub const CURRENT: u32 = 7;
+pub const CURRENT: u32 = 8;
 pub const STABLE: u32 = 7;
 pub const SUPPORTED: RangeInclusive<u32> = 2..=CURRENT;
 
+pub const EXTERNAL_PROXY_PROTOCOL: u32 = 3;
+pub const DIRECT_JOIN_PROTOCOL: u32 = 4;
+pub const NEW_QUERY_RESPONSE_PROTOCOL: u32 = 5;
 pub const NEW_AUTH_PROTOCOL: u32 = 6;
 pub const ENCRYPTED_PROTOCOL: u32 = 7;
+pub const COMPRESSION_PROTOCOL: u32 = 8;
+/// Messages from clients on this protocol or newer can't have trailing bytes
+pub const STRICT_PARSING_PROTOCOL: u32 = 8;
+/// An empty ClosedWorld from clients on this protocol or newer closes the world to everyone
+pub const CLOSE_ALL_PROTOCOL: u32 = 8;
+/// OnlineGame includes the owner's UUID on this protocol or newer
+pub const OWNER_UUID_PROTOCOL: u32 = 8;
+/// Clients send a brand string in the handshake on this protocol or newer
+pub const BRAND_PROTOCOL: u32 = 8;
+/// ConnectionInfo includes the server's version on this protocol or newer
+pub const SERVER_VERSION_PROTOCOL: u32 = 8;
+/// Clients accept a different connection ID than they requested on this protocol or newer
+pub const ASSIGNED_ID_PROTOCOL: u32 = 8;
+
+/// What a client supports, derived once from its protocol version. Check these instead of comparing
+/// versions directly.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ProtocolCapabilities {
+    /// Proxy joins should go through the external proxy assigned to the host
+    pub prefers_external_proxy: bool,
+    /// Joins are requested with RequestDirectJoin rather than RequestJoin
+    pub supports_direct_join: bool,
+    pub supports_new_query_response: bool,
+    pub supports_new_auth: bool,
+    pub supports_encryption: bool,
+    pub supports_compression: bool,
+    /// Messages can't have trailing bytes
+    pub strict_parsing: bool,
+    /// An empty ClosedWorld closes the world to everyone
+    pub supports_close_all: bool,
+    /// OnlineGame includes the owner's UUID
+    pub supports_owner_uuid: bool,
+    /// The handshake ends with the client's brand, such as "fabric 0.5.0+1.21"
+    pub sends_brand: bool,
+    /// ConnectionInfo includes the server's version
+    pub supports_server_version: bool,
+    /// The server may replace a taken connection ID, and the client uses the one in
+    /// ConnectionInfo
+    pub accepts_assigned_id: bool,
+}
+
+impl ProtocolCapabilities {
+    pub fn from_version(protocol_version: u32) -> Self {
+        Self {
+            prefers_external_proxy: protocol_version >= EXTERNAL_PROXY_PROTOCOL,
+            supports_direct_join: protocol_version >= DIRECT_JOIN_PROTOCOL,
+            supports_new_query_response: protocol_version >= NEW_QUERY_RESPONSE_PROTOCOL,
+            supports_new_auth: protocol_version >= NEW_AUTH_PROTOCOL,
+            supports_encryption: protocol_version >= ENCRYPTED_PROTOCOL,
+            supports_compression: protocol_version >= COMPRESSION_PROTOCOL,
+            strict_parsing: protocol_version >= STRICT_PARSING_PROTOCOL,
+            supports_close_all: protocol_version >= CLOSE_ALL_PROTOCOL,
+            supports_owner_uuid: protocol_version >= OWNER_UUID_PROTOCOL,
+            sends_brand: protocol_version >= BRAND_PROTOCOL,
+            supports_server_version: protocol_version >= SERVER_VERSION_PROTOCOL,
+            accepts_assigned_id: protocol_version >= ASSIGNED_ID_PROTOCOL,
+        }
+    }
+}
 
 pub fn get_version_name(protocol: u32) -> &'static str {
     match protocol {
@@ -15,6 +77,7 @@ pub fn get_version_name(protocol: u32) -> &'static str {
         5 => "0.4.4",
         6 => "0.4.14",
         7 => "0.5.0",
+        8 => "0.5.1",
         _ => panic!("Invalid protocol version {protocol}"),
     }
 }
@@ -0,0 +1,24 @@
+use crate::connection::connection_id::ConnectionId;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How long the two sides of a punch have to report its result
+pub const PUNCH_EXPIRY: Duration = Duration::from_secs(30);
+
+/// A punch that was forwarded to its target. PunchSuccess and PunchFailed are only relayed between
+/// these two connections, and only once.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ActivePunch {
+    pub initiator: ConnectionId,
+    pub target: ConnectionId,
+    /// Matched against the expiry queue, so that a stale queue entry can't expire a newer punch
+    /// that reused the ID
+    pub expiry: Instant,
+}
+
+impl ActivePunch {
+    /// Whether a message from `from` to `to` is between the two sides of this punch
+    pub fn is_between(&self, from: ConnectionId, to: ConnectionId) -> bool {
+        (from, to) == (self.initiator, self.target) || (from, to) == (self.target, self.initiator)
+    }
+}
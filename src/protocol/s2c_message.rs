@@ -1,4 +1,7 @@
 use crate::connection::connection_id::ConnectionId;
+use crate::connection::proxy_connection_id::ProxyConnectionId;
+use crate::protocol::message_table::{MessageInfo, check_table, lookup};
+use crate::protocol::protocol_versions::ProtocolCapabilities;
 use crate::protocol::security::SecurityLevel;
 use crate::serialization::fielded::FieldedSerializer;
 use crate::serialization::serializable::PacketSerializable;
@@ -28,8 +31,12 @@ pub const CANCEL_PORT_LOOKUP_ID: u8 = 19;
 pub const PORT_LOOKUP_SUCCESS_ID: u8 = 20;
 pub const PUNCH_REQUEST_CANCELLED_ID: u8 = 21;
 pub const PUNCH_SUCCESS_ID: u8 = 22;
+pub const IS_OFFLINE_TO_ID: u8 = 23;
+pub const SERVER_CAPABILITIES_ID: u8 = 24;
+pub const FRIENDS_ONLINE_ID: u8 = 25;
+pub const FRIEND_REQUEST_CANCELLED_ID: u8 = 26;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum WorldHostS2CMessage {
     Error {
         message: String,
@@ -42,6 +49,11 @@ pub enum WorldHostS2CMessage {
         host: String,
         port: u16,
         owner_cid: ConnectionId,
+        /// Whether the join should use a punched connection. Always false until Punch joins are
+        /// handled by the server.
+        punch_transfer: bool,
+        /// Only sent to protocol 8+ clients
+        owner_uuid: Uuid,
     },
     FriendRequest {
         from_user: Uuid,
@@ -72,15 +84,15 @@ pub enum WorldHostS2CMessage {
         data: Vec<u8>,
     },
     ProxyC2SPacket {
-        connection_id: u64,
+        connection_id: ProxyConnectionId,
         data: Vec<u8>,
     },
     ProxyConnect {
-        connection_id: u64,
+        connection_id: ProxyConnectionId,
         remote_addr: IpAddr,
     },
     ProxyDisconnect {
-        connection_id: u64,
+        connection_id: ProxyConnectionId,
     },
     ConnectionInfo {
         connection_id: ConnectionId,
@@ -89,6 +101,8 @@ pub enum WorldHostS2CMessage {
         user_ip: String,
         protocol_version: u32,
         punch_port: u16,
+        /// Only sent to protocol 8+ clients
+        server_version: String,
     },
     ExternalProxyServer {
         host: String,
@@ -135,6 +149,32 @@ pub enum WorldHostS2CMessage {
         host: String,
         port: u16,
     },
+    IsOfflineTo {
+        user: Uuid,
+    },
+    ServerCapabilities {
+        allowed_join_types: u8,
+    },
+    /// Direct response to ListOnline with the requested friends that are connected
+    FriendsOnline {
+        friends: Vec<OnlineFriend>,
+    },
+    FriendRequestCancelled {
+        from_user: Uuid,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OnlineFriend {
+    pub user: Uuid,
+    pub connection_id: ConnectionId,
+    pub security: SecurityLevel,
+}
+
+impl FieldedSerializer for OnlineFriend {
+    fn fields(&self) -> Vec<&(dyn PacketSerializable + '_)> {
+        vec![&self.user, &self.connection_id, &self.security]
+    }
 }
 
 impl WorldHostS2CMessage {
@@ -165,37 +205,19 @@ impl WorldHostS2CMessage {
             PortLookupSuccess { .. } => PORT_LOOKUP_SUCCESS_ID,
             PunchRequestCancelled { .. } => PUNCH_REQUEST_CANCELLED_ID,
             PunchSuccess { .. } => PUNCH_SUCCESS_ID,
+            IsOfflineTo { .. } => IS_OFFLINE_TO_ID,
+            ServerCapabilities { .. } => SERVER_CAPABILITIES_ID,
+            FriendsOnline { .. } => FRIENDS_ONLINE_ID,
+            FriendRequestCancelled { .. } => FRIEND_REQUEST_CANCELLED_ID,
         }
     }
 
-    #[allow(deprecated)]
+    pub fn name(&self) -> &'static str {
+        message_name(self.type_id())
+    }
+
     pub fn first_protocol(&self) -> u32 {
-        use WorldHostS2CMessage::*;
-        match self {
-            Error { .. } => 2,
-            IsOnlineTo { .. } => 2,
-            OnlineGame { .. } => 2,
-            FriendRequest { .. } => 2,
-            PublishedWorld { .. } => 2,
-            ClosedWorld { .. } => 2,
-            RequestJoin { .. } => 2,
-            QueryRequest { .. } => 2,
-            QueryResponse { .. } => 2,
-            ProxyC2SPacket { .. } => 2,
-            ProxyConnect { .. } => 2,
-            ProxyDisconnect { .. } => 2,
-            ConnectionInfo { .. } => 2,
-            ExternalProxyServer { .. } => 2,
-            OutdatedWorldHost { .. } => 4,
-            ConnectionNotFound { .. } => 4,
-            NewQueryResponse { .. } => 5,
-            Warning { .. } => 6,
-            PunchOpenRequest { .. } => 7,
-            CancelPortLookup { .. } => 7,
-            PortLookupSuccess { .. } => 7,
-            PunchRequestCancelled { .. } => 7,
-            PunchSuccess { .. } => 7,
-        }
+        S2C_MESSAGES[self.type_id() as usize].first_protocol
     }
 }
 
@@ -210,7 +232,9 @@ impl FieldedSerializer for WorldHostS2CMessage {
                 host,
                 port,
                 owner_cid,
-            } => vec![host, port, owner_cid, &false],
+                punch_transfer,
+                owner_uuid,
+            } => vec![host, port, owner_cid, punch_transfer, owner_uuid],
             FriendRequest {
                 from_user,
                 security,
@@ -252,6 +276,7 @@ impl FieldedSerializer for WorldHostS2CMessage {
                 user_ip,
                 protocol_version,
                 punch_port,
+                server_version,
             } => vec![
                 connection_id,
                 base_ip,
@@ -259,6 +284,7 @@ impl FieldedSerializer for WorldHostS2CMessage {
                 user_ip,
                 protocol_version,
                 punch_port,
+                server_version,
             ],
             ExternalProxyServer {
                 host,
@@ -301,6 +327,81 @@ impl FieldedSerializer for WorldHostS2CMessage {
                 host,
                 port,
             } => vec![punch_id, host, port],
+            IsOfflineTo { user } => vec![user],
+            ServerCapabilities { allowed_join_types } => vec![allowed_join_types],
+            FriendsOnline { friends } => vec![friends],
+            FriendRequestCancelled { from_user } => vec![from_user],
         }
     }
+
+    fn fields_for(&self, protocol_version: u32) -> Vec<&(dyn PacketSerializable + '_)> {
+        use WorldHostS2CMessage::*;
+        match self {
+            OnlineGame {
+                host,
+                port,
+                owner_cid,
+                punch_transfer,
+                ..
+            } if !ProtocolCapabilities::from_version(protocol_version).supports_owner_uuid => {
+                vec![host, port, owner_cid, punch_transfer]
+            }
+            ConnectionInfo {
+                connection_id,
+                base_ip,
+                base_port,
+                user_ip,
+                protocol_version: info_protocol_version,
+                punch_port,
+                ..
+            } if !ProtocolCapabilities::from_version(protocol_version).supports_server_version => {
+                vec![
+                    connection_id,
+                    base_ip,
+                    base_port,
+                    user_ip,
+                    info_protocol_version,
+                    punch_port,
+                ]
+            }
+            _ => self.fields(),
+        }
+    }
+}
+
+/// Every S2C message, indexed by type ID
+pub const S2C_MESSAGES: &[MessageInfo] = &[
+    MessageInfo::new("Error", ERROR_ID, 2),
+    MessageInfo::new("IsOnlineTo", IS_ONLINE_TO_ID, 2),
+    MessageInfo::new("OnlineGame", ONLINE_GAME_ID, 2),
+    MessageInfo::new("FriendRequest", FRIEND_REQUEST_ID, 2),
+    MessageInfo::new("PublishedWorld", PUBLISHED_WORLD_ID, 2),
+    MessageInfo::new("ClosedWorld", CLOSED_WORLD_ID, 2),
+    MessageInfo::new("RequestJoin", REQUEST_JOIN_ID, 2),
+    MessageInfo::new("QueryRequest", QUERY_REQUEST_ID, 2),
+    MessageInfo::new("QueryResponse", QUERY_RESPONSE_ID, 2),
+    MessageInfo::new("ProxyC2SPacket", PROXY_C2S_PACKET_ID, 2),
+    MessageInfo::new("ProxyConnect", PROXY_CONNECT_ID, 2),
+    MessageInfo::new("ProxyDisconnect", PROXY_DISCONNECT_ID, 2),
+    MessageInfo::new("ConnectionInfo", CONNECTION_INFO_ID, 2),
+    MessageInfo::new("ExternalProxyServer", EXTERNAL_PROXY_SERVER_ID, 2),
+    MessageInfo::new("OutdatedWorldHost", OUTDATED_WORLD_HOST_ID, 4),
+    MessageInfo::new("ConnectionNotFound", CONNECTION_NOT_FOUND_ID, 4),
+    MessageInfo::new("NewQueryResponse", NEW_QUERY_RESPONSE_ID, 5),
+    MessageInfo::new("Warning", WARNING_ID, 6),
+    MessageInfo::new("PunchOpenRequest", PUNCH_OPEN_REQUEST_ID, 7),
+    MessageInfo::new("CancelPortLookup", CANCEL_PORT_LOOKUP_ID, 7),
+    MessageInfo::new("PortLookupSuccess", PORT_LOOKUP_SUCCESS_ID, 7),
+    MessageInfo::new("PunchRequestCancelled", PUNCH_REQUEST_CANCELLED_ID, 7),
+    MessageInfo::new("PunchSuccess", PUNCH_SUCCESS_ID, 7),
+    MessageInfo::new("IsOfflineTo", IS_OFFLINE_TO_ID, 8),
+    MessageInfo::new("ServerCapabilities", SERVER_CAPABILITIES_ID, 8),
+    MessageInfo::new("FriendsOnline", FRIENDS_ONLINE_ID, 8),
+    MessageInfo::new("FriendRequestCancelled", FRIEND_REQUEST_CANCELLED_ID, 8),
+];
+
+const _: () = check_table(S2C_MESSAGES);
+
+pub fn message_name(id: u8) -> &'static str {
+    lookup(S2C_MESSAGES, id).map_or("Unknown", |info| info.name)
 }
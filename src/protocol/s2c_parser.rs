@@ -0,0 +1,484 @@
+//! Decoder for [WorldHostS2CMessage], for test clients talking to the server. Nothing in the
+//! server itself reads S2C messages.
+
+use crate::invalid_data;
+use crate::protocol::data_ext::WHReadBytesExt;
+use crate::protocol::protocol_versions::ProtocolCapabilities;
+use crate::protocol::s2c_message::*;
+use crate::protocol::security::SecurityLevel;
+use byteorder::{BigEndian, ReadBytesExt};
+use std::io;
+use std::io::{Cursor, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use tokio_util::bytes::Buf;
+use uuid::Uuid;
+
+impl WorldHostS2CMessage {
+    #[allow(deprecated)]
+    // Only called by the tests below and by test clients built with the client feature
+    #[cfg_attr(not(test), expect(dead_code))]
+    /// Parses a message sent to a client on `protocol_version`
+    pub fn parse(id: u8, data: &[u8], protocol_version: u32) -> io::Result<Self> {
+        use WorldHostS2CMessage::*;
+        let cursor = &mut Cursor::new(data);
+        let message = match id {
+            ERROR_ID => Error {
+                message: cursor.read_string()?,
+                critical: read_bool(cursor)?,
+            },
+            IS_ONLINE_TO_ID => IsOnlineTo {
+                user: cursor.read_uuid()?,
+            },
+            ONLINE_GAME_ID => OnlineGame {
+                host: cursor.read_string()?,
+                port: cursor.read_u16::<BigEndian>()?,
+                owner_cid: cursor.read_connection_id()?,
+                punch_transfer: read_bool(cursor)?,
+                owner_uuid: if ProtocolCapabilities::from_version(protocol_version)
+                    .supports_owner_uuid
+                {
+                    cursor.read_uuid()?
+                } else {
+                    Uuid::nil()
+                },
+            },
+            FRIEND_REQUEST_ID => FriendRequest {
+                from_user: cursor.read_uuid()?,
+                security: read_security_level(cursor)?,
+            },
+            PUBLISHED_WORLD_ID => PublishedWorld {
+                user: cursor.read_uuid()?,
+                connection_id: cursor.read_connection_id()?,
+                security: read_security_level(cursor)?,
+            },
+            CLOSED_WORLD_ID => ClosedWorld {
+                user: cursor.read_uuid()?,
+            },
+            REQUEST_JOIN_ID => RequestJoin {
+                user: cursor.read_uuid()?,
+                connection_id: cursor.read_connection_id()?,
+                security: read_security_level(cursor)?,
+            },
+            QUERY_REQUEST_ID => QueryRequest {
+                friend: cursor.read_uuid()?,
+                connection_id: cursor.read_connection_id()?,
+                security: read_security_level(cursor)?,
+            },
+            QUERY_RESPONSE_ID => {
+                let friend = cursor.read_uuid()?;
+                let length = cursor.read_u32::<BigEndian>()?;
+                if length as usize > cursor.remaining() {
+                    invalid_data!("QueryResponse length {length} is longer than the message");
+                }
+                let mut data = vec![0; length as usize];
+                cursor.read_exact(&mut data)?;
+                QueryResponse {
+                    friend,
+                    length,
+                    data,
+                }
+            }
+            PROXY_C2S_PACKET_ID => ProxyC2SPacket {
+                connection_id: cursor.read_proxy_connection_id()?,
+                data: read_remaining(cursor),
+            },
+            PROXY_CONNECT_ID => ProxyConnect {
+                connection_id: cursor.read_proxy_connection_id()?,
+                remote_addr: read_ip_addr(cursor)?,
+            },
+            PROXY_DISCONNECT_ID => ProxyDisconnect {
+                connection_id: cursor.read_proxy_connection_id()?,
+            },
+            CONNECTION_INFO_ID => ConnectionInfo {
+                connection_id: cursor.read_connection_id()?,
+                base_ip: cursor.read_string()?,
+                base_port: cursor.read_u16::<BigEndian>()?,
+                user_ip: cursor.read_string()?,
+                protocol_version: cursor.read_u32::<BigEndian>()?,
+                punch_port: cursor.read_u16::<BigEndian>()?,
+                server_version: if ProtocolCapabilities::from_version(protocol_version)
+                    .supports_server_version
+                {
+                    cursor.read_string()?
+                } else {
+                    String::new()
+                },
+            },
+            EXTERNAL_PROXY_SERVER_ID => ExternalProxyServer {
+                host: cursor.read_string()?,
+                port: cursor.read_u16::<BigEndian>()?,
+                base_addr: cursor.read_string()?,
+                mc_port: cursor.read_u16::<BigEndian>()?,
+            },
+            OUTDATED_WORLD_HOST_ID => OutdatedWorldHost {
+                recommended_version: cursor.read_string()?,
+            },
+            CONNECTION_NOT_FOUND_ID => ConnectionNotFound {
+                connection_id: cursor.read_connection_id()?,
+            },
+            NEW_QUERY_RESPONSE_ID => NewQueryResponse {
+                friend: cursor.read_uuid()?,
+                data: read_remaining(cursor),
+            },
+            WARNING_ID => Warning {
+                message: cursor.read_string()?,
+                important: read_bool(cursor)?,
+            },
+            PUNCH_OPEN_REQUEST_ID => PunchOpenRequest {
+                punch_id: cursor.read_uuid()?,
+                purpose: cursor.read_string()?,
+                from_host: cursor.read_string()?,
+                from_port: cursor.read_u16::<BigEndian>()?,
+                connection_id: cursor.read_connection_id()?,
+                user: cursor.read_uuid()?,
+                security: read_security_level(cursor)?,
+            },
+            CANCEL_PORT_LOOKUP_ID => CancelPortLookup {
+                lookup_id: cursor.read_uuid()?,
+            },
+            PORT_LOOKUP_SUCCESS_ID => PortLookupSuccess {
+                lookup_id: cursor.read_uuid()?,
+                host: cursor.read_string()?,
+                port: cursor.read_u16::<BigEndian>()?,
+            },
+            PUNCH_REQUEST_CANCELLED_ID => PunchRequestCancelled {
+                punch_id: cursor.read_uuid()?,
+            },
+            PUNCH_SUCCESS_ID => PunchSuccess {
+                punch_id: cursor.read_uuid()?,
+                host: cursor.read_string()?,
+                port: cursor.read_u16::<BigEndian>()?,
+            },
+            IS_OFFLINE_TO_ID => IsOfflineTo {
+                user: cursor.read_uuid()?,
+            },
+            SERVER_CAPABILITIES_ID => ServerCapabilities {
+                allowed_join_types: cursor.read_u8()?,
+            },
+            FRIENDS_ONLINE_ID => FriendsOnline {
+                friends: read_online_friends(cursor)?,
+            },
+            FRIEND_REQUEST_CANCELLED_ID => FriendRequestCancelled {
+                from_user: cursor.read_uuid()?,
+            },
+            _ => invalid_data!("Received message with unknown typeId from server: {id}"),
+        };
+        if cursor.has_remaining() {
+            invalid_data!("Message {id} has {} trailing bytes", cursor.remaining());
+        }
+        Ok(message)
+    }
+}
+
+fn read_bool(cursor: &mut Cursor<&[u8]>) -> io::Result<bool> {
+    match cursor.read_u8()? {
+        0 => Ok(false),
+        1 => Ok(true),
+        value => invalid_data!("Invalid bool {value}"),
+    }
+}
+
+fn read_security_level(cursor: &mut Cursor<&[u8]>) -> io::Result<SecurityLevel> {
+    match cursor.read_u8()? {
+        0 => Ok(SecurityLevel::Insecure),
+        1 => Ok(SecurityLevel::Offline),
+        2 => Ok(SecurityLevel::Secure),
+        value => invalid_data!("Invalid security level {value}"),
+    }
+}
+
+fn read_ip_addr(cursor: &mut Cursor<&[u8]>) -> io::Result<IpAddr> {
+    match cursor.read_u8()? {
+        4 => Ok(Ipv4Addr::from_bits(cursor.read_u32::<BigEndian>()?).into()),
+        16 => Ok(Ipv6Addr::from_bits(cursor.read_u128::<BigEndian>()?).into()),
+        length => invalid_data!("Invalid IP address length {length}"),
+    }
+}
+
+fn read_online_friends(cursor: &mut Cursor<&[u8]>) -> io::Result<Vec<OnlineFriend>> {
+    let len = cursor.read_u32::<BigEndian>()? as usize;
+    // Each friend is at least 25 bytes, so this can't be used to allocate a huge Vec
+    if len * 25 > cursor.remaining() {
+        invalid_data!("FriendsOnline has {len} friends, but not enough data for them");
+    }
+    let mut friends = Vec::with_capacity(len);
+    for _ in 0..len {
+        friends.push(OnlineFriend {
+            user: cursor.read_uuid()?,
+            connection_id: cursor.read_connection_id()?,
+            security: read_security_level(cursor)?,
+        });
+    }
+    Ok(friends)
+}
+
+fn read_remaining(cursor: &mut Cursor<&[u8]>) -> Vec<u8> {
+    let data = cursor.chunk().to_vec();
+    cursor.advance(data.len());
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::connection_id::ConnectionId;
+    use crate::connection::proxy_connection_id::ProxyConnectionId;
+    use crate::protocol::protocol_versions::{CURRENT, STABLE};
+    use crate::serialization::fielded::FieldedSerializer;
+    use std::collections::BTreeSet;
+
+    const USER: Uuid = Uuid::from_u128(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef);
+    const OTHER_USER: Uuid = Uuid::from_u128(0xfedc_ba98_7654_3210_fedc_ba98_7654_3210);
+
+    fn cid(id: u64) -> ConnectionId {
+        ConnectionId::new(id).unwrap()
+    }
+
+    #[allow(deprecated)]
+    fn every_message() -> Vec<WorldHostS2CMessage> {
+        use WorldHostS2CMessage::*;
+        vec![
+            Error {
+                message: "Something went wrong".to_string(),
+                critical: true,
+            },
+            IsOnlineTo { user: USER },
+            OnlineGame {
+                host: "example.com".to_string(),
+                port: 25565,
+                owner_cid: cid(1234),
+                punch_transfer: true,
+                owner_uuid: OTHER_USER,
+            },
+            FriendRequest {
+                from_user: USER,
+                security: SecurityLevel::Secure,
+            },
+            PublishedWorld {
+                user: USER,
+                connection_id: cid(1),
+                security: SecurityLevel::Offline,
+            },
+            ClosedWorld { user: USER },
+            RequestJoin {
+                user: USER,
+                connection_id: cid(2),
+                security: SecurityLevel::Insecure,
+            },
+            QueryRequest {
+                friend: USER,
+                connection_id: cid(3),
+                security: SecurityLevel::Secure,
+            },
+            QueryResponse {
+                friend: USER,
+                length: 3,
+                data: vec![1, 2, 3],
+            },
+            ProxyC2SPacket {
+                connection_id: ProxyConnectionId(u64::MAX),
+                data: vec![0, 255, 7],
+            },
+            ProxyConnect {
+                connection_id: ProxyConnectionId(5),
+                remote_addr: Ipv4Addr::new(192, 0, 2, 1).into(),
+            },
+            ProxyDisconnect {
+                connection_id: ProxyConnectionId(6),
+            },
+            ConnectionInfo {
+                connection_id: cid(7),
+                base_ip: "world-host.example".to_string(),
+                base_port: 9646,
+                user_ip: "198.51.100.4".to_string(),
+                protocol_version: 8,
+                punch_port: 9647,
+                server_version: "0.5.0".to_string(),
+            },
+            ExternalProxyServer {
+                host: "proxy.example".to_string(),
+                port: 9656,
+                base_addr: "proxy.example".to_string(),
+                mc_port: 25565,
+            },
+            OutdatedWorldHost {
+                recommended_version: "0.5.1".to_string(),
+            },
+            ConnectionNotFound {
+                connection_id: cid(8),
+            },
+            NewQueryResponse {
+                friend: USER,
+                data: vec![9, 8, 7, 6],
+            },
+            Warning {
+                message: "Careful".to_string(),
+                important: false,
+            },
+            PunchOpenRequest {
+                punch_id: OTHER_USER,
+                purpose: "world-host:join".to_string(),
+                from_host: "203.0.113.9".to_string(),
+                from_port: 50000,
+                connection_id: cid(9),
+                user: USER,
+                security: SecurityLevel::Offline,
+            },
+            CancelPortLookup {
+                lookup_id: OTHER_USER,
+            },
+            PortLookupSuccess {
+                lookup_id: OTHER_USER,
+                host: "203.0.113.9".to_string(),
+                port: 50001,
+            },
+            PunchRequestCancelled {
+                punch_id: OTHER_USER,
+            },
+            PunchSuccess {
+                punch_id: OTHER_USER,
+                host: "203.0.113.10".to_string(),
+                port: 50002,
+            },
+            IsOfflineTo { user: USER },
+            ServerCapabilities {
+                allowed_join_types: 0b101,
+            },
+            FriendsOnline {
+                friends: vec![
+                    OnlineFriend {
+                        user: USER,
+                        connection_id: cid(10),
+                        security: SecurityLevel::Secure,
+                    },
+                    OnlineFriend {
+                        user: OTHER_USER,
+                        connection_id: cid(11),
+                        security: SecurityLevel::Insecure,
+                    },
+                ],
+            },
+            FriendRequestCancelled {
+                from_user: OTHER_USER,
+            },
+        ]
+    }
+
+    /// What a client on `protocol_version` sees after the fields it doesn't support are dropped
+    fn as_seen_by(message: &WorldHostS2CMessage, protocol_version: u32) -> WorldHostS2CMessage {
+        let capabilities = ProtocolCapabilities::from_version(protocol_version);
+        let mut message = message.clone();
+        match &mut message {
+            WorldHostS2CMessage::OnlineGame { owner_uuid, .. }
+                if !capabilities.supports_owner_uuid =>
+            {
+                *owner_uuid = Uuid::nil();
+            }
+            WorldHostS2CMessage::ConnectionInfo { server_version, .. }
+                if !capabilities.supports_server_version =>
+            {
+                server_version.clear();
+            }
+            _ => {}
+        }
+        message
+    }
+
+    fn serialize(message: &WorldHostS2CMessage, protocol_version: u32) -> Vec<u8> {
+        let mut buf = vec![];
+        message.serialize_for(protocol_version, &mut buf);
+        buf
+    }
+
+    #[test]
+    fn every_message_is_covered() {
+        let ids = every_message()
+            .iter()
+            .map(|message| message.type_id())
+            .collect::<BTreeSet<_>>();
+        let all_ids = S2C_MESSAGES.iter().map(|info| info.id).collect();
+        assert_eq!(ids, all_ids);
+    }
+
+    #[test]
+    fn round_trips_every_message() {
+        for protocol_version in [STABLE, CURRENT] {
+            for message in every_message() {
+                let data = serialize(&message, protocol_version);
+                let parsed = WorldHostS2CMessage::parse(message.type_id(), &data, protocol_version)
+                    .unwrap_or_else(|e| {
+                        panic!("{} failed on {protocol_version}: {e}", message.name())
+                    });
+                assert_eq!(
+                    parsed,
+                    as_seen_by(&message, protocol_version),
+                    "on {protocol_version}"
+                );
+                assert_eq!(serialize(&parsed, protocol_version), data);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        for message in every_message() {
+            let mut data = serialize(&message, CURRENT);
+            data.push(0);
+            let result = WorldHostS2CMessage::parse(message.type_id(), &data, CURRENT);
+            // These end with the rest of the message, so the extra byte is part of it
+            if matches!(
+                message,
+                WorldHostS2CMessage::ProxyC2SPacket { .. }
+                    | WorldHostS2CMessage::NewQueryResponse { .. }
+            ) {
+                assert!(result.is_ok(), "{}", message.name());
+            } else {
+                assert!(result.is_err(), "{}", message.name());
+            }
+        }
+    }
+
+    #[test]
+    fn online_game_layout() {
+        let message = WorldHostS2CMessage::OnlineGame {
+            host: "ab".to_string(),
+            port: 0x1234,
+            owner_cid: cid(0x0102),
+            punch_transfer: true,
+            owner_uuid: USER,
+        };
+        let mut expected = vec![
+            0, 2, b'a', b'b', // host
+            0x12, 0x34, // port
+            0, 0, 0, 0, 0, 0, 0x01, 0x02, // owner_cid
+            1,    // punch_transfer
+        ];
+        assert_eq!(serialize(&message, STABLE), expected);
+        expected.extend(USER.as_bytes());
+        assert_eq!(serialize(&message, CURRENT), expected);
+    }
+
+    #[test]
+    fn connection_info_layout() {
+        let message = WorldHostS2CMessage::ConnectionInfo {
+            connection_id: cid(0x0304),
+            base_ip: "b".to_string(),
+            base_port: 0x2526,
+            user_ip: "u".to_string(),
+            protocol_version: 7,
+            punch_port: 0x2527,
+            server_version: "0.5".to_string(),
+        };
+        let mut expected = vec![
+            0, 0, 0, 0, 0, 0, 0x03, 0x04, // connection_id
+            0, 1, b'b', // base_ip
+            0x25, 0x26, // base_port
+            0, 1, b'u', // user_ip
+            0, 0, 0, 7, // protocol_version
+            0x25, 0x27, // punch_port
+        ];
+        assert_eq!(serialize(&message, STABLE), expected);
+        expected.extend([0, 3, b'0', b'.', b'5']);
+        assert_eq!(serialize(&message, CURRENT), expected);
+    }
+}
@@ -9,11 +9,12 @@ pub enum SecurityLevel {
 }
 
 impl SecurityLevel {
-    pub fn from(uuid: Uuid, secure_auth: bool) -> SecurityLevel {
+    /// Nobody is Secure when the server is in offline mode, since their UUID wasn't verified
+    pub fn from(uuid: Uuid, secure_auth: bool, offline_mode: bool) -> SecurityLevel {
         use SecurityLevel::*;
         if !secure_auth {
             Insecure
-        } else if uuid.get_version_num() != 4 {
+        } else if offline_mode || uuid.get_version_num() != 4 {
             Offline
         } else {
             Secure
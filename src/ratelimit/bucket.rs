@@ -1,68 +1,460 @@
 use crate::ratelimit::error::RateLimited;
+use crate::util::shrink_if_sparse;
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fmt::{Display, Formatter};
 use std::hash::Hash;
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
+/// 20 connections per minute and 400 per hour
+pub const DEFAULT_RATE_LIMITS: &str = "per_minute:20/60s,per_hour:400/1h";
+
+/// A bucket as passed to --rate-limit
+#[derive(Clone, Debug)]
+pub struct RateLimitSpec {
+    pub name: String,
+    pub max_count: u32,
+    pub period: Duration,
+    /// Whether this is a [RateLimitBucket::new_token_bucket] instead of a fixed window
+    pub token_bucket: bool,
+}
+
 #[derive(Debug)]
 pub struct RateLimitBucket<K: Eq + Hash + Copy> {
     name: String,
+    kind: RateLimitKind,
     max_count: u32,
     expiry: Duration,
     entries: Mutex<HashMap<K, RateLimitEntry>>,
+    checks: AtomicU64,
+    rejections: AtomicU64,
+}
+
+/// Counts since the server started, except for `tracked_keys`
+#[derive(Clone, Debug)]
+pub struct RateLimitStats {
+    pub bucket: String,
+    pub checks: u64,
+    pub rejections: u64,
+    /// Keys with an entry that hasn't been pumped yet
+    pub tracked_keys: usize,
+}
+
+impl Display for RateLimitStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} checks, {} rejected, {} keys",
+            self.bucket, self.checks, self.rejections, self.tracked_keys
+        )
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
-struct RateLimitEntry {
-    time: Instant,
-    count: u32,
+enum RateLimitKind {
+    /// Allows `max_count` requests, then nothing until `expiry` passes without an allowed request
+    FixedWindow,
+    /// Allows bursts of up to `max_count` requests, refilled smoothly at `max_count` per `expiry`.
+    /// Unlike a fixed window, a full burst can't be repeated right after the window resets.
+    TokenBucket,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum RateLimitEntry {
+    FixedWindow {
+        time: Instant,
+        count: u32,
+    },
+    /// Each allowed request moves `refilled_at` forward by one token's worth of time. Requests are
+    /// allowed until that would put it more than `expiry` ahead.
+    TokenBucket {
+        refilled_at: Instant,
+    },
 }
 
 impl<K: Eq + Hash + Copy> RateLimitBucket<K> {
     pub fn new(name: String, max_count: u32, expiry: Duration) -> Self {
+        Self::with_kind(name, RateLimitKind::FixedWindow, max_count, expiry)
+    }
+
+    /// A bucket that allows bursts of up to `max_count` requests, then one more every
+    /// `period / max_count`
+    pub fn new_token_bucket(name: String, max_count: u32, period: Duration) -> Self {
+        Self::with_kind(name, RateLimitKind::TokenBucket, max_count, period)
+    }
+
+    pub fn from_spec(spec: &RateLimitSpec) -> Self {
+        if spec.token_bucket {
+            Self::new_token_bucket(spec.name.clone(), spec.max_count, spec.period)
+        } else {
+            Self::new(spec.name.clone(), spec.max_count, spec.period)
+        }
+    }
+
+    fn with_kind(name: String, kind: RateLimitKind, max_count: u32, expiry: Duration) -> Self {
         Self {
             name,
+            kind,
             max_count,
             expiry,
             entries: Mutex::new(HashMap::new()),
+            checks: AtomicU64::new(0),
+            rejections: AtomicU64::new(0),
         }
     }
 
     pub fn ratelimit(&self, key: K) -> Option<RateLimited> {
+        self.ratelimit_at(key, Instant::now())
+    }
+
+    fn ratelimit_at(&self, key: K, current_time: Instant) -> Option<RateLimited> {
+        self.checks.fetch_add(1, Ordering::Relaxed);
+        // Decided and updated under one lock and one lookup, so that concurrent requests for the
+        // same key can't both be counted from the same old entry
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(key);
+        let old_entry = match &entry {
+            Entry::Occupied(entry) => Some(*entry.get()),
+            Entry::Vacant(_) => None,
+        };
+        let result = match self.kind {
+            RateLimitKind::FixedWindow => self.ratelimit_fixed_window(old_entry, current_time),
+            RateLimitKind::TokenBucket => self.ratelimit_token_bucket(old_entry, current_time),
+        };
+        match result {
+            Ok(new_entry) => {
+                entry.insert_entry(new_entry);
+                None
+            }
+            Err(remaining) => {
+                self.rejections.fetch_add(1, Ordering::Relaxed);
+                Some(RateLimited::new(self.name.to_string(), remaining))
+            }
+        }
+    }
+
+    pub fn stats(&self) -> RateLimitStats {
+        RateLimitStats {
+            bucket: self.name.clone(),
+            checks: self.checks.load(Ordering::Relaxed),
+            rejections: self.rejections.load(Ordering::Relaxed),
+            tracked_keys: self.entries.lock().unwrap().len(),
+        }
+    }
+
+    /// Returns the entry to store if allowed, or the time remaining if not
+    fn ratelimit_fixed_window(
+        &self,
+        entry: Option<RateLimitEntry>,
+        current_time: Instant,
+    ) -> Result<RateLimitEntry, Duration> {
+        let count = match entry {
+            Some(RateLimitEntry::FixedWindow { time, count })
+                if current_time - time < self.expiry =>
+            {
+                if count >= self.max_count {
+                    // Not `time - current_time + expiry`, since Instant subtraction saturates to 0
+                    return Err(self.expiry - (current_time - time));
+                }
+                count
+            }
+            _ => 0,
+        };
+        Ok(RateLimitEntry::FixedWindow {
+            time: current_time,
+            count: count + 1,
+        })
+    }
+
+    /// Returns the entry to store if allowed, or the time remaining if not
+    fn ratelimit_token_bucket(
+        &self,
+        entry: Option<RateLimitEntry>,
+        current_time: Instant,
+    ) -> Result<RateLimitEntry, Duration> {
+        let refilled_at = match entry {
+            Some(RateLimitEntry::TokenBucket { refilled_at }) => refilled_at.max(current_time),
+            _ => current_time,
+        };
+        let refilled_at = refilled_at + self.token_interval();
+        let ahead = refilled_at - current_time;
+        if ahead > self.expiry {
+            return Err(ahead - self.expiry);
+        }
+        Ok(RateLimitEntry::TokenBucket { refilled_at })
+    }
+
+    fn token_interval(&self) -> Duration {
+        self.expiry / self.max_count
+    }
+
+    /// Returns how many slots were reclaimed from the entries map
+    pub(super) fn pump_limits(&self) -> usize {
         let mut entries = self.entries.lock().unwrap();
-        let entry = entries.get(&key);
         let current_time = Instant::now();
-        if entry.is_none() || current_time - entry.unwrap().time >= self.expiry {
-            entries.insert(
-                key,
-                RateLimitEntry {
-                    time: current_time,
-                    count: 1,
-                },
-            );
-            return None;
+        entries.retain(|_, entry| match *entry {
+            RateLimitEntry::FixedWindow { time, .. } => current_time - time < self.expiry,
+            RateLimitEntry::TokenBucket { refilled_at } => refilled_at > current_time,
+        });
+        shrink_if_sparse(&mut entries)
+    }
+}
+
+/// Inspects entries without counting a request
+impl<K: Eq + Hash + Copy> RateLimitBucket<K> {
+    /// What [Self::ratelimit] would return right now
+    pub fn check(&self, key: K) -> Option<RateLimited> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: K, current_time: Instant) -> Option<RateLimited> {
+        let entry = self.entries.lock().unwrap().get(&key).copied();
+        let result = match self.kind {
+            RateLimitKind::FixedWindow => self.ratelimit_fixed_window(entry, current_time),
+            RateLimitKind::TokenBucket => self.ratelimit_token_bucket(entry, current_time),
+        };
+        result
+            .err()
+            .map(|remaining| RateLimited::new(self.name.to_string(), remaining))
+    }
+
+    /// How many more requests would be allowed right now
+    pub fn remaining(&self, key: K) -> u32 {
+        self.remaining_at(key, Instant::now())
+    }
+
+    fn remaining_at(&self, key: K, current_time: Instant) -> u32 {
+        let entry = self.entries.lock().unwrap().get(&key).copied();
+        match entry {
+            Some(RateLimitEntry::FixedWindow { time, count })
+                if current_time - time < self.expiry =>
+            {
+                self.max_count.saturating_sub(count)
+            }
+            Some(RateLimitEntry::TokenBucket { refilled_at }) => {
+                let available = self.expiry.saturating_sub(refilled_at - current_time);
+                let tokens = available.as_nanos() / self.token_interval().as_nanos().max(1);
+                tokens.min(self.max_count as u128) as u32
+            }
+            _ => self.max_count,
         }
-        let entry = *entry.unwrap();
-        if entry.count < self.max_count {
-            entries.insert(
-                key,
-                RateLimitEntry {
-                    time: current_time,
-                    count: entry.count + 1,
-                },
-            );
-            return None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn buckets(max_count: u32, period: Duration) -> [RateLimitBucket<u8>; 2] {
+        [
+            RateLimitBucket::new("fixed".to_string(), max_count, period),
+            RateLimitBucket::new_token_bucket("token".to_string(), max_count, period),
+        ]
+    }
+
+    fn burst(bucket: &RateLimitBucket<u8>, at: Instant) -> u32 {
+        let mut allowed = 0;
+        while bucket.ratelimit_at(0, at).is_none() {
+            allowed += 1;
         }
-        Some(RateLimited::new(
-            self.name.to_string(),
-            entry.time - current_time + self.expiry,
-        ))
+        allowed
     }
 
-    pub(super) fn pump_limits(&self) {
-        self.entries
-            .lock()
-            .unwrap()
-            .retain(|_, entry| entry.time.elapsed() < self.expiry)
+    #[test]
+    fn default_buckets_are_fixed_windows() {
+        for spec in DEFAULT_RATE_LIMITS.split(',') {
+            assert!(!spec.ends_with(":token-bucket"), "{spec}");
+        }
+    }
+
+    /// Hammers one key from several threads, returning how many requests were allowed in each
+    /// window
+    fn allowed_per_window(bucket: &RateLimitBucket<u8>, windows: u32) -> Vec<u32> {
+        const THREADS: usize = 8;
+        const REQUESTS_PER_THREAD: usize = 500;
+        let start = Instant::now();
+        let barrier = std::sync::Barrier::new(THREADS);
+        (0..windows)
+            .map(|window| {
+                let at = start + bucket.expiry * window;
+                std::thread::scope(|scope| {
+                    let threads = (0..THREADS)
+                        .map(|_| {
+                            scope.spawn(|| {
+                                barrier.wait();
+                                (0..REQUESTS_PER_THREAD)
+                                    .filter(|_| bucket.ratelimit_at(0, at).is_none())
+                                    .count() as u32
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    threads.into_iter().map(|t| t.join().unwrap()).sum()
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn concurrent_fixed_window_allows_exactly_max_count() {
+        let bucket = RateLimitBucket::new("fixed".to_string(), 100, Duration::from_secs(60));
+        assert_eq!(allowed_per_window(&bucket, 5), [100; 5]);
+        let stats = bucket.stats();
+        assert_eq!(stats.checks, 5 * 8 * 500);
+        assert_eq!(stats.rejections, 5 * (8 * 500 - 100));
+    }
+
+    #[test]
+    fn concurrent_token_bucket_allows_exactly_max_count() {
+        let bucket =
+            RateLimitBucket::new_token_bucket("token".to_string(), 100, Duration::from_secs(60));
+        assert_eq!(allowed_per_window(&bucket, 5), [100; 5]);
+    }
+
+    #[test]
+    fn concurrent_requests_at_real_time() {
+        let bucket = RateLimitBucket::new("fixed".to_string(), 100, Duration::from_secs(3600));
+        let allowed = std::thread::scope(|scope| {
+            let threads = (0..8)
+                .map(|_| scope.spawn(|| (0..500).filter(|_| bucket.ratelimit(0).is_none()).count()))
+                .collect::<Vec<_>>();
+            threads
+                .into_iter()
+                .map(|t| t.join().unwrap())
+                .sum::<usize>()
+        });
+        assert_eq!(allowed, 100);
+    }
+
+    proptest! {
+        #[test]
+        fn full_burst_at_the_boundary(max_count in 1..50u32, period_ms in 1..100_000u64) {
+            let period = Duration::from_millis(period_ms);
+            let start = Instant::now();
+            for bucket in buckets(max_count, period) {
+                prop_assert_eq!(burst(&bucket, start), max_count);
+                // A full burst is allowed again once the period has passed, by either kind
+                prop_assert_eq!(burst(&bucket, start + period), max_count);
+            }
+        }
+
+        #[test]
+        fn just_before_the_boundary(max_count in 1..50u32, period_ms in 1..100_000u64) {
+            let period = Duration::from_millis(period_ms);
+            let start = Instant::now();
+            let just_before = start + period - Duration::from_nanos(1);
+            let [fixed, token] = buckets(max_count, period);
+
+            prop_assert_eq!(burst(&fixed, start), max_count);
+            let limited = fixed.ratelimit_at(0, just_before).unwrap();
+            prop_assert_eq!(limited.remaining, Duration::from_nanos(1));
+
+            // The token bucket has refilled all but the last token by then
+            prop_assert_eq!(burst(&token, start), max_count);
+            let refilled = (period - Duration::from_nanos(1)).as_nanos()
+                / token.token_interval().as_nanos();
+            prop_assert_eq!(burst(&token, just_before) as u128, refilled.min(max_count as u128));
+        }
+
+        #[test]
+        fn check_never_changes_ratelimit(
+            max_count in 1..10u32,
+            token_bucket in any::<bool>(),
+            steps in prop::collection::vec((0..500u64, any::<bool>()), 1..200),
+        ) {
+            let period = Duration::from_secs(1);
+            let spec = RateLimitSpec {
+                name: "bucket".to_string(),
+                max_count,
+                period,
+                token_bucket,
+            };
+            let checked = RateLimitBucket::from_spec(&spec);
+            let unchecked = RateLimitBucket::from_spec(&spec);
+            let mut time = Instant::now();
+            for (gap_ms, ratelimit) in steps {
+                time += Duration::from_millis(gap_ms);
+                if ratelimit {
+                    let expected = unchecked.ratelimit_at(0, time).map(|limited| limited.remaining);
+                    let predicted = checked.check_at(0, time).map(|limited| limited.remaining);
+                    let actual = checked.ratelimit_at(0, time).map(|limited| limited.remaining);
+                    prop_assert_eq!(actual, expected);
+                    prop_assert_eq!(predicted, expected);
+                } else {
+                    for _ in 0..3 {
+                        checked.check_at(0, time);
+                        checked.remaining_at(0, time);
+                    }
+                }
+            }
+            prop_assert_eq!(checked.stats().rejections, unchecked.stats().rejections);
+        }
+
+        #[test]
+        fn remaining_matches_allowed_burst(
+            max_count in 1..50u32,
+            token_bucket in any::<bool>(),
+            used in 0..60u32,
+            wait_ms in 0..2_000u64,
+        ) {
+            let spec = RateLimitSpec {
+                name: "bucket".to_string(),
+                max_count,
+                period: Duration::from_secs(1),
+                token_bucket,
+            };
+            let bucket = RateLimitBucket::from_spec(&spec);
+            let start = Instant::now();
+            for _ in 0..used {
+                bucket.ratelimit_at(0, start);
+            }
+            let later = start + Duration::from_millis(wait_ms);
+            let remaining = bucket.remaining_at(0, later);
+            prop_assert_eq!(burst(&bucket, later), remaining);
+        }
+
+        #[test]
+        fn fixed_window_allows_at_most_max_count_per_period(
+            max_count in 1..10u32,
+            gaps_ms in prop::collection::vec(0..2_000u64, 1..200),
+        ) {
+            let period = Duration::from_secs(1);
+            let bucket = RateLimitBucket::new("fixed".to_string(), max_count, period);
+            let mut time = Instant::now();
+            let mut allowed = vec![];
+            for gap in gaps_ms {
+                time += Duration::from_millis(gap);
+                if bucket.ratelimit_at(0, time).is_none() {
+                    allowed.push(time);
+                }
+            }
+            for (i, &from) in allowed.iter().enumerate() {
+                let in_window = allowed[i..].iter().take_while(|&&t| t - from < period).count();
+                prop_assert!(in_window <= max_count as usize);
+            }
+        }
+
+        #[test]
+        fn token_bucket_allows_at_most_its_rate(
+            max_count in 1..10u32,
+            gaps_ms in prop::collection::vec(0..2_000u64, 1..200),
+        ) {
+            let period = Duration::from_secs(1);
+            let bucket = RateLimitBucket::new_token_bucket("token".to_string(), max_count, period);
+            let interval = bucket.token_interval();
+            let mut time = Instant::now();
+            let mut first = None;
+            let mut allowed = 0u32;
+            for gap in gaps_ms {
+                time += Duration::from_millis(gap);
+                if bucket.ratelimit_at(0, time).is_none() {
+                    let first = *first.get_or_insert(time);
+                    allowed += 1;
+                    prop_assert!(interval * allowed <= time - first + period);
+                }
+            }
+        }
     }
 }
@@ -1,4 +1,4 @@
-use crate::ratelimit::bucket::RateLimitBucket;
+use crate::ratelimit::bucket::{RateLimitBucket, RateLimitStats};
 use crate::ratelimit::error::RateLimited;
 use std::hash::Hash;
 
@@ -22,9 +22,78 @@ impl<K: Eq + Hash + Copy> RateLimiter<K> {
         result
     }
 
-    pub fn pump_limits(&self) {
-        for bucket in &self.buckets {
-            bucket.pump_limits();
-        }
+    /// What [Self::ratelimit] would return right now, without counting a request. If several
+    /// buckets are exceeded, this is the one with the longest time remaining.
+    pub fn check(&self, key: K) -> Option<RateLimited> {
+        self.buckets
+            .iter()
+            .filter_map(|bucket| bucket.check(key))
+            .max_by_key(|limited| limited.remaining)
+    }
+
+    /// How many more requests would be allowed right now by every bucket. None if there are no
+    /// buckets.
+    pub fn remaining(&self, key: K) -> Option<u32> {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.remaining(key))
+            .min()
+    }
+
+    pub fn stats(&self) -> Vec<RateLimitStats> {
+        self.buckets.iter().map(RateLimitBucket::stats).collect()
+    }
+
+    /// Returns how many map slots were reclaimed
+    pub fn pump_limits(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.pump_limits()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limiter() -> RateLimiter<u8> {
+        RateLimiter::new(vec![
+            RateLimitBucket::new("per_hour".to_string(), 3, Duration::from_secs(3600)),
+            RateLimitBucket::new("per_minute".to_string(), 2, Duration::from_secs(60)),
+        ])
+    }
+
+    #[tokio::test]
+    async fn check_reports_longest_wait() {
+        let limiter = limiter();
+        assert_eq!(limiter.remaining(0), Some(2));
+        assert!(limiter.check(0).is_none());
+        assert!(limiter.ratelimit(0).await.is_none());
+        assert!(limiter.ratelimit(0).await.is_none());
+        assert_eq!(limiter.remaining(0), Some(0));
+        assert_eq!(limiter.check(0).unwrap().bucket, "per_minute");
+
+        assert_eq!(limiter.ratelimit(0).await.unwrap().bucket, "per_minute");
+        // per_hour counted that request, so it's now exceeded too and has the longer wait
+        let limited = limiter.check(0).unwrap();
+        assert_eq!(limited.bucket, "per_hour");
+        assert!(limited.remaining > Duration::from_secs(60));
+        assert_eq!(limiter.ratelimit(0).await.unwrap().bucket, "per_minute");
+    }
+
+    #[tokio::test]
+    async fn keys_are_independent() {
+        let limiter = limiter();
+        limiter.ratelimit(0).await;
+        limiter.ratelimit(0).await;
+        assert_eq!(limiter.remaining(0), Some(0));
+        assert_eq!(limiter.remaining(1), Some(2));
+        assert!(limiter.check(1).is_none());
+    }
+
+    #[test]
+    fn no_buckets() {
+        let limiter = RateLimiter::<u8>::new(vec![]);
+        assert_eq!(limiter.remaining(0), None);
+        assert!(limiter.check(0).is_none());
     }
 }
@@ -2,6 +2,18 @@ use crate::serialization::serializable::PacketSerializable;
 
 pub trait FieldedSerializer {
     fn fields(&self) -> Vec<&(dyn PacketSerializable + '_)>;
+
+    /// The fields sent to a client on `protocol_version`, for types whose layout changed between
+    /// versions
+    fn fields_for(&self, _protocol_version: u32) -> Vec<&(dyn PacketSerializable + '_)> {
+        self.fields()
+    }
+
+    fn serialize_for(&self, protocol_version: u32, buf: &mut Vec<u8>) {
+        for field in self.fields_for(protocol_version) {
+            field.serialize_to(buf);
+        }
+    }
 }
 
 impl<T: FieldedSerializer> PacketSerializable for T {
@@ -1,3 +1,4 @@
+use crate::protocol::s2c_message::OnlineFriend;
 use std::io::Write;
 use std::net::IpAddr;
 use uuid::Uuid;
@@ -12,6 +13,12 @@ impl PacketSerializable for bool {
     }
 }
 
+impl PacketSerializable for u8 {
+    fn serialize_to(&self, buf: &mut Vec<u8>) {
+        buf.push(*self)
+    }
+}
+
 impl PacketSerializable for u16 {
     fn serialize_to(&self, buf: &mut Vec<u8>) {
         buf.write_all(&self.to_be_bytes()).unwrap()
@@ -42,10 +49,17 @@ impl PacketSerializable for Vec<u8> {
     }
 }
 
+/// Strings are always prefixed with their u16 length, matching `read_string`, so they're safe
+/// anywhere in a message. Strings longer than [u16::MAX] bytes are cut off at the last char
+/// boundary that fits, rather than corrupting the length.
 impl PacketSerializable for String {
     fn serialize_to(&self, buf: &mut Vec<u8>) {
-        (self.len() as u16).serialize_to(buf);
-        buf.write_all(self.as_bytes()).unwrap()
+        let mut len = self.len().min(u16::MAX as usize);
+        while !self.is_char_boundary(len) {
+            len -= 1;
+        }
+        (len as u16).serialize_to(buf);
+        buf.write_all(&self.as_bytes()[..len]).unwrap()
     }
 }
 
@@ -63,3 +77,47 @@ impl PacketSerializable for IpAddr {
         }
     }
 }
+
+macro_rules! big_endian_serializable {
+    ($($ty:ty),+) => {
+        $(
+            impl PacketSerializable for $ty {
+                fn serialize_to(&self, buf: &mut Vec<u8>) {
+                    buf.write_all(&self.to_be_bytes()).unwrap()
+                }
+            }
+        )+
+    };
+}
+
+big_endian_serializable!(i16, i32, i64, f32, f64);
+
+/// Written as a presence byte followed by the value if there is one
+impl<T: PacketSerializable> PacketSerializable for Option<T> {
+    fn serialize_to(&self, buf: &mut Vec<u8>) {
+        self.is_some().serialize_to(buf);
+        if let Some(value) = self {
+            value.serialize_to(buf);
+        }
+    }
+}
+
+/// Prefixed with a u32 count, matching `read_vec`
+impl PacketSerializable for Vec<Uuid> {
+    fn serialize_to(&self, buf: &mut Vec<u8>) {
+        (self.len() as u32).serialize_to(buf);
+        for uuid in self {
+            uuid.serialize_to(buf);
+        }
+    }
+}
+
+/// Prefixed with a u32 count, matching `read_vec`
+impl PacketSerializable for Vec<OnlineFriend> {
+    fn serialize_to(&self, buf: &mut Vec<u8>) {
+        (self.len() as u32).serialize_to(buf);
+        for friend in self {
+            friend.serialize_to(buf);
+        }
+    }
+}
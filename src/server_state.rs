@@ -1,64 +1,184 @@
 use crate::SERVER_VERSION;
+use crate::cli::args::Args;
+use crate::connection::advisory_cache::AdvisoryCache;
 use crate::connection::connection_id::ConnectionId;
 use crate::connection::connection_set::ConnectionSet;
+use crate::connection::proxy_connection_id::ProxyConnectionId;
 use crate::json_data::ExternalProxy;
-use crate::modules::analytics::run_analytics;
+use crate::modules::admin_server::run_admin_server;
+use crate::modules::analytics::{
+    AnalyticsRotation, AnalyticsWriter, IntervalCounters, run_analytics,
+};
 use crate::modules::main_server::run_main_server;
-use crate::modules::proxy_server::run_proxy_server;
+use crate::modules::maintenance::run_maintenance;
+use crate::modules::proxy_server::{ProxyWrite, run_proxy_server};
+use crate::modules::reload::run_reload_handler;
+use crate::modules::shutdown::run_shutdown_handler;
 use crate::modules::signalling_server::run_signalling_server;
+use crate::protocol::block_lists::BlockLists;
+use crate::protocol::compat::CompatMode;
+use crate::protocol::delivered_friend_requests::DeliveredFriendRequests;
+use crate::protocol::join_type::JoinTypeKind;
+use crate::protocol::pending_joins::PendingJoins;
 use crate::protocol::port_lookup::ActivePortLookup;
+use crate::protocol::presence::PresenceSubscriptions;
+use crate::protocol::punch::ActivePunch;
+use crate::ratelimit::bucket::RateLimitSpec;
+use crate::util::Redacted;
+use crate::util::ip_range_map::CsvSource;
+use arc_swap::{ArcSwap, ArcSwapOption};
 use linked_hash_set::LinkedHashSet;
 use log::{info, warn};
 use queues::Queue;
-use std::collections::HashMap;
+use reqwest::Url;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
-use tokio::net::tcp::OwnedWriteHalf;
 use tokio::sync::Mutex;
 use tokio::time::Instant;
+use tokio_rustls::rustls::ServerConfig;
 use try_catch::catch;
 use uuid::Uuid;
 
 #[derive(Debug)]
 pub struct FullServerConfig {
     pub port: u16,
+    /// None if TLS connections aren't accepted
+    pub tls_config: Option<Redacted<Arc<ServerConfig>>>,
+    pub tls_port: u16,
     pub base_addr: Option<String>,
     pub in_java_port: u16,
     pub ex_java_port: u16,
+    pub allowed_join_types: Vec<JoinTypeKind>,
+    pub substitute_join_types: bool,
+    pub setup_timeout: Duration,
+    pub require_setup_advisories: bool,
+    pub max_proxy_packet_size: usize,
+    pub allow_unrequested_joins: bool,
+    /// None unless another server's behavior is mirrored
+    pub compat: Option<CompatMode>,
+    pub friends_only_direct_joins: bool,
+    pub max_friends: usize,
+    pub max_open_to_friends: usize,
+    /// Zero if delivered friend requests shouldn't be kept for the admin API to replay
+    pub friend_request_retention: Duration,
+    pub relaxed_usernames: bool,
+    /// Tried in order. Empty if Mojang's session server should be used.
+    pub session_hosts: Vec<Url>,
+    pub services_host: Option<Url>,
+    pub offline_mode: bool,
+    pub verify_client_ip: bool,
+    pub strict_auth: bool,
+    pub auth_timeout: Duration,
+    /// Zero if verifications shouldn't be cached
+    pub auth_cache_time: Duration,
+    /// None if all message types should be logged
+    pub debug_messages: Option<HashSet<&'static str>>,
+    pub shutdown_time: Option<Duration>,
+    /// None if the admin API is disabled
+    pub admin_port: Option<u16>,
+    /// Empty if connections aren't rate limited
+    pub rate_limits: Vec<RateLimitSpec>,
     pub analytics_time: Duration,
-    pub external_servers: Option<Vec<Arc<ExternalProxy>>>,
+    pub analytics_file: PathBuf,
+    pub analytics_max_countries: usize,
+    pub analytics_grid: bool,
+    pub analytics_max_grid_cells: usize,
+    pub analytics_max_brands: usize,
+    pub analytics_rotation: AnalyticsRotation,
+    pub analytics_rotation_size: u64,
+    pub analytics_gzip: bool,
+    pub analytics_webhook: Option<Url>,
+    pub analytics_webhook_secret: Option<Redacted<String>>,
+    /// None if there's no external_proxies.json. Replaced when it's reloaded.
+    pub external_servers: ArcSwapOption<Vec<Arc<ExternalProxy>>>,
+    /// None if the default GeoLite2 City CSVs should be downloaded
+    pub ip_info_sources: Option<Vec<CsvSource>>,
+    /// Whether IP info locations are packed with 16 bits per axis instead of 11
+    pub precise_locations: bool,
+    /// Whether IP info sources that fail to load or parse stop the server from starting
+    pub strict_geo: bool,
+    /// Empty if clients shouldn't be classified as hosted
+    pub asn_sources: Vec<CsvSource>,
+    pub hosting_asns: HashSet<u32>,
+    /// None if addresses missing from the IP info database have no location
+    pub geo_lookup_url: Option<String>,
+    pub geo_lookup_timeout: Duration,
+    pub geo_lookup_concurrency: usize,
+    /// None if the GeoLite2 City CSVs should be downloaded
+    #[cfg(feature = "maxminddb")]
+    pub ip_info_mmdb: Option<PathBuf>,
+    /// Connection IDs that only the mapped user may claim. Replaced when reserved_ids.json is
+    /// reloaded.
+    pub reserved_ids: ArcSwap<HashMap<ConnectionId, Uuid>>,
 }
 
 pub struct ServerState {
     pub config: FullServerConfig,
 
-    pub connections: Mutex<ConnectionSet>,
+    pub connections: ConnectionSet,
 
-    pub proxy_connections: Mutex<HashMap<u64, (ConnectionId, Mutex<OwnedWriteHalf>)>>,
+    pub proxy_connections: Mutex<HashMap<ProxyConnectionId, (ConnectionId, Mutex<ProxyWrite>)>>,
 
     pub remembered_friend_requests: Mutex<HashMap<Uuid, LinkedHashSet<Uuid>>>,
     pub received_friend_requests: Mutex<HashMap<Uuid, LinkedHashSet<Uuid>>>,
+    pub delivered_friend_requests: Mutex<DeliveredFriendRequests>,
 
     pub port_lookups: Mutex<HashMap<Uuid, ActivePortLookup>>,
     pub port_lookup_by_expiry: Mutex<Queue<(Instant, ActivePortLookup)>>,
+
+    pub active_punches: Mutex<HashMap<Uuid, ActivePunch>>,
+    pub punch_by_expiry: Mutex<Queue<(Instant, Uuid)>>,
+
+    pub presence_subscriptions: Mutex<PresenceSubscriptions>,
+
+    pub block_lists: Mutex<BlockLists>,
+
+    pub setup_advisories: Mutex<AdvisoryCache>,
+
+    pub pending_joins: Mutex<PendingJoins>,
+
+    pub analytics_counters: IntervalCounters,
+    /// Users that connected since the last analytics sample. Only populated if analytics are
+    /// enabled.
+    pub users_seen: Mutex<HashSet<Uuid>>,
+    pub analytics_writer: Mutex<AnalyticsWriter>,
 }
 
 impl ServerState {
     pub fn new(config: FullServerConfig) -> Self {
+        let analytics_writer = AnalyticsWriter::new(&config);
         Self {
             config,
 
-            connections: Mutex::new(ConnectionSet::new()),
+            connections: ConnectionSet::new(),
 
             proxy_connections: Mutex::new(HashMap::new()),
 
             remembered_friend_requests: Mutex::new(HashMap::new()),
             received_friend_requests: Mutex::new(HashMap::new()),
+            delivered_friend_requests: Mutex::new(DeliveredFriendRequests::default()),
 
             port_lookups: Mutex::new(HashMap::new()),
             port_lookup_by_expiry: Mutex::new(Queue::new()),
+
+            active_punches: Mutex::new(HashMap::new()),
+            punch_by_expiry: Mutex::new(Queue::new()),
+
+            presence_subscriptions: Mutex::new(PresenceSubscriptions::default()),
+
+            block_lists: Mutex::new(BlockLists::default()),
+
+            setup_advisories: Mutex::new(AdvisoryCache::default()),
+
+            pending_joins: Mutex::new(PendingJoins::default()),
+
+            analytics_counters: IntervalCounters::default(),
+            users_seen: Mutex::new(HashSet::new()),
+            analytics_writer: Mutex::new(analytics_writer),
         }
     }
 
@@ -81,15 +201,19 @@ impl ServerState {
             }};
         }
 
+        run_sub_server!(run_shutdown_handler);
+        run_sub_server!(run_reload_handler);
         run_sub_server!(run_analytics);
+        run_sub_server!(run_maintenance);
         run_sub_server!(run_proxy_server);
         run_sub_server!(run_signalling_server);
+        run_sub_server!(run_admin_server);
         run_main_server(state).await;
     }
 
     fn ping_external_servers(&self) {
-        if let Some(servers) = &self.config.external_servers {
-            for proxy in servers {
+        if let Some(servers) = self.config.external_servers.load_full() {
+            for proxy in servers.iter() {
                 if let Some(proxy_addr) = &proxy.addr {
                     let proxy_addr = proxy_addr.clone();
                     let proxy_port = proxy.port;
@@ -110,3 +234,114 @@ impl ServerState {
         }
     }
 }
+
+impl FullServerConfig {
+    /// Combines the command line with the files read at startup. The local entry in
+    /// external_proxies.json provides the base address if --base-addr isn't passed.
+    pub fn from_args(
+        args: Args,
+        tls_config: Option<Arc<ServerConfig>>,
+        external_servers: Option<Vec<ExternalProxy>>,
+        reserved_ids: HashMap<ConnectionId, Uuid>,
+    ) -> Self {
+        let mut base_addr = args.base_addr;
+        let local_base_addr = external_servers
+            .iter()
+            .flatten()
+            .find(|server| server.addr.is_none())
+            .and_then(|server| server.base_addr.clone());
+        if let Some(local_base_addr) = local_base_addr {
+            if base_addr.is_none() {
+                base_addr = Some(local_base_addr);
+            } else {
+                info!(
+                    "Both the CLI and external_proxies.json specify baseAddr for the local server."
+                );
+                info!("--base-addr from the CLI will override the value in external_proxies.json.");
+            }
+        }
+        Self {
+            port: args.port,
+            tls_config: tls_config.map(Redacted),
+            tls_port: args.tls_port,
+            base_addr,
+            in_java_port: args.in_java_port,
+            ex_java_port: args.ex_java_port.unwrap_or(args.in_java_port),
+            allowed_join_types: args.allowed_join_types,
+            substitute_join_types: args.substitute_join_types,
+            setup_timeout: args.setup_timeout,
+            require_setup_advisories: args.require_setup_advisories,
+            max_proxy_packet_size: args.max_proxy_packet_size as usize,
+            allow_unrequested_joins: args.allow_unrequested_joins,
+            compat: args.compat,
+            friends_only_direct_joins: args.friends_only_direct_joins,
+            max_friends: args.max_friends as usize,
+            max_open_to_friends: args.max_open_to_friends as usize,
+            friend_request_retention: args.friend_request_retention,
+            relaxed_usernames: args.relaxed_usernames,
+            session_hosts: args.session_hosts,
+            services_host: args.services_host,
+            offline_mode: args.offline_mode,
+            verify_client_ip: args.verify_client_ip,
+            strict_auth: args.strict_auth,
+            auth_timeout: args.auth_timeout,
+            // Strict servers verify every connection with the session server
+            auth_cache_time: if args.strict_auth {
+                Duration::ZERO
+            } else {
+                args.auth_cache_time
+            },
+            debug_messages: args.debug_messages.map(|names| names.into_iter().collect()),
+            shutdown_time: args.shutdown_time,
+            admin_port: args.admin_port,
+            rate_limits: if args.no_rate_limit {
+                vec![]
+            } else {
+                args.rate_limits
+            },
+            analytics_time: args.analytics_time,
+            analytics_file: args.analytics_file,
+            analytics_max_countries: args.analytics_max_countries,
+            analytics_grid: args.analytics_grid,
+            analytics_max_grid_cells: args.analytics_max_grid_cells,
+            analytics_max_brands: args.analytics_max_brands,
+            analytics_rotation: args.analytics_rotation,
+            analytics_rotation_size: args.analytics_rotation_size,
+            analytics_gzip: args.analytics_gzip,
+            analytics_webhook: args.analytics_webhook,
+            analytics_webhook_secret: args.analytics_webhook_secret.map(Redacted),
+            external_servers: ArcSwapOption::from_pointee(
+                external_servers.map(|servers| servers.into_iter().map(Arc::new).collect()),
+            ),
+            ip_info_sources: args.ip_info_sources.map(|sources| {
+                sources
+                    .iter()
+                    .map(|source| CsvSource::parse(source))
+                    .collect()
+            }),
+            precise_locations: args.precise_locations,
+            strict_geo: args.strict_geo,
+            asn_sources: args
+                .asn_sources
+                .iter()
+                .map(|source| CsvSource::parse(source))
+                .collect(),
+            hosting_asns: args.hosting_asns.into_iter().collect(),
+            geo_lookup_url: args.geo_lookup_url,
+            geo_lookup_timeout: args.geo_lookup_timeout,
+            geo_lookup_concurrency: args.geo_lookup_concurrency as usize,
+            #[cfg(feature = "maxminddb")]
+            ip_info_mmdb: args.ip_info_mmdb,
+            reserved_ids: ArcSwap::from_pointee(reserved_ids),
+        }
+    }
+
+    /// The config the server runs with when no options are passed
+    #[cfg(test)]
+    pub fn for_test() -> Self {
+        use clap::Parser;
+
+        let args = Args::try_parse_from(["world-host-server"]).unwrap();
+        Self::from_args(args, None, None, HashMap::new())
+    }
+}
@@ -1,17 +1,31 @@
 use crate::invalid_data;
 use crate::minecraft_crypt::Aes128Cfb;
 use crate::protocol::c2s_message::WorldHostC2SMessage;
+use crate::protocol::protocol_versions::ProtocolCapabilities;
 use crate::protocol::s2c_message::WorldHostS2CMessage;
-use crate::serialization::serializable::PacketSerializable;
+use crate::serialization::fielded::FieldedSerializer;
 use cfb8::cipher::AsyncStreamCipher;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
 use log::warn;
 use std::io;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use std::io::{Read, Write};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-pub struct SocketReadWrapper(pub OwnedReadHalf);
+/// Boxed so that plain TCP and TLS connections can be handled the same way
+pub struct SocketReadWrapper(pub Box<dyn AsyncRead + Unpin + Send>);
 
-pub struct SocketWriteWrapper(pub OwnedWriteHalf);
+pub struct SocketWriteWrapper(pub Box<dyn AsyncWrite + Unpin + Send>);
+
+pub const MAX_MESSAGE_SIZE: usize = 2 * 1024 * 1024;
+
+/// Set on the type ID of messages whose body is deflated. Only used with clients that have
+/// [ProtocolCapabilities::supports_compression].
+pub const COMPRESSED_FLAG: u8 = 0x80;
+
+/// Message bodies at most this big are never compressed
+pub const COMPRESSION_THRESHOLD: usize = 1024;
 
 impl SocketReadWrapper {
     pub async fn recv_message(
@@ -32,7 +46,7 @@ impl SocketReadWrapper {
             invalid_data!("Message is empty");
         }
 
-        if size > 2 * 1024 * 1024 {
+        if size > MAX_MESSAGE_SIZE {
             const SKIP_BUFFER_SIZE: usize = 2048;
             let mut skip_buf = [0; SKIP_BUFFER_SIZE];
             let mut remaining = size;
@@ -51,35 +65,97 @@ impl SocketReadWrapper {
             cipher.decrypt(&mut data);
         }
 
-        WorldHostC2SMessage::parse(data[0], &data[1..], max_protocol_version)
+        let id = data[0];
+        if id & COMPRESSED_FLAG != 0
+            && max_protocol_version.is_some_and(|version| {
+                ProtocolCapabilities::from_version(version).supports_compression
+            })
+        {
+            let body = inflate(&data[1..])?;
+            return WorldHostC2SMessage::parse(id & !COMPRESSED_FLAG, &body, max_protocol_version);
+        }
+        WorldHostC2SMessage::parse(id, &data[1..], max_protocol_version)
     }
 }
 
+/// The size cap applies to the inflated body, so a small message can't inflate into a huge one
+fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    DeflateDecoder::new(data)
+        .take(MAX_MESSAGE_SIZE as u64 + 1)
+        .read_to_end(&mut body)?;
+    if body.len() > MAX_MESSAGE_SIZE {
+        invalid_data!("Messages bigger than 2 MB are not allowed.");
+    }
+    Ok(body)
+}
+
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    // Writing to a Vec can't fail
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
 impl SocketWriteWrapper {
     pub async fn send_message(
         &mut self,
         message: &WorldHostS2CMessage,
+        protocol_version: u32,
         encrypt_cipher: &mut Option<Aes128Cfb>,
     ) -> io::Result<()> {
+        self.write_message(message, protocol_version, encrypt_cipher)
+            .await?;
+        self.0.flush().await
+    }
+
+    /// Like [`Self::send_message`], but doesn't flush, allowing multiple messages to be sent
+    /// together.
+    pub async fn write_message(
+        &mut self,
+        message: &WorldHostS2CMessage,
+        protocol_version: u32,
+        encrypt_cipher: &mut Option<Aes128Cfb>,
+    ) -> io::Result<()> {
+        let buf = Self::encode_message(message, protocol_version, encrypt_cipher, false);
+        self.0.write_all(&buf).await
+    }
+
+    /// Frames and encrypts a message for a client on `protocol_version` without writing it. If
+    /// `compress` is set, bodies over [COMPRESSION_THRESHOLD] are deflated when that makes them
+    /// smaller.
+    pub fn encode_message(
+        message: &WorldHostS2CMessage,
+        protocol_version: u32,
+        encrypt_cipher: &mut Option<Aes128Cfb>,
+        compress: bool,
+    ) -> Vec<u8> {
         let mut buf = vec![message.type_id()];
-        message.serialize_to(&mut buf);
+        message.serialize_for(protocol_version, &mut buf);
+        if compress && buf.len() - 1 > COMPRESSION_THRESHOLD {
+            let compressed = deflate(&buf[1..]);
+            if compressed.len() < buf.len() - 1 {
+                buf.truncate(1);
+                buf[0] |= COMPRESSED_FLAG;
+                buf.extend(compressed);
+            }
+        }
         buf.splice(0..0, (buf.len() as u32).to_be_bytes());
         if let Some(cipher) = encrypt_cipher {
             cipher.encrypt(&mut buf);
         }
-        self.0.write_all(&buf).await?;
-        self.0.flush().await
+        buf
     }
 
     pub async fn close_error(&mut self, message: String, encrypt_cipher: &mut Option<Aes128Cfb>) {
+        let message = WorldHostS2CMessage::Error {
+            message,
+            critical: true,
+        };
+        // The client's version may not be known yet, but Error is the same on every version
+        let protocol_version = message.first_protocol();
         if let Err(error) = self
-            .send_message(
-                &WorldHostS2CMessage::Error {
-                    message,
-                    critical: true,
-                },
-                encrypt_cipher,
-            )
+            .send_message(&message, protocol_version, encrypt_cipher)
             .await
         {
             warn!("Error in critical error sending: {error}");
@@ -89,3 +165,120 @@ impl SocketWriteWrapper {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::c2s_message::LIST_ONLINE_ID;
+    use crate::protocol::protocol_versions::{COMPRESSION_PROTOCOL, STABLE};
+    use std::io::Cursor;
+    use uuid::Uuid;
+
+    fn error_message(length: usize) -> WorldHostS2CMessage {
+        WorldHostS2CMessage::Error {
+            message: "a".repeat(length),
+            critical: false,
+        }
+    }
+
+    /// Decodes a frame produced by [SocketWriteWrapper::encode_message], returning whether it was
+    /// compressed
+    fn decode(frame: &[u8], protocol_version: u32) -> (WorldHostS2CMessage, bool) {
+        let size = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+        assert_eq!(size, frame.len() - 4);
+        let id = frame[4];
+        let compressed = id & COMPRESSED_FLAG != 0;
+        let body = if compressed {
+            inflate(&frame[5..]).unwrap()
+        } else {
+            frame[5..].to_vec()
+        };
+        let message =
+            WorldHostS2CMessage::parse(id & !COMPRESSED_FLAG, &body, protocol_version).unwrap();
+        (message, compressed)
+    }
+
+    /// A client frame with `body` deflated
+    fn compressed_frame(id: u8, body: &[u8]) -> Vec<u8> {
+        let compressed = deflate(body);
+        let mut frame = ((compressed.len() + 1) as u32).to_be_bytes().to_vec();
+        frame.push(id | COMPRESSED_FLAG);
+        frame.extend(compressed);
+        frame
+    }
+
+    async fn recv(frame: Vec<u8>, protocol_version: u32) -> io::Result<WorldHostC2SMessage> {
+        SocketReadWrapper(Box::new(Cursor::new(frame)))
+            .recv_message(&mut None, Some(protocol_version))
+            .await
+    }
+
+    #[test]
+    fn small_messages_not_compressed() {
+        // The body is a length prefix, the message and a bool
+        let message = error_message(COMPRESSION_THRESHOLD - 3);
+        let frame =
+            SocketWriteWrapper::encode_message(&message, COMPRESSION_PROTOCOL, &mut None, true);
+        assert_eq!(decode(&frame, COMPRESSION_PROTOCOL), (message, false));
+    }
+
+    #[test]
+    fn large_messages_compressed() {
+        let message = error_message(COMPRESSION_THRESHOLD - 2);
+        let frame =
+            SocketWriteWrapper::encode_message(&message, COMPRESSION_PROTOCOL, &mut None, true);
+        assert!(frame.len() < COMPRESSION_THRESHOLD);
+        assert_eq!(
+            decode(&frame, COMPRESSION_PROTOCOL),
+            (message.clone(), true)
+        );
+
+        let frame =
+            SocketWriteWrapper::encode_message(&message, COMPRESSION_PROTOCOL, &mut None, false);
+        assert_eq!(decode(&frame, COMPRESSION_PROTOCOL), (message, false));
+    }
+
+    #[tokio::test]
+    async fn compressed_client_messages() {
+        let friends = (0..100).map(Uuid::from_u128).collect::<Vec<_>>();
+        let mut body = (friends.len() as u32).to_be_bytes().to_vec();
+        for friend in &friends {
+            body.extend(friend.as_bytes());
+        }
+        assert!(body.len() > COMPRESSION_THRESHOLD);
+        let message = recv(
+            compressed_frame(LIST_ONLINE_ID, &body),
+            COMPRESSION_PROTOCOL,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            message,
+            WorldHostC2SMessage::ListOnline { friends: parsed } if parsed == friends
+        ));
+
+        // Older clients can't compress, so the flag is just part of an unknown type ID
+        assert!(
+            recv(compressed_frame(LIST_ONLINE_ID, &body), STABLE)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn inflated_size_capped() {
+        assert_eq!(
+            inflate(&deflate(&[0; MAX_MESSAGE_SIZE])).unwrap().len(),
+            MAX_MESSAGE_SIZE
+        );
+
+        let frame = compressed_frame(LIST_ONLINE_ID, &vec![0; MAX_MESSAGE_SIZE + 1]);
+        assert!(frame.len() < 64 * 1024);
+        let error = recv(frame, COMPRESSION_PROTOCOL).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            "Messages bigger than 2 MB are not allowed."
+        );
+    }
+}
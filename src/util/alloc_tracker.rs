@@ -0,0 +1,83 @@
+//! A global allocator for tests that records the largest allocation made by the current thread, so
+//! that parsers can be checked to not allocate based on untrusted lengths, and how many allocations
+//! it made, so that hot paths can be checked to not allocate per item
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct TrackingAllocator;
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+thread_local! {
+    static LARGEST: Cell<usize> = const { Cell::new(0) };
+    static COUNT: Cell<usize> = const { Cell::new(0) };
+}
+
+fn record(size: usize) {
+    // Fails while the thread is being torn down, when nothing is being measured anyway
+    let _ = LARGEST.try_with(|largest| largest.set(largest.get().max(size)));
+    let _ = COUNT.try_with(|count| count.set(count.get() + 1));
+}
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record(layout.size());
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        record(layout.size());
+        unsafe { System.alloc_zeroed(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record(new_size);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+/// Runs `f`, returning its result and the size of the largest allocation it made on this thread
+pub fn largest_allocation<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    let previous = LARGEST.with(|largest| largest.replace(0));
+    let result = f();
+    let size = LARGEST.with(|largest| largest.replace(previous));
+    (result, size)
+}
+
+/// Runs `f`, returning its result and how many allocations and reallocations it made on this
+/// thread
+pub fn allocation_count<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    let previous = COUNT.with(|count| count.replace(0));
+    let result = f();
+    let count = COUNT.with(|count| count.replace(previous));
+    (result, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_largest_allocation() {
+        let (data, size) = largest_allocation(|| vec![0u8; 10_000]);
+        assert_eq!(data.len(), 10_000);
+        assert!(size >= 10_000);
+        let ((), size) = largest_allocation(|| {});
+        assert_eq!(size, 0);
+    }
+
+    #[test]
+    fn counts_allocations() {
+        let (boxes, count) = allocation_count(|| (0..10).map(Box::new).collect::<Vec<_>>());
+        assert_eq!(boxes.len(), 10);
+        assert_eq!(count, 11);
+        let ((), count) = allocation_count(|| {});
+        assert_eq!(count, 0);
+    }
+}
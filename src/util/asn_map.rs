@@ -0,0 +1,43 @@
+use crate::util::ip_range_map::{CsvSource, IpRangeMap};
+use std::net::IpAddr;
+
+/// AWS, Google Cloud, Azure, Oracle Cloud, Alibaba Cloud, DigitalOcean, Linode, Vultr, OVH,
+/// Hetzner, Scaleway, and Contabo
+pub const DEFAULT_HOSTING_ASNS: &str =
+    "16509,14618,396982,8075,31898,45102,14061,63949,20473,16276,24940,12876,51167";
+
+/// The autonomous system that each address range belongs to
+pub struct AsnMap {
+    ranges: IpRangeMap,
+}
+
+impl AsnMap {
+    /// Loads CSVs like sapics/ip-location-db's asn-*-num files, whose first three columns are the
+    /// first address, last address, and ASN. Also returns the sources that failed to load, which
+    /// are left out.
+    pub async fn load_from_csvs(sources: &[CsvSource]) -> (Self, Vec<CsvSource>) {
+        let (ranges, failed) = IpRangeMap::load_from_csvs(sources, parse_record, false).await;
+        (Self { ranges }, failed)
+    }
+
+    pub fn get(&self, addr: IpAddr) -> Option<u32> {
+        self.ranges.get(addr)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+}
+
+fn parse_record(
+    record: csv_async::Result<csv_async::StringRecord>,
+) -> anyhow::Result<Option<(u128, u128, u32)>> {
+    let record = record?;
+    if record.len() < 3 || record[2].is_empty() {
+        return Ok(None);
+    }
+    let start_of_range = record[0].parse()?;
+    let end_of_range = record[1].parse()?;
+    let asn = record[2].parse()?;
+    Ok(Some((start_of_range, end_of_range, asn)))
+}
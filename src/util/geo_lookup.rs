@@ -0,0 +1,128 @@
+use crate::USER_AGENT;
+use crate::lat_long::LatitudeLongitude;
+use crate::util::ip_info::IpInfo;
+use anyhow::bail;
+use dashmap::DashMap;
+use log::{debug, warn};
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::time::{Instant, timeout};
+
+/// How long a found location is remembered
+const FOUND_CACHE_TIME: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long an address without a location is remembered, including after failed lookups
+const NOT_FOUND_CACHE_TIME: Duration = Duration::from_secs(60 * 60);
+
+/// Looks up addresses that aren't in the [IpInfoMap](crate::util::ip_info_map::IpInfoMap) with an
+/// HTTP API that answers like ip-api.com
+pub struct GeoLookup {
+    client: reqwest::Client,
+    /// `{ip}` is replaced with the address
+    url_template: String,
+    timeout: Duration,
+    permits: Semaphore,
+    /// Results as [IpInfo::to_u64], with when they were looked up
+    cache: DashMap<IpAddr, (Instant, Option<u64>)>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeoLookupResponse {
+    /// ip-api.com sends "fail" for reserved and unknown addresses
+    status: Option<String>,
+    country_code: Option<String>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+}
+
+impl GeoLookup {
+    pub fn new(url_template: String, timeout: Duration, concurrency: usize) -> Self {
+        let client = reqwest::ClientBuilder::new()
+            .timeout(timeout)
+            .user_agent(USER_AGENT)
+            .build()
+            .unwrap();
+        Self {
+            client,
+            url_template,
+            timeout,
+            permits: Semaphore::new(concurrency),
+            cache: DashMap::new(),
+        }
+    }
+
+    /// Never takes longer than the timeout, including time spent waiting for a free request slot.
+    /// Failures are logged and treated as not found.
+    pub async fn get(&self, addr: IpAddr) -> Option<IpInfo> {
+        if let Some(entry) = self.cache.get(&addr)
+            && entry.0.elapsed() < cache_time(entry.1)
+        {
+            return entry.1.map(IpInfo::from_u64);
+        }
+        let result = match timeout(self.timeout, self.fetch(addr)).await {
+            Ok(Ok(info)) => info,
+            Ok(Err(error)) => {
+                warn!("Failed to look up location of {addr}: {error}");
+                None
+            }
+            Err(_) => {
+                warn!("Timed out looking up location of {addr}");
+                None
+            }
+        };
+        let result = result.map(|info| info.to_u64());
+        self.cache.insert(addr, (Instant::now(), result));
+        result.map(IpInfo::from_u64)
+    }
+
+    async fn fetch(&self, addr: IpAddr) -> anyhow::Result<Option<IpInfo>> {
+        let _permit = self.permits.acquire().await?;
+        let url = self.url_template.replace("{ip}", &addr.to_string());
+        let response: GeoLookupResponse = self
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if response
+            .status
+            .as_deref()
+            .is_some_and(|status| status != "success")
+        {
+            debug!("No location found for {addr}");
+            return Ok(None);
+        }
+        let (Some(country_code), Some(lat), Some(long)) =
+            (response.country_code, response.lat, response.lon)
+        else {
+            return Ok(None);
+        };
+        let Ok(country) = country_code.parse() else {
+            bail!("Invalid country code {country_code}");
+        };
+        Ok(Some(IpInfo {
+            country,
+            lat_long: LatitudeLongitude(lat, long),
+        }))
+    }
+
+    /// Forgets expired results. Returns how many were removed.
+    pub fn prune(&self) -> usize {
+        let before = self.cache.len();
+        self.cache
+            .retain(|_, (looked_up_at, result)| looked_up_at.elapsed() < cache_time(*result));
+        before - self.cache.len()
+    }
+}
+
+fn cache_time(result: Option<u64>) -> Duration {
+    if result.is_some() {
+        FOUND_CACHE_TIME
+    } else {
+        NOT_FOUND_CACHE_TIME
+    }
+}
@@ -8,6 +8,15 @@ pub struct IpInfo {
 }
 
 impl IpInfo {
+    /// Stands in for the location of entries that only have a country. No real latitude is this
+    /// far south, so it survives packing into a u32.
+    #[cfg(feature = "embedded-geo")]
+    pub const NO_LOCATION: LatitudeLongitude = LatitudeLongitude(-180.0, -180.0);
+
+    pub fn has_location(&self) -> bool {
+        self.lat_long.0 >= -90.0
+    }
+
     pub fn from_u32(x: u32) -> Self {
         Self {
             country: int_to_country(x & COUNTRY_MASK),
@@ -20,11 +29,28 @@ impl IpInfo {
         let country = country_to_int(self.country);
         (lat_long << LAT_LONG_SHIFT) | country
     }
+
+    /// Like [Self::from_u32], but with 16 bits per axis (about 0.0055° instead of 0.18°)
+    pub fn from_u64(x: u64) -> Self {
+        Self {
+            country: int_to_country(x as u32 & COUNTRY_MASK),
+            lat_long: fixed32_to_lat_long(x >> LAT_LONG_SHIFT),
+        }
+    }
+
+    pub fn to_u64(&self) -> u64 {
+        let lat_long = lat_long_to_fixed32(self.lat_long);
+        let country = country_to_int(self.country) as u64;
+        (lat_long << LAT_LONG_SHIFT) | country
+    }
 }
 
 const FIXED_11_SHIFT: u32 = 11;
 const FIXED_11_MAGNITUDE: f64 = (1 << FIXED_11_SHIFT) as f64;
 const FIXED_11_MASK: u32 = (1 << FIXED_11_SHIFT) - 1;
+const FIXED_16_SHIFT: u32 = 16;
+const FIXED_16_MAGNITUDE: f64 = (1 << FIXED_16_SHIFT) as f64;
+const FIXED_16_MASK: u64 = (1 << FIXED_16_SHIFT) - 1;
 const COUNTRY_CHAR_BASE: u32 = 'A' as u32;
 const COUNTRY_CHAR_SHIFT: u32 = 5;
 const COUNTRY_CHAR_MASK: u32 = (1 << COUNTRY_CHAR_SHIFT) - 1;
@@ -51,6 +77,27 @@ fn lat_long_to_fixed22(lat_long: LatitudeLongitude) -> u32 {
     (lat << FIXED_11_SHIFT) | long
 }
 
+fn fixed16_to_double(fixed: u64) -> f64 {
+    (fixed as f64 * 360.0 / FIXED_16_MAGNITUDE) - 180.0
+}
+
+/// 180° is clamped so that it doesn't carry into the other axis
+fn double_to_fixed16(double: f64) -> u64 {
+    (((double + 180.0) / 360.0 * FIXED_16_MAGNITUDE) as u64).min(FIXED_16_MASK)
+}
+
+fn fixed32_to_lat_long(fixed: u64) -> LatitudeLongitude {
+    let lat = fixed16_to_double((fixed >> FIXED_16_SHIFT) & FIXED_16_MASK);
+    let long = fixed16_to_double(fixed & FIXED_16_MASK);
+    LatitudeLongitude(lat, long)
+}
+
+fn lat_long_to_fixed32(lat_long: LatitudeLongitude) -> u64 {
+    let lat = double_to_fixed16(lat_long.0);
+    let long = double_to_fixed16(lat_long.1);
+    (lat << FIXED_16_SHIFT) | long
+}
+
 fn country_char_to_int(char: u8) -> u32 {
     char as u32 - COUNTRY_CHAR_BASE
 }
@@ -64,8 +111,117 @@ fn country_to_int(country: CountryCode) -> u32 {
     (country_char_to_int(chars[0]) << COUNTRY_CHAR_SHIFT) | country_char_to_int(chars[1])
 }
 
+/// Codes that aren't assigned to a country (including ones that aren't even letters) become
+/// [CountryCode::UNKNOWN]
 fn int_to_country(int: u32) -> CountryCode {
     let char1 = country_int_to_char((int >> COUNTRY_CHAR_SHIFT) & COUNTRY_CHAR_MASK);
     let char2 = country_int_to_char(int & COUNTRY_CHAR_MASK);
-    CountryCode::new(char1, char2).unwrap()
+    CountryCode::new(char1, char2)
+        .ok()
+        .filter(CountryCode::is_assigned)
+        .unwrap_or(CountryCode::UNKNOWN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One step of a 16-bit axis
+    const PRECISE_STEP: f64 = 360.0 / FIXED_16_MAGNITUDE;
+
+    fn precise_round_trip(lat: f64, long: f64) -> IpInfo {
+        let country = CountryCode::new('G', 'B').unwrap();
+        let info = IpInfo::from_u64(
+            IpInfo {
+                country,
+                lat_long: LatitudeLongitude(lat, long),
+            }
+            .to_u64(),
+        );
+        assert!(info.country == country);
+        info
+    }
+
+    fn assert_within_step(actual: f64, expected: f64) {
+        // Encoding truncates, so decoded values are never above the original
+        assert!(
+            actual <= expected && expected - actual <= PRECISE_STEP,
+            "{actual} vs {expected}"
+        );
+    }
+
+    #[test]
+    fn precise_round_trip_precision() {
+        for (lat, long) in [
+            (51.5074, -0.1278),
+            (-33.8688, 151.2093),
+            (0.0, 0.0),
+            (35.6762, 139.6503),
+            (-0.001, 0.001),
+        ] {
+            let info = precise_round_trip(lat, long);
+            assert_within_step(info.lat_long.0, lat);
+            assert_within_step(info.lat_long.1, long);
+            assert!(info.has_location());
+        }
+    }
+
+    #[test]
+    fn precise_poles_and_antimeridian() {
+        let north = precise_round_trip(90.0, 0.0);
+        assert_eq!(north.lat_long.0, 90.0);
+        let south = precise_round_trip(-90.0, 0.0);
+        assert_eq!(south.lat_long.0, -90.0);
+        assert!(south.has_location());
+
+        let west = precise_round_trip(0.0, -180.0);
+        assert_eq!(west.lat_long.1, -180.0);
+        // 180° is clamped to the last step rather than carrying into the latitude
+        let east = precise_round_trip(0.0, 180.0);
+        assert_eq!(east.lat_long.0, 0.0);
+        assert_within_step(east.lat_long.1, 180.0);
+        let corner = precise_round_trip(90.0, 180.0);
+        assert_eq!(corner.lat_long.0, 90.0);
+        assert_within_step(corner.lat_long.1, 180.0);
+    }
+
+    #[test]
+    fn precise_is_more_precise() {
+        let mut coarse_error = 0f64;
+        let mut precise_error = 0f64;
+        for i in 0..1000 {
+            let lat_long = LatitudeLongitude(-90.0 + i as f64 * 0.1799, -180.0 + i as f64 * 0.3599);
+            let info = IpInfo {
+                country: CountryCode::UNKNOWN,
+                lat_long,
+            };
+            let coarse = IpInfo::from_u32(info.to_u32());
+            let precise = IpInfo::from_u64(info.to_u64());
+            // The location bits don't disturb the country
+            assert!(precise.country == CountryCode::UNKNOWN);
+            for (original, coarse, precise) in [
+                (lat_long.0, coarse.lat_long.0, precise.lat_long.0),
+                (lat_long.1, coarse.lat_long.1, precise.lat_long.1),
+            ] {
+                coarse_error = coarse_error.max(original - coarse);
+                precise_error = precise_error.max(original - precise);
+            }
+        }
+        assert!(coarse_error < 360.0 / FIXED_11_MAGNITUDE);
+        assert!(precise_error < PRECISE_STEP);
+        assert!(coarse_error > PRECISE_STEP * 10.0);
+    }
+
+    #[cfg(feature = "embedded-geo")]
+    #[test]
+    fn precise_no_location() {
+        let info = IpInfo::from_u64(
+            IpInfo {
+                country: CountryCode::UNKNOWN,
+                lat_long: IpInfo::NO_LOCATION,
+            }
+            .to_u64(),
+        );
+        assert!(!info.has_location());
+    }
 }
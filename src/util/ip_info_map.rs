@@ -1,102 +1,350 @@
+use crate::country_code::CountryCode;
 use crate::lat_long::LatitudeLongitude;
 use crate::util::ip_info::IpInfo;
-use crate::util::range_map::{U32ToU32RangeMap, U128ToU32RangeMap};
-use async_compression::tokio::bufread::GzipDecoder;
-use futures::{StreamExt, TryStreamExt};
+use crate::util::ip_range_map::{CsvSource, IpRangeMap, UnusualRecord};
+#[cfg(feature = "maxminddb")]
+use anyhow::bail;
+#[cfg(feature = "maxminddb")]
 use log::error;
-use reqwest::IntoUrl;
 use std::net::IpAddr;
-use tokio_util::compat::TokioAsyncReadCompatExt;
-use tokio_util::io::StreamReader;
+#[cfg(feature = "maxminddb")]
+use std::path::Path;
 
 pub struct IpInfoMap {
-    four_map: U32ToU32RangeMap,
-    six_map: U128ToU32RangeMap,
+    ranges: IpInfoRanges,
+    /// Queried instead of the range maps if set
+    #[cfg(feature = "maxminddb")]
+    mmdb: Option<maxminddb::Reader<Vec<u8>>>,
 }
 
-const U32_MAX: u128 = u32::MAX as u128;
+/// Locations are packed into each range's value, trading memory for precision
+enum IpInfoRanges {
+    /// Values are [IpInfo::to_u32]
+    Standard(IpRangeMap<u32>),
+    /// Values are [IpInfo::to_u64]
+    Precise(IpRangeMap<u64>),
+}
 
 impl IpInfoMap {
-    pub async fn load_from_compressed_geolite_city_files<T: IntoUrl>(
-        urls: Vec<T>,
-    ) -> anyhow::Result<Self> {
-        let mut four_map = U32ToU32RangeMap::new();
-        let mut six_map = U128ToU32RangeMap::new();
-        for url in urls {
-            csv_async::AsyncReader::from_reader(
-                GzipDecoder::new(StreamReader::new(
-                    reqwest::get(url)
-                        .await?
-                        .bytes_stream()
-                        .map_err(std::io::Error::other),
-                ))
-                .compat(),
-            )
-            .into_records()
-            .for_each(|record| {
-                match parse_record(record) {
-                    Ok(info) => {
-                        if let Some((start_of_range, end_of_range, info)) = info {
-                            if end_of_range < U32_MAX {
-                                four_map.put(start_of_range as u32, end_of_range as u32, info);
-                            } else {
-                                six_map.put(start_of_range, end_of_range, info);
-                            }
-                        }
-                    }
-                    Err(err) => error!("Failed to parse record: {err:?}"),
-                }
-                futures::future::ready(())
-            })
-            .await;
+    /// Ranges from later sources win over earlier overlapping ones. Also returns the sources that
+    /// failed to load, which are left out. If `precise`, locations are stored with 16 bits per
+    /// axis instead of 11, which takes 4 more bytes per range. If `strict`, sources with too many
+    /// records that fail to parse count as failed.
+    pub async fn load_from_geolite_city_csvs(
+        sources: &[CsvSource],
+        precise: bool,
+        strict: bool,
+    ) -> (Self, Vec<CsvSource>) {
+        let (ranges, failed) = if precise {
+            let (ranges, failed) =
+                IpRangeMap::load_from_csvs(sources, parse_precise_record, strict).await;
+            (IpInfoRanges::Precise(ranges), failed)
+        } else {
+            let (ranges, failed) = IpRangeMap::load_from_csvs(sources, parse_record, strict).await;
+            (IpInfoRanges::Standard(ranges), failed)
+        };
+        let map = Self {
+            ranges,
+            #[cfg(feature = "maxminddb")]
+            mmdb: None,
+        };
+        (map, failed)
+    }
+
+    /// Like [Self::load_from_geolite_city_csvs], but replaces the contents of this map. Its range
+    /// maps are reused if they have the same precision, so rebuilding with about the same number of
+    /// ranges doesn't reallocate.
+    pub async fn rebuild_from_geolite_city_csvs(
+        &mut self,
+        sources: &[CsvSource],
+        precise: bool,
+        strict: bool,
+    ) -> Vec<CsvSource> {
+        #[cfg(feature = "maxminddb")]
+        {
+            self.mmdb = None;
+        }
+        match (&mut self.ranges, precise) {
+            (IpInfoRanges::Standard(ranges), false) => {
+                ranges
+                    .rebuild_from_csvs(sources, parse_record, strict)
+                    .await
+            }
+            (IpInfoRanges::Precise(ranges), true) => {
+                ranges
+                    .rebuild_from_csvs(sources, parse_precise_record, strict)
+                    .await
+            }
+            _ => {
+                let (map, failed) =
+                    Self::load_from_geolite_city_csvs(sources, precise, strict).await;
+                *self = map;
+                failed
+            }
         }
-        four_map.shrink_to_fit();
-        six_map.shrink_to_fit();
-        Ok(Self { four_map, six_map })
     }
 
+    /// Loads the country-only data built in with the embedded-geo feature. Entries have
+    /// [IpInfo::NO_LOCATION] instead of a location.
+    #[cfg(feature = "embedded-geo")]
+    pub async fn load_embedded_countries() -> Self {
+        const SOURCES: [CsvSource; 2] = [
+            CsvSource::Embedded(
+                "geolite2-country-ipv4-num.csv.gz",
+                include_bytes!(concat!(
+                    env!("OUT_DIR"),
+                    "/geolite2-country-ipv4-num.csv.gz"
+                )),
+            ),
+            CsvSource::Embedded(
+                "geolite2-country-ipv6-num.csv.gz",
+                include_bytes!(concat!(
+                    env!("OUT_DIR"),
+                    "/geolite2-country-ipv6-num.csv.gz"
+                )),
+            ),
+        ];
+        let (ranges, _) = IpRangeMap::load_from_csvs(&SOURCES, parse_country_record, false).await;
+        Self {
+            ranges: IpInfoRanges::Standard(ranges),
+            #[cfg(feature = "maxminddb")]
+            mmdb: None,
+        }
+    }
+
+    /// Loads a MaxMind City database, such as GeoLite2-City.mmdb. The database is kept in memory
+    /// and queried directly, since converting it would lose its network boundaries.
+    #[cfg(feature = "maxminddb")]
+    pub fn load_from_mmdb(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let reader = maxminddb::Reader::open_readfile(path)?;
+        if !reader.metadata.database_type.contains("City") {
+            bail!(
+                "Expected a City database, but got {}",
+                reader.metadata.database_type
+            );
+        }
+        Ok(Self {
+            mmdb: Some(reader),
+            ..Self::default()
+        })
+    }
+
+    /// IPv4-mapped IPv6 addresses are looked up as the IPv4 address they contain
     pub fn get(&self, addr: IpAddr) -> Option<IpInfo> {
-        let addr_bits = match addr {
-            IpAddr::V4(ipv4) => ipv4.to_bits() as u128,
-            IpAddr::V6(ipv6) => ipv6.to_bits(),
-        };
-        if addr_bits <= U32_MAX {
-            self.four_map.get(&(addr_bits as u32))
-        } else {
-            self.six_map.get(&addr_bits)
+        #[cfg(feature = "maxminddb")]
+        if let Some(reader) = &self.mmdb {
+            return lookup_mmdb(reader, addr.to_canonical());
+        }
+        match &self.ranges {
+            IpInfoRanges::Standard(ranges) => ranges.get(addr).map(IpInfo::from_u32),
+            IpInfoRanges::Precise(ranges) => ranges.get(addr).map(IpInfo::from_u64),
         }
-        .map(IpInfo::from_u32)
     }
 
+    /// For a MaxMind database, this is the number of nodes in its search tree
     pub fn len(&self) -> usize {
-        self.four_map.len() + self.six_map.len()
+        #[cfg(feature = "maxminddb")]
+        if let Some(reader) = &self.mmdb {
+            return reader.metadata.node_count as usize;
+        }
+        match &self.ranges {
+            IpInfoRanges::Standard(ranges) => ranges.len(),
+            IpInfoRanges::Precise(ranges) => ranges.len(),
+        }
     }
 }
 
 fn parse_record(
     record: csv_async::Result<csv_async::StringRecord>,
 ) -> anyhow::Result<Option<(u128, u128, u32)>> {
+    Ok(parse_city_record(record)?.map(|(start, end, ip_info)| (start, end, ip_info.to_u32())))
+}
+
+fn parse_precise_record(
+    record: csv_async::Result<csv_async::StringRecord>,
+) -> anyhow::Result<Option<(u128, u128, u64)>> {
+    Ok(parse_city_record(record)?.map(|(start, end, ip_info)| (start, end, ip_info.to_u64())))
+}
+
+fn parse_city_record(
+    record: csv_async::Result<csv_async::StringRecord>,
+) -> anyhow::Result<Option<(u128, u128, IpInfo)>> {
     let record = record?;
     if record.len() < 9 || record[7].is_empty() || record[8].is_empty() {
         return Ok(None);
     }
     let start_of_range = record[0].parse()?;
     let end_of_range = record[1].parse()?;
-    let country = record[2].parse()?;
+    let country = parse_country(&record[2])?;
     let lat = record[7].parse()?;
     let long = record[8].parse()?;
     let ip_info = IpInfo {
         country,
         lat_long: LatitudeLongitude(lat, long),
     };
+    Ok(Some((start_of_range, end_of_range, ip_info)))
+}
+
+/// For geolite2-country CSVs, which only have the range and country
+#[cfg(feature = "embedded-geo")]
+fn parse_country_record(
+    record: csv_async::Result<csv_async::StringRecord>,
+) -> anyhow::Result<Option<(u128, u128, u32)>> {
+    let record = record?;
+    if record.len() < 3 || record[2].is_empty() {
+        return Ok(None);
+    }
+    let start_of_range = record[0].parse()?;
+    let end_of_range = record[1].parse()?;
+    let country = parse_country(&record[2])?;
+    let ip_info = IpInfo {
+        country,
+        lat_long: IpInfo::NO_LOCATION,
+    };
     Ok(Some((start_of_range, end_of_range, ip_info.to_u32())))
 }
 
+/// Lowercase codes are accepted, but anything else that isn't a country code is an
+/// [UnusualRecord]
+fn parse_country(code: &str) -> anyhow::Result<CountryCode> {
+    code.trim()
+        .to_ascii_uppercase()
+        .parse()
+        .map_err(|_| UnusualRecord(format!("country code {code:?}")).into())
+}
+
+/// Like [parse_city_record], addresses without a country and location have no info
+#[cfg(feature = "maxminddb")]
+fn lookup_mmdb(reader: &maxminddb::Reader<Vec<u8>>, addr: IpAddr) -> Option<IpInfo> {
+    let city = match reader.lookup::<maxminddb::geoip2::City>(addr) {
+        Ok(city) => city,
+        Err(maxminddb::MaxMindDBError::AddressNotFoundError(_)) => return None,
+        Err(err) => {
+            error!("Failed to look up {addr} in IP info database: {err}");
+            return None;
+        }
+    };
+    let country = city.country?.iso_code?.parse().ok()?;
+    let location = city.location?;
+    Some(IpInfo {
+        country,
+        lat_long: LatitudeLongitude(location.latitude?, location.longitude?),
+    })
+}
+
 impl Default for IpInfoMap {
     fn default() -> Self {
         Self {
-            four_map: U32ToU32RangeMap::new(),
-            six_map: U128ToU32RangeMap::new(),
+            ranges: IpInfoRanges::Standard(IpRangeMap::default()),
+            #[cfg(feature = "maxminddb")]
+            mmdb: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const LONDON: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 5));
+    const CHICAGO: IpAddr = IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8));
+
+    fn fixture() -> CsvSource {
+        CsvSource::File(
+            concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/testdata/geolite2-city-ipv4-num.csv"
+            )
+            .into(),
+        )
+    }
+
+    fn country(map: &IpInfoMap, addr: IpAddr) -> Option<String> {
+        map.get(addr).map(|info| info.country.to_string())
+    }
+
+    #[tokio::test]
+    async fn rebuild() {
+        let (mut map, failed) =
+            IpInfoMap::load_from_geolite_city_csvs(&[fixture()], false, true).await;
+        assert!(failed.is_empty());
+        let len = map.len();
+        assert_eq!(country(&map, LONDON).as_deref(), Some("GB"));
+
+        let failed = map
+            .rebuild_from_geolite_city_csvs(&[fixture()], false, true)
+            .await;
+        assert!(failed.is_empty());
+        assert_eq!(map.len(), len);
+        assert_eq!(country(&map, LONDON).as_deref(), Some("GB"));
+        assert_eq!(country(&map, CHICAGO).as_deref(), Some("US"));
+        assert!(matches!(map.ranges, IpInfoRanges::Standard(_)));
+
+        // Changing the precision replaces the range maps
+        let failed = map
+            .rebuild_from_geolite_city_csvs(&[fixture()], true, true)
+            .await;
+        assert!(failed.is_empty());
+        assert!(matches!(map.ranges, IpInfoRanges::Precise(_)));
+        assert_eq!(map.len(), len);
+        let london = map.get(LONDON).unwrap();
+        assert!((london.lat_long.0 - 51.5085).abs() < 0.01);
+        assert!((london.lat_long.1 - -0.1257).abs() < 0.01);
+    }
+
+    #[cfg(feature = "maxminddb")]
+    fn mmdb(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    #[cfg(feature = "maxminddb")]
+    #[test]
+    fn mmdb_lookups() {
+        let map = IpInfoMap::load_from_mmdb(mmdb("city-test.mmdb")).unwrap();
+        assert!(map.len() > 0);
+        let london = map.get("81.2.69.170".parse().unwrap()).unwrap();
+        assert_eq!(london.country.to_string(), "GB");
+        assert_eq!(london.lat_long.0, 51.5142);
+        assert_eq!(london.lat_long.1, -0.0931);
+        // The test database only has IPv4 networks under ::/96, so this is only found if it's
+        // canonicalized first
+        let mapped = map.get("::ffff:81.2.69.170".parse().unwrap()).unwrap();
+        assert_eq!(mapped.country.to_string(), "GB");
+        assert_eq!(
+            country(&map, "89.160.20.113".parse().unwrap()).as_deref(),
+            Some("SE")
+        );
+        assert_eq!(
+            country(&map, "2001:218::1".parse().unwrap()).as_deref(),
+            Some("JP")
+        );
+        // No location
+        assert_eq!(country(&map, "1.2.3.4".parse().unwrap()), None);
+        // Not in the database
+        assert_eq!(country(&map, "81.2.69.192".parse().unwrap()), None);
+        assert_eq!(country(&map, "2001:db8::1".parse().unwrap()), None);
+    }
+
+    #[cfg(feature = "maxminddb")]
+    #[test]
+    fn mmdb_rejects_other_databases() {
+        let Err(error) = IpInfoMap::load_from_mmdb(mmdb("country-test.mmdb")) else {
+            panic!("Loaded a Country database");
+        };
+        assert_eq!(
+            error.to_string(),
+            "Expected a City database, but got GeoIP2-Country"
+        );
+        assert!(IpInfoMap::load_from_mmdb(mmdb("missing.mmdb")).is_err());
+        assert!(
+            IpInfoMap::load_from_mmdb(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/testdata/geolite2-city-ipv4-num.csv"
+            ))
+            .is_err()
+        );
+    }
+}
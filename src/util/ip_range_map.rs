@@ -0,0 +1,494 @@
+use crate::util::range_map::RangeMap;
+use async_compression::tokio::bufread::GzipDecoder;
+use futures::{StreamExt, TryStreamExt};
+use log::{error, info, warn};
+use reqwest::Url;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::pin::pin;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::time::{Instant, sleep};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+use tokio_util::io::StreamReader;
+
+/// Maps IPv4 and IPv6 address ranges to a packed value
+pub struct IpRangeMap<V: Copy = u32> {
+    four_map: RangeMap<u32, V>,
+    six_map: RangeMap<u128, V>,
+}
+
+/// Turns a CSV record into an inclusive range of addresses as integers and its value, or None if
+/// the record should be skipped
+pub type RecordParser<V = u32> =
+    fn(csv_async::Result<csv_async::StringRecord>) -> anyhow::Result<Option<(u128, u128, V)>>;
+
+/// Returned by a [RecordParser] for records with unusual values, such as an unknown country code.
+/// These are skipped and counted without logging each one.
+#[derive(Debug)]
+pub struct UnusualRecord(pub String);
+
+impl Display for UnusualRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unusual {}", self.0)
+    }
+}
+
+impl std::error::Error for UnusualRecord {}
+
+const U32_MAX: u128 = u32::MAX as u128;
+/// Sources with more records than this failing to parse (including unusual ones) are warned about,
+/// since their format may have changed
+const MAX_FAILURE_RATIO: f64 = 0.1;
+
+impl<V: Copy + Send + 'static> IpRangeMap<V> {
+    /// Sources are downloaded and parsed concurrently, but ranges from later sources still win
+    /// over earlier overlapping ones. Each source is retried with backoff before it's given up on.
+    /// Sources that still fail are logged, skipped, and returned, so that the rest of the map is
+    /// still usable. If `strict`, sources with too many records that fail to parse are treated as
+    /// failed too.
+    pub async fn load_from_csvs(
+        sources: &[CsvSource],
+        parse_record: RecordParser<V>,
+        strict: bool,
+    ) -> (Self, Vec<CsvSource>) {
+        let (entries, failed) = RangeEntries::load(sources, parse_record, strict).await;
+        (entries.into_map(), failed)
+    }
+
+    /// Like [Self::load_from_csvs], but replaces the contents of this map. Its capacity is kept,
+    /// so rebuilding with about the same number of ranges doesn't reallocate.
+    pub async fn rebuild_from_csvs(
+        &mut self,
+        sources: &[CsvSource],
+        parse_record: RecordParser<V>,
+        strict: bool,
+    ) -> Vec<CsvSource> {
+        let (entries, failed) = RangeEntries::load(sources, parse_record, strict).await;
+        self.four_map.rebuild_from_unsorted(entries.four);
+        self.six_map.rebuild_from_unsorted(entries.six);
+        failed
+    }
+
+    /// IPv4-mapped IPv6 addresses are looked up as the IPv4 address they contain
+    pub fn get(&self, addr: IpAddr) -> Option<V> {
+        let addr_bits = match addr.to_canonical() {
+            IpAddr::V4(ipv4) => ipv4.to_bits() as u128,
+            IpAddr::V6(ipv6) => ipv6.to_bits(),
+        };
+        if addr_bits <= U32_MAX {
+            self.four_map.get(&(addr_bits as u32))
+        } else {
+            self.six_map.get(&addr_bits)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.four_map.len() + self.six_map.len()
+    }
+}
+
+impl<V: Copy> Default for IpRangeMap<V> {
+    fn default() -> Self {
+        Self {
+            four_map: RangeMap::new(),
+            six_map: RangeMap::new(),
+        }
+    }
+}
+
+/// Where a CSV of address ranges is read from. Any of these may be gzipped.
+#[derive(Debug, Clone)]
+pub enum CsvSource {
+    Url(Url),
+    File(PathBuf),
+    /// Built into the binary, with a name for logging
+    #[cfg(feature = "embedded-geo")]
+    Embedded(&'static str, &'static [u8]),
+}
+
+impl CsvSource {
+    /// http and https URLs are downloaded, and anything else is a path
+    pub fn parse(source: &str) -> Self {
+        match Url::parse(source) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => Self::Url(url),
+            _ => Self::File(PathBuf::from(source)),
+        }
+    }
+}
+
+impl Display for CsvSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CsvSource::Url(url) => url.fmt(f),
+            CsvSource::File(path) => path.display().fmt(f),
+            #[cfg(feature = "embedded-geo")]
+            CsvSource::Embedded(name, _) => write!(f, "embedded {name}"),
+        }
+    }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Collected before building the maps, since ranges from the IPv6 file can land in the IPv4 map
+/// out of order
+struct RangeEntries<V> {
+    four: Vec<(u32, u32, V)>,
+    six: Vec<(u128, u128, V)>,
+    /// For the source these were read from. Not updated by [Self::append].
+    counts: RecordCounts,
+}
+
+impl<V> Default for RangeEntries<V> {
+    fn default() -> Self {
+        Self {
+            four: Vec::new(),
+            six: Vec::new(),
+            counts: RecordCounts::default(),
+        }
+    }
+}
+
+/// What happened to each record in a source
+#[derive(Copy, Clone, Default)]
+struct RecordCounts {
+    parsed: usize,
+    /// The parser returned None, usually for missing values
+    skipped: usize,
+    /// The parser returned [UnusualRecord]
+    unusual: usize,
+    failed: usize,
+}
+
+impl RecordCounts {
+    fn failure_ratio(&self) -> f64 {
+        let total = self.parsed + self.skipped + self.unusual + self.failed;
+        if total == 0 {
+            return 0.0;
+        }
+        (self.unusual + self.failed) as f64 / total as f64
+    }
+}
+
+/// Attempts per source, including the first
+const MAX_ATTEMPTS: u32 = 4;
+/// Doubled after each failed attempt
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(2);
+/// Failed records logged per source. The rest are only counted.
+const MAX_LOGGED_FAILURES: usize = 10;
+
+impl<V: Copy + Send + 'static> RangeEntries<V> {
+    /// See [IpRangeMap::load_from_csvs]
+    async fn load(
+        sources: &[CsvSource],
+        parse_record: RecordParser<V>,
+        strict: bool,
+    ) -> (Self, Vec<CsvSource>) {
+        let tasks: Vec<_> = sources
+            .iter()
+            .map(|source| {
+                let source = source.clone();
+                tokio::spawn(async move {
+                    let start = Instant::now();
+                    let result = Self::read_source_with_retries(&source, parse_record).await;
+                    (start.elapsed(), result)
+                })
+            })
+            .collect();
+        let mut entries = Self::default();
+        let mut failed = Vec::new();
+        for (task, source) in tasks.into_iter().zip(sources) {
+            let (duration, result) = task.await.unwrap();
+            match result {
+                Ok(source_entries) => {
+                    let counts = source_entries.counts;
+                    info!(
+                        "Loaded {source} in {duration:?} ({} ranges, {} skipped, {} unusual, {} failed)",
+                        source_entries.len(),
+                        counts.skipped,
+                        counts.unusual,
+                        counts.failed
+                    );
+                    let failure_percent = counts.failure_ratio() * 100.0;
+                    if counts.failure_ratio() > MAX_FAILURE_RATIO {
+                        if strict {
+                            error!(
+                                "{failure_percent:.1}% of records in {source} failed to parse. Leaving it out."
+                            );
+                            failed.push(source.clone());
+                            continue;
+                        }
+                        warn!(
+                            "{failure_percent:.1}% of records in {source} failed to parse. Has its format changed?"
+                        );
+                    }
+                    entries.append(source_entries);
+                }
+                Err(err) => {
+                    error!("Failed to load {source} in {duration:?}: {err}");
+                    failed.push(source.clone());
+                }
+            }
+        }
+        (entries, failed)
+    }
+}
+
+impl<V: Copy> RangeEntries<V> {
+    async fn read_source_with_retries(
+        source: &CsvSource,
+        parse_record: RecordParser<V>,
+    ) -> anyhow::Result<Self> {
+        let mut delay = INITIAL_RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            match Self::read_source(source, parse_record).await {
+                Ok(entries) => return Ok(entries),
+                Err(err) if attempt < MAX_ATTEMPTS => {
+                    warn!(
+                        "Failed to load {source} (attempt {attempt}/{MAX_ATTEMPTS}), retrying in {delay:?}: {err}"
+                    );
+                    sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn read_source(
+        source: &CsvSource,
+        parse_record: RecordParser<V>,
+    ) -> anyhow::Result<Self> {
+        let mut entries = Self::default();
+        match source {
+            CsvSource::Url(url) => {
+                let stream = reqwest::get(url.clone())
+                    .await?
+                    .error_for_status()?
+                    .bytes_stream()
+                    .map_err(io::Error::other);
+                entries
+                    .read_csv(StreamReader::new(stream), parse_record)
+                    .await?;
+            }
+            CsvSource::File(path) => {
+                entries
+                    .read_csv(BufReader::new(File::open(path).await?), parse_record)
+                    .await?;
+            }
+            #[cfg(feature = "embedded-geo")]
+            CsvSource::Embedded(_, data) => entries.read_csv(*data, parse_record).await?,
+        }
+        Ok(entries)
+    }
+
+    fn len(&self) -> usize {
+        self.four.len() + self.six.len()
+    }
+
+    /// Keeps the order of `other` after this, so that its ranges win over overlapping ones here
+    fn append(&mut self, mut other: Self) {
+        self.four.append(&mut other.four);
+        self.six.append(&mut other.six);
+    }
+
+    async fn read_csv<R: AsyncBufRead + Unpin + Send>(
+        &mut self,
+        mut reader: R,
+        parse_record: RecordParser<V>,
+    ) -> anyhow::Result<()> {
+        if reader.fill_buf().await?.starts_with(&GZIP_MAGIC) {
+            self.read_records(GzipDecoder::new(reader), parse_record)
+                .await
+        } else {
+            self.read_records(reader, parse_record).await
+        }
+    }
+
+    /// Fails on I/O errors, such as a dropped download, so that the source can be retried. Other
+    /// errors only skip and count the record. The CSVs have no header row, so every row is a
+    /// record.
+    async fn read_records<R: AsyncRead + Unpin + Send>(
+        &mut self,
+        reader: R,
+        parse_record: RecordParser<V>,
+    ) -> anyhow::Result<()> {
+        let reader = csv_async::AsyncReaderBuilder::new()
+            .has_headers(false)
+            .create_reader(reader.compat());
+        let mut records = pin!(reader.into_records());
+        while let Some(record) = records.next().await {
+            if let Err(err) = &record
+                && matches!(err.kind(), csv_async::ErrorKind::Io(_))
+            {
+                return Err(record.unwrap_err().into());
+            }
+            match parse_record(record) {
+                Ok(Some((start_of_range, end_of_range, value))) => {
+                    self.counts.parsed += 1;
+                    if end_of_range < U32_MAX {
+                        self.four
+                            .push((start_of_range as u32, end_of_range as u32, value));
+                    } else {
+                        self.six.push((start_of_range, end_of_range, value));
+                    }
+                }
+                Ok(None) => self.counts.skipped += 1,
+                Err(err) if err.is::<UnusualRecord>() => self.counts.unusual += 1,
+                Err(err) => {
+                    self.counts.failed += 1;
+                    if self.counts.failed <= MAX_LOGGED_FAILURES {
+                        error!("Failed to parse record: {err:?}");
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn into_map(self) -> IpRangeMap<V> {
+        IpRangeMap {
+            four_map: RangeMap::from_unsorted(self.four),
+            six_map: RangeMap::from_unsorted(self.six),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::path::Path;
+
+    const FIXTURE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/testdata/geolite2-city-ipv4-num.csv"
+    );
+
+    fn v4(addr: &str) -> u32 {
+        addr.parse::<Ipv4Addr>().unwrap().to_bits()
+    }
+
+    fn v6(addr: &str) -> u128 {
+        addr.parse::<Ipv6Addr>().unwrap().to_bits()
+    }
+
+    fn lookup(map: &IpRangeMap<char>, addr: &str) -> Option<char> {
+        map.get(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn get_by_address_family() {
+        let map = IpRangeMap {
+            four_map: RangeMap::from_unsorted(vec![
+                (v4("1.2.3.0"), v4("1.2.3.255"), 'a'),
+                (v4("10.0.0.0"), v4("10.255.255.255"), 'b'),
+            ]),
+            six_map: RangeMap::from_unsorted(vec![(v6("2001:db8::"), v6("2001:db8::ffff"), 'c')]),
+        };
+        for (addr, expected) in [
+            ("1.2.3.0", Some('a')),
+            ("1.2.3.255", Some('a')),
+            ("1.2.4.0", None),
+            ("10.20.30.40", Some('b')),
+            // Mapped addresses are looked up as IPv4
+            ("::ffff:1.2.3.4", Some('a')),
+            ("::ffff:10.20.30.40", Some('b')),
+            ("::ffff:1.2.4.0", None),
+            ("2001:db8::", Some('c')),
+            ("2001:db8::ffff", Some('c')),
+            ("2001:db8::1:0", None),
+            // Only the mapped form is canonicalized, not 6to4 or NAT64
+            ("2002:102:304::", None),
+            ("64:ff9b::102:304", None),
+        ] {
+            assert_eq!(lookup(&map, addr), expected, "{addr}");
+        }
+    }
+
+    /// Keeps the range and the country code
+    fn parse_country(
+        record: csv_async::Result<csv_async::StringRecord>,
+    ) -> anyhow::Result<Option<(u128, u128, [u8; 2])>> {
+        let record = record?;
+        let country = record[2].as_bytes().try_into()?;
+        Ok(Some((record[0].parse()?, record[1].parse()?, country)))
+    }
+
+    async fn read_csv(data: &[u8]) -> RangeEntries<[u8; 2]> {
+        let mut entries = RangeEntries::default();
+        entries.read_csv(data, parse_country).await.unwrap();
+        entries
+    }
+
+    #[tokio::test]
+    async fn reads_plain_and_gzipped_csvs() {
+        let plain = std::fs::read(FIXTURE).unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&plain).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        assert!(gzipped.starts_with(&GZIP_MAGIC));
+
+        for data in [plain, gzipped] {
+            let entries = read_csv(&data).await;
+            // The first row is a record, not a header
+            assert_eq!(entries.counts.parsed, 4);
+            assert_eq!(entries.four[0], (v4("1.0.0.0"), v4("1.0.0.255"), *b"AU"));
+            let map = entries.into_map();
+            assert_eq!(lookup_country(&map, "1.0.0.1"), Some(*b"AU"));
+            assert_eq!(lookup_country(&map, "8.8.8.8"), Some(*b"US"));
+            assert_eq!(lookup_country(&map, "203.0.113.5"), Some(*b"GB"));
+            assert_eq!(lookup_country(&map, "203.0.114.0"), None);
+        }
+    }
+
+    fn lookup_country(map: &IpRangeMap<[u8; 2]>, addr: &str) -> Option<[u8; 2]> {
+        map.get(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn parse_source() {
+        for url in [
+            "https://example.com/geolite2-city-ipv4-num.csv.gz",
+            "http://localhost:8080/city.csv",
+        ] {
+            assert!(matches!(CsvSource::parse(url), CsvSource::Url(_)), "{url}");
+        }
+        for path in [
+            "/var/lib/geo/city.csv",
+            "geo/city.csv.gz",
+            "city.csv",
+            "file:///var/lib/geo/city.csv",
+            "ftp://example.com/city.csv",
+            "C:\\geo\\city.csv",
+        ] {
+            assert!(
+                matches!(CsvSource::parse(path), CsvSource::File(parsed) if parsed == Path::new(path)),
+                "{path}"
+            );
+        }
+    }
+
+    /// Paused, so that the retries don't take real time
+    #[tokio::test(start_paused = true)]
+    async fn missing_file_fails() {
+        let missing = CsvSource::File(PathBuf::from("/nonexistent/city.csv"));
+        let sources = [CsvSource::File(FIXTURE.into()), missing];
+        let (map, failed) = IpRangeMap::load_from_csvs(&sources, parse_country, true).await;
+        assert_eq!(failed.len(), 1);
+        assert!(
+            matches!(&failed[0], CsvSource::File(path) if path == Path::new("/nonexistent/city.csv"))
+        );
+        // The rest of the sources are still loaded
+        assert_eq!(map.len(), 4);
+        assert_eq!(lookup_country(&map, "8.8.8.8"), Some(*b"US"));
+    }
+}
@@ -47,6 +47,9 @@ impl MinecraftPacketRead for Cursor<&[u8]> {
         let mut position = 0;
 
         loop {
+            if !self.has_remaining() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
             let current = self.get_u8() as i32;
             value |= (current & VARINT_SEGMENT_BITS) << position;
 
@@ -1,30 +1,64 @@
 use linked_hash_set::LinkedHashSet;
 use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
 use std::hash::Hash;
 
+#[cfg(test)]
+pub mod alloc_tracker;
+pub mod asn_map;
+pub mod geo_lookup;
 pub mod ip_info;
 pub mod ip_info_map;
+pub mod ip_range_map;
 pub mod java_util;
 pub mod mc_packet;
 pub mod range_map;
 
+/// Hides a value from [Debug] output, such as the config dump logged at startup
+#[derive(Clone)]
+pub struct Redacted<T>(pub T);
+
+impl<T> Debug for Redacted<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
 pub fn copy_to_fixed_size<T: Default + Copy, const N: usize>(data: &[T]) -> [T; N] {
     let mut result = [T::default(); N];
     result.copy_from_slice(data);
     result
 }
 
+/// Maps with fewer than this many slots are never shrunk
+const MIN_SHRINK_CAPACITY: usize = 64;
+
+/// Shrinks a map that is less than a quarter full down to twice its length, so that capacity from
+/// a traffic spike isn't kept forever. Values are moved, not dropped. Returns how many slots were
+/// reclaimed.
+pub fn shrink_if_sparse<K: Hash + Eq, V>(map: &mut HashMap<K, V>) -> usize {
+    let capacity = map.capacity();
+    if capacity <= MIN_SHRINK_CAPACITY || map.len() * 4 >= capacity {
+        return 0;
+    }
+    map.shrink_to(map.len() * 2);
+    capacity - map.capacity()
+}
+
+/// Returns whether `b` was in `a`'s set
 pub fn remove_double_key<A: Hash + Eq, B: Hash + Eq>(
     map: &mut HashMap<A, LinkedHashSet<B>>,
     a: &A,
     b: &B,
-) {
-    if let Some(sub) = map.get_mut(a) {
-        sub.remove(b);
-        if sub.is_empty() {
-            map.remove(a);
-        }
+) -> bool {
+    let Some(sub) = map.get_mut(a) else {
+        return false;
+    };
+    let removed = sub.remove(b);
+    if sub.is_empty() {
+        map.remove(a);
     }
+    removed
 }
 
 pub fn add_with_circle_limit<Q: Hash + Eq>(
@@ -64,3 +98,46 @@ macro_rules! __invalid_data_impl {
         return std::io::Result::Err(std::io::Error::new(std::io::ErrorKind::InvalidData, $msg))
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn shrink_after_spike() {
+        let values: Vec<_> = (0..1000).map(Arc::new).collect();
+        let mut map: HashMap<_, _> = values.iter().map(|v| (**v, v.clone())).collect();
+        map.retain(|&k, _| k % 100 == 0);
+        let capacity = map.capacity();
+        assert!(capacity > MIN_SHRINK_CAPACITY && map.len() * 4 < capacity);
+
+        let reclaimed = shrink_if_sparse(&mut map);
+        assert_eq!(reclaimed, capacity - map.capacity());
+        assert!(map.capacity() >= map.len() * 2 && map.capacity() < capacity);
+        // The remaining values were moved, not dropped or cloned
+        assert_eq!(map.len(), 10);
+        for (key, value) in &map {
+            assert!(Arc::ptr_eq(value, &values[*key]));
+            assert_eq!(Arc::strong_count(value), 2);
+        }
+        assert_eq!(shrink_if_sparse(&mut map), 0);
+    }
+
+    #[test]
+    fn dense_and_small_maps_kept() {
+        let mut dense: HashMap<_, _> = (0..1000).map(|i| (i, i)).collect();
+        dense.retain(|&k, _| k % 2 == 0);
+        let capacity = dense.capacity();
+        assert!(dense.len() * 4 >= capacity);
+        assert_eq!(shrink_if_sparse(&mut dense), 0);
+        assert_eq!(dense.capacity(), capacity);
+
+        let mut small: HashMap<_, _> = HashMap::with_capacity(MIN_SHRINK_CAPACITY / 2);
+        small.insert(1, 1);
+        let capacity = small.capacity();
+        assert!(capacity <= MIN_SHRINK_CAPACITY);
+        assert_eq!(shrink_if_sparse(&mut small), 0);
+        assert_eq!(small.capacity(), capacity);
+    }
+}
@@ -1,5 +1,7 @@
-use log::error;
+use anyhow::bail;
+use std::collections::BinaryHeap;
 use std::fmt::Debug;
+use std::iter;
 
 pub struct RangeMap<K: Copy + Debug + Ord, V: Copy> {
     key: Vec<K>,
@@ -7,6 +9,32 @@ pub struct RangeMap<K: Copy + Debug + Ord, V: Copy> {
     len: usize,
 }
 
+/// Keys that ranges can be split at
+pub trait RangeKey: Copy + Debug + Ord {
+    fn checked_next(self) -> Option<Self>;
+    fn checked_prev(self) -> Option<Self>;
+}
+
+impl RangeKey for u32 {
+    fn checked_next(self) -> Option<Self> {
+        self.checked_add(1)
+    }
+
+    fn checked_prev(self) -> Option<Self> {
+        self.checked_sub(1)
+    }
+}
+
+impl RangeKey for u128 {
+    fn checked_next(self) -> Option<Self> {
+        self.checked_add(1)
+    }
+
+    fn checked_prev(self) -> Option<Self> {
+        self.checked_sub(1)
+    }
+}
+
 impl<K: Copy + Debug + Ord, V: Copy> RangeMap<K, V> {
     pub fn new() -> Self {
         Self {
@@ -20,29 +48,59 @@ impl<K: Copy + Debug + Ord, V: Copy> RangeMap<K, V> {
         self.len
     }
 
+    pub fn clear(&mut self) {
+        self.key.clear();
+        self.value.clear();
+        self.len = 0;
+    }
+
+    /// Makes room for at least `additional` more ranges
+    pub fn reserve(&mut self, additional: usize) {
+        self.key.reserve(additional * 2);
+        self.value.reserve(additional);
+    }
+
     pub fn shrink_to_fit(&mut self) {
         self.key.shrink_to_fit();
         self.value.shrink_to_fit();
     }
 
-    pub fn put(&mut self, min: K, max: K, value: V) {
-        if self.len > 0 {
-            let key_index = self.len << 1;
-            let prev_index = key_index - 2;
-            let prev_max = self.key[prev_index + 1];
-            if min <= prev_max {
-                let prev_min = self.key[prev_index];
-                error!(
-                    "Range {min:?}..={max:?} isn't greater than previous max range {prev_min:?}..={prev_max:?}"
-                );
-                return;
-            }
-        }
+    /// Only for ranges that are greater than every range already in the map
+    fn push(&mut self, min: K, max: K, value: V) {
         self.key.extend_from_slice(&[min, max]);
         self.value.push(value);
         self.len += 1;
     }
 
+    /// Replaces the contents of this map with inclusive `(min, max, value)` ranges that are
+    /// already sorted and don't overlap, keeping its capacity. If they aren't, the map is left
+    /// empty.
+    pub fn replace_from_sorted(
+        &mut self,
+        ranges: impl IntoIterator<Item = (K, K, V)>,
+    ) -> anyhow::Result<()> {
+        self.clear();
+        let ranges = ranges.into_iter();
+        self.reserve(ranges.size_hint().0);
+        for (min, max, value) in ranges {
+            if min > max || self.key.last().is_some_and(|last| *last >= min) {
+                self.clear();
+                bail!("Range {min:?}..={max:?} is empty or out of order");
+            }
+            self.push(min, max, value);
+        }
+        Ok(())
+    }
+
+    /// Inclusive `(min, max, value)` ranges in order
+    #[allow(dead_code)] // Useful for inspecting and converting maps
+    pub fn iter(&self) -> impl Iterator<Item = (K, K, V)> + '_ {
+        self.key
+            .chunks_exact(2)
+            .zip(&self.value)
+            .map(|(key, value)| (key[0], key[1], *value))
+    }
+
     pub fn get(&self, key: &K) -> Option<V> {
         let index = self.key.binary_search(key).unwrap_or_else(|e| e);
         if (index & 1) == 1 || (index < (self.len << 1) && self.key[index] == *key) {
@@ -53,5 +111,301 @@ impl<K: Copy + Debug + Ord, V: Copy> RangeMap<K, V> {
     }
 }
 
-pub type U32ToU32RangeMap = RangeMap<u32, u32>;
-pub type U128ToU32RangeMap = RangeMap<u128, u32>;
+impl<K: RangeKey, V: Copy> RangeMap<K, V> {
+    /// Builds a map from inclusive `(min, max, value)` ranges in any order. Where ranges overlap,
+    /// the one that comes later in `entries` wins, and the earlier ones are split around it.
+    /// Ranges with `min > max` are empty and ignored.
+    pub fn from_unsorted(entries: Vec<(K, K, V)>) -> Self {
+        let mut map = Self::new();
+        map.rebuild_from_unsorted(entries);
+        map.shrink_to_fit();
+        map
+    }
+
+    /// Like [Self::from_unsorted], but replaces the contents of this map. Its capacity is kept, so
+    /// rebuilding with about the same number of ranges doesn't reallocate.
+    pub fn rebuild_from_unsorted(&mut self, entries: Vec<(K, K, V)>) {
+        self.replace_from_sorted(split_unsorted(entries))
+            .expect("Split ranges should be sorted");
+    }
+}
+
+/// Splits inclusive `(min, max, value)` ranges in any order into sorted ranges that don't overlap.
+/// See [RangeMap::from_unsorted].
+fn split_unsorted<K: RangeKey, V: Copy>(
+    entries: Vec<(K, K, V)>,
+) -> impl Iterator<Item = (K, K, V)> {
+    let mut values = Vec::with_capacity(entries.len());
+    let mut ranges = Vec::with_capacity(entries.len());
+    for (min, max, value) in entries {
+        if min <= max {
+            ranges.push((min, max, values.len()));
+        }
+        values.push(value);
+    }
+    // Stable, so ranges with the same min stay in input order
+    ranges.sort_by_key(|(min, _, _)| *min);
+
+    // (index, max) of the ranges covering the current position, latest entry on top. Ranges that
+    // have ended are only removed once they reach the top.
+    let mut active = BinaryHeap::new();
+    let mut next_range = 0;
+    // None once every range has been passed
+    let mut position = ranges.first().map(|(min, _, _)| *min);
+    // (min, max, index) of the piece that hasn't been returned yet, so that pieces of one entry
+    // split by a shorter earlier entry are joined back together
+    let mut pending: Option<(K, K, usize)> = None;
+    iter::from_fn(move || {
+        loop {
+            let Some(mut current) = position else {
+                return pending
+                    .take()
+                    .map(|(min, max, index)| (min, max, values[index]));
+            };
+            if active.is_empty() {
+                match ranges.get(next_range) {
+                    Some((min, _, _)) => current = current.max(*min),
+                    None => {
+                        position = None;
+                        continue;
+                    }
+                }
+            }
+            while let Some(&(min, max, index)) = ranges.get(next_range)
+                && min <= current
+            {
+                active.push((index, max));
+                next_range += 1;
+            }
+            while active.peek().is_some_and(|(_, max)| *max < current) {
+                active.pop();
+            }
+            let Some(&(index, max)) = active.peek() else {
+                position = Some(current);
+                continue;
+            };
+
+            // Stops where the next range starts, since it may take over from there
+            let end = match ranges.get(next_range) {
+                Some((next_min, _, _)) => max.min(next_min.checked_prev().unwrap()),
+                None => max,
+            };
+            position = end.checked_next();
+            match &mut pending {
+                Some((_, pending_end, pending_index))
+                    if *pending_index == index && pending_end.checked_next() == Some(current) =>
+                {
+                    *pending_end = end;
+                }
+                _ => {
+                    if let Some((min, max, index)) = pending.replace((current, end, index)) {
+                        return Some((min, max, values[index]));
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    type Ranges = [(u32, u32, char)];
+
+    /// Checks the ranges of `map`, and lookups just outside and at the edges of each of them
+    fn assert_ranges(map: &RangeMap<u32, char>, expected: &Ranges) {
+        assert_eq!(map.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(map.len(), expected.len());
+        let expected_at = |key: u32| {
+            expected
+                .iter()
+                .find(|(min, max, _)| (*min..=*max).contains(&key))
+                .map(|(_, _, value)| *value)
+        };
+        for &(min, max, _) in expected {
+            let keys = [min.checked_prev(), Some(min), Some(max), max.checked_next()];
+            for key in keys.into_iter().flatten() {
+                assert_eq!(map.get(&key), expected_at(key), "key {key}");
+            }
+        }
+    }
+
+    #[test]
+    fn split_unsorted() {
+        const MAX: u32 = u32::MAX;
+        let cases: &[(&Ranges, &Ranges)] = &[
+            // Later entries win on identical ranges
+            (&[(10, 20, 'a'), (10, 20, 'b')], &[(10, 20, 'b')]),
+            // Nested, in both orders
+            (
+                &[(10, 30, 'a'), (15, 20, 'b')],
+                &[(10, 14, 'a'), (15, 20, 'b'), (21, 30, 'a')],
+            ),
+            (&[(15, 20, 'b'), (10, 30, 'a')], &[(10, 30, 'a')]),
+            // Partial overlaps
+            (
+                &[(10, 20, 'a'), (15, 30, 'b')],
+                &[(10, 14, 'a'), (15, 30, 'b')],
+            ),
+            (
+                &[(15, 30, 'b'), (10, 20, 'a')],
+                &[(10, 20, 'a'), (21, 30, 'b')],
+            ),
+            // Adjacent ranges aren't merged, even with equal values
+            (
+                &[(10, 19, 'a'), (20, 29, 'a'), (30, 39, 'b')],
+                &[(10, 19, 'a'), (20, 29, 'a'), (30, 39, 'b')],
+            ),
+            // Reverse order, with gaps
+            (
+                &[(50, 59, 'c'), (30, 39, 'b'), (10, 19, 'a')],
+                &[(10, 19, 'a'), (30, 39, 'b'), (50, 59, 'c')],
+            ),
+            // Empty ranges are dropped without shifting the values after them
+            (
+                &[(10, 19, 'a'), (25, 20, 'b'), (30, 39, 'c')],
+                &[(10, 19, 'a'), (30, 39, 'c')],
+            ),
+            (&[(1, 0, 'a')], &[]),
+            (&[], &[]),
+            // The ends of the key space
+            (
+                &[(MAX - 10, MAX, 'b'), (0, 10, 'a')],
+                &[(0, 10, 'a'), (MAX - 10, MAX, 'b')],
+            ),
+            (
+                &[(0, MAX, 'a'), (0, 0, 'b'), (MAX, MAX, 'c')],
+                &[(0, 0, 'b'), (1, MAX - 1, 'a'), (MAX, MAX, 'c')],
+            ),
+            (
+                &[(0, 0, 'b'), (MAX, MAX, 'c'), (0, MAX, 'a')],
+                &[(0, MAX, 'a')],
+            ),
+            // A range split by several shorter ones, with one of them split too
+            (
+                &[(0, 100, 'a'), (10, 30, 'b'), (20, 25, 'c'), (50, 60, 'd')],
+                &[
+                    (0, 9, 'a'),
+                    (10, 19, 'b'),
+                    (20, 25, 'c'),
+                    (26, 30, 'b'),
+                    (31, 49, 'a'),
+                    (50, 60, 'd'),
+                    (61, 100, 'a'),
+                ],
+            ),
+        ];
+        for (entries, expected) in cases {
+            let map = RangeMap::from_unsorted(entries.to_vec());
+            assert_ranges(&map, expected);
+        }
+    }
+
+    #[test]
+    fn clear_then_rebuild() {
+        let mut map = RangeMap::from_unsorted(vec![(10, 19, 'a'), (30, 39, 'b')]);
+        map.clear();
+        assert_ranges(&map, &[]);
+        assert_eq!(map.get(&10), None);
+
+        map.rebuild_from_unsorted(vec![(15, 34, 'c'), (0, 9, 'd')]);
+        assert_ranges(&map, &[(0, 9, 'd'), (15, 34, 'c')]);
+        map.rebuild_from_unsorted(vec![(15, 34, 'c'), (20, 20, 'e')]);
+        assert_ranges(&map, &[(15, 19, 'c'), (20, 20, 'e'), (21, 34, 'c')]);
+    }
+
+    #[test]
+    fn replace_from_sorted_rejects_unsorted() {
+        for ranges in [
+            // Overlapping
+            vec![(10, 20, 'a'), (20, 30, 'b')],
+            // Out of order
+            vec![(30, 39, 'b'), (10, 19, 'a')],
+            // Empty
+            vec![(10, 19, 'a'), (25, 20, 'b')],
+        ] {
+            let mut map = RangeMap::from_unsorted(vec![(0, 5, 'z')]);
+            assert!(
+                map.replace_from_sorted(ranges.clone()).is_err(),
+                "{ranges:?}"
+            );
+            assert_ranges(&map, &[]);
+            assert_eq!(map.get(&0), None);
+            assert_eq!(map.get(&10), None);
+        }
+
+        let mut map = RangeMap::new();
+        map.replace_from_sorted([(0, 9, 'a'), (10, 19, 'b'), (u32::MAX, u32::MAX, 'c')])
+            .unwrap();
+        assert_ranges(
+            &map,
+            &[(0, 9, 'a'), (10, 19, 'b'), (u32::MAX, u32::MAX, 'c')],
+        );
+    }
+
+    #[test]
+    fn rebuild_reuses_capacity() {
+        let ranges = |offset: u32| {
+            (0..1000)
+                .rev()
+                .map(|i| (i * 10 + offset, i * 10 + offset + 5, 'a'))
+                .collect::<Vec<_>>()
+        };
+        let mut map = RangeMap::new();
+        map.rebuild_from_unsorted(ranges(0));
+        let key_capacity = map.key.capacity();
+        let value_capacity = map.value.capacity();
+        let key_pointer = map.key.as_ptr();
+        for offset in 1..4 {
+            map.rebuild_from_unsorted(ranges(offset));
+            assert_eq!(map.len(), 1000);
+            assert_eq!(map.get(&(offset + 5)), Some('a'));
+            assert_eq!(map.get(&(offset + 6)), None);
+            assert_eq!(map.key.capacity(), key_capacity);
+            assert_eq!(map.value.capacity(), value_capacity);
+            assert_eq!(map.key.as_ptr(), key_pointer);
+        }
+
+        // replace_from_sorted reserves from the size hint up front
+        let mut map = RangeMap::new();
+        map.replace_from_sorted((0..1000).map(|i| (i * 2, i * 2, 'a')))
+            .unwrap();
+        let key_capacity = map.key.capacity();
+        assert!(key_capacity >= 2000);
+        map.replace_from_sorted((0..1000).map(|i| (i * 3, i * 3, 'b')))
+            .unwrap();
+        assert_eq!(map.key.capacity(), key_capacity);
+        assert_eq!(map.get(&2997), Some('b'));
+    }
+
+    proptest! {
+        /// Compares against the latest entry covering each key. Values are entry indices, so
+        /// pieces of one entry must also have been joined back together.
+        #[test]
+        fn split_matches_latest_entry(
+            entries in prop::collection::vec((0..64u32, 0..64u32), 0..16),
+        ) {
+            let entries = entries
+                .into_iter()
+                .enumerate()
+                .map(|(index, (min, max))| (min, max, index))
+                .collect::<Vec<_>>();
+            let map = RangeMap::from_unsorted(entries.clone());
+            for key in 0..70 {
+                let expected = entries
+                    .iter()
+                    .rev()
+                    .find(|(min, max, _)| (*min..=*max).contains(&key))
+                    .map(|(_, _, index)| *index);
+                prop_assert_eq!(map.get(&key), expected, "key {}", key);
+            }
+            let ranges = map.iter().collect::<Vec<_>>();
+            for pair in ranges.windows(2) {
+                prop_assert!(pair[0].1 < pair[1].0);
+                prop_assert!(pair[0].2 != pair[1].2 || pair[0].1 + 1 != pair[1].0);
+            }
+        }
+    }
+}
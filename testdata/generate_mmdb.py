@@ -0,0 +1,181 @@
+#!/usr/bin/env python3
+"""Writes the small MaxMind DB files in this directory that the maxminddb feature's tests use.
+
+city-test.mmdb is a GeoIP2-City database and country-test.mmdb is a GeoIP2-Country database, both
+with IPv6 search trees. IPv4 networks are only stored under ::/96, so an IPv4-mapped address is only
+found if it's looked up as IPv4. Run from anywhere to regenerate them.
+"""
+
+import ipaddress
+import os
+import struct
+
+RECORD_SIZE = 24
+METADATA_MARKER = b"\xab\xcd\xefMaxMind.com"
+
+
+class U16(int):
+    pass
+
+
+class U64(int):
+    pass
+
+
+def control(type_id, size):
+    first = (type_id << 5) if type_id <= 7 else 0
+    if size < 29:
+        first |= size
+        extra = b""
+    elif size < 285:
+        first |= 29
+        extra = bytes([size - 29])
+    elif size < 65821:
+        first |= 30
+        extra = (size - 285).to_bytes(2, "big")
+    else:
+        first |= 31
+        extra = (size - 65821).to_bytes(3, "big")
+    out = bytes([first])
+    if type_id > 7:
+        out += bytes([type_id - 7])
+    return out + extra
+
+
+def encode_uint(type_id, value):
+    data = value.to_bytes((value.bit_length() + 7) // 8, "big")
+    return control(type_id, len(data)) + data
+
+
+def encode(value):
+    if isinstance(value, bool):
+        return control(14, int(value))
+    if isinstance(value, U16):
+        return encode_uint(5, value)
+    if isinstance(value, U64):
+        return encode_uint(9, value)
+    if isinstance(value, int):
+        return encode_uint(6, value)
+    if isinstance(value, float):
+        return control(3, 8) + struct.pack(">d", value)
+    if isinstance(value, str):
+        data = value.encode()
+        return control(2, len(data)) + data
+    if isinstance(value, dict):
+        out = control(7, len(value))
+        for key, item in value.items():
+            out += encode(key) + encode(item)
+        return out
+    if isinstance(value, list):
+        return control(11, len(value)) + b"".join(encode(item) for item in value)
+    raise TypeError(value)
+
+
+def network_bits(network):
+    network = ipaddress.ip_network(network)
+    if network.version == 4:
+        address = int(network.network_address)
+        prefix = network.prefixlen + 96
+    else:
+        address = int(network.network_address)
+        prefix = network.prefixlen
+    return [(address >> (127 - i)) & 1 for i in range(prefix)]
+
+
+def write_database(path, database_type, records):
+    # Each node is [left, right], where a record is ("node", index), ("data", offset) or None
+    nodes = [[None, None]]
+    data = b""
+    for network, record in records:
+        offset = len(data)
+        data += encode(record)
+        bits = network_bits(network)
+        node = 0
+        for bit in bits[:-1]:
+            child = nodes[node][bit]
+            if child is None:
+                nodes.append([None, None])
+                child = ("node", len(nodes) - 1)
+                nodes[node][bit] = child
+            assert child[0] == "node", f"{network} overlaps another network"
+            node = child[1]
+        assert nodes[node][bits[-1]] is None, f"{network} overlaps another network"
+        nodes[node][bits[-1]] = ("data", offset)
+
+    node_count = len(nodes)
+
+    def record_value(record):
+        if record is None:
+            return node_count
+        kind, value = record
+        return value if kind == "node" else node_count + 16 + value
+
+    tree = b"".join(
+        record_value(left).to_bytes(3, "big") + record_value(right).to_bytes(3, "big")
+        for left, right in nodes
+    )
+    metadata = {
+        "binary_format_major_version": U16(2),
+        "binary_format_minor_version": U16(0),
+        "build_epoch": U64(1700000000),
+        "database_type": database_type,
+        "description": {"en": f"{database_type} test database for world-host-server"},
+        "ip_version": U16(6),
+        "languages": ["en"],
+        "node_count": node_count,
+        "record_size": U16(RECORD_SIZE),
+    }
+    with open(path, "wb") as file:
+        file.write(tree + bytes(16) + data + METADATA_MARKER + encode(metadata))
+
+
+def country(iso_code, name):
+    return {"iso_code": iso_code, "names": {"en": name}}
+
+
+def main():
+    directory = os.path.dirname(os.path.abspath(__file__))
+    write_database(
+        os.path.join(directory, "city-test.mmdb"),
+        "GeoIP2-City",
+        [
+            (
+                "81.2.69.160/27",
+                {
+                    "city": {"names": {"en": "London"}},
+                    "country": country("GB", "United Kingdom"),
+                    "location": {
+                        "accuracy_radius": U16(100),
+                        "latitude": 51.5142,
+                        "longitude": -0.0931,
+                    },
+                },
+            ),
+            (
+                "89.160.20.112/28",
+                {
+                    "city": {"names": {"en": "Linköping"}},
+                    "country": country("SE", "Sweden"),
+                    "location": {"latitude": 58.4167, "longitude": 15.6167},
+                },
+            ),
+            (
+                "2001:218::/32",
+                {
+                    "country": country("JP", "Japan"),
+                    "location": {"latitude": 35.68536, "longitude": 139.75309},
+                },
+            ),
+            # A country without a location
+            ("1.2.3.0/24", {"country": country("AU", "Australia")}),
+        ],
+    )
+    write_database(
+        os.path.join(directory, "country-test.mmdb"),
+        "GeoIP2-Country",
+        [("81.2.69.160/27", {"country": country("GB", "United Kingdom")})],
+    )
+
+
+if __name__ == "__main__":
+    main()